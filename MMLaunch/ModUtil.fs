@@ -32,6 +32,8 @@ module ModUtil =
         VertDeclPath: string
         ExpectedPrimCount: int
         ExpectedVertCount: int
+        // hex string, null if the snapshot didn't have one (omitted from the yaml)
+        GeomFingerprint: string
     }
 
     type YamlMod = {
@@ -183,6 +185,11 @@ mods:"""
                 File.Copy(refMMObjFile,modMMObjFile)
                 modMMObjFile
 
+            // the geometry fingerprint is written by native code for d3d11 snapshots
+            let fingerprint =
+                let fpSrc = Path.Combine(snapSrcDir, srcBasename + "_fingerprint.txt")
+                if File.Exists(fpSrc) then File.ReadAllText(fpSrc).Trim() else null
+
             // generate ref yaml
             let refYamlFile = 
                 let refYamlFile = Path.Combine(modOutDir, refBasename + ".yaml")
@@ -192,6 +199,7 @@ mods:"""
                     YamlRef.VertDeclPath = Path.GetFileName(vbDeclFile)
                     YamlRef.ExpectedPrimCount = pCount
                     YamlRef.ExpectedVertCount = vCount
+                    YamlRef.GeomFingerprint = fingerprint
                 }
                 let sr = new Serializer()
                 use sw = new StreamWriter(refYamlFile)
@@ -265,6 +265,9 @@ module CoreTypes =
         Mesh : Mesh
         PrimCount: int
         VertCount: int
+        /// Geometry fingerprint recorded when the reference was snapshotted (a hash of the vertex layout computed
+        /// by native code).  Used to tell apart meshes with the same prim and vert count.  0 if unknown.
+        Fingerprint: uint32
     }
 
     /// Storage for a Deletion mod.
@@ -62,6 +62,8 @@ module InteropTypes =
         DeclSizeBytes: int
         VertSizeBytes: int
         IndexElemSizeBytes: int
+        /// Geometry fingerprint of the reference, 0 if it doesn't have one
+        RefFingerprint: uint32
         // official end of "mod numbers" in native struct
         UpdateTangentSpace: int
         // Size must match MaxModTexPathLen from native code
@@ -93,6 +95,7 @@ module InteropTypes =
         DeclSizeBytes = 0
         VertSizeBytes = 0
         IndexElemSizeBytes = 0
+        RefFingerprint = 0u
         Tex0Path = ""
         Tex1Path = ""
         Tex2Path = ""
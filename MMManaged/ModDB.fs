@@ -355,6 +355,16 @@ module ModDB =
         // for determining mod substitution
         let pCount = defaultArg (node |> Yaml.getOptionalValue "ExpectedPrimCount" |> Yaml.toOptionalInt) mesh.Triangles.Length
         let vCount = defaultArg (node |> Yaml.getOptionalValue "ExpectedVertCount" |> Yaml.toOptionalInt) mesh.Positions.Length
+        // optional geometry fingerprint (hex), only checked by native code when a draw matches the counts
+        let fingerprint =
+            match node |> Yaml.getOptionalValue "GeomFingerprint" |> Yaml.toOptionalString with
+            | None -> 0u
+            | Some s when s.Trim() = "" -> 0u
+            | Some s ->
+                try
+                    Convert.ToUInt32(s.Trim(), 16)
+                with
+                    | _ -> failwithf "Invalid GeomFingerprint in %s: %s" filename s
 
 //        let sw = new Util.StopwatchTracker("apply transforms: " + filename)
 //        let mesh = applyMeshTransforms (getMeshTransforms node) mesh
@@ -369,6 +379,7 @@ module ModDB =
               Mesh = mesh
               PrimCount = pCount
               VertCount = vCount
+              Fingerprint = fingerprint
             })
 
     /// Load a file.  Supported types are yaml and mmobj files.  Other types (such as binary vertex data), cannot be
@@ -416,6 +427,7 @@ module ModDB =
                   // override values for these can only come from yaml, so since we don't have a yaml file, just use the mesh values
                   PrimCount = mesh.Triangles.Length
                   VertCount = mesh.Positions.Length
+                  Fingerprint = 0u
                 })]
         | _ -> failwithf "Don't know how to load: %s" filename
 
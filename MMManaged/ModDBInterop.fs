@@ -258,6 +258,7 @@ module ModDBInterop =
             DeclSizeBytes = declSizeBytes
             VertSizeBytes = vertSizeBytes
             IndexElemSizeBytes = indexElemSizeBytes
+            RefFingerprint = meshrel.DBRef.Fingerprint
             Tex0Path = modm.Tex0Path
             Tex1Path = modm.Tex1Path
             Tex2Path = modm.Tex2Path
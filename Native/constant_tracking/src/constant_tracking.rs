@@ -10,7 +10,7 @@ use shared_dx::util;
 
 
 pub use std::collections::HashMap;
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 
 /*
 The memory model for these constants, from empirical tests as the documentation is sparse,
@@ -26,7 +26,7 @@ case since I'm only interested in the constants that the game has set explicitly
 So I use Option types to track whether a particular constant was set to any value explicitly.
 */
 
-#[derive(Debug,PartialEq,Serialize,Copy,Clone)]
+#[derive(Debug,PartialEq,Serialize,Deserialize,Copy,Clone)]
 pub struct Vec4<T: Serialize> {
     a: T,
     b: T,
@@ -34,6 +34,15 @@ pub struct Vec4<T: Serialize> {
     d: T
 }
 
+impl<T: Serialize + Copy> Vec4<T> {
+    pub fn new(a:T, b:T, c:T, d:T) -> Self {
+        Self { a, b, c, d }
+    }
+    pub fn to_array(&self) -> [T; 4] {
+        [self.a, self.b, self.c, self.d]
+    }
+}
+
 
 pub fn vecToVec4<T>(vec:&Vec<T>, offset: usize) -> Vec4<T>
 where T: Copy + serde::Serialize {
@@ -1,5 +1,6 @@
 
 use shared_dx::types::DevicePointer;
+use shared_dx::frame_stats::FrameStats;
 use types::TexPtr;
 pub use winapi::shared::d3d9::*;
 pub use winapi::shared::d3d9types::*;
@@ -7,7 +8,7 @@ pub use winapi::shared::minwindef::*;
 pub use winapi::shared::windef::{HWND, RECT};
 pub use winapi::shared::winerror::{E_FAIL, S_OK};
 pub use winapi::um::winnt::{HRESULT, LPCWSTR};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use std::fmt;
 use fnv::FnvHashMap;
 use fnv::FnvHashSet;
@@ -43,12 +44,14 @@ pub struct FrameMetrics {
     pub dip_calls: u32,
     pub frames: u32,
     pub total_frames: u64,
-    pub last_call_log: SystemTime,
-    pub last_frame_log: SystemTime,
+    pub last_call_log: Option<Instant>,
+    pub last_frame_log: Option<Instant>,
     pub last_fps: f64,
-    pub last_fps_update: SystemTime,
+    pub last_fps_update: Option<Instant>,
     pub low_framerate: bool,
     pub rendered_prims: Vec<RenderedPrimType>,
+    /// Frame time and draw call percentiles, reported with the other metrics
+    pub frame_stats: FrameStats,
 }
 
 pub type LoadedModsMap = FnvHashMap<u32, Vec<native_mod::NativeModData>>;
@@ -69,9 +72,34 @@ pub struct ClrState {
     pub run_context: String,
 }
 
+/// How the vert count used to key d3d11 mods is computed.  Set per game with the profile
+/// dword `GameProfileD3D11KeyMode`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum D3D11KeyMode {
+    /// Byte width of the vertex buffer divided by the vertex size.  Meshes that share a vertex
+    /// buffer all get the same vert count.
+    BufferSize,
+    /// Number of vertices between the lowest and highest index used by the draw.  Requires the
+    /// index data to be precopied; draws without it fall back to `BufferSize`.
+    IndexRange,
+}
+
+impl D3D11KeyMode {
+    pub fn from_dword(v:u32) -> Self {
+        match v {
+            1 => D3D11KeyMode::IndexRange,
+            _ => D3D11KeyMode::BufferSize,
+        }
+    }
+}
+
 pub struct RunConf {
     pub precopy_data: bool,
+    /// Limits for the precopied data, see `shared_dx::precopy_store`
+    pub precopy_budget_mb: u32,
+    pub precopy_max_age_secs: u32,
     pub force_tex_cpu_read: bool,
+    pub d3d11_key_mode: D3D11KeyMode,
 }
 pub struct HookState {
     pub run_conf: RunConf,
@@ -98,7 +126,10 @@ pub struct HookState {
     pub selected_on_stage: [bool; MAX_STAGE],
     pub curr_texture_index: usize,
     pub is_snapping: bool,
+    /// Wall clock time the snapshot started, used to name the session in the manifest
     pub snap_start: SystemTime,
+    /// Monotonic time the snapshot started, used for the snapshot window
+    pub snap_start_instant: Option<Instant>,
     pub d3dx_fn: Option<d3dx::D3DXFn>,
     pub device: Option<DevicePointer>,
     pub metrics: FrameMetrics,
@@ -136,7 +167,10 @@ lazy_static! {
 pub static mut GLOBAL_STATE: HookState = HookState {
     run_conf: RunConf {
         precopy_data: false,
+        precopy_budget_mb: shared_dx::precopy_store::DEF_PRECOPY_BUDGET_MB,
+        precopy_max_age_secs: shared_dx::precopy_store::DEF_PRECOPY_MAX_AGE_SECS,
         force_tex_cpu_read: false,
+        d3d11_key_mode: D3D11KeyMode::BufferSize,
     },
     clr: { ClrState { runtime_pointer: None, run_context: String::new() } },
     interop_state: None,
@@ -157,6 +191,7 @@ pub static mut GLOBAL_STATE: HookState = HookState {
     curr_texture_index: 0,
     is_snapping: false,
     snap_start: std::time::UNIX_EPOCH,
+    snap_start_instant: None,
     vertex_constants: None,
     pixel_constants: None,
     anim_snap_state: None,
@@ -166,12 +201,13 @@ pub static mut GLOBAL_STATE: HookState = HookState {
         dip_calls: 0,
         frames: 0,
         total_frames: 0,
-        last_call_log: std::time::UNIX_EPOCH,
-        last_frame_log: std::time::UNIX_EPOCH,
-        last_fps_update: std::time::UNIX_EPOCH,
+        last_call_log: None,
+        last_frame_log: None,
+        last_fps_update: None,
         last_fps: 120.0,
         low_framerate: false,
         rendered_prims: vec![],
+        frame_stats: FrameStats::new(),
     }
 };
 
@@ -0,0 +1,91 @@
+//! Runs commands from a file, so that scripts can drive the hook (e.g. to take snapshots
+//! automatically).  If the `CommandFile` root registry value is 1, the render thread checks about
+//! once a second for `commands.$ExeBaseName.txt` in the log dir.  When it exists the file is
+//! deleted and its commands are run in order; the format is described in `types::command`.
+//!
+//! The file is deleted before the commands run so that they only run once; if it can't be
+//! deleted, nothing is run.  Scripts should write the file under a different name and rename it.
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use shared_dx::clock;
+use shared_dx::types::DevicePointer;
+use shared_dx::util::write_log_file;
+use types::command::parse_command_list;
+
+use crate::input_commands;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct CommandFile {
+    /// None if the command file is disabled
+    path: Option<String>,
+    last_poll: Option<Instant>,
+}
+
+thread_local! {
+    static COMMAND_FILE: RefCell<Option<CommandFile>> = RefCell::new(None);
+}
+
+/// Returns the command file path if enabled.  The registry is only read once.
+fn command_file_path() -> Option<String> {
+    match unsafe { util::reg_query_root_dword("CommandFile") } {
+        Ok(1) => {},
+        _ => return None,
+    }
+    let logpath = shared_dx::util::get_log_file_path();
+    let dir = std::path::Path::new(&logpath).parent()?.to_string_lossy().to_string();
+    let basen = util::get_module_name_base().unwrap_or_else(|_| "unknown".to_owned());
+    let path = format!("{}\\commands.{}.txt", dir, basen);
+    write_log_file(&format!("watching for command file {}", path));
+    Some(path)
+}
+
+/// Read and delete the command file, if there is one.
+fn take_commands(path: &str) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    if let Err(e) = std::fs::remove_file(path) {
+        write_log_file(&format!("ERROR: can't delete command file {}, ignoring it: {:?}", path, e));
+        return None;
+    }
+    Some(text)
+}
+
+/// Run the commands in the command file if it exists.  Must be called from the render thread.
+/// `device` is only called if there are commands to run.
+pub fn poll<F>(device: F) where F: FnOnce() -> Option<DevicePointer> {
+    let path = COMMAND_FILE.with(|cf| {
+        let mut cf = cf.borrow_mut();
+        let cf = cf.get_or_insert_with(|| CommandFile { path: command_file_path(), last_poll: None });
+        // disabled, don't bother with the clock
+        let path = cf.path.as_ref()?;
+        let now = clock::now();
+        match clock::since(now, cf.last_poll) {
+            Some(elapsed) if elapsed < POLL_INTERVAL => return None,
+            _ => cf.last_poll = Some(now),
+        }
+        Some(path.clone())
+    });
+    let text = match path.as_deref().and_then(take_commands) {
+        Some(text) => text,
+        None => return,
+    };
+    let (commands, errors) = parse_command_list(&text);
+    for e in errors.iter() {
+        write_log_file(&format!("command file: {}", e));
+    }
+    if commands.is_empty() {
+        return;
+    }
+    let device = match device() {
+        Some(d) => d,
+        None => {
+            write_log_file("command file: no device, commands ignored");
+            return;
+        }
+    };
+    for cmd in commands {
+        write_log_file(&format!("command file: running {}", cmd));
+        input_commands::run_command(cmd, device);
+    }
+}
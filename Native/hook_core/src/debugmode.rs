@@ -1,5 +1,6 @@
-use std::{time::SystemTime, cell::{RefCell, RefMut}, fmt::Debug};
+use std::{time::Instant, cell::{RefCell, RefMut}, fmt::Debug};
 
+use shared_dx::clock;
 use shared_dx::util::write_log_file;
 
 #[allow(non_camel_case_types)]
@@ -9,6 +10,7 @@ pub enum DebugModeCalledFns {
     Hook_ContextRelease,
     Hook_ContextVSSetConstantBuffers,
     Hook_ContextDrawIndexed,
+    Hook_ContextDrawIndexedInstanced,
     Hook_ContextIASetVertexBuffers,
     Hook_ContextIASetInputLayout,
     Hook_ContextIASetPrimitiveTopology,
@@ -38,7 +40,7 @@ pub struct DebugMode {
 
     draw_hooked: bool,
     call_counts: [u64; 100],
-    start_time: Option<SystemTime>,
+    start_time: Option<Instant>,
     rehook_enabled: [bool; 100],
 }
 
@@ -69,12 +71,7 @@ pub fn log(msg:&str) {
 
 /// Return seconds since debug mode was initialized.
 pub fn seconds_since_start(dm:&RefMut<DebugMode>) -> u64 {
-    if let Some(start_time) = dm.start_time {
-        let since_start = SystemTime::now().duration_since(start_time).unwrap_or_else(|_| std::time::Duration::from_millis(0));
-        since_start.as_secs()
-    } else {
-        0
-    }
+    clock::since(clock::now(), dm.start_time).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 /// Check if debug mode is enabled and Init it if so.  This check is done by looking for
@@ -90,7 +87,7 @@ pub fn check_init(mmroot:&str) {
         DEBUG_MODE.with(|dm| {
             let mut dm = dm.borrow_mut();
             dm.enabled = true;
-            dm.start_time = Some(SystemTime::now());
+            dm.start_time = Some(clock::now());
             write_log_file(&format!("DebugMode: enabled, init will be slower. remove/rename {} to disable it", dmfile));
             match std::fs::read_to_string(path) {
                 Ok(lines) => {
@@ -213,6 +210,7 @@ pub fn rehook_enabled(fn_id: DebugModeCalledFns) -> bool {
                 DebugModeCalledFns::Hook_ContextIASetPrimitiveTopology if secs_since_start > 50 => true,
                 DebugModeCalledFns::Hook_ContextVSSetConstantBuffers if secs_since_start > 90 => true,
                 DebugModeCalledFns::Hook_ContextDrawIndexed if secs_since_start > 120 => true,
+                DebugModeCalledFns::Hook_ContextDrawIndexedInstanced if secs_since_start > 120 => true,
                 _ => false,
             };
             if fn_enabled {
@@ -47,13 +47,12 @@ use std::ptr::null_mut;
 use std::sync::RwLock;
 use std::sync::RwLockReadGuard;
 use std::sync::atomic::Ordering;
-use std::time::SystemTime;
 
 use winapi::Interface;
 use winapi::ctypes::c_void;
 use winapi::shared::{basetsd::SIZE_T, dxgiformat::DXGI_FORMAT, guiddef::GUID,
     winerror::{E_NOINTERFACE, HRESULT, E_FAIL},
-    dxgi::{IDXGIAdapter, DXGI_SWAP_CHAIN_DESC, IDXGISwapChain}, minwindef::{FARPROC, HMODULE, UINT}};
+    dxgi::{IDXGIAdapter, DXGI_SWAP_CHAIN_DESC, IDXGISwapChain, IDXGISwapChainVtbl}, minwindef::{FARPROC, HMODULE, UINT}};
 use winapi::um::d3d11::D3D11_CPU_ACCESS_READ;
 use winapi::um::d3d11::D3D11_TEXTURE2D_DESC;
 use winapi::um::d3d11::ID3D11Texture2D;
@@ -68,14 +67,15 @@ use crate::debugmode;
 use crate::debugmode::DebugModeCalledFns;
 use crate::hook_device::{load_d3d_lib, init_device_state_once, init_log};
 use shared_dx::{util::write_log_file,
-    types_dx11::{HookDirect3D11, HookDirect3D11Context, HookDirect3D11Device},
+    types_dx11::{HookDirect3D11, HookDirect3D11Context, HookDirect3D11Device, HookDXGISwapChain},
     types::{HookDeviceState, HookD3D11State, DX11Metrics, DevicePointer},
-    error::*, dx11rs::{DX11RenderState, VertexFormat}};
+    error::*, dx11rs::{DX11RenderState, VertexFormat}, precopy_store::BufferKind, clock};
 use util::mm_verify_load;
 use device_state::{DEVICE_STATE, dev_state_d3d11_nolock, dev_state_d3d11_write};
 use crate::hook_render_d3d11::*;
 
 static mut DEVICE_REALFN: RwLock<Option<HookDirect3D11Device>> = RwLock::new(None);
+static SWAPCHAIN_REALFN: RwLock<Option<HookDXGISwapChain>> = RwLock::new(None);
 
 use global_state::{GLOBAL_STATE, GLOBAL_STATE_LOCK};
 
@@ -282,7 +282,7 @@ const TRACK_REHOOK_TIME:bool = false;
 pub unsafe fn apply_context_hooks(context:*mut ID3D11DeviceContext, first_hook:bool) -> Result<i32> {
     let rehook_start =
         if TRACK_REHOOK_TIME {
-            Some(SystemTime::now())
+            Some(clock::now())
         } else {
             None
         };
@@ -365,6 +365,10 @@ pub unsafe fn apply_context_hooks(context:*mut ID3D11DeviceContext, first_hook:b
         (*vtbl).DrawIndexed = hook_draw_indexed;
         func_hooked += 1;
     }
+    if debugmode::draw_hook_enabled() && (*vtbl).DrawIndexedInstanced as usize != hook_draw_indexed_instanced as usize {
+        (*vtbl).DrawIndexedInstanced = hook_draw_indexed_instanced;
+        func_hooked += 1;
+    }
     if (*vtbl).IASetVertexBuffers as usize != hook_IASetVertexBuffers as usize {
         (*vtbl).IASetVertexBuffers = hook_IASetVertexBuffers;
         func_hooked += 1;
@@ -383,9 +387,7 @@ pub unsafe fn apply_context_hooks(context:*mut ID3D11DeviceContext, first_hook:b
     }
 
     if TRACK_REHOOK_TIME {
-        let now = SystemTime::now();
-        let elapsed = now.duration_since(
-            rehook_start.unwrap_or(SystemTime::UNIX_EPOCH));
+        let elapsed = clock::since(clock::now(), rehook_start);
         let _ = elapsed.map(|dur| {
             let nanos = dur.subsec_nanos() as u64 + dur.as_secs() * 1_000_000_000;
             dev_state_d3d11_nolock().map(|state| {
@@ -604,11 +606,74 @@ pub unsafe fn apply_device_hook(device:*mut ID3D11Device) -> Result<()> {
     Ok(())
 }
 
-unsafe fn hook_d3d11(device:*mut ID3D11Device,_swapchain:*mut IDXGISwapChain, context:*mut ID3D11DeviceContext) ->
+/// Hook Present on the swap chain.  This is only used to count frames (for snapshot windows), so
+/// failure here isn't fatal.  Like the device, the vtable is modified in place, so this hooks
+/// every swap chain of the same type.
+pub unsafe fn apply_swapchain_hook(swapchain:*mut IDXGISwapChain) -> Result<()> {
+    write_log_file(&format!("hooking d3d11 swapchain: {:x}", swapchain as usize));
+    let vtbl: *mut IDXGISwapChainVtbl = std::mem::transmute((*swapchain).lpVtbl);
+
+    let mut lock = SWAPCHAIN_REALFN.write()
+        .map_err(|e| HookError::D3D11DeviceHookFailed(
+        format!("swapchain hooks lock failed: {}", e)))?;
+    if lock.is_none() {
+        let real_present = (*vtbl).Present;
+        if real_present as usize == hook_present as usize {
+            return Err(HookError::D3D11DeviceHookFailed(
+                format!("unable to hook Present due to missing real function")));
+        }
+        *lock = Some(HookDXGISwapChain {
+            real_present,
+        });
+    }
+
+    let vsize = std::mem::size_of::<IDXGISwapChainVtbl>();
+    let old_prot = util::unprotect_memory(vtbl as *mut c_void, vsize)?;
+    (*vtbl).Present = hook_present;
+    util::protect_memory(vtbl as *mut c_void, vsize, old_prot)?;
+    Ok(())
+}
+
+unsafe extern "system" fn hook_present(
+    THIS: *mut IDXGISwapChain,
+    SyncInterval: UINT,
+    Flags: UINT,
+) -> HRESULT {
+    let real_present = match SWAPCHAIN_REALFN.read() {
+        Ok(lock) => match lock.as_ref() {
+            Some(sc) => sc.real_present,
+            None => {
+                write_log_file("Error: hook_present returning E_FAIL due to missing realfn");
+                return E_FAIL;
+            }
+        },
+        Err(e) => {
+            write_log_file(&format!("Error: hook_present returning E_FAIL due to bad state: {:?}", e));
+            return E_FAIL;
+        }
+    };
+
+    hook_snapshot::note_present();
+    GLOBAL_STATE.metrics.frame_stats.end_frame(clock::now());
+    if GLOBAL_STATE.is_snapping {
+        // this may set is_snapping = false if the snapshot is done
+        hook_snapshot::present_process();
+    }
+    process_remote_commands();
+
+    (real_present)(THIS, SyncInterval, Flags)
+}
+
+unsafe fn hook_d3d11(device:*mut ID3D11Device,swapchain:*mut IDXGISwapChain, context:*mut ID3D11DeviceContext) ->
     Result<HookDirect3D11> {
 
     apply_device_hook(device)?;
 
+    if !swapchain.is_null() {
+        apply_swapchain_hook(swapchain).unwrap_or_else(|e|
+            write_log_file(&format!("failed to hook swapchain, present based snapshot windows not available: {:?}", e)));
+    }
+
     write_log_file(&format!("hooking new d3d11 context: {:x}", context as usize));
     let vtbl: *mut ID3D11DeviceContextVtbl = std::mem::transmute((*context).lpVtbl);
     let ct = (*context).GetType();
@@ -701,6 +766,11 @@ pub unsafe fn query_and_set_runconf_in_globalstate(check_precopy:bool) -> bool {
         if old_precopy != GLOBAL_STATE.run_conf.precopy_data {
             changed = true;
         }
+        // limits don't require a rehook, the expire thread picks them up
+        GLOBAL_STATE.run_conf.precopy_budget_mb = util::reg_query_root_dword("SnapPreCopyBudgetMB")
+            .unwrap_or(shared_dx::precopy_store::DEF_PRECOPY_BUDGET_MB);
+        GLOBAL_STATE.run_conf.precopy_max_age_secs = util::reg_query_root_dword("SnapPreCopyMaxAgeSecs")
+            .unwrap_or(shared_dx::precopy_store::DEF_PRECOPY_MAX_AGE_SECS);
     }
 
     let force_tex_cpu_read = util::reg_query_root_dword("SnapForceTexCpuRead");
@@ -713,8 +783,9 @@ pub unsafe fn query_and_set_runconf_in_globalstate(check_precopy:bool) -> bool {
     if old_force_tex_cpu_read != GLOBAL_STATE.run_conf.force_tex_cpu_read {
         changed = true;
     }
-    write_log_file(&format!("runconf: precopy data: {}, force tex cpu read: {} (setting changed: {})",
-        GLOBAL_STATE.run_conf.precopy_data, GLOBAL_STATE.run_conf.force_tex_cpu_read, changed));
+    let rc = &GLOBAL_STATE.run_conf;
+    write_log_file(&format!("runconf: precopy data: {} (budget {}MB, max age {}s), force tex cpu read: {} (setting changed: {})",
+        rc.precopy_data, rc.precopy_budget_mb, rc.precopy_max_age_secs, rc.force_tex_cpu_read, changed));
     changed
 }
 
@@ -748,10 +819,9 @@ fn init_d3d11(device:*mut ID3D11Device, swapchain:*mut IDXGISwapChain, context:*
             metrics: DX11Metrics::new(),
             rs: DX11RenderState::new(),
             app_hwnds: Vec::new(),
-            last_timebased_update: SystemTime::now(),
+            last_timebased_update: clock::now(),
             app_foreground: false,
-            last_data_expire: SystemTime::now(),
-            last_data_expire_type_flip: false,
+            last_data_expire: clock::now(),
         }));
 
         // TODO11: d3d9 also has: d3d_resource_count: 0,
@@ -835,39 +905,49 @@ fn vertex_format_from_layout(layout: Vec<D3D11_INPUT_ELEMENT_DESC>) -> VertexFor
     // try to compute size, but if any offsets are D3D11_APPEND_ALIGNED_ELEMENT, give up
     // because I don't want to write the code to interpret that right now.
 
-    // sort by offset, then size is highest offset + size of format for it
-    let size = {
-        let mut layout = layout.clone();
-        layout.sort_by_key( |el| el.AlignedByteOffset);
-
-        let append_aligned_found =
-            layout.iter().find(|x| x.AlignedByteOffset == D3D11_APPEND_ALIGNED_ELEMENT);
-        if append_aligned_found.is_some() {
-            write_log_file("WARNING: vertex has dynamic size, not computed");
-            0
-        } else {
-            let high_el = layout.iter().rev().find(|el|
-                el.Format != DXGI_FORMAT_UNKNOWN && el.InputSlotClass == D3D11_INPUT_PER_VERTEX_DATA);
-            match high_el {
-                Some(el) => {
-                    let fmtsize = get_format_size_bytes(&el.Format)
-                        .unwrap_or_else(|| {
-                            write_log_file(&format!("ERROR: no size for format: {:?}", el.Format));
-                            0
-                        });
-                    el.AlignedByteOffset + fmtsize
-                },
-                None => {
-                    write_log_file(
-                        "ERROR: can't compute vertex size, no high offset found");
+    // the size of each slot is the highest offset + size of format in it.  per-instance slots
+    // are ignored, they don't change the vertex count.
+    let mut slot_sizes:Vec<u32> = vec![];
+    let append_aligned_found =
+        layout.iter().find(|x| x.AlignedByteOffset == D3D11_APPEND_ALIGNED_ELEMENT);
+    if append_aligned_found.is_some() {
+        write_log_file("WARNING: vertex has dynamic size, not computed");
+    } else {
+        for el in layout.iter().filter(|el|
+            el.Format != DXGI_FORMAT_UNKNOWN && el.InputSlotClass == D3D11_INPUT_PER_VERTEX_DATA) {
+            let fmtsize = get_format_size_bytes(&el.Format)
+                .unwrap_or_else(|| {
+                    write_log_file(&format!("ERROR: no size for format: {:?}", el.Format));
                     0
-                }
+                });
+            let slot = el.InputSlot as usize;
+            if slot >= slot_sizes.len() {
+                slot_sizes.resize(slot + 1, 0);
             }
+            slot_sizes[slot] = slot_sizes[slot].max(el.AlignedByteOffset + fmtsize);
         }
-    };
+        if slot_sizes.is_empty() {
+            write_log_file(
+                "ERROR: can't compute vertex size, no high offset found");
+        }
+    }
+    let position_slot = layout.iter().find(|el| {
+        let name = unsafe { CStr::from_ptr(el.SemanticName) }.to_string_lossy().to_ascii_lowercase();
+        name.starts_with("position")
+    }).map(|el| el.InputSlot).unwrap_or(0);
+    let size = slot_sizes.get(position_slot as usize).copied().unwrap_or(0);
+    let names = layout.iter()
+        .map(|el| unsafe { CStr::from_ptr(el.SemanticName) }.to_string_lossy())
+        .collect::<Vec<_>>();
+    let fingerprint = types::native_mod::geom_fingerprint(
+        layout.iter().zip(names.iter()).map(|(el,name)| (name.as_ref(), el.SemanticIndex, el.Format)),
+        size);
     VertexFormat {
         layout,
-        size
+        size,
+        slot_sizes,
+        position_slot,
+        fingerprint,
     }
 }
 
@@ -998,28 +1078,30 @@ unsafe extern "system" fn hook_CreateBuffer(
         // if its an index buffer with data, we need to copy it out
         let is_ib = (*pDesc).BindFlags & D3D11_BIND_INDEX_BUFFER != 0;
         let is_vb = (*pDesc).BindFlags & D3D11_BIND_VERTEX_BUFFER != 0;
+        let mut copied = false;
         if !pDesc.is_null() && (is_ib || is_vb)
             && !pInitialData.is_null() && !(*pInitialData).pSysMem.is_null() {
             if (*pInitialData).SysMemPitch != 0 || (*pInitialData).SysMemSlicePitch != 0 {
                 write_log_file(&format!("WARNING: hook_CreateBuffer: index or vertex buffer created with pitch or slice pitch, copy unimplemented"));
             } else {
+                copied = true;
                 let vlen = (*pDesc).ByteWidth as usize;
                 let mut dest_v:Vec<u8> = Vec::with_capacity(vlen);
                 std::ptr::copy_nonoverlapping::<u8>((*pInitialData).pSysMem as *const u8, dest_v.as_mut_ptr(), vlen);
                 dest_v.set_len(vlen);
-                dev_state_d3d11_write()
+                let kind = if is_ib { BufferKind::Index } else { BufferKind::Vertex };
+                // anything evicted to stay in budget is dropped after the lock is released
+                let _evicted = dev_state_d3d11_write()
                 .map(|(_lock,ds)| {
-                    if is_ib {
-                        ds.rs.device_index_buffer_data.insert(*ppBuffer as usize, dest_v);
-                        ds.rs.device_index_buffer_createtime.push((*ppBuffer as usize, SystemTime::now()));
-                    }
-                    else if is_vb {
-                        ds.rs.device_vertex_buffer_data.insert(*ppBuffer as usize, dest_v);
-                        ds.rs.device_vertex_buffer_createtime.push((*ppBuffer as usize, SystemTime::now()));
-                    }
+                    ds.rs.precopy.insert(*ppBuffer as usize, kind, dest_v, clock::now())
                 });
             }
         }
+        if !copied && (is_ib || is_vb) {
+            // the address may have held a buffer that was copied, that data is stale now
+            let _old = dev_state_d3d11_write()
+            .map(|(_lock,ds)| ds.rs.precopy.forget(*ppBuffer as usize));
+        }
     }
 
     res
@@ -1110,6 +1192,10 @@ unsafe extern "system" fn hook_CreateTexture2D(
             write_log_file(&format!("hook_CreateTexture2D: retry failed"));
         }
     }
+    if res == 0 && !ppTexture2D.is_null() && !(*ppTexture2D).is_null() {
+        // the address may have held a texture that was snapped, this is a different one
+        dev_state_d3d11_write().map(|(_lock,ds)| ds.rs.texture_created(*ppTexture2D as usize));
+    }
 
     res
 }
@@ -1689,4 +1775,40 @@ pub mod tests {
         cleanup(device, "create_and_draw")
 
     }
+
+    #[test]
+    fn vertex_format_multi_stream() {
+        use winapi::um::d3d11::D3D11_INPUT_PER_INSTANCE_DATA;
+        use winapi::shared::dxgiformat::{DXGI_FORMAT_R32G32_FLOAT, DXGI_FORMAT_R32G32B32A32_FLOAT};
+        let el = |name:&'static [u8], fmt, slot, offset, class| D3D11_INPUT_ELEMENT_DESC {
+            SemanticName: name.as_ptr() as *const i8,
+            SemanticIndex: 0,
+            Format: fmt,
+            InputSlot: slot,
+            AlignedByteOffset: offset,
+            InputSlotClass: class,
+            InstanceDataStepRate: 0
+        };
+        // uvs in slot 0, position + normal in slot 1, instance transform in slot 2
+        let vf = vertex_format_from_layout(vec![
+            el(b"TEXCOORD\0", DXGI_FORMAT_R32G32_FLOAT, 0, 0, D3D11_INPUT_PER_VERTEX_DATA),
+            el(b"POSITION\0", DXGI_FORMAT_R32G32B32_FLOAT, 1, 0, D3D11_INPUT_PER_VERTEX_DATA),
+            el(b"NORMAL\0", DXGI_FORMAT_R32G32B32_FLOAT, 1, 12, D3D11_INPUT_PER_VERTEX_DATA),
+            el(b"TEXCOORD\0", DXGI_FORMAT_R32G32B32A32_FLOAT, 2, 0, D3D11_INPUT_PER_INSTANCE_DATA),
+        ]);
+        assert_eq!(vf.position_slot, 1);
+        assert_eq!(vf.size, 24);
+        assert_eq!(vf.slot_sizes, vec![8, 24]);
+        assert_eq!(vf.vertex_slots(), vec![(0,8), (1,24)]);
+        assert!(vf.is_multi_stream());
+
+        // interleaved the way snapshots and mods store it: uv, then position and normal
+        let ivf = vf.interleaved();
+        assert_eq!(ivf.size, 32);
+        assert_eq!(ivf.slot_sizes, vec![32]);
+        assert!(!ivf.is_multi_stream());
+        assert_eq!(ivf.layout.len(), 3);
+        assert!(ivf.layout.iter().all(|el| el.InputSlot == 0));
+        assert_eq!(ivf.layout.iter().map(|el| el.AlignedByteOffset).collect::<Vec<_>>(), vec![0, 8, 20]);
+    }
 }
@@ -20,7 +20,9 @@ use dnclr::{init_clr, reload_managed_dll};
 use util;
 use mod_load;
 use mod_load::AsyncLoadState;
+use crate::command_file;
 use crate::input_commands;
+use crate::ipc_commands;
 use crate::mod_render;
 use mod_stats::mod_stats;
 use global_state::{GLOBAL_STATE, GLOBAL_STATE_LOCK};
@@ -31,9 +33,11 @@ use types::native_mod;
 
 use std;
 use std::ptr::null_mut;
-use std::time::SystemTime;
+use std::cell::RefCell;
 
 use shared_dx::util::*;
+use shared_dx::clock;
+use shared_dx::frame_stats::{self, DrawTimer};
 use shared_dx::error::*;
 use shared_dx::types::*;
 
@@ -97,6 +101,45 @@ fn get_selected_texture_stage() -> Option<DWORD> {
 /// Controls how often `process_metrics` reports stats (regardless of how frequently it is called)
 const METRICS_MIN_INTERVAL_SECS:f64 = 10.0;
 
+thread_local! {
+    static FRAME_STATS_CSV: RefCell<Option<Option<String>>> = RefCell::new(None);
+}
+
+/// Returns the CSV file for frame stats if enabled by the `FrameStatsCsv` root registry value
+/// (1 = write `framestats.$ExeBaseName.csv` in the log dir).  The registry is only read once.
+fn frame_stats_csv_path() -> Option<String> {
+    FRAME_STATS_CSV.with(|p| {
+        p.borrow_mut().get_or_insert_with(|| {
+            match unsafe { util::reg_query_root_dword("FrameStatsCsv") } {
+                Ok(1) => {},
+                _ => return None,
+            }
+            let logpath = shared_dx::util::get_log_file_path();
+            let dir = std::path::Path::new(&logpath).parent()?.to_string_lossy().to_string();
+            let basen = util::get_module_name_base().unwrap_or_else(|_| "unknown".to_owned());
+            let path = format!("{}\\framestats.{}.csv", dir, basen);
+            write_log_file(&format!("writing frame stats to {}", path));
+            Some(path)
+        }).clone()
+    })
+}
+
+/// Log the frame stats percentiles for the interval (and append them to the CSV file if that
+/// is enabled), then start a new interval.
+fn report_frame_stats(metrics:&mut FrameMetrics) {
+    let summary = metrics.frame_stats.summary();
+    metrics.frame_stats.reset();
+    if summary.is_empty() {
+        return;
+    }
+    write_log_file(&summary.to_string());
+    if let Some(path) = frame_stats_csv_path() {
+        let time = util::format_time(&clock::wall_now());
+        frame_stats::append_csv(&path, &time, &summary)
+            .unwrap_or_else(|e| write_log_file(&format!("failed to write frame stats csv: {:?}", e)));
+    }
+}
+
 /// Perform a metrics update if the number of dip calls exceeds `interval`.  If
 /// an update is performed, the tracked primitive list will also be cleared.  If there
 /// is no update it will be cleared too, unless the caller passes true for `preserve_prims`.
@@ -108,11 +151,11 @@ pub fn process_metrics(metrics:&mut FrameMetrics, preserve_prims:bool, interval:
     if metrics.dip_calls > interval {
         let mut report_dips_fps = true;
 
-        let now = SystemTime::now();
-        let elapsed = now.duration_since(metrics.last_call_log);
+        let now = clock::now();
+        let elapsed = clock::since(now, metrics.last_call_log);
         let mut dip_stats_updated = false;
         match elapsed {
-            Ok(d) => {
+            Some(d) => {
                 let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
                 if secs >= METRICS_MIN_INTERVAL_SECS {
                     // process dx11 metrics (if any).  do this first because if we are using dx11 we
@@ -144,6 +187,10 @@ pub fn process_metrics(metrics:&mut FrameMetrics, preserve_prims:bool, interval:
                             let rehook_ms = metrics.rehook_time_nanos / 1000 / 1000;
                             write_log_file(&format!("  rehook calls: {}, total ms: {}", metrics.rehook_calls, rehook_ms));
                         }
+                        let precopy = state.rs.precopy.stats();
+                        if precopy.inserted > 0 {
+                            write_log_file(&format!("  precopy: {}", precopy));
+                        }
                         if metrics.drawn_recently.len() > 0 {
                             write_log_file("  drawn recently:");
                             for (pv, ds) in &metrics.drawn_recently {
@@ -162,6 +209,7 @@ pub fn process_metrics(metrics:&mut FrameMetrics, preserve_prims:bool, interval:
                             metrics.dip_calls, 2, secs, 2, dipsec, 2, metrics.last_fps
                         ));
                     }
+                    report_frame_stats(metrics);
                     unsafe {&mut GLOBAL_STATE}.active_texture_set.as_ref().map(|set| {
                         if set.len() > 0 {
                             write_log_file(&format!(
@@ -170,11 +218,12 @@ pub fn process_metrics(metrics:&mut FrameMetrics, preserve_prims:bool, interval:
                             ))
                         }
                     });
-                    metrics.last_call_log = now;
+                    metrics.last_call_log = Some(now);
                     metrics.dip_calls = 0;
                 }
             }
-            Err(e) => write_log_file(&format!("Error getting elapsed duration: {:?}", e)),
+            // first call, start the interval
+            None => metrics.last_call_log = Some(now),
         }
 
         // dump out the prim list every so often if we are tracking that.
@@ -334,7 +383,10 @@ pub fn do_per_frame_operations(device: *mut IDirect3DDevice9) -> Result<()> {
     const METRICS_DIPS_INTERVAL:u32 = 1_000_000;
     process_metrics(metrics, false, METRICS_DIPS_INTERVAL);
 
-    mod_stats::update(&SystemTime::now());
+    mod_stats::update(&clock::now());
+
+    ipc_commands::process_requests(|| Some(DevicePointer::D3D9(device)));
+    command_file::poll(|| Some(DevicePointer::D3D9(device)));
 
     Ok(())
 }
@@ -451,14 +503,15 @@ pub unsafe extern "system" fn hook_present(
         .map_or(S_OK, |_hdstate| {
             metrics.frames += 1;
             metrics.total_frames += 1;
+            metrics.frame_stats.end_frame(clock::now());
             if metrics.frames % 90 == 0 {
                 // enforce min fps
                 // NOTE: when low, it just sets a boolean flag to disable mod rendering,
                 // but we could also use virtual protect to temporarily swap out the hook functions
                 // (except for present)
-                let now = SystemTime::now();
-                let elapsed = now.duration_since(metrics.last_fps_update);
-                if let Ok(d) = elapsed {
+                let now = clock::now();
+                let elapsed = clock::since(now, metrics.last_fps_update);
+                if let Some(d) = elapsed {
                     let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
                     let fps = metrics.frames as f64 / secs;
                     let smooth_fps = 0.3 * fps + 0.7 * metrics.last_fps;
@@ -475,7 +528,10 @@ pub unsafe extern "system" fn hook_present(
                     //     "{} frames in {} secs ({} instant, {} smooth) (low: {})",
                     //     hookdevice.frames, secs, fps, smooth_fps, hookdevice.low_framerate
                     // ));
-                    metrics.last_fps_update = now;
+                    metrics.last_fps_update = Some(now);
+                    metrics.frames = 0;
+                } else {
+                    metrics.last_fps_update = Some(now);
                     metrics.frames = 0;
                 }
             }
@@ -497,6 +553,7 @@ pub unsafe extern "system" fn hook_present(
         });
     }
 
+    hook_snapshot::note_present();
     if GLOBAL_STATE.is_snapping {
         // this may set is_snapping = false if the snapshot is done
         hook_snapshot::present_process();
@@ -731,7 +788,7 @@ pub enum CheckRenderModResult {
 }
 /// Check for a mod to render, and if one is found, render it using the supplied function `F`.
 /// Returns `CheckRenderModResult` to indicate to result of this check.
-pub unsafe fn check_and_render_mod<F>(primCount:u32, NumVertices: u32, rfunc:F) -> CheckRenderModResult
+pub unsafe fn check_and_render_mod<F>(primCount:u32, NumVertices: u32, fingerprint:u32, rfunc:F) -> CheckRenderModResult
 where
     F: FnOnce(&ModD3DData,&NativeModData) -> bool {
 
@@ -740,8 +797,8 @@ where
         .and_then(|mods| {
             profile_start!(hdip, mod_select);
 
-            let r = mod_render::select(mods,
-                primCount, NumVertices,
+            let r = mod_render::select_with_fingerprint(mods,
+                primCount, NumVertices, fingerprint,
                 GLOBAL_STATE.metrics.total_frames);
             profile_end!(hdip, mod_select);
             r
@@ -778,6 +835,9 @@ where
             }
         });
 
+    if res.is_some() {
+        GLOBAL_STATE.metrics.frame_stats.note_mod_draw();
+    }
     match (res,loading_mod_name) {
         (None,None) => CheckRenderModResult::NotRendered,
         (Some(mod_type),_) if mod_type == types::interop::ModType::Deletion as i32 => CheckRenderModResult::Deleted,
@@ -798,6 +858,7 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
     let force_modding_off = false;
 
     profile_start!(hdip, hook_dip);
+    let mut draw_timer = DrawTimer::start(GLOBAL_STATE.metrics.frame_stats.sample_draw());
 
     // no re-entry please
     profile_start!(hdip, dip_check);
@@ -821,7 +882,7 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
     let mut metrics = &mut GLOBAL_STATE.metrics;
 
     if !GLOBAL_STATE.is_snapping && (metrics.low_framerate || !GLOBAL_STATE.show_mods || force_modding_off) {
-        return (hookdevice.real_draw_indexed_primitive)(
+        let r = draw_timer.time_real(|| (hookdevice.real_draw_indexed_primitive)(
             THIS,
             PrimitiveType,
             BaseVertexIndex,
@@ -829,7 +890,9 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
             NumVertices,
             startIndex,
             primCount,
-        );
+        ));
+        metrics.frame_stats.note_draw(draw_timer.overhead());
+        return r;
     }
 
     // for snapshot selection, check to see if current selected texture is being rendered, and if
@@ -874,7 +937,8 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
     }
 
     // if there is a matching mod, render it
-    let mod_status = check_and_render_mod(primCount, NumVertices,
+    // d3d9 doesn't compute geometry fingerprints
+    let mod_status = check_and_render_mod(primCount, NumVertices, native_mod::NO_FINGERPRINT,
         |d3dd,nmod| {
             if let ModD3DData::D3D9(d3dd) = d3dd {
                 render_mod_d3d9(THIS, d3dd, nmod,
@@ -911,7 +975,7 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
                 None
             }
         };
-        let r = (hookdevice.real_draw_indexed_primitive)(
+        let r = draw_timer.time_real(|| (hookdevice.real_draw_indexed_primitive)(
             THIS,
             PrimitiveType,
             BaseVertexIndex,
@@ -919,7 +983,7 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
             NumVertices,
             startIndex,
             primCount,
-        );
+        ));
         if override_texture != null_mut() {
             (*THIS).SetTexture(sel_stage, save_texture);
         }
@@ -930,7 +994,7 @@ pub unsafe extern "system" fn hook_draw_indexed_primitive(
     profile_end!(hdip, real_dip);
 
     metrics.dip_calls += 1;
-
+    metrics.frame_stats.note_draw(draw_timer.overhead());
 
     GLOBAL_STATE.in_dip = false;
     profile_end!(hdip, hook_dip);
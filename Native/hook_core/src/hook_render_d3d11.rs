@@ -1,21 +1,25 @@
 use std::cell::RefCell;
 use std::mem::MaybeUninit;
 use std::ptr::{null_mut, null};
-use std::sync::atomic::Ordering;
-use std::time::{SystemTime, Duration};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Instant, Duration};
 
-use global_state::{GLOBAL_STATE, METRICS_TRACK_MOD_PRIMS, HWND};
+use global_state::{GLOBAL_STATE, METRICS_TRACK_MOD_PRIMS, HWND, D3D11KeyMode};
 use mod_stats::mod_stats;
 use shared_dx::dx11rs::{DX11RenderState};
+use shared_dx::precopy_store::{PrecopyLimits, BufferKind};
+use snaplib::index_topology::{read_indices, restart_index, strip_prim_count, strip_prim_count_fallback, index_range};
 use shared_dx::types::{HookDeviceState, DevicePointer, DX11Metrics, D3D11Tex};
 use shared_dx::types_dx11::{HookDirect3D11Context};
 use shared_dx::util::{write_log_file, ReleaseOnDrop};
+use shared_dx::clock;
+use shared_dx::frame_stats::DrawTimer;
 use types::TexPtr;
 use types::d3ddata::ModD3DData11;
 use types::interop::{SnapshotRendData, D3D11SnapshotRendData};
-use types::native_mod::{ModD3DData, ModD3DState, NativeModData};
+use types::native_mod::{ModD3DData, ModD3DState, NativeModData, NO_FINGERPRINT};
 use winapi::ctypes::c_void;
-use winapi::shared::dxgiformat::{DXGI_FORMAT, DXGI_FORMAT_UNKNOWN, DXGI_FORMAT_R8G8B8A8_UNORM};
+use winapi::shared::dxgiformat::{DXGI_FORMAT, DXGI_FORMAT_UNKNOWN, DXGI_FORMAT_R8G8B8A8_UNORM, DXGI_FORMAT_R16_UINT, DXGI_FORMAT_R32_UINT};
 use winapi::shared::dxgitype::DXGI_SAMPLE_DESC;
 use winapi::shared::winerror::{E_NOINTERFACE};
 use winapi::um::d3d11::{ID3D11Buffer, ID3D11InputLayout, D3D11_PRIMITIVE_TOPOLOGY,
@@ -23,17 +27,19 @@ use winapi::um::d3d11::{ID3D11Buffer, ID3D11InputLayout, D3D11_PRIMITIVE_TOPOLOG
     D3D11_USAGE_DEFAULT, D3D11_BIND_SHADER_RESOURCE, D3D11_SUBRESOURCE_DATA,
     ID3D11Texture2D, ID3D11Resource};
 use winapi::shared::ntdef::ULONG;
-use winapi::um::d3dcommon::{D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST, D3D11_SRV_DIMENSION_TEXTURE2D};
+use winapi::um::d3dcommon::{D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST, D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP, D3D11_SRV_DIMENSION_TEXTURE2D};
 use winapi::um::processthreadsapi::GetCurrentProcessId;
 use winapi::um::unknwnbase::IUnknown;
 use winapi::um::winuser::{EnumWindows, GetWindowThreadProcessId, GetParent, GetDesktopWindow, GetForegroundWindow};
 use winapi::um::{d3d11::ID3D11DeviceContext, winnt::INT};
 use winapi::shared::minwindef::UINT;
-use device_state::{dev_state, dev_state_d3d11_nolock, dev_state_d3d11_write};
+use device_state::{dev_state, dev_state_d3d11_nolock, dev_state_d3d11_read, dev_state_d3d11_write};
 use shared_dx::error::{Result, HookError};
 use crate::hook_device_d3d11::apply_context_hooks;
 use crate::hook_render::{process_metrics, frame_init_clr, frame_load_mods, check_and_render_mod, CheckRenderModResult, track_set_texture, get_override_tex_if_selected};
 use crate::{input_commands, debugmode, mod_render};
+use crate::ipc_commands;
+use crate::command_file;
 use winapi::um::d3d11::D3D11_BUFFER_DESC;
 use crate::debugmode::DebugModeCalledFns;
 use fnv::FnvHashMap;
@@ -238,20 +244,21 @@ pub unsafe extern "system" fn hook_IASetVertexBuffers(
         Some(state) => {
             if NumBuffers > 0 && ppVertexBuffers != null_mut() {
                 for idx in 0..NumBuffers {
-                    let pbuf = (*ppVertexBuffers).offset(idx as isize);
+                    let pbuf = *ppVertexBuffers.offset(idx as isize);
 
+                    // replace only the slots that were set.  a null buffer unbinds the slot.
+                    let slot = StartSlot + idx;
+                    state.rs.vb_state.retain(|(s,_,_)| *s != slot);
                     if pbuf != null_mut() {
-                        // clear on first add of a valid buffer, the game appears to be calling this
-                        // with 1 null buffer sometimes (and then calling draw) and I don't know why its
-                        // doing that.
-                        if idx == 0 {
-                            state.rs.vb_state.clear();
-                        }
                         let mut desc:D3D11_BUFFER_DESC = std::mem::zeroed();
                         (*pbuf).GetDesc(&mut desc);
                         let bw = desc.ByteWidth;
-                        let stride = desc.StructureByteStride;
-                        let vbinfo = (idx,bw,stride);
+                        let stride = if pStrides.is_null() {
+                            desc.StructureByteStride
+                        } else {
+                            *pStrides.offset(idx as isize)
+                        };
+                        let vbinfo = (slot,bw,stride);
                         state.rs.vb_state.push(vbinfo);
                     }
                 }
@@ -312,58 +319,188 @@ pub unsafe extern "system" fn hook_IASetInputLayout(
     )
 }
 
-fn compute_prim_vert_count(index_count: UINT, rs:&DX11RenderState) -> Option<(u32,u32)> {
+/// What the index data of a draw says about it.
+#[derive(Clone, Copy, Debug)]
+struct DrawIndexInfo {
+    /// Lowest and highest vertex index used, None if the draw references no vertices
+    range: Option<(u32,u32)>,
+    /// Number of triangles drawn, for strips this doesn't count the ones across cuts
+    prim_count: u32,
+}
+
+/// Max entries in `INDEX_INFOS`; when full, entries for buffers that were replaced are dropped.
+const MAX_INDEX_INFOS: usize = 16384;
+
+thread_local! {
+    /// Index info by (index buffer, first index, index count, is strip), with the precopy
+    /// generation of the buffer it was computed from.  Only used when the index data is
+    /// precopied.  If the buffer address was reused the generation won't match.
+    static INDEX_INFOS: RefCell<FnvHashMap<(usize,u32,u32,bool),(u64,DrawIndexInfo)>> =
+        RefCell::new(FnvHashMap::default());
+}
+
+/// Number of draws whose key fell back to not using the index data that have been logged
+static INDEX_FALLBACKS_LOGGED: AtomicU32 = AtomicU32::new(0);
+const MAX_INDEX_FALLBACK_LOGS: u32 = 20;
+
+/// Read the vertex range and prim count of a draw from the precopied index data.  Returns None
+/// if the data isn't available (not precopied, or the buffer is unbound or has an unknown
+/// format).
+///
+/// The info is cached for as long as the index buffer exists, so a draw keeps the same key
+/// after the data is evicted from the precopy store, unless the store drops the buffer's
+/// generation (see `PrecopyStore::generation`).  A draw that is first seen after its data was
+/// evicted has no info though.
+unsafe fn draw_index_info(context:*mut ID3D11DeviceContext, index_count:UINT, start_index:UINT, rs:&DX11RenderState) -> Option<DrawIndexInfo> {
+    if !GLOBAL_STATE.run_conf.precopy_data || context.is_null() {
+        return None;
+    }
+    let mut ibuffer: *mut ID3D11Buffer = null_mut();
+    let mut ibuffer_format: DXGI_FORMAT = DXGI_FORMAT_UNKNOWN;
+    let mut ibuffer_offset: UINT = 0;
+    (*context).IAGetIndexBuffer(&mut ibuffer, &mut ibuffer_format, &mut ibuffer_offset);
+    if ibuffer.is_null() {
+        return None;
+    }
+    let _ib_rod = ReleaseOnDrop::new(ibuffer);
+    let index_size:UINT = match ibuffer_format {
+        DXGI_FORMAT_R16_UINT => 2,
+        DXGI_FORMAT_R32_UINT => 4,
+        _ => return None,
+    };
+    let is_strip = rs.prim_topology == D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP;
+    let start = start_index + ibuffer_offset / index_size;
+    let key = (ibuffer as usize, start, index_count, is_strip);
+    // the generations change when buffers are created on other threads, so everything that
+    // looks at the store has to be done with the lock held
+    dev_state_d3d11_read().and_then(|(_lock,ds)| {
+        let precopy = &ds.rs.precopy;
+        let generation = precopy.generation(ibuffer as usize)?;
+        let cached = INDEX_INFOS.with(|c| c.borrow().get(&key).copied());
+        match cached {
+            Some((gen, info)) if gen == generation => return Some(info),
+            _ => {}
+        }
+        let info = precopy.peek(ibuffer as usize, BufferKind::Index)
+            .and_then(|data| read_indices(data, index_size as usize, start as usize, index_count as usize))
+            .map(|indices| if is_strip {
+                let restart = restart_index(index_size as usize);
+                DrawIndexInfo {
+                    range: index_range(&indices, Some(restart)),
+                    prim_count: strip_prim_count(&indices, restart),
+                }
+            } else {
+                DrawIndexInfo {
+                    range: index_range(&indices[0..(index_count - index_count % 3) as usize], None),
+                    prim_count: index_count / 3,
+                }
+            })?;
+        INDEX_INFOS.with(|c| {
+            let mut c = c.borrow_mut();
+            if c.len() >= MAX_INDEX_INFOS {
+                c.retain(|k,(gen,_)| precopy.generation(k.0) == Some(*gen));
+                if c.len() >= MAX_INDEX_INFOS {
+                    c.clear();
+                }
+            }
+            c.insert(key, (generation, info));
+        });
+        Some(info)
+    })
+}
+
+/// Prim count used to key mods for a draw of `index_count` indices.  `index_info` is what the
+/// precopied index data says about the draw, if it is available.  Without it the cuts in a strip
+/// can't be found, so strips fall back to `strip_prim_count_fallback` (see
+/// `snaplib::index_topology`).
+fn key_prim_count(index_count:UINT, is_strip:bool, index_info:Option<&DrawIndexInfo>) -> u32 {
+    match index_info {
+        Some(info) => info.prim_count,
+        None if is_strip => strip_prim_count_fallback(index_count),
+        None => index_count / 3,
+    }
+}
+
+/// Compute the prim and vert count used to key mods for a draw, and the lowest vertex index that
+/// the vert count starts from, as (prim count, vert count, min index).  Triangle lists and strips
+/// are supported, other topologies return None.  The prim count of a strip comes from the index
+/// data so that cuts are handled, see `key_prim_count`.
+///
+/// The vert count depends on the game's key mode, see `D3D11KeyMode`.
+unsafe fn compute_prim_vert_count(context:*mut ID3D11DeviceContext, index_count: UINT, start_index:UINT,
+    rs:&DX11RenderState) -> Option<(u32,u32,u32)> {
     if index_count <= 6 { // = 2 triangles generally, mods can't be this small or even close to this small
         // don't bother
         return None;
     }
-    // assumes triangle list, actual topology is in render state but we shouldn't even be in
-    // here if its not triangle list.
-    let prim_count = index_count / 3;
-
-    // vert count has to be computed from the current vertex buffer
-    // stream and the current input layout (vertex size)
-    let vb_state = &rs.vb_state;
-    let vb_size = match vb_state.len() {
-        1 => {
-            let (_index,byteWidth,_stride) = vb_state[0];
-            if byteWidth == 0 {
+    let is_strip = match rs.prim_topology {
+        D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST => false,
+        D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP => true,
+        _ => return None,
+    };
+    let index_range_mode = GLOBAL_STATE.run_conf.d3d11_key_mode == D3D11KeyMode::IndexRange;
+    let index_info = if is_strip || index_range_mode {
+        draw_index_info(context, index_count, start_index, rs)
+    } else {
+        None
+    };
+    let prim_count = key_prim_count(index_count, is_strip, index_info.as_ref());
+    if prim_count == 0 {
+        return None;
+    }
+
+    if index_info.is_none() && (is_strip || index_range_mode) {
+        let logged = INDEX_FALLBACKS_LOGGED.load(Ordering::Relaxed);
+        if logged < MAX_INDEX_FALLBACK_LOGS {
+            INDEX_FALLBACKS_LOGGED.store(logged + 1, Ordering::Relaxed);
+            let why = if GLOBAL_STATE.run_conf.precopy_data { "not precopied or evicted" } else { "precopy is off" };
+            let what = if index_range_mode { "using buffer size for vert count" } else { "strip cuts not counted" };
+            write_log_file(&format!("compute_prim_vert_count: index data not available ({}), {}: {} prims, start index {}",
+                why, what, prim_count, start_index));
+            if logged + 1 == MAX_INDEX_FALLBACK_LOGS {
+                write_log_file("compute_prim_vert_count: not logging further index data fallbacks");
+            }
+        }
+    }
+
+    if index_range_mode {
+        match index_info.map(|info| info.range) {
+            Some(Some((min,max))) => return Some((prim_count, max - min + 1, min)),
+            Some(None) => return None,
+            None => {}
+        }
+    }
+
+    // vert count has to be computed from the current input layout (vertex size) and the vertex
+    // buffer bound to the slot that holds the position, other streams (normals, uvs, instance
+    // data etc) don't count.
+    let vf = rs.get_current_vertex_format()?;
+    if vf.size == 0 {
+        return None;
+    }
+    let (vb_size, stride) = match rs.vb_state.iter().find(|(slot,_,_)| *slot == vf.position_slot) {
+        Some((_slot,byteWidth,stride)) => {
+            if *byteWidth == 0 {
                 write_log_file("compute_prim_vert_count: current vb has zero byte size");
                 return None;
             }
-            byteWidth
+            (*byteWidth, *stride)
         },
-        0 => {
+        None if rs.vb_state.is_empty() => {
             write_log_file("compute_prim_vert_count: no current vertex buffer set");
             return None;
         },
-        _n => {
-            // not sure how to figure out which one to use, maybe log warning
+        None => {
+            // position slot not bound, nothing sensible to compute
             return None;
         }
     };
-    let curr_input_layout = &rs.current_input_layout;
-    let curr_layouts = &rs.context_input_layouts_by_ptr;
-    let vert_size = {
-        let curr_input_layout = *curr_input_layout as usize;
-        if curr_input_layout > 0 {
-            curr_layouts.get(&curr_input_layout).map(|vf| vf.size)
-            .unwrap_or(0)
-        } else {
-            0
-        }
-    };
-    if vert_size == 0 {
-        return None;
-    }
 
-    let vert_count = if vert_size > 0 {
-        vb_size / vert_size
-    } else {
-        0
-    };
+    // the stride is usually the vertex size, but the buffer may have padding (or other data)
+    // between vertices
+    let vert_count = vb_size / stride.max(vf.size);
 
-    Some((prim_count,vert_count))
+    Some((prim_count,vert_count,0))
 }
 
 fn update_drawn_recently(metrics:&mut DX11Metrics, prim_count:u32, vert_count: u32, checkres:&CheckRenderModResult) {
@@ -450,20 +587,59 @@ pub unsafe extern "system" fn hook_draw_indexed(
     StartIndexLocation: UINT,
     BaseVertexLocation: INT,
 ) {
+    debugmode::note_called(DebugModeCalledFns::Hook_ContextDrawIndexed, THIS as usize);
+    draw_indexed(THIS, IndexCount, StartIndexLocation, BaseVertexLocation, None,
+        |ctx| (ctx.real_draw_indexed)(
+            THIS,
+            IndexCount,
+            StartIndexLocation,
+            BaseVertexLocation,
+        ));
+}
+
+pub unsafe extern "system" fn hook_draw_indexed_instanced(
+    THIS: *mut ID3D11DeviceContext,
+    IndexCountPerInstance: UINT,
+    InstanceCount: UINT,
+    StartIndexLocation: UINT,
+    BaseVertexLocation: INT,
+    StartInstanceLocation: UINT,
+) {
+    debugmode::note_called(DebugModeCalledFns::Hook_ContextDrawIndexedInstanced, THIS as usize);
+    draw_indexed(THIS, IndexCountPerInstance, StartIndexLocation, BaseVertexLocation,
+        Some((InstanceCount, StartInstanceLocation)),
+        |ctx| (ctx.real_draw_indexed_instanced)(
+            THIS,
+            IndexCountPerInstance,
+            InstanceCount,
+            StartIndexLocation,
+            BaseVertexLocation,
+            StartInstanceLocation,
+        ));
+}
+
+/// Shared implementation of the indexed draw hooks.  `instances` is the (instance count, start
+/// instance) for instanced draws, the geometry checks are the same either way since prim and vert
+/// counts are per instance.  `real_draw` makes the original draw call.
+unsafe fn draw_indexed<F>(
+    THIS: *mut ID3D11DeviceContext,
+    IndexCount: UINT,
+    StartIndexLocation: UINT,
+    BaseVertexLocation: INT,
+    instances: Option<(UINT,UINT)>,
+    real_draw: F,
+) where F: Fn(&HookDirect3D11Context) {
     if MM_DISABLE {
         match get_hook_context() {
             Ok(ctx) => {
-                (ctx.real_draw_indexed)(
-                    THIS,
-                    IndexCount,
-                    StartIndexLocation,
-                    BaseVertexLocation,
-                );
+                real_draw(ctx);
                 return
             },
             Err(_) => return,
         }
     }
+    let mut draw_timer = DrawTimer::start(GLOBAL_STATE.metrics.frame_stats.sample_draw());
+
     // Helper local function for periodic operations, since I don't have any idea of when the frame
     // ends in this API right now
     let periodic = || {
@@ -491,6 +667,11 @@ pub unsafe extern "system" fn hook_draw_indexed(
 
         process_metrics(&mut GLOBAL_STATE.metrics, true, 250000);
 
+        if !hook_snapshot::present_seen() {
+            // normally done from the present hook
+            process_remote_commands();
+        }
+
         profile_end!(hdi, periodic);
     };
 
@@ -501,7 +682,6 @@ pub unsafe extern "system" fn hook_draw_indexed(
     }
 
     profile_start!(hdi, start);
-    debugmode::note_called(DebugModeCalledFns::Hook_ContextDrawIndexed, THIS as usize);
 
     let hook_context = match get_hook_context() {
         Ok(ctx) => ctx,
@@ -515,13 +695,9 @@ pub unsafe extern "system" fn hook_draw_indexed(
         periodic(); // need to do this so that input processes
 
         profile_start!(hdi, draw_input);
-        (hook_context.real_draw_indexed)(
-            THIS,
-            IndexCount,
-            StartIndexLocation,
-            BaseVertexLocation,
-        );
+        draw_timer.time_real(|| real_draw(hook_context));
         profile_end!(hdi, draw_input);
+        GLOBAL_STATE.metrics.frame_stats.note_draw(draw_timer.overhead());
 
         profile_end!(hdi, total);
         return;
@@ -546,13 +722,13 @@ pub unsafe extern "system" fn hook_draw_indexed(
 
     if GLOBAL_STATE.is_snapping {
         dev_state_d3d11_nolock().map(|state| {
-            let checkres = compute_prim_vert_count(IndexCount, &state.rs);
-            let (prim_count, vert_count) = checkres.unwrap_or_else(|| (0,0));
+            let checkres = compute_prim_vert_count(THIS, IndexCount, StartIndexLocation, &state.rs);
+            let (prim_count, vert_count, min_index) = checkres.unwrap_or_else(|| (0,0,0));
             let mut sd = types::interop::SnapshotData {
                 sd_size: std::mem::size_of::<types::interop::SnapshotData>() as u32,
                 prim_type: state.rs.prim_topology as i32,
                 base_vertex_index: BaseVertexLocation,
-                min_vertex_index: 0,
+                min_vertex_index: min_index,
                 num_vertices: vert_count,
                 start_index: StartIndexLocation,
                 prim_count: prim_count,
@@ -561,6 +737,7 @@ pub unsafe extern "system" fn hook_draw_indexed(
                     d3d11: D3D11SnapshotRendData::new(),
                 },
             };
+            hook_snapshot::set_draw_info(IndexCount, instances.map(|(count,_start)| count).unwrap_or(1));
             hook_snapshot::take(&mut state.devptr, &mut sd, this_is_selected);
         });
 
@@ -573,16 +750,17 @@ pub unsafe extern "system" fn hook_draw_indexed(
     // TODO11 use the lock function here or switch to thread local for RS
     let state = dev_state_d3d11_nolock();
     let draw_input = state.map(|state| {
-        // this is the only prim type I support but don't log if it is something else since
-        // it would be spammy (maybe log if trying to take a snapshot)
-        if state.rs.prim_topology != D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST {
+        // lists and strips are the only prim types I support but don't log if it is something
+        // else since it would be spammy (maybe log if trying to take a snapshot)
+        if state.rs.prim_topology != D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST
+            && state.rs.prim_topology != D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP {
             profile_end!(hdi, geom_check);
             return true;
         }
-        let checkres = compute_prim_vert_count(IndexCount, &state.rs);
+        let checkres = compute_prim_vert_count(THIS, IndexCount, StartIndexLocation, &state.rs);
         profile_end!(hdi, geom_check);
         match checkres {
-            Some((prim_count,vert_count)) if vert_count > 2  => {
+            Some((prim_count,vert_count,_min_index)) if vert_count > 2  => {
                 // if primitive tracking is enabled, log just the primcount,vertcount if we were able
                 // to compute it, otherwise log whatever we have
                 if global_state::METRICS_TRACK_PRIMS && prim_count > 2 { // filter out some spammy useless stuff
@@ -607,11 +785,15 @@ pub unsafe extern "system" fn hook_draw_indexed(
                 } else {
                     profile_end!(hdi, mod_precheck);
                     profile_start!(hdi, mod_check);
-                    let mod_status = check_and_render_mod(prim_count, vert_count,
+                    // only needed on a key hit, so compute it here rather than for every draw
+                    let fingerprint = state.rs.get_current_vertex_format()
+                        .map(|vf| vf.fingerprint)
+                        .unwrap_or(NO_FINGERPRINT);
+                    let mod_status = check_and_render_mod(prim_count, vert_count, fingerprint,
                         |d3dd,nmod| {
                             profile_start!(hdi, mod_render);
                             let res = if let ModD3DData::D3D11(d3d11d) = d3dd {
-                                render_mod_d3d11(THIS, hook_context, d3d11d, nmod, override_texture, sel_stage, (prim_count,vert_count))
+                                render_mod_d3d11(THIS, hook_context, d3d11d, nmod, override_texture, sel_stage, (prim_count,vert_count), instances)
                             } else {
                                 false
                             };
@@ -682,12 +864,7 @@ pub unsafe extern "system" fn hook_draw_indexed(
         };
         profile_end!(hdi, draw_ovtex_check);
         profile_start!(hdi, draw_input);
-        (hook_context.real_draw_indexed)(
-            THIS,
-            IndexCount,
-            StartIndexLocation,
-            BaseVertexLocation,
-        );
+        draw_timer.time_real(|| real_draw(hook_context));
         profile_end!(hdi, draw_input);
         profile_start!(hdi, draw_ovtex_reset);
         save_srv.as_mut().map(|srv| {
@@ -701,6 +878,7 @@ pub unsafe extern "system" fn hook_draw_indexed(
     periodic();
 
     GLOBAL_STATE.in_dip = false;
+    GLOBAL_STATE.metrics.frame_stats.note_draw(draw_timer.overhead());
 
     profile_end!(hdi, total);
     profile_summarize!(hdi, 10.0);
@@ -744,8 +922,6 @@ unsafe fn find_app_windows() {
 }
 
 const DEF_EXPIRE_CHECK_SECS:u64 = 60;
-const DEF_EXPIRE_DUR_SECS:u64 = 600;
-const DEF_EXPIRE_MAX_PCT:u32 = 40;
 
 struct ExpireThread {
     thread: std::thread::JoinHandle<()>,
@@ -753,10 +929,10 @@ struct ExpireThread {
 
 thread_local! {
     static EXPIRE_THREAD: RefCell<Option<ExpireThread>> = RefCell::new(None);
-    static LAST_EXPIRE_THREAD_START: RefCell<SystemTime> = RefCell::new(SystemTime::now());
+    static LAST_EXPIRE_THREAD_START: RefCell<Instant> = RefCell::new(clock::now());
 }
 
-fn check_expire_thread(now:&SystemTime) {
+fn check_expire_thread(now:&Instant) {
     let started = EXPIRE_THREAD.with(|et| {
         if let Some(t) = et.borrow().as_ref() {
             return !t.thread.is_finished()
@@ -766,16 +942,16 @@ fn check_expire_thread(now:&SystemTime) {
     if started {
         return
     }
-    let dur = now.duration_since(LAST_EXPIRE_THREAD_START.with(|x| *x.borrow())).unwrap_or_else(|_x| Duration::from_secs(0));
+    let dur = now.saturating_duration_since(LAST_EXPIRE_THREAD_START.with(|x| *x.borrow()));
     if dur < Duration::from_secs(10) {
         return
     }
     write_log_file("starting data expire thread");
     let thread = std::thread::spawn(|| {
-        let mut last_check = SystemTime::now();
+        let mut last_check = clock::now();
         let mut clear_list:Vec<Vec<u8>> = Vec::new();
         loop {
-            let now = SystemTime::now();
+            let now = clock::now();
             let checked = expire_data(&now,&last_check, &mut clear_list);
             let mut sleep_secs = 10;
             if checked {
@@ -793,105 +969,50 @@ fn check_expire_thread(now:&SystemTime) {
 }
 
 /// This essentially a garbage collector for data we might have copied for snapshots.
-/// It makes a reasonable effort to balance wasted memory with cpu usage.  Runs in a separate thread
+/// The age and budget limits are enforced by the `PrecopyStore`; this just applies the current
+/// limits from the run conf and releases the data.  Runs in a separate thread
 /// though it can still slow down the device thread a bit because it has to write lock on the
 /// shared data structures used to store the data.
-fn expire_data(now:&SystemTime, last_data_expire:&SystemTime, clear_list:&mut Vec<Vec<u8>>) -> bool {
+fn expire_data(now:&Instant, last_data_expire:&Instant, clear_list:&mut Vec<Vec<u8>>) -> bool {
+    profile_start!(hdi, expire_data);
     let mut checked = false;
     let min = Duration::from_secs(DEF_EXPIRE_CHECK_SECS);
-    let elapsed = now.duration_since(*last_data_expire).unwrap_or_else(|_x| min);
+    let elapsed = now.saturating_duration_since(*last_data_expire);
     if elapsed > min {
-        let start = SystemTime::now();
-        let mut cleartype = "";
-        let (expired_els,total_els) = unsafe {
+        let start = clock::now();
+        let limits = unsafe {
+            PrecopyLimits::new(GLOBAL_STATE.run_conf.precopy_budget_mb, GLOBAL_STATE.run_conf.precopy_max_age_secs)
+        };
+        let (expired_els,total_els,rem_size) = unsafe {
             dev_state_d3d11_write()}.map(|(_lock,state)| {
             state.last_data_expire = *now;
             checked = true;
-            let expire_dur = Duration::from_secs(DEF_EXPIRE_DUR_SECS);
-            let cutoff = *now - expire_dur;
-            let mut expire_data =
-                |ct_list:&mut Vec<(usize,SystemTime)>, data_map:&mut FnvHashMap<usize, Vec<u8>>,
-                _size_and_nl:&mut (usize,usize)| {
-                if ct_list.len() == 0 {
-                    return 0;
-                }
-                // cap how many of these we do at a time
-                let max_el = ((DEF_EXPIRE_MAX_PCT as f32 / 100.0) * ct_list.len() as f32) as usize;
-                // enumerate and walk elements, removing until we find one that is newer than cutoff
-                // don't need to sort, since newer stuff is pushed, oldest will always be near front.
-                let newpos =
-                    ct_list.iter_mut()
-                    .enumerate()
-                    .take(max_el)
-                    .find(|(_pos,(ptr,created))| {
-                        let old = created < &cutoff;
-                        if old {
-                            let dat = data_map.remove(&ptr);
-                            if let Some(dat) = dat {
-                                clear_list.push(dat)
-                            }
-                        }
-                        !old
-                    }).map(|(pos,_)| pos);
-                let clear_to = match newpos {
-                    Some(newpos) => newpos,
-                    None => max_el, // all in range are old
-                };
-                if clear_to > 0 {
-                    ct_list.drain(..clear_to);
-                }
-                clear_to
-            };
-            // do vertex or index data but not both to reduce cpu/lock time a bit
-            state.last_data_expire_type_flip = !state.last_data_expire_type_flip;
-            let expired_els;
-            let total_els;
-            //let which_map;
-            match state.last_data_expire_type_flip {
-                true => {
-                    // expire vertex data
-                    cleartype = "vertex";
-                    total_els = state.rs.device_vertex_buffer_createtime.len();
-                    expired_els = expire_data(&mut state.rs.device_vertex_buffer_createtime,
-                        &mut state.rs.device_vertex_buffer_data,
-                        &mut state.rs.device_vertex_buffer_totalsize_nextlog);
-                    //which_map = &state.rs.device_vertex_buffer_data;
-                }
-                false => {
-                    // expire index data
-                    cleartype = "index";
-                    total_els = state.rs.device_index_buffer_createtime.len();
-                    expired_els = expire_data(&mut state.rs.device_index_buffer_createtime,
-                        &mut state.rs.device_index_buffer_data,
-                        &mut state.rs.device_index_buffer_totalsize_nextlog);
-                    //which_map = &state.rs.device_index_buffer_data;
-                },
-            };
-            // let calc_rem_size = |data_map:&FnvHashMap<usize, Vec<u8>>| {
-            //     data_map.iter().fold(0, |acc, (_k,v)| acc + v.capacity())
-            // };
-            // let rem_size = calc_rem_size(which_map);
-            (expired_els,total_els)
-        }).unwrap_or_else(|| (0,0));
-        let expire_elapsed = SystemTime::now().duration_since(start).unwrap_or_else(|_x| Duration::from_secs(0));
-        let start = SystemTime::now();
-        let totalfreed = clear_list.iter().fold(0, |acc, v| acc + v.capacity());
+            if state.rs.precopy.limits() != limits {
+                state.rs.precopy.set_limits(limits);
+            }
+            let total_els = state.rs.precopy.len();
+            let expired_els = state.rs.precopy.expire(*now, clear_list);
+            (expired_els,total_els,state.rs.precopy.total_bytes())
+        }).unwrap_or_else(|| (0,0,0));
+        let expire_elapsed = clock::now().saturating_duration_since(start);
+        let start = clock::now();
+        let totalfreed = clear_list.iter().fold(0, |acc, v| acc + v.len());
         // this actually releases the memory so kinda important, it can take some time but we're
         // outside the lock so the render thread should no longer be blocked.
         clear_list.clear();
-        let clear_elapsed = SystemTime::now().duration_since(start).unwrap_or_else(|_x| Duration::from_secs(0));
-        let rem_size = 0;
-        let mut msg = format!("expired {}/{} {} objects total {:3.3} MB in {} (expire) + {} (clear) microseconds",
-            expired_els, total_els, cleartype, totalfreed as f32 / 1024.0 / 1024.0, expire_elapsed.as_micros(), clear_elapsed.as_micros());
+        let clear_elapsed = clock::now().saturating_duration_since(start);
+        let mut msg = format!("expired {}/{} buffer objects total {:3.3} MB in {} (expire) + {} (clear) microseconds",
+            expired_els, total_els, totalfreed as f32 / 1024.0 / 1024.0, expire_elapsed.as_micros(), clear_elapsed.as_micros());
         if rem_size > 0 {
             msg.push_str(&format!(", remaining size: {:3.2} MB", rem_size as f32 / 1024.0 / 1024.0));
         }
         write_log_file(&msg);
     }
+    profile_end!(hdi, expire_data);
     checked
 }
 
-unsafe fn time_based_update(mselapsed:u128, now:SystemTime, context:*mut ID3D11DeviceContext) {
+unsafe fn time_based_update(mselapsed:u128, now:Instant, context:*mut ID3D11DeviceContext) {
     if mselapsed > 500 {
         if let Some(state) = dev_state_d3d11_nolock() {
             state.last_timebased_update = now;
@@ -1014,20 +1135,21 @@ unsafe fn time_based_update(mselapsed:u128, now:SystemTime, context:*mut ID3D11D
     }
 }
 
+/// Serve IPC requests and poll the command file.  Called once per frame from the present hook,
+/// or from the draw hook if present isn't hooked.
+pub fn process_remote_commands() {
+    ipc_commands::process_requests(|| dev_state_d3d11_nolock().map(|state| state.devptr));
+    command_file::poll(|| dev_state_d3d11_nolock().map(|state| state.devptr));
+}
+
 /// Called by DrawIndexed every few 10s of MS but not exactly every frame.
 fn draw_periodic(context:*mut ID3D11DeviceContext) {
     unsafe {
-        let now = SystemTime::now();
+        let now = clock::now();
         let (el_sec,el_ms) =
             dev_state_d3d11_nolock().map(|state| {
-                let elapsed = now.duration_since(state.last_timebased_update);
-
-                match elapsed {
-                    Ok(elapsed) => {
-                        (elapsed.as_secs(), elapsed.as_millis())
-                    },
-                    Err(_) => (0,0)
-                }
+                let elapsed = now.saturating_duration_since(state.last_timebased_update);
+                (elapsed.as_secs(), elapsed.as_millis())
             }).unwrap_or((0,0));
         let time = (el_sec * 1000) as u128 + el_ms;
         time_based_update(time, now, context);
@@ -1037,7 +1159,7 @@ fn draw_periodic(context:*mut ID3D11DeviceContext) {
 unsafe fn render_mod_d3d11(context:*mut ID3D11DeviceContext, hook_context: &mut HookDirect3D11Context,
      d3dd:&ModD3DData11, _nmod:&NativeModData,
     override_texture: *mut ID3D11ShaderResourceView, override_stage:u32,
-    _primVerts:(u32,u32)) -> bool {
+    _primVerts:(u32,u32), instances:Option<(UINT,UINT)>) -> bool {
     if context.is_null() {
         return false;
     }
@@ -1063,19 +1185,20 @@ unsafe fn render_mod_d3d11(context:*mut ID3D11DeviceContext, hook_context: &mut
         curr_vbuffers.iter().filter(|vb| !vb.is_null())
          .map(|vb| ReleaseOnDrop::new(*vb)).collect::<Vec<_>>();
 
-    // set the mod vertex buffer
-    let vbuffer = d3dd.vb;
-    let vbuffer_stride = [d3dd.vert_size as UINT];
-    let vbuffer_offset = [0 as UINT];
-
-    // call direct to avoid entering our hook function
-    (hook_context.real_ia_set_vertex_buffers)(
-        context,
-        0,
-        1,
-        &vbuffer,
-        vbuffer_stride.as_ptr(),
-        vbuffer_offset.as_ptr());
+    // set the mod vertex buffers, one per stream of the layout
+    for (slot, vbuffer, stride) in d3dd.vbs.iter() {
+        let vbuffer_stride = [*stride as UINT];
+        let vbuffer_offset = [0 as UINT];
+
+        // call direct to avoid entering our hook function
+        (hook_context.real_ia_set_vertex_buffers)(
+            context,
+            *slot,
+            1,
+            vbuffer,
+            vbuffer_stride.as_ptr(),
+            vbuffer_offset.as_ptr());
+    }
 
     // if the mod has textures, need to set the pixel shader resources for them
     let mut orig_srvs: [*mut ID3D11ShaderResourceView; 16] = [null_mut(); 16];
@@ -1135,8 +1258,20 @@ unsafe fn render_mod_d3d11(context:*mut ID3D11DeviceContext, hook_context: &mut
         None
     };
 
-    // draw
-    (*context).Draw(d3dd.vert_count as UINT, 0);
+    // mods are always triangle lists, but the game may be drawing a strip.  bypass our hook so
+    // the tracked topology stays the game's.
+    let mut curr_topology: D3D11_PRIMITIVE_TOPOLOGY = 0;
+    (*context).IAGetPrimitiveTopology(&mut curr_topology);
+    if curr_topology != D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST {
+        (hook_context.real_ia_set_primitive_topology)(context, D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+    }
+
+    // draw.  for instanced draws the game's per-instance streams (if any) are still bound to
+    // the other slots, so draw the mod once per instance.
+    match instances {
+        Some((count,start)) => (*context).DrawInstanced(d3dd.vert_count as UINT, count, 0, start),
+        None => (*context).Draw(d3dd.vert_count as UINT, 0),
+    }
 
     // restore overridden tex
     override_save_srv.as_mut().map(|srv| {
@@ -1150,18 +1285,25 @@ unsafe fn render_mod_d3d11(context:*mut ID3D11DeviceContext, hook_context: &mut
         (hook_context.real_ps_set_shader_resources)(context, 0, 16, orig_srvs.as_ptr());
     }
 
+    // restore topology
+    if curr_topology != D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST {
+        (hook_context.real_ia_set_primitive_topology)(context, curr_topology);
+    }
+
     // restore index buffer
     (*context).IASetIndexBuffer(curr_ibuffer, curr_ibuffer_format, curr_ibuffer_offset);
 
-    // restore vertex buffer
-    // find first null vbuffer to get actual number of buffers to restore
-    let first_null = curr_vbuffers.iter()
-        .position(|&x| x.is_null()).unwrap_or(0);
+    // restore vertex buffers, up to the last slot that the game or the mod used (slots the game
+    // didn't use are set back to null)
+    let game_slots = curr_vbuffers.iter()
+        .rposition(|&x| !x.is_null()).map_or(0, |last| last + 1);
+    let mod_slots = d3dd.vbs.iter().map(|(slot,_,_)| *slot as usize + 1).max().unwrap_or(0);
+    let restore_count = game_slots.max(mod_slots).min(MAX_VBUFFERS);
 
     (hook_context.real_ia_set_vertex_buffers)(
         context,
         0,
-        first_null as UINT,
+        restore_count as UINT,
         curr_vbuffers.as_ptr(),
         curr_vbuffer_strides.as_ptr(),
         curr_vbuffer_offsets.as_ptr());
@@ -1291,3 +1433,26 @@ fn create_selection_texture_dx11() -> Result<()> {
 //         THIS,
 //     );
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snaplib::index_topology::{strip_to_list, RESTART_INDEX_16};
+
+    #[test]
+    fn test_key_prim_count() {
+        // with the index data the triangles across cuts aren't counted, so the key matches the
+        // prim count of the snapshot
+        let strip = [0, 1, 2, 3, 4, 5, RESTART_INDEX_16, 6, 7, 8, 9, RESTART_INDEX_16, 10, 11];
+        let info = DrawIndexInfo {
+            range: index_range(&strip, Some(RESTART_INDEX_16)),
+            prim_count: strip_prim_count(&strip, RESTART_INDEX_16),
+        };
+        let with_data = key_prim_count(strip.len() as u32, true, Some(&info));
+        assert_eq!(with_data, 6);
+        assert_eq!(with_data as usize, strip_to_list(&strip, RESTART_INDEX_16).len() / 3);
+        // without it they are
+        assert_eq!(key_prim_count(strip.len() as u32, true, None), 12);
+        assert_eq!(key_prim_count(strip.len() as u32, false, None), 4);
+    }
+}
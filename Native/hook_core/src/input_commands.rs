@@ -1,6 +1,7 @@
 
 use shared_dx::types::DevicePointer;
 use types::TexPtr;
+use types::command::Command;
 pub use winapi::shared::d3d9::*;
 pub use winapi::shared::d3d9types::*;
 pub use winapi::shared::minwindef::*;
@@ -11,6 +12,7 @@ use fnv::FnvHashSet;
 
 use std::ptr::null_mut;
 use shared_dx::util::*;
+use shared_dx::clock;
 use global_state::GLOBAL_STATE;
 use device_state::dev_state;
 use crate::hook_device_d3d11::apply_device_hook;
@@ -18,7 +20,11 @@ use crate::hook_device_d3d11::query_and_set_runconf_in_globalstate;
 use crate::hook_render::hook_set_texture;
 use crate::hook_render::MAX_STAGE;
 use crate::hook_render::CLR_OK;
+use crate::mod_render;
 use crate::input;
+use input::gamepad::Gamepad;
+use input::keybindings::{GamepadBindings, KeyBindings};
+use input::xinput::XInputGamepads;
 use mod_load::AsyncLoadState;
 
 use dnclr::reload_managed_dll;
@@ -26,7 +32,6 @@ use dnclr::reload_managed_dll;
 use shared_dx::error::*;
 use util::*;
 use winapi::ctypes::c_void;
-use std::time::SystemTime;
 
 use snaplib::anim_snap_state::AnimSnapState;
 use snaplib::anim_snap_state::AnimConstants;
@@ -85,9 +90,9 @@ pub fn init_snapshot_mode() {
             Ok(c) => c
         };
 
-        if snap_conf.snap_anim {
-            hook_snapshot::reset();
+        hook_snapshot::reset();
 
+        if snap_conf.snap_anim {
             let expected_primverts:HashSet<(UINT,UINT)> =
                 match snap_conf.autosnap.as_ref() {
                     Some(hm) => hm.iter().map(|m| (m.prims,m.verts) ).collect(),
@@ -104,7 +109,7 @@ pub fn init_snapshot_mode() {
                 expected_primverts,
                 seen_primverts: HashSet::new(),
                 sequence_vconstants: Vec::new(),
-                sequence_start_time: SystemTime::now(), // this will get overwritten when we actually start the constant sequences
+                sequence_start_time: clock::now(), // this will get overwritten when we actually start the constant sequences
                 snap_dir: "".to_owned(),
                 curr_frame: 0,
                 start_frame: 0,
@@ -117,13 +122,14 @@ pub fn init_snapshot_mode() {
             let snap_on_count = snap_conf.snap_anim_on_count;
             anim_state.sequence_vconstants.resize_with(max_seq, || AnimConstants {
                 snapped_at: std::time::SystemTime::UNIX_EPOCH,
+                sequence_offset: std::time::Duration::ZERO,
                 prim_count: 0,
                 vert_count: 0,
                 sequence: 0,
                 constants: constant_tracking::ConstantGroup::new(),
                 capture_count: 0,
                 frame: 0,
-                player_transform: Err(HookError::SnapshotFailed("".to_owned())),
+                transforms: [None; 4],
                 snap_on_count,
                 // worldmat: std::mem::zeroed(),
                 // viewmat: std::mem::zeroed(),
@@ -135,7 +141,8 @@ pub fn init_snapshot_mode() {
         }
 
         GLOBAL_STATE.is_snapping = true;
-        GLOBAL_STATE.snap_start = SystemTime::now();
+        GLOBAL_STATE.snap_start = clock::wall_now();
+        GLOBAL_STATE.snap_start_instant = Some(clock::now());
     }
 }
 
@@ -272,7 +279,7 @@ pub fn cmd_clear_mods(device: DevicePointer) {
     });
 }
 
-fn cmd_reload_mods(device: DevicePointer) {
+pub fn cmd_reload_mods(device: DevicePointer) {
     if is_loading_mods() {
         write_log_file("cannot reload now; mods are loading");
         return;
@@ -332,54 +339,68 @@ fn select_next_variant() {
                 continue;
             }
 
-            // get the current variant for this mod
+            // get the current variant for this mod and select the next one
             let sel_index_entry = mstate.selected_variant.entry(*mkey).or_insert(0);
-            let mut sel_index = *sel_index_entry;
-            let start = sel_index;
-            // select next, skipping over child mods.  stop if we wrap to where we started
-            sel_index += 1;
-            loop {
-                if sel_index >= nmdv.len() {
-                    sel_index = 0;
-                }
-                if sel_index == start {
-                    break;
-                }
-                if nmdv[sel_index].parent_mod_names.is_empty() {
-                    // found one
-                    write_log_file(&format!("selected next variant: {}", nmdv[sel_index].name));
-                    *sel_index_entry = sel_index;
-                    break;
-                }
-                // keep looking
-                sel_index += 1;
+            if let Some(sel_index) = mod_render::next_variant(nmdv, *sel_index_entry) {
+                write_log_file(&format!("selected next variant: {}", nmdv[sel_index].name));
+                *sel_index_entry = sel_index;
             }
         }
     });
 }
 
-fn setup_fkey_input(device: DevicePointer, inp: &mut input::Input) {
-    write_log_file("using fkey input layout");
-    // If you change these, be sure to change LocStrings/ProfileText in MMLaunch!
+/// Run a command, whatever its source (keyboard, IPC or command file).  Must be called on the
+/// render thread.
+pub fn run_command(cmd: Command, device: DevicePointer) {
+    match cmd {
+        Command::ReloadMods => cmd_reload_mods(device),
+        Command::ToggleShowMods => cmd_toggle_show_mods(),
+        Command::SelectNextTexture => cmd_select_next_texture(device),
+        Command::SelectPrevTexture => cmd_select_prev_texture(device),
+        Command::ClearTextureLists => cmd_clear_texture_lists(device),
+        Command::TakeSnapshot => cmd_take_snapshot(),
+        Command::NextVariant => select_next_variant(),
+    }
+}
 
+// If you change these, be sure to change LocStrings/ProfileText in MMLaunch!
+const FKEY_BINDINGS: [(u8, Command); 8] = [
+    (input::DIK_F1, Command::ReloadMods),
+    (input::DIK_F2, Command::ToggleShowMods),
+    (input::DIK_F3, Command::SelectNextTexture),
+    (input::DIK_F4, Command::SelectPrevTexture),
+    (input::DIK_F6, Command::ClearTextureLists),
+    (input::DIK_F7, Command::TakeSnapshot),
+    (input::DIK_NUMPAD8, Command::NextVariant),
+    (input::DIK_NUMPAD9, Command::NextVariant),
+];
+
+const PUNCT_BINDINGS: [(u8, Command); 8] = [
+    (input::DIK_BACKSLASH, Command::ReloadMods),
+    (input::DIK_RBRACKET, Command::ToggleShowMods),
+    (input::DIK_SEMICOLON, Command::ClearTextureLists),
+    (input::DIK_COMMA, Command::SelectNextTexture),
+    (input::DIK_PERIOD, Command::SelectPrevTexture),
+    (input::DIK_SLASH, Command::TakeSnapshot),
+    // Running out of punct!  oh well use these
+    (input::DIK_NUMPAD8, Command::NextVariant),
+    (input::DIK_NUMPAD9, Command::NextVariant),
+    // _punctKeyMap[DIK_MINUS] = [&]() { this->loadEverything(); };
+];
+
+fn bind_commands(device: DevicePointer, inp: &mut input::Input, bindings: &[(u8, Command)]) {
     // Allow the handlers to take a copy of the device pointer in the closure.
     // This means that these handlers must be cleared when the device is destroyed,
     // (see purge_device_resources)
     // but lets us avoid passing a context argument through the input layer.
-    inp.add_press_fn(input::DIK_F1, Box::new(move || cmd_reload_mods(device)));
-    inp.add_press_fn(input::DIK_F2, Box::new(cmd_toggle_show_mods));
-    inp.add_press_fn(
-        input::DIK_F3,
-        Box::new(move || cmd_select_next_texture(device)),
-    );
-    inp.add_press_fn(
-        input::DIK_F4,
-        Box::new(move || cmd_select_prev_texture(device)),
-    );
-    inp.add_press_fn(input::DIK_F6, Box::new(move || cmd_clear_texture_lists(device)));
-    inp.add_press_fn(input::DIK_F7, Box::new(cmd_take_snapshot));
-    inp.add_press_fn(input::DIK_NUMPAD8, Box::new(select_next_variant));
-    inp.add_press_fn(input::DIK_NUMPAD9, Box::new(select_next_variant));
+    for &(key, cmd) in bindings {
+        inp.add_press_fn(key, Box::new(move || run_command(cmd, device)));
+    }
+}
+
+fn setup_fkey_input(device: DevicePointer, inp: &mut input::Input) {
+    write_log_file("using fkey input layout");
+    bind_commands(device, inp, &FKEY_BINDINGS);
 
     // Disabling this because its ineffective: the reload will complete without error, but
     // The old managed code will still be used.  The old C++ code
@@ -390,30 +411,50 @@ fn setup_fkey_input(device: DevicePointer, inp: &mut input::Input) {
 
 fn setup_punct_input(device: DevicePointer, inp: &mut input::Input) {
     write_log_file("using punct key input layout");
-    // If you change these, be sure to change LocStrings/ProfileText in MMLaunch!
-    inp.add_press_fn(input::DIK_BACKSLASH, Box::new(move || cmd_reload_mods(device)));
-    inp.add_press_fn(input::DIK_RBRACKET, Box::new(cmd_toggle_show_mods));
-    inp.add_press_fn(input::DIK_SEMICOLON, Box::new(move || cmd_clear_texture_lists(device)));
-    inp.add_press_fn(
-        input::DIK_COMMA,
-        Box::new(move || cmd_select_next_texture(device)),
-    );
-    inp.add_press_fn(
-        input::DIK_PERIOD,
-        Box::new(move || cmd_select_prev_texture(device)),
-    );
-    inp.add_press_fn(input::DIK_SLASH, Box::new(cmd_take_snapshot));
+    bind_commands(device, inp, &PUNCT_BINDINGS);
+}
 
-    // Running out of punct!  oh well use these
-    inp.add_press_fn(input::DIK_NUMPAD8, Box::new(select_next_variant));
-    inp.add_press_fn(input::DIK_NUMPAD9, Box::new(select_next_variant));
+fn setup_file_input(device: DevicePointer, inp: &mut input::Input, kb: &KeyBindings) {
+    write_log_file(&format!("using key bindings file ({} bindings)", kb.bindings.len()));
+    for b in kb.bindings.iter() {
+        let cmd = b.command;
+        inp.add_chord_fn(b.chord, b.repeat, Box::new(move || run_command(cmd, device)));
+    }
+}
 
-    // _punctKeyMap[DIK_MINUS] = [&]() { this->loadEverything(); };
+/// Bind the combos from the gamepad bindings file, if there is one, creating the gamepad
+/// source if needed.  Problems are logged; the keyboard works regardless.
+fn setup_gamepad_input(device: DevicePointer, inp: &mut input::Input) {
+    let mm_root = match unsafe { GLOBAL_STATE.mm_root.clone() } {
+        Some(root) => root,
+        None => return,
+    };
+    let gb = match GamepadBindings::load(&mm_root) {
+        Ok(Some(gb)) => gb,
+        Ok(None) => return,
+        Err(e) => {
+            write_log_file(&format!("ERROR: failed to load gamepad bindings, gamepad disabled: {:?}", e));
+            return;
+        }
+    };
+    if inp.gamepad_mut().is_none() {
+        match XInputGamepads::new() {
+            Ok(xi) => inp.set_gamepad(Gamepad::new(Box::new(xi))),
+            Err(e) => {
+                write_log_file(&format!("ERROR: can't read gamepads: {:?}", e));
+                return;
+            }
+        }
+    }
+    write_log_file(&format!("using gamepad bindings file ({} bindings)", gb.bindings.len()));
+    if let Some(gp) = inp.gamepad_mut() {
+        for &(combo, cmd) in gb.bindings.iter() {
+            gp.add_combo_fn(combo, Box::new(move || run_command(cmd, device)));
+        }
+    }
 }
 
 pub fn setup_input(device: DevicePointer, inp: &mut input::Input) -> Result<()> {
-    use std::ffi::CStr;
-
     // if we fail to set it up repeatedly, don't spam log forever
     inp.setup_attempts += 1;
     if inp.setup_attempts == 10 {
@@ -428,8 +469,31 @@ pub fn setup_input(device: DevicePointer, inp: &mut input::Input) -> Result<()>
         // )));
     }
 
-    // Set key bindings.  Input also assumes that CONTROL modifier is required for these as well.
-    // TODO: should push this out to conf file eventually so that they can be customized without rebuild
+    // if the keyboard setup fails it is retried later, so only add the gamepad once it worked
+    setup_keyboard_input(device, inp)?;
+    setup_gamepad_input(device, inp);
+    Ok(())
+}
+
+fn setup_keyboard_input(device: DevicePointer, inp: &mut input::Input) -> Result<()> {
+    use std::ffi::CStr;
+
+    // Use the key bindings file if there is one; otherwise use the profile chosen in the
+    // launcher.  The profile bindings all require the CONTROL modifier.
+    let mm_root = unsafe { GLOBAL_STATE.mm_root.clone() };
+    match mm_root.map(|root| KeyBindings::load(&root)) {
+        Some(Ok(Some(kb))) => {
+            setup_file_input(device, inp, &kb);
+            return Ok(());
+        },
+        Some(Err(e)) => {
+            write_log_file(&format!("ERROR: failed to load key bindings, using FKeys: {:?}", e));
+            setup_fkey_input(device, inp);
+            return Ok(());
+        },
+        Some(Ok(None)) | None => {},
+    }
+
     let interop_state = unsafe { &GLOBAL_STATE.interop_state };
     interop_state
         .as_ref()
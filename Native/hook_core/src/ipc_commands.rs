@@ -0,0 +1,208 @@
+//! Answers requests from the local IPC server (see the `ipc` crate), which gives tools and the
+//! launcher a live view of the hook.  The server is started if the `IpcServer` root registry value
+//! is 1; its endpoint is named after the exe, e.g. `\\.\pipe\modelmod.Game` for `Game.exe`.
+//!
+//! The server thread can't touch the global state, so it queues requests and waits for the
+//! render thread to answer them in `process_requests`, which is called from the per frame (or
+//! periodic draw) processing.  If the game isn't rendering, requests time out.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use global_state::GLOBAL_STATE;
+use lazy_static::lazy_static;
+use ipc::protocol::{Request, Response};
+use ipc::transport::{endpoint_for, IpcServer};
+use serde_json::{json, Value};
+use shared_dx::frame_stats::Percentiles;
+use shared_dx::types::DevicePointer;
+use shared_dx::util::write_log_file;
+use types::command::Command;
+use types::native_mod::ModD3DState;
+
+use crate::input_commands;
+
+/// How long the server waits for the render thread to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Requests beyond this many waiting for the render thread are refused
+const MAX_PENDING: usize = 16;
+
+lazy_static! {
+    static ref PENDING: Mutex<Vec<(Request, Sender<Response>)>> = Mutex::new(vec![]);
+    static ref SERVER: Mutex<Option<IpcServer>> = Mutex::new(None);
+}
+static HAS_PENDING: AtomicBool = AtomicBool::new(false);
+static START: Once = Once::new();
+
+fn start_server() {
+    match unsafe { util::reg_query_root_dword("IpcServer") } {
+        Ok(1) => {},
+        _ => return,
+    }
+    let name = match util::get_module_name_base() {
+        Ok(name) => name,
+        Err(e) => {
+            write_log_file(&format!("ipc: can't start server, no module name: {:?}", e));
+            return;
+        }
+    };
+    let endpoint = endpoint_for(&name);
+    match IpcServer::start(&endpoint, Arc::new(queue_request), write_log_file) {
+        Ok(server) => {
+            write_log_file(&format!("ipc: server listening on {}", endpoint));
+            if let Ok(mut s) = SERVER.lock() {
+                *s = Some(server);
+            }
+        },
+        Err(e) => write_log_file(&format!("ipc: failed to start server on {}: {:?}", endpoint, e)),
+    }
+}
+
+/// Called on the server thread: hand the request to the render thread and wait for the answer.
+fn queue_request(req: Request) -> Response {
+    let (tx, rx) = channel();
+    match PENDING.lock() {
+        Ok(mut pending) => {
+            if pending.len() >= MAX_PENDING {
+                return Response::error("too many pending requests");
+            }
+            pending.push((req, tx));
+            HAS_PENDING.store(true, Ordering::SeqCst);
+        },
+        Err(_) => return Response::error("request queue unavailable"),
+    }
+    rx.recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or_else(|_| Response::error("timed out waiting for the render thread"))
+}
+
+/// Start the server if needed and answer any queued requests.  Must be called from the render
+/// thread.  `device` is only called if there is a request to answer.
+pub fn process_requests<F>(device: F) where F: FnOnce() -> Option<DevicePointer> {
+    START.call_once(start_server);
+    if !HAS_PENDING.load(Ordering::SeqCst) {
+        return;
+    }
+    let pending = match PENDING.lock() {
+        Ok(mut pending) => {
+            HAS_PENDING.store(false, Ordering::SeqCst);
+            std::mem::take(&mut *pending)
+        },
+        Err(_) => return,
+    };
+    let device = device();
+    for (req, tx) in pending {
+        // the server may have given up waiting, that's fine
+        let _ = tx.send(handle_request(req, device));
+    }
+}
+
+fn handle_request(req: Request, device: Option<DevicePointer>) -> Response {
+    match req {
+        Request::Stats => stats(),
+        Request::Mods => mods(),
+        Request::Selected => selected(),
+        Request::Snapshot => {
+            let device = match device {
+                Some(d) => d,
+                None => return Response::error("no device"),
+            };
+            if unsafe { GLOBAL_STATE.is_snapping } {
+                return Response::error("a snapshot is already in progress");
+            }
+            input_commands::run_command(Command::TakeSnapshot, device);
+            Response::ok(json!({ "started": unsafe { GLOBAL_STATE.is_snapping } }))
+        },
+        Request::Reload => {
+            let device = match device {
+                Some(d) => d,
+                None => return Response::error("no device"),
+            };
+            if input_commands::is_loading_mods() {
+                return Response::error("mods are loading");
+            }
+            input_commands::run_command(Command::ReloadMods, device);
+            Response::ok(json!({ "reloading": true }))
+        },
+        Request::Run { command } => {
+            let cmd = match Command::from_name(&command) {
+                Some(cmd) => cmd,
+                None => return Response::error(&format!("unknown command: {}", command)),
+            };
+            let device = match device {
+                Some(d) => d,
+                None => return Response::error("no device"),
+            };
+            write_log_file(&format!("ipc: running command {}", cmd));
+            input_commands::run_command(cmd, device);
+            Response::ok(json!({ "ran": cmd.name() }))
+        },
+    }
+}
+
+fn percentiles(p: Option<Percentiles>) -> Value {
+    match p {
+        Some(p) => json!({ "p50": p.p50, "p95": p.p95, "p99": p.p99, "max": p.max }),
+        None => Value::Null,
+    }
+}
+
+fn stats() -> Response {
+    let gs = unsafe { &GLOBAL_STATE };
+    let m = &gs.metrics;
+    let fs = m.frame_stats.summary();
+    let loaded_mods = gs.loaded_mods.as_ref().map(|lm| lm.mods.values().map(|v| v.len()).sum::<usize>()).unwrap_or(0);
+    Response::ok(json!({
+        "total_frames": m.total_frames,
+        "fps": m.last_fps,
+        "low_framerate": m.low_framerate,
+        "show_mods": gs.show_mods,
+        "snapping": gs.is_snapping,
+        "loaded_mods": loaded_mods,
+        "frame_stats": {
+            "frames": fs.frames,
+            "frame_ms": percentiles(fs.frame_ms),
+            "draws": percentiles(fs.draws),
+            "mod_draws": percentiles(fs.mod_draws),
+            "hook_us_per_draw": percentiles(fs.draw_overhead_us),
+        },
+    }))
+}
+
+fn mods() -> Response {
+    let gs = unsafe { &GLOBAL_STATE };
+    let frame = gs.metrics.total_frames;
+    let mut mods: Vec<Value> = gs.loaded_mods.as_ref().map(|lm| {
+        lm.mods.values().flatten().map(|nmod| {
+            let state = match nmod.d3d_data {
+                ModD3DState::Unloaded => "unloaded",
+                ModD3DState::Partial(_) => "partial",
+                ModD3DState::Loaded(_) => "loaded",
+            };
+            json!({
+                "name": nmod.name,
+                "prims": nmod.mod_data.numbers.ref_prim_count,
+                "verts": nmod.mod_data.numbers.ref_vert_count,
+                "state": state,
+                "recently_rendered": nmod.recently_rendered(frame),
+                "parents": nmod.parent_mod_names,
+            })
+        }).collect()
+    }).unwrap_or_default();
+    mods.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Response::ok(json!({ "mods": mods }))
+}
+
+fn selected() -> Response {
+    let gs = unsafe { &GLOBAL_STATE };
+    let textures = gs.active_texture_list.as_ref();
+    let texture = textures
+        .and_then(|l| l.get(gs.curr_texture_index))
+        .map(|t| format!("0x{:x}", t));
+    Response::ok(json!({
+        "making_selection": gs.making_selection,
+        "index": gs.curr_texture_index,
+        "textures": textures.map(|l| l.len()).unwrap_or(0),
+        "texture": texture,
+    }))
+}
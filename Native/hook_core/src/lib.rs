@@ -37,6 +37,8 @@ mod hook_device;
 //mod hook_constants;
 mod mod_render;
 mod hook_device_d3d11;
+mod ipc_commands;
+mod command_file;
 
 pub use interop::{LogError, LogInfo, LogWarn};
 pub use interop::{OnInitialized, SaveTexture};
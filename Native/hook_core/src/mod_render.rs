@@ -1,5 +1,5 @@
 use global_state::LoadedModState;
-use types::native_mod::NativeModData;
+use types::native_mod::{NativeModData, NO_FINGERPRINT};
 use shared_dx::util::*;
 
 fn find_parent<'a>(name:&str, mvec:&'a mut Vec<NativeModData>) -> Option<&'a mut NativeModData> {
@@ -72,6 +72,14 @@ pub fn preselect(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32) ->
 /// call `preselect` first to determine if this function even needs to be called.  `select` does
 /// early out as soon as it knows there is no mod, but still incurs a bit of extra cost.
 pub fn select(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, current_frame_num:u64) -> Option<&NativeModData> {
+    select_with_fingerprint(mstate, prim_count, vert_count, NO_FINGERPRINT, current_frame_num)
+}
+
+/// Like `select`, but mods whose reference has a geometry fingerprint that differs from
+/// `fingerprint` are ignored, as if they had a different key.  This keeps unrelated meshes that
+/// happen to have the same prim and vert count from showing up as variants of each other.
+pub fn select_with_fingerprint(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, fingerprint:u32,
+    current_frame_num:u64) -> Option<&NativeModData> {
     let mod_key = NativeModData::mod_key(vert_count, prim_count);
     let r = mstate.mods.get(&mod_key);
     // just get out of here if we didn't have a match
@@ -83,13 +91,21 @@ pub fn select(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, curre
     let mut target_mod_index:usize = 0;
     let r2 = r.and_then(|nmods| {
         let mut num_active_parents = 0;
-        let num_mods = nmods.len();
+        // mods for some other geometry don't count
+        let mut num_mods = 0;
+        let mut first_match = None;
         for (midx,nmod) in nmods.iter().enumerate() {
+            if !nmod.fingerprint_matches(fingerprint) {
+                debug_spam!(|| format!("fingerprint mismatch for {}", nmod.name));
+                continue;
+            }
+            num_mods += 1;
+            first_match.get_or_insert(midx);
             if nmod.parent_mod_names.is_empty() {
-                debug_spam!(|| format!("no parents for {} (num mods {})", nmod.name, num_mods));
+                debug_spam!(|| format!("no parents for {} (num mods {})", nmod.name, nmods.len()));
                 continue;
             }
-            debug_spam!(|| format!("check parents for {} (nummods: {}, parents: {:?})", nmod.name, num_mods, nmod.parent_mod_names));
+            debug_spam!(|| format!("check parents for {} (nummods: {}, parents: {:?})", nmod.name, nmods.len(), nmod.parent_mod_names));
             iter_parent_mods(nmod, mstate, &mut |parent:&NativeModData| {
                 if parent.recently_rendered(current_frame_num) {
                     target_mod_index = midx;
@@ -100,6 +116,10 @@ pub fn select(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, curre
                 }
             });
         }
+        let first_match = first_match?;
+        if num_active_parents == 0 {
+            target_mod_index = first_match;
+        }
 
         // return Some(()) if we found a valid one.
         match num_mods {
@@ -111,17 +131,20 @@ pub fn select(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, curre
                 Some(())
             },
             // just one mod it doesn't have a parent, or if it does and there is just one parent
-            n if n == 1 && (nmods[0].parent_mod_names.is_empty() || num_active_parents == 1) => {
+            n if n == 1 && (nmods[first_match].parent_mod_names.is_empty() || num_active_parents == 1) => {
                 // write_log_file(&format!("rend mod {} because just one mod with parname '{}' or {} parents",
                 // nmods[target_mod_index].name, nmods[0].parent_mod_name, num_active_parents));
                 Some(())
             },
             // more than one mod, 0 or >1 active parents, so if we have a selected variant
-            // index, use that index
+            // index, use that index.  if the selected variant is for other geometry, use the
+            // first mod for this geometry.
             n if n > 1 => { //&& mstate.selected_variant.contains_key(&mod_key)
                 let tmic = target_mod_index;
-                let sel_index = mstate.selected_variant.get(&mod_key).unwrap_or(&tmic);
-                if *sel_index < n {
+                let sel_index = mstate.selected_variant.get(&mod_key)
+                    .filter(|idx| nmods.get(**idx).map(|nmod| nmod.fingerprint_matches(fingerprint)).unwrap_or(true))
+                    .unwrap_or(&tmic);
+                if *sel_index < nmods.len() {
                     // currently child mods can't be variants - this avoids messy cases with
                     // one or more children whose parents may or may not have rendered recently.
                     nmods.get(*sel_index).and_then(|nmod| {
@@ -172,6 +195,24 @@ pub fn select(mstate: &mut LoadedModState, prim_count:u32, vert_count:u32, curre
     r
 }
 
+/// The index of the variant after `selected` in `nmods`, wrapping around, or None if there isn't
+/// another one.  Child mods can't be variants so they are skipped.  Mods with the same key can
+/// be for different geometry (see `select_with_fingerprint`), so only mods that match the
+/// fingerprint of the geometry drawn most recently are considered; this is taken from the mod
+/// that rendered last (preferring the selected one).
+pub fn next_variant(nmods:&[NativeModData], selected:usize) -> Option<usize> {
+    let last = nmods.iter().enumerate()
+        .max_by_key(|(idx,nmod)| (nmod.last_frame_render, *idx == selected))
+        .map(|(_,nmod)| nmod)?;
+    let fingerprint = last.mod_data.numbers.ref_fingerprint;
+    (1..nmods.len())
+        .map(|step| (selected + step) % nmods.len())
+        .find(|idx| {
+            let nmod = &nmods[*idx];
+            nmod.parent_mod_names.is_empty() && nmod.fingerprint_matches(fingerprint)
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -390,6 +431,80 @@ mod tests {
         assert!(r.is_none(), "unexpected mod: {:?}", r.unwrap().name);
     }
 
+    #[test]
+    fn fingerprints() {
+        let fp_a = types::native_mod::geom_fingerprint([("POSITION",0,6), ("TEXCOORD",0,16)], 20);
+        let fp_b = types::native_mod::geom_fingerprint([("POSITION",0,6), ("NORMAL",0,6)], 24);
+        assert_ne!(fp_a, fp_b);
+        assert_ne!(fp_a, NO_FINGERPRINT);
+        // semantic names are case insensitive
+        assert_eq!(fp_a, types::native_mod::geom_fingerprint([("position",0,6), ("texcoord",0,16)], 20));
+
+        let mut modmap:LoadedModsMap = new_fnv_map(10);
+        let mut moda = new_mod("ModA", 100, 200);
+        moda.mod_data.numbers.ref_fingerprint = fp_a;
+        add_mod(&mut modmap, moda);
+        let mut modb = new_mod("ModB", 100, 200);
+        modb.mod_data.numbers.ref_fingerprint = fp_b;
+        add_mod(&mut modmap, modb);
+        let mut mstate = new_state(modmap);
+        // the mods have the same key but each only renders for its own geometry, so they
+        // aren't variants
+        let r = select_with_fingerprint(&mut mstate, 100, 200, fp_a, 0);
+        assert_eq!(r.expect("no mod found").name, "moda".to_string());
+        let r = select_with_fingerprint(&mut mstate, 100, 200, fp_b, 0);
+        assert_eq!(r.expect("no mod found").name, "modb".to_string());
+        // selecting the other mod as a variant doesn't affect this geometry
+        let mk = NativeModData::mod_key(200, 100);
+        mstate.selected_variant.insert(mk, 1);
+        let r = select_with_fingerprint(&mut mstate, 100, 200, fp_a, 0);
+        assert_eq!(r.expect("no mod found").name, "moda".to_string());
+        // other geometry with the same key gets nothing
+        let fp_c = types::native_mod::geom_fingerprint([("POSITION",0,2)], 12);
+        let r = select_with_fingerprint(&mut mstate, 100, 200, fp_c, 0);
+        assert!(r.is_none(), "unexpected mod: {:?}", r.unwrap().name);
+        // an unknown fingerprint (d3d9) behaves as before, so these are variants
+        let r = select(&mut mstate, 100, 200, 0);
+        assert_eq!(r.expect("no mod found").name, "modb".to_string());
+
+        // a mod without a fingerprint renders for any geometry
+        let mut modmap:LoadedModsMap = new_fnv_map(10);
+        add_mod(&mut modmap, new_mod("ModOld", 100, 200));
+        let mut mstate = new_state(modmap);
+        let r = select_with_fingerprint(&mut mstate, 100, 200, fp_c, 0);
+        assert_eq!(r.expect("no mod found").name, "modold".to_string());
+    }
+
+    #[test]
+    fn next_variants() {
+        let fp_a = types::native_mod::geom_fingerprint([("POSITION",0,6), ("TEXCOORD",0,16)], 20);
+        let fp_b = types::native_mod::geom_fingerprint([("POSITION",0,6), ("NORMAL",0,6)], 24);
+        let mut nmods = vec![];
+        for (name, fp) in [("a1", fp_a), ("b1", fp_b), ("a2", fp_a), ("old", NO_FINGERPRINT), ("b2", fp_b)] {
+            let mut nmod = new_mod(name, 100, 200);
+            nmod.mod_data.numbers.ref_fingerprint = fp;
+            nmods.push(nmod);
+        }
+        // geometry a was drawn last: its variants (and the mod without a fingerprint) cycle
+        nmods[0].last_frame_render = 10;
+        assert_eq!(next_variant(&nmods, 0), Some(2));
+        assert_eq!(next_variant(&nmods, 2), Some(3));
+        assert_eq!(next_variant(&nmods, 3), Some(0));
+        // a selection for the other geometry moves to the next one for this geometry
+        assert_eq!(next_variant(&nmods, 1), Some(2));
+        // geometry b drawn last
+        nmods[4].last_frame_render = 11;
+        assert_eq!(next_variant(&nmods, 4), Some(1));
+        assert_eq!(next_variant(&nmods, 1), Some(3));
+        // both drawn in the same frame, the selected one wins
+        nmods[0].last_frame_render = 11;
+        assert_eq!(next_variant(&nmods, 0), Some(2));
+        // children are skipped, and a lone mod has no next variant
+        nmods[2].parent_mod_names.push("parent".to_owned());
+        assert_eq!(next_variant(&nmods, 0), Some(3));
+        assert_eq!(next_variant(&nmods[0..1], 0), None);
+    }
+
     #[test]
     fn uniq_keys() {
         // slow test to make sure the modkey hash doesn't have obvious, bad collisions
@@ -1,7 +1,9 @@
 use device_state::dev_state_d3d11_nolock;
+use device_state::dev_state_d3d11_write;
 use device_state::dev_state_d3d11_read;
 use global_state::HookState;
 use shared_dx::types::DevicePointer;
+use shared_dx::precopy_store::BufferKind;
 use types::d3dx::D3DXFn;
 use types::interop::D3D11SnapshotRendData;
 use types::interop::D3D9SnapshotRendData;
@@ -16,11 +18,12 @@ use winapi::shared::minwindef::{DWORD, UINT, BOOL};
 
 use constant_tracking;
 use d3dx;
-use global_state::{GLOBAL_STATE};
-use winapi::um::{d3d11::{D3D11_BIND_RENDER_TARGET, D3D11_BUFFER_DESC, D3D11_INPUT_ELEMENT_DESC,
+use global_state::{GLOBAL_STATE, D3D11KeyMode};
+use winapi::um::{d3d11::{D3D11_BIND_DEPTH_STENCIL, D3D11_BIND_RENDER_TARGET, D3D11_BIND_UNORDERED_ACCESS, D3D11_BUFFER_DESC, D3D11_INPUT_ELEMENT_DESC, D3D11_INPUT_PER_VERTEX_DATA,
     D3D11_SHADER_RESOURCE_VIEW_DESC, D3D11_TEXTURE2D_DESC, ID3D11Buffer, ID3D11Device,
     ID3D11DeviceContext, ID3D11Resource, ID3D11ShaderResourceView,
-    ID3D11Texture2D, ID3D11View}, d3dcommon::D3D11_SRV_DIMENSION_TEXTURE2D};
+    ID3D11Texture2D, ID3D11View, D3D11_USAGE_DYNAMIC, D3D11_USAGE_STAGING}, d3dcommon::{D3D11_SRV_DIMENSION_TEXTURE2D,
+    D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST, D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP}};
 
 use std;
 use std::collections::BTreeMap;
@@ -30,42 +33,105 @@ use std::ptr::null_mut;
 use std::time::SystemTime;
 
 use shared_dx::util::*;
+use shared_dx::clock;
 use shared_dx::error::*;
 
-use snaplib::snap_config::{SnapConfig};
-use snaplib::anim_frame::{AnimFrame, AnimFrameFile};
+use snaplib::snap_config::{SnapConfig, SnapWindow};
+use snaplib::anim_frame::AnimFrameFile;
 use snaplib::anim_frame::RenderStateMap;
 use snaplib::anim_frame::write_obj_to_file;
 use snaplib::anim_snap_state::AnimSnapState;
+use snaplib::anim_export;
+use snaplib::snap_manifest::{SnapManifest, ManifestDraw, session_manifest_name};
+use snaplib::texture_dedup::TextureDedup;
+use snaplib::vertex_streams::{VertexStream, stream_offsets, interleave};
+use snaplib::index_topology::{read_indices, write_indices, strip_to_list, restart_index};
+use snaplib::render_state_d3d11::{D3D11RenderStateFile, BlendState, RenderTargetBlend,
+    RasterizerState, DepthStencilState, StencilOp, SamplerState, SrvInfo};
+use snaplib::frame_context::{FrameContextProvider, NullFrameContextProvider};
 
 use std::collections::HashMap;
+use fnv::FnvHashSet;
 
 use std::sync::RwLock;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 lazy_static! {
-    // Snapshotting currently stops after a certain amount of real time has passed from the start of
-    // the snap, specified by this constant.
-    // One might expect that just snapping everything drawn within a single begin/end scene combo is
-    // sufficient, but this often misses data,
-    // and sometimes fails to snapshot anything at all.  This may be because the game is using multiple
-    // begin/end combos, so maybe
-    // present->present would be more reliable (TODO: check this)
-    // Using a window makes it much more likely that something useful is captured, at the expense of
-    // some duplicates; even though
-    // some objects may still be missed.  Some investigation to make this more reliable would be useful.
+    // See the comment at the top of snap_config.rs for a discussion of the snapshot window.
 
     pub static ref SNAP_CONFIG: Arc<RwLock<SnapConfig>> = Arc::new(RwLock::new(SnapConfig {
         snap_ms: 250, // TODO11: dx11 needs longer, 5 seconds?
+        snap_window: None,
         snap_anim: false,
         snap_anim_on_count: 2,
         // TODO: should read these limits from device, it might support fewer!
         vconsts_to_capture: 256,
         pconsts_to_capture: 224,
         autosnap: None,
+        autosnap_wildcards: None,
         require_gpu: None,
         plugins: None,
+        anim_export: None,
     }));
+
+    /// Supplies the extra transforms recorded with each anim capture.
+    static ref FRAME_CONTEXT_PROVIDER: Mutex<Box<dyn FrameContextProvider>> =
+        Mutex::new(Box::new(NullFrameContextProvider));
+
+    /// Meshes that have been autosnapped in the current snapshot window, so that each prim/vert
+    /// combo is only captured once.  Cleared by `reset()`.
+    static ref AUTOSNAPPED: Mutex<FnvHashSet<(UINT,UINT)>> = Mutex::new(FnvHashSet::default());
+
+    /// Manifest for the current snapshot window, written when the window ends.
+    static ref SNAP_MANIFEST: Mutex<Option<SnapManifest>> = Mutex::new(None);
+
+    /// Texture de-duplication for the current snapshot window.
+    static ref TEXTURE_DEDUP: Mutex<TextureDedup> = Mutex::new(TextureDedup::new());
+}
+
+/// Counters for the current snapshot window, reset by `reset()`.
+static SNAP_WINDOW_PRESENTS: AtomicU64 = AtomicU64::new(0);
+static SNAP_WINDOW_DRAWS: AtomicU64 = AtomicU64::new(0);
+static SNAP_WINDOW_SNAPS: AtomicU64 = AtomicU64::new(0);
+/// Set once any present has been observed, if this is false present based windows can't work.
+static PRESENT_SEEN: AtomicBool = AtomicBool::new(false);
+
+/// Index count and instance count (1 for non-instanced draws) of the draw being passed to
+/// `take()`, these don't fit in `SnapshotData`.
+static DRAW_INDEX_COUNT: AtomicU32 = AtomicU32::new(0);
+static DRAW_INSTANCE_COUNT: AtomicU32 = AtomicU32::new(1);
+
+/// Called by the d3d11 draw hooks before `take()`.  The index count is needed to convert strips
+/// and the instance count is recorded with the capture.
+pub fn set_draw_info(index_count:u32, instance_count:u32) {
+    DRAW_INDEX_COUNT.store(index_count, Ordering::Relaxed);
+    DRAW_INSTANCE_COUNT.store(instance_count, Ordering::Relaxed);
+}
+
+/// Should be called by the present hooks on every present, whether or not a snapshot is in
+/// progress.
+pub fn note_present() {
+    PRESENT_SEEN.store(true, Ordering::Relaxed);
+    if unsafe { GLOBAL_STATE.is_snapping } {
+        SNAP_WINDOW_PRESENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// True once any present has been observed by `note_present()`.
+pub fn present_seen() -> bool {
+    PRESENT_SEEN.load(Ordering::Relaxed)
+}
+
+/// Replace the frame context provider used for anim snapshots.  The default provider
+/// supplies no extra transforms.
+pub fn set_frame_context_provider(provider: Box<dyn FrameContextProvider>) -> Result<()> {
+    let mut lock = FRAME_CONTEXT_PROVIDER.lock()
+        .map_err(|e| HookError::SnapshotFailed(format!("failed to lock frame context provider: {}", e)))?;
+    write_log_file(&format!("anim frame context provider: {}", provider.name()));
+    *lock = provider;
+    Ok(())
 }
 
 fn snapshot_extra() -> bool {
@@ -108,6 +174,7 @@ pub fn take(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, thi
     if devptr.is_null() {
         return;
     }
+    SNAP_WINDOW_DRAWS.fetch_add(1, Ordering::Relaxed);
     if sd.prim_count == 0 || sd.num_vertices == 0 {
         return;
     }
@@ -124,6 +191,8 @@ pub fn take(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, thi
     let gs = unsafe {&mut GLOBAL_STATE };
     let autosnap = if let Some(_) = &gs.anim_snap_state {
         auto_snap_anim(devptr, sd, gs, &snap_conf)
+    } else if gs.is_snapping && !this_is_selected {
+        auto_snap_mesh(sd, &snap_conf)
     } else {
         false
     };
@@ -134,6 +203,8 @@ pub fn take(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, thi
         return;
     }
 
+    SNAP_WINDOW_SNAPS.fetch_add(1, Ordering::Relaxed);
+
     let pre_rc;
     // snap in a block so that drops within activate and we can check ref count after
     unsafe {
@@ -182,12 +253,26 @@ pub fn take(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, thi
                         });
                         let sprefix = String::from_utf16(&sprefix).unwrap_or_else(|_| "".to_owned());
 
+
                         // write_log_file(&format!("snap save dir: {}", dir));
                         // write_log_file(&format!("snap prefix: {}", sprefix));
 
-                        let _ = save_textures(devptr, &bufs, &dir, &sprefix).map_err(|e| {
+                        let shared_textures = save_textures(devptr, &bufs, &dir, &sprefix).map_err(|e| {
                             write_log_file(&format!("failed to save textures: {:?}", e));
-                        });
+                        }).unwrap_or_default();
+
+                        add_to_manifest(devptr, sd, &dir, &sprefix, gs.snap_start, shared_textures);
+
+                        // the mod tool copies this into the reference yaml, so that mods made
+                        // from this snapshot don't render on other meshes with the same counts
+                        let fingerprint = dev_state_d3d11_nolock()
+                            .and_then(|ds| ds.rs.get_current_vertex_format().map(|vf| vf.fingerprint));
+                        if let Some(fingerprint) = fingerprint {
+                            let file = format!("{}/{}_fingerprint.txt", &dir, &sprefix);
+                            let _r = std::fs::write(&file, format!("{:#010x}", fingerprint)).map_err(|e| {
+                                write_log_file(&format!("failed to write geometry fingerprint: {:?}", e));
+                            });
+                        }
 
                         let (gotpix,gotvert) = shader_capture::take_snapshot(devptr, &dir, &sprefix);
                         let vc = if gotvert { &gs.vertex_constants } else { &None };
@@ -243,12 +328,17 @@ pub fn take(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, thi
     }
 }
 
-unsafe fn save_textures(devtr:&mut DevicePointer, buffers:&Box<dyn SnapDeviceBuffers>, snap_dir:&str, snap_prefix:&str) -> Result<()> {
+/// Save the textures for a d3d11 snapshot.  Textures are de-duplicated within the session, see
+/// `TextureDedup`.  Returns the shared texture path for each stage that was saved.
+unsafe fn save_textures(devtr:&mut DevicePointer, buffers:&Box<dyn SnapDeviceBuffers>, snap_dir:&str, snap_prefix:&str) -> Result<BTreeMap<u32,String>> {
     // in d3d9 the managed code already did this
     let device = match devtr {
         DevicePointer::D3D11(d) => *d,
-        _ => return Ok(()),
+        _ => return Ok(BTreeMap::new()),
     };
+    let mut dedup = TEXTURE_DEDUP.lock()
+        .map_err(|e| HookError::SnapshotFailed(format!("failed to lock texture dedup: {}", e)))?;
+    let mut shared_textures = BTreeMap::new();
     let d3dx_fn = GLOBAL_STATE
         .d3dx_fn
         .as_ref()
@@ -309,8 +399,33 @@ unsafe fn save_textures(devtr:&mut DevicePointer, buffers:&Box<dyn SnapDeviceBuf
             write_log_file(&format!("tex {} [{:?}] has usage {}, cpu access flags {}, bindflags {}, miscflags {}, format {}",
                 idx, heightwidth_format, desc.Usage, desc.CPUAccessFlags, desc.BindFlags, desc.MiscFlags, desc.Format));
 
-            let out = format!("{}/{}_texture{}.dds", snap_dir, snap_prefix, idx);
-            let out = util::to_wide_str(&out);
+            let out_file = format!("{}/{}_texture{}.dds", snap_dir, snap_prefix, idx);
+            let out_path = std::path::Path::new(&out_file);
+
+            // textures the game can't have changed since they were saved earlier in the session are
+            // just linked
+            let unchanging = desc.Usage != D3D11_USAGE_DYNAMIC && desc.Usage != D3D11_USAGE_STAGING
+                && desc.BindFlags & (D3D11_BIND_UNORDERED_ACCESS | D3D11_BIND_DEPTH_STENCIL) == 0;
+            let resource = if unchanging {
+                dev_state_d3d11_read()
+                    .and_then(|(_lock,ds)| ds.rs.texture_generation(texptr as usize))
+                    .map(|gen| (texptr as usize, gen))
+            } else {
+                None
+            };
+            if let Some(resource) = resource {
+                match dedup.store_known(snap_dir, resource, out_path) {
+                    Ok(Some(shared)) => {
+                        num_saved += 1;
+                        shared_textures.insert(*idx, shared);
+                        continue;
+                    },
+                    Ok(None) => {},
+                    Err(e) => write_log_file(&format!("failed to link texture {} to shared folder, saving it: {:?}", idx, e)),
+                }
+            }
+
+            let out = util::to_wide_str(&out_file);
             const D3DX11_IFF_DDS: u32 = 4;
             let hr = (d3dx_fn.D3DX11SaveTextureToFileW)(context, resptr, D3DX11_IFF_DDS, out.as_ptr());
             if hr != 0 {
@@ -318,18 +433,104 @@ unsafe fn save_textures(devtr:&mut DevicePointer, buffers:&Box<dyn SnapDeviceBuf
                 write_log_file(&format!("failed to save texture from srv {}: {}", idx, hr));
             } else {
                 num_saved += 1;
+                // if this fails the texture is still there, just not shared
+                match dedup.store(snap_dir, out_path, resource) {
+                    Ok(shared) => { shared_textures.insert(*idx, shared); },
+                    Err(e) => write_log_file(&format!("failed to move texture {} to shared folder: {:?}", idx, e)),
+                }
             }
         }
     }
     if num_2d > 0 && num_2d == (num_saved + num_skipped) {
         write_log_file(&format!("wrote {} textures for snapshot {}", num_saved, &snap_prefix));
-        Ok(())
+        Ok(shared_textures)
     } else {
         Err(HookError::SnapshotFailed(
             format!("failed to save some textures for snapshot: {}; {} of {} successfully saved, {} skipped", &snap_prefix, num_saved, num_2d, num_skipped)))
     }
 }
 
+fn add_to_manifest(devptr:&DevicePointer, sd:&types::interop::SnapshotData, dir:&str, prefix:&str, snap_start:SystemTime,
+    shared_textures:BTreeMap<u32,String>) {
+    if dir.is_empty() || prefix.is_empty() {
+        return;
+    }
+    let mut manifest = match SNAP_MANIFEST.lock() {
+        Ok(m) => m,
+        Err(e) => {
+            write_log_file(&format!("failed to lock snap manifest: {}", e));
+            return;
+        }
+    };
+    let manifest = manifest.get_or_insert_with(|| {
+        let api = match devptr {
+            DevicePointer::D3D9(_) => "d3d9",
+            DevicePointer::D3D11(_) => "d3d11",
+        };
+        SnapManifest::new(api, &util::format_time(&snap_start))
+    });
+    let mut draw = ManifestDraw::new(prefix, sd.prim_count, sd.num_vertices,
+        sd.base_vertex_index, sd.start_index, sd.prim_type);
+    draw.shared_textures = shared_textures;
+    draw.instance_count = DRAW_INSTANCE_COUNT.load(Ordering::Relaxed);
+    manifest.add(dir, draw);
+}
+
+/// Write the manifest for the window that just ended, if anything was captured.
+fn write_manifest(window:SnapWindow, elapsed:std::time::Duration, snap_start:SystemTime) {
+    let (dups, bytes_saved) = TEXTURE_DEDUP.lock()
+        .map(|dd| (dd.dup_count, dd.bytes_saved))
+        .unwrap_or((0, 0));
+    if dups > 0 {
+        write_log_file(&format!("texture dedup: {} duplicate textures linked, {} bytes saved", dups, bytes_saved));
+    }
+    let manifest = match SNAP_MANIFEST.lock() {
+        Ok(mut m) => m.take(),
+        Err(e) => {
+            write_log_file(&format!("failed to lock snap manifest: {}", e));
+            return;
+        }
+    };
+    let mut manifest = match manifest {
+        Some(m) => m,
+        None => return,
+    };
+    manifest.window = window.to_string();
+    manifest.duration_ms = elapsed.as_millis() as u64;
+    manifest.presents = SNAP_WINDOW_PRESENTS.load(Ordering::Relaxed);
+    manifest.draws = SNAP_WINDOW_DRAWS.load(Ordering::Relaxed);
+    manifest.textures_deduplicated = dups;
+    manifest.texture_bytes_saved = bytes_saved;
+    // the snapshot dir is shared by all sessions, so name the manifest by the session start.
+    // this also updates manifest.yaml.
+    let session = snap_start.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    match manifest.write(&session_manifest_name(session)) {
+        Ok(file) => write_log_file(&format!("wrote snapshot manifest: {}", file)),
+        Err(e) => write_log_file(&format!("failed to write snapshot manifest: {:?}", e)),
+    }
+}
+
+/// Check the mesh against the autosnap list and wildcards.  Returns true if it should be snapped,
+/// which is only the case the first time a given prim/vert combo is seen in the snapshot window.
+fn auto_snap_mesh(sd:&types::interop::SnapshotData, snap_conf:&SnapConfig) -> bool {
+    if !snap_conf.autosnap_matches(sd.prim_count, sd.num_vertices) {
+        return false;
+    }
+    let mut snapped = match AUTOSNAPPED.lock() {
+        Ok(s) => s,
+        Err(e) => {
+            write_log_file(&format!("failed to lock autosnap list: {}", e));
+            return false;
+        }
+    };
+    if snapped.insert((sd.prim_count, sd.num_vertices)) {
+        write_log_file(&format!("autosnap: {}p {}v", sd.prim_count, sd.num_vertices));
+        true
+    } else {
+        false
+    }
+}
+
 fn auto_snap_anim(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotData, gs:&mut HookState, snap_conf:&SnapConfig) -> bool {
     let device = match devptr {
         DevicePointer::D3D9(d) => *d as *mut _,
@@ -363,7 +564,7 @@ fn auto_snap_anim(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotDat
                 }
 
                 if ass.next_vconst_idx == 0 {
-                    ass.sequence_start_time = SystemTime::now();
+                    ass.sequence_start_time = clock::now();
                 }
                 // seen everything once, so we can start snapping the constants now
                 if ass.next_vconst_idx >= ass.sequence_vconstants.len() {
@@ -374,21 +575,20 @@ fn auto_snap_anim(devptr:&mut DevicePointer, sd:&mut types::interop::SnapshotDat
                     // (*THIS).GetTransform(D3DTS_WORLD, std::mem::transmute(next.worldmat.m.as_mut_ptr()));
                     // (*THIS).GetTransform(D3DTS_VIEW, std::mem::transmute(next.viewmat.m.as_mut_ptr()));
                     // (*THIS).GetTransform(D3DTS_PROJECTION, std::mem::transmute(next.projmat.m.as_mut_ptr()));
-                    next.snapped_at = SystemTime::now();
+                    next.snapped_at = clock::wall_now();
+                    next.sequence_offset = clock::now().saturating_duration_since(ass.sequence_start_time);
                     next.prim_count = sd.prim_count;
                     next.vert_count = sd.num_vertices;
                     next.sequence = ass.next_vconst_idx;
                     next.frame = ass.curr_frame;
                     next.capture_count = *cap_count;
                     ass.next_vconst_idx += 1;
-                    //unsafe {
-                        //this was where I would call into the external toolbox app to get the
-                        //player transform.  I removed this module because it was game-specific
-                        //and not generalized, but the code still exists in the gamesnap branch.
-                        // TBSTATE.as_mut().map(|tbstate| {
-                        //     next.player_transform = tbstate.get_player_transform();
-                        // });
-                    //}
+                    // this used to call into a game specific external toolbox to get the player
+                    // transform, now that is up to the provider (if any)
+                    match FRAME_CONTEXT_PROVIDER.lock() {
+                        Ok(mut provider) => next.capture_transforms(provider.as_mut()),
+                        Err(_) => next.transforms = [None; 4],
+                    }
                 }
             }
             else if !ass.seen_primverts.contains(primvert) {
@@ -511,23 +711,186 @@ unsafe fn save_render_state_d3d9(device:*mut IDirect3DDevice9) -> Box<dyn SnapRe
     })
 }
 
-struct D3D11SnapRenderState {}
+struct D3D11SnapRenderState {
+    state: Option<D3D11RenderStateFile>,
+}
 impl SnapRendState for D3D11SnapRenderState {
     fn has_state(&self) -> bool {
-        false
+        // all states unbound is still meaningful (it means D3D11 defaults), so as long as the
+        // state could be read, it is written
+        self.state.is_some()
     }
-    fn save(self: Box<Self>, _file:&str) -> Result<()> {
-        Ok(())
+    fn save(self: Box<Self>, file:&str) -> Result<()> {
+        match self.state {
+            Some(state) => write_obj_to_file(&file, false, &state),
+            None => Ok(()),
+        }
     }
 }
 
-fn save_render_state_d3d11() -> Box<dyn SnapRendState> {
-    Box::new(D3D11SnapRenderState{})
+unsafe fn read_render_state_d3d11(device:*mut ID3D11Device) -> Result<D3D11RenderStateFile> {
+    use winapi::um::d3d11::{D3D11_COMMONSHADER_SAMPLER_SLOT_COUNT, ID3D11BlendState,
+        ID3D11RasterizerState, ID3D11DepthStencilState, ID3D11SamplerState,
+        D3D11_BLEND_DESC, D3D11_RASTERIZER_DESC, D3D11_DEPTH_STENCIL_DESC, D3D11_SAMPLER_DESC,
+        D3D11_DEPTH_STENCILOP_DESC};
+
+    let mut context:*mut ID3D11DeviceContext = null_mut();
+    (*device).GetImmediateContext(&mut context);
+    if context.is_null() {
+        return Err(HookError::SnapshotFailed("failed to get immediate context".to_string()));
+    }
+    let _context_rod = ReleaseOnDrop::new(context);
+
+    let mut rstate = D3D11RenderStateFile::default();
+
+    let mut bs:*mut ID3D11BlendState = null_mut();
+    let mut blend_factor = [0.0f32; 4];
+    let mut sample_mask:UINT = 0;
+    (*context).OMGetBlendState(&mut bs, &mut blend_factor, &mut sample_mask);
+    if !bs.is_null() {
+        let _rod = ReleaseOnDrop::new(bs);
+        let mut desc:D3D11_BLEND_DESC = std::mem::zeroed();
+        (*bs).GetDesc(&mut desc);
+        let num_rt = if desc.IndependentBlendEnable != 0 { desc.RenderTarget.len() } else { 1 };
+        rstate.blend = Some(BlendState {
+            alpha_to_coverage_enable: desc.AlphaToCoverageEnable != 0,
+            independent_blend_enable: desc.IndependentBlendEnable != 0,
+            blend_factor,
+            sample_mask,
+            render_targets: desc.RenderTarget[0..num_rt].iter().map(|rt| RenderTargetBlend {
+                blend_enable: rt.BlendEnable != 0,
+                src_blend: rt.SrcBlend,
+                dest_blend: rt.DestBlend,
+                blend_op: rt.BlendOp,
+                src_blend_alpha: rt.SrcBlendAlpha,
+                dest_blend_alpha: rt.DestBlendAlpha,
+                blend_op_alpha: rt.BlendOpAlpha,
+                render_target_write_mask: rt.RenderTargetWriteMask,
+            }).collect(),
+        });
+    }
+
+    let mut rs:*mut ID3D11RasterizerState = null_mut();
+    (*context).RSGetState(&mut rs);
+    if !rs.is_null() {
+        let _rod = ReleaseOnDrop::new(rs);
+        let mut desc:D3D11_RASTERIZER_DESC = std::mem::zeroed();
+        (*rs).GetDesc(&mut desc);
+        rstate.rasterizer = Some(RasterizerState {
+            fill_mode: desc.FillMode,
+            cull_mode: desc.CullMode,
+            front_counter_clockwise: desc.FrontCounterClockwise != 0,
+            depth_bias: desc.DepthBias,
+            depth_bias_clamp: desc.DepthBiasClamp,
+            slope_scaled_depth_bias: desc.SlopeScaledDepthBias,
+            depth_clip_enable: desc.DepthClipEnable != 0,
+            scissor_enable: desc.ScissorEnable != 0,
+            multisample_enable: desc.MultisampleEnable != 0,
+            antialiased_line_enable: desc.AntialiasedLineEnable != 0,
+        });
+    }
+
+    let mut dss:*mut ID3D11DepthStencilState = null_mut();
+    let mut stencil_ref:UINT = 0;
+    (*context).OMGetDepthStencilState(&mut dss, &mut stencil_ref);
+    if !dss.is_null() {
+        let _rod = ReleaseOnDrop::new(dss);
+        let mut desc:D3D11_DEPTH_STENCIL_DESC = std::mem::zeroed();
+        (*dss).GetDesc(&mut desc);
+        let stencil_op = |op:&D3D11_DEPTH_STENCILOP_DESC| StencilOp {
+            fail_op: op.StencilFailOp,
+            depth_fail_op: op.StencilDepthFailOp,
+            pass_op: op.StencilPassOp,
+            func: op.StencilFunc,
+        };
+        rstate.depth_stencil = Some(DepthStencilState {
+            depth_enable: desc.DepthEnable != 0,
+            depth_write_mask: desc.DepthWriteMask,
+            depth_func: desc.DepthFunc,
+            stencil_enable: desc.StencilEnable != 0,
+            stencil_read_mask: desc.StencilReadMask,
+            stencil_write_mask: desc.StencilWriteMask,
+            front_face: stencil_op(&desc.FrontFace),
+            back_face: stencil_op(&desc.BackFace),
+            stencil_ref,
+        });
+    }
+
+    const NUM_SAMPLERS:usize = D3D11_COMMONSHADER_SAMPLER_SLOT_COUNT as usize;
+    let mut samplers:[*mut ID3D11SamplerState; NUM_SAMPLERS] = [null_mut(); NUM_SAMPLERS];
+    (*context).PSGetSamplers(0, NUM_SAMPLERS as UINT, samplers.as_mut_ptr());
+    for (slot, sampler) in samplers.iter().enumerate() {
+        if sampler.is_null() {
+            continue;
+        }
+        let _rod = ReleaseOnDrop::new(*sampler);
+        let mut desc:D3D11_SAMPLER_DESC = std::mem::zeroed();
+        (**sampler).GetDesc(&mut desc);
+        rstate.samplers.insert(slot as u32, SamplerState {
+            filter: desc.Filter,
+            address_u: desc.AddressU,
+            address_v: desc.AddressV,
+            address_w: desc.AddressW,
+            mip_lod_bias: desc.MipLODBias,
+            max_anisotropy: desc.MaxAnisotropy,
+            comparison_func: desc.ComparisonFunc,
+            border_color: desc.BorderColor,
+            min_lod: desc.MinLOD,
+            max_lod: desc.MaxLOD,
+        });
+    }
+
+    let mut srvs:[*mut ID3D11ShaderResourceView; MAX_SRV as usize] = [null_mut(); MAX_SRV as usize];
+    (*context).PSGetShaderResources(0, MAX_SRV, srvs.as_mut_ptr());
+    for (slot, srv) in srvs.iter().enumerate() {
+        if srv.is_null() {
+            continue;
+        }
+        let _rod = ReleaseOnDrop::new(*srv);
+        let mut desc:D3D11_SHADER_RESOURCE_VIEW_DESC = std::mem::zeroed();
+        (**srv).GetDesc(&mut desc);
+        let mut info = SrvInfo {
+            dimension: desc.ViewDimension,
+            format: desc.Format,
+            width: None,
+            height: None,
+            mip_levels: None,
+        };
+        if desc.ViewDimension == D3D11_SRV_DIMENSION_TEXTURE2D {
+            let viewptr:*mut ID3D11View = *srv as *mut _;
+            let mut resptr:*mut ID3D11Resource = null_mut();
+            (*viewptr).GetResource(&mut resptr);
+            if !resptr.is_null() {
+                let _res_rod = ReleaseOnDrop::new(resptr);
+                let mut texptr:*mut ID3D11Texture2D = null_mut();
+                let riid = &ID3D11Texture2D::uuidof();
+                let hr = (*resptr).QueryInterface(riid, &mut texptr as *mut *mut _ as *mut *mut c_void);
+                if hr == 0 && !texptr.is_null() {
+                    let _tex_rod = ReleaseOnDrop::new(texptr);
+                    let mut tdesc:D3D11_TEXTURE2D_DESC = std::mem::zeroed();
+                    (*texptr).GetDesc(&mut tdesc);
+                    info.width = Some(tdesc.Width);
+                    info.height = Some(tdesc.Height);
+                    info.mip_levels = Some(tdesc.MipLevels);
+                }
+            }
+        }
+        rstate.srvs.insert(slot as u32, info);
+    }
+
+    Ok(rstate)
+}
+
+fn save_render_state_d3d11(device:*mut ID3D11Device) -> Box<dyn SnapRendState> {
+    let state = unsafe { read_render_state_d3d11(device) }
+        .map_err(|e| write_log_file(&format!("failed to read d3d11 render state: {:?}", e)))
+        .ok();
+    Box::new(D3D11SnapRenderState { state })
 }
 fn save_render_state(devptr:&mut DevicePointer) -> Box<dyn SnapRendState> {
     match devptr {
         &mut DevicePointer::D3D9(device) => unsafe { save_render_state_d3d9(device) },
-        &mut DevicePointer::D3D11(_) => save_render_state_d3d11(),
+        &mut DevicePointer::D3D11(device) => save_render_state_d3d11(device),
     }
 }
 
@@ -627,8 +990,8 @@ unsafe fn set_buffers_d3d11(device:*mut ID3D11Device, sd:&mut types::interop::Sn
             .ok_or(HookError::SnapshotFailed("snap aborted, vertex layout lacks texcoord so this will not capture properly".to_owned()))?;
 
         let mut ld = vf.layout.clone();
-        let layout_data_size = std::mem::size_of::<D3D11_INPUT_ELEMENT_DESC>() * ld.len();
-        let decl_data = ld.as_mut_ptr();
+        let vertex_slots = vf.vertex_slots();
+        let position_slot = vf.position_slot;
 
         let mut context:*mut ID3D11DeviceContext = null_mut();
         (*device).GetImmediateContext(&mut context);
@@ -651,9 +1014,10 @@ unsafe fn set_buffers_d3d11(device:*mut ID3D11Device, sd:&mut types::interop::Sn
 
         // determine if we have the data for the buffer since at this time we can't read it
         // directly via Map
-        let ib_copy = dev_state_d3d11_read()
+        // write lock because reading marks the data as recently used
+        let ib_copy = dev_state_d3d11_write()
             .map(|(_lock,ds)| {
-                ds.rs.device_index_buffer_data.get(&(curr_ibuffer as usize)).map(|v| v.clone())
+                ds.rs.precopy.get(curr_ibuffer as usize, BufferKind::Index).map(|v| v.clone())
             }).flatten()
             .ok_or_else(|| {
                 HookError::SnapshotFailed("failed to get index buffer data, was not previously saved".to_string())
@@ -668,11 +1032,44 @@ unsafe fn set_buffers_d3d11(device:*mut ID3D11Device, sd:&mut types::interop::Sn
             _ => return Err(HookError::SnapshotFailed(format!("unknown index buffer format: {:x}", curr_ibuffer_format))),
         };
 
-        // should match expected size
-        let ex_size = (sd.prim_count * 3 * index_size) as usize;
-        if ib_copy.len() != ex_size {
-            return Err(HookError::SnapshotFailed(format!("index buffer data size mismatch, expected: {}, got: {}", ex_size, ib_copy.len())));
-        }
+        // in index range mode the mod key only covers the vertices between the lowest and highest
+        // index used by the draw, so the snapshot is trimmed to those
+        let index_range_mode = GLOBAL_STATE.run_conf.d3d11_key_mode == D3D11KeyMode::IndexRange;
+        let is_strip = sd.prim_type == D3D_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP as i32;
+        let ib_copy = if is_strip || index_range_mode {
+            // only the part of the buffer that was drawn is needed.  managed code only reads
+            // lists, so strips are converted.
+            let index_count = DRAW_INDEX_COUNT.load(Ordering::Relaxed) as usize;
+            let start = sd.start_index as usize + (curr_ibuffer_offset / index_size) as usize;
+            let mut indices = read_indices(&ib_copy, index_size as usize, start, index_count)
+                .ok_or_else(|| HookError::SnapshotFailed(format!("index buffer too small for draw: {} bytes, start {}, count {}",
+                    ib_copy.len(), start, index_count)))?;
+            if is_strip {
+                indices = strip_to_list(&indices, restart_index(index_size as usize));
+                write_log_file(&format!("converted strip of {} indices to list of {} prims", index_count, indices.len() / 3));
+            } else {
+                indices.truncate(index_count - index_count % 3);
+            }
+            if indices.len() / 3 != sd.prim_count as usize {
+                return Err(HookError::SnapshotFailed(format!("prim count mismatch, expected: {}, got: {}",
+                    sd.prim_count, indices.len() / 3)));
+            }
+            if index_range_mode {
+                for i in indices.iter_mut() {
+                    *i = i.saturating_sub(sd.min_vertex_index);
+                }
+            }
+            sd.start_index = 0;
+            sd.prim_type = D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST as i32;
+            write_indices(&indices, index_size as usize)
+        } else {
+            // should match expected size
+            let ex_size = (sd.prim_count * 3 * index_size) as usize;
+            if ib_copy.len() != ex_size {
+                return Err(HookError::SnapshotFailed(format!("index buffer data size mismatch, expected: {}, got: {}", ex_size, ib_copy.len())));
+            }
+            ib_copy
+        };
 
         write_log_file(&format!("index buffer size: {}, format: {}", ib_copy.len(), curr_ibuffer_format));
 
@@ -688,29 +1085,105 @@ unsafe fn set_buffers_d3d11(device:*mut ID3D11Device, sd:&mut types::interop::Sn
         let _vb_rods =
             curr_vbuffers.iter().filter(|vb| !vb.is_null())
              .map(|vb| ReleaseOnDrop::new(*vb)).collect::<Vec<_>>();
-        // filter active
-        let curr_vbuffers = curr_vbuffers.iter().filter(|vb| !vb.is_null()).collect::<Vec<_>>();
-        if curr_vbuffers.is_empty() {
-            return Err(HookError::SnapshotFailed("no vertex buffers".to_string()));
-        }
-        if curr_vbuffers.len() > 1 {
-            return Err(HookError::SnapshotFailed(format!("more than 1 vertex buffer not supported (got {})", curr_vbuffers.len())));
+        // copy the data for each stream used by the layout.  per-instance streams are skipped,
+        // they don't contain geometry.
+        let mut vb_copies:Vec<(u32,Vec<u8>)> = vec![];
+        for (slot,_size) in vertex_slots.iter() {
+            let vb = curr_vbuffers.get(*slot as usize).copied().unwrap_or(null_mut());
+            if vb.is_null() {
+                return Err(HookError::SnapshotFailed(format!("no vertex buffer bound to slot {}", slot)));
+            }
+            let data = dev_state_d3d11_write()
+                .map(|(_lock,ds)| {
+                    ds.rs.precopy.get(vb as usize, BufferKind::Vertex).map(|v| v.clone())
+                }).flatten()
+                .ok_or_else(|| {
+                    HookError::SnapshotFailed(format!("failed to get vertex buffer data for slot {}, was not previously saved", slot))
+                })?;
+            vb_copies.push((*slot, data));
         }
-        // copy the data
-        let vb_copy = dev_state_d3d11_read()
-            .map(|(_lock,ds)| {
-                let vb_usize = *curr_vbuffers[0] as usize;
-                ds.rs.device_vertex_buffer_data.get(&vb_usize).map(|v| v.clone())
-            }).flatten()
-            .ok_or_else(|| {
-                HookError::SnapshotFailed("failed to get vertex buffer data, was not previously saved".to_string())
-            })?;
-        // number of vertices should be = size / vert size
-        let num_verts = vb_copy.len() / vert_size;
-        if sd.num_vertices != num_verts as u32 {
-            return Err(HookError::SnapshotFailed(format!("vertex buffer data size mismatch, expected: {}, got: {}", sd.num_vertices, num_verts)));
+        let pos_data_len = vb_copies.iter().find(|(slot,_)| *slot == position_slot)
+            .map(|(_,data)| data.len())
+            .ok_or_else(|| HookError::SnapshotFailed("no vertex stream for position".to_string()))?;
+        // stride of each stream, the vertex size if the stride wasn't set
+        let stride_of = |slot:u32, size:u32| {
+            let stride = curr_vbuffer_strides[slot as usize];
+            if stride == 0 { size as usize } else { stride as usize }
+        };
+        let pos_stride = stride_of(position_slot, vert_size as u32).max(vert_size);
+        let num_verts = if index_range_mode {
+            sd.num_vertices as usize
+        } else {
+            // number of vertices should be = size / stride, as in the mod key
+            let num_verts = pos_data_len / pos_stride;
+            if sd.num_vertices != num_verts as u32 {
+                return Err(HookError::SnapshotFailed(format!("vertex buffer data size mismatch, expected: {}, got: {}", sd.num_vertices, num_verts)));
+            }
+            num_verts
+        };
+        // first vertex copied from each stream, only nonzero in index range mode
+        let first_vert = if index_range_mode {
+            let first_vert = sd.base_vertex_index as i64 + sd.min_vertex_index as i64;
+            if first_vert < 0 {
+                return Err(HookError::SnapshotFailed(format!("first vertex is negative: base {}, min index {}",
+                    sd.base_vertex_index, sd.min_vertex_index)));
+            }
+            first_vert as usize
+        } else {
+            0
+        };
+        // a single packed stream can be used as is, otherwise the streams are interleaved (which
+        // also removes any padding between vertices)
+        let (vb_copy, vert_size) = if vb_copies.len() == 1 && !index_range_mode && pos_stride == vert_size {
+            (vb_copies.pop().map(|(_,data)| data).unwrap_or_default(), vert_size)
+        } else {
+            let streams = vertex_slots.iter().zip(vb_copies.iter()).map(|((slot,size),(_,data))| {
+                let stride = stride_of(*slot, *size);
+                if index_range_mode {
+                    VertexStream {
+                        slot: *slot,
+                        data,
+                        offset: curr_vbuffer_offsets[*slot as usize] as usize + first_vert * stride,
+                        stride,
+                        size: *size as usize,
+                    }
+                } else {
+                    VertexStream {
+                        slot: *slot,
+                        data,
+                        // the position stream is keyed from the whole buffer, so offsets are only
+                        // honored for the others
+                        offset: if *slot == position_slot { 0 } else { curr_vbuffer_offsets[*slot as usize] as usize },
+                        stride,
+                        size: *size as usize,
+                    }
+                }
+            }).collect::<Vec<_>>();
+            let (offsets, interleaved_size) = stream_offsets(&streams);
+            let data = interleave(&streams, num_verts)?;
+            // rewrite the layout so that it describes the interleaved data
+            ld.retain(|el| offsets.iter().any(|(slot,_)| *slot == el.InputSlot)
+                && el.InputSlotClass == D3D11_INPUT_PER_VERTEX_DATA);
+            for el in ld.iter_mut() {
+                let base = offsets.iter().find(|(slot,_)| *slot == el.InputSlot).map(|(_,base)| *base).unwrap_or(0);
+                el.AlignedByteOffset += base;
+                el.InputSlot = 0;
+            }
+            write_log_file(&format!("interleaved {} vertex streams (slot,offset: {:?}), vertsize: {}",
+                streams.len(), offsets, interleaved_size));
+            (data, interleaved_size as usize)
+        };
+        if index_range_mode {
+            // the copied verts start at the lowest index used and the indices were rebased to match
+            write_log_file(&format!("copied verts {} to {} for index range", first_vert, first_vert + num_verts));
+            sd.base_vertex_index = 0;
+            sd.min_vertex_index = 0;
         }
-        write_log_file(&format!("vertex buffer size: {}, num verts: {}, vertsize: {}", vb_copy.len(), num_verts, vert_size));
+        write_log_file(&format!("vertex buffer size: {}, num verts: {}, vertsize: {}, instances: {}", vb_copy.len(), num_verts, vert_size,
+            DRAW_INSTANCE_COUNT.load(Ordering::Relaxed)));
+
+        let layout_data_size = std::mem::size_of::<D3D11_INPUT_ELEMENT_DESC>() * ld.len();
+        let decl_data = ld.as_mut_ptr();
 
         // now save all the srvs that might contain textures, note any that are 2D and save the
         // indexes of those so that managed code has them
@@ -772,11 +1245,11 @@ fn write_anim_snap_state(ass:&AnimSnapState) -> Result<()> {
     if !ass.seen_all {
         return Err(HookError::SnapshotFailed("error, not all expected primvert combos were seen!".to_owned()));
     }
-    let snap_on_count = match SNAP_CONFIG.read() {
+    let (snap_on_count, anim_export) = match SNAP_CONFIG.read() {
         Err(e) => {
             return Err(HookError::SnapshotFailed(format!("failed to lock snap config: {}", e)))
         },
-        Ok(c) => c.snap_anim_on_count
+        Ok(c) => (c.snap_anim_on_count, c.anim_export.clone())
     };
 
     let mut frames_by_mesh:HashMap<(UINT,UINT), AnimFrameFile> = HashMap::new();
@@ -798,36 +1271,7 @@ fn write_anim_snap_state(ass:&AnimSnapState) -> Result<()> {
         let frame_file = frames_by_mesh.entry((aseq.prim_count, aseq.vert_count))
             .or_insert_with(AnimFrameFile::new);
 
-        let pxform = match aseq.player_transform.as_ref() {
-            Err(e) => {
-                return Err(HookError::SnapshotFailed(
-                    format!("player transform not available at frame {}, aborting constant write (error: {:?})", frame, e)
-                ));
-            },
-            Ok(xfrm) => {
-                xfrm
-            }
-        };
-        let mut pxform = pxform.split(" ");
-        // meh https://stackoverflow.com/questions/31046763/does-rust-have-anything-like-scanf
-        let parse_next = |split: &mut std::str::Split<&str>| -> Result<f32> {
-            let res = split.next().ok_or(HookError::SnapshotFailed("Failed transform parse".to_owned()))?;
-            Ok(res.parse().map_err(|_| HookError::SnapshotFailed("failed to parse float".to_owned()))?)
-        };
-        let x = parse_next(&mut pxform)?;
-        let y = parse_next(&mut pxform)?;
-        let z = parse_next(&mut pxform)?;
-        let rot = parse_next(&mut pxform)?;
-        let pxform = constant_tracking::vecToVec4(&vec![x,y,z,rot], 0);
-        let framedata = AnimFrame {
-            snapped_at: aseq.snapped_at,
-            floats: aseq.constants.floats.get_as_btree(),
-            transform1: Some(pxform),
-            transform2: None,
-            transform3: None,
-            transform4: None,
-        };
-        frame_file.frames.push(framedata);
+        frame_file.frames.push(aseq.to_frame());
     }
     let anim_dir = &ass.snap_dir;
 
@@ -836,34 +1280,59 @@ fn write_anim_snap_state(ass:&AnimSnapState) -> Result<()> {
     for ((prims,verts), frame_file) in frames_by_mesh {
         let out_file = format!("{}/animframes_{}p_{}v.dat", anim_dir, prims, verts);
         frame_file.write_to_file(&out_file)?;
+        if let Some(conf) = anim_export.as_ref() {
+            let out_base = format!("{}/animframes_{}p_{}v", anim_dir, prims, verts);
+            match anim_export::export(&frame_file, conf, &out_base) {
+                Ok(f) => write_log_file(&format!("exported anim frames to {}", f)),
+                Err(e) => write_log_file(&format!("failed to export anim frames: {:?}", e)),
+            }
+        }
     }
     write_log_file("wrote anim sequences");
     Ok(())
 }
 
+/// Returns true if the snapshot window has ended.  If the window is present based but no present
+/// has been seen, falls back to `snap_ms`.
+fn snap_window_done(window:SnapWindow, snap_ms:u32, elapsed:std::time::Duration) -> bool {
+    let time_done = |ms:u32| elapsed >= std::time::Duration::from_millis(ms as u64);
+    match window {
+        SnapWindow::Time(ms) => time_done(ms),
+        SnapWindow::Presents(n) if PRESENT_SEEN.load(Ordering::Relaxed) =>
+            SNAP_WINDOW_PRESENTS.load(Ordering::Relaxed) >= n as u64,
+        SnapWindow::Presents(_) => time_done(snap_ms),
+        SnapWindow::Draws(n) => SNAP_WINDOW_DRAWS.load(Ordering::Relaxed) >= n as u64,
+    }
+}
+
 pub fn present_process() {
-    let snap_ms = match SNAP_CONFIG.read() {
+    let (window, snap_ms) = match SNAP_CONFIG.read() {
         Err(e) => {
             write_log_file(&format!("failed to lock snap config: {}", e));
-            0
+            (SnapWindow::Time(0), 0)
         },
-        Ok(c) => c.snap_ms
+        Ok(c) => (c.snap_window(), c.snap_ms)
     };
 
     let gs = unsafe { &mut GLOBAL_STATE };
 
     if gs.is_snapping {
-        let now = SystemTime::now();
-        let max_dur = std::time::Duration::from_millis(snap_ms as u64);
-        if now
-            .duration_since(gs.snap_start)
-            .unwrap_or(max_dur)
-            >= max_dur
-        {
-            write_log_file("ending snapshot");
+        let elapsed = clock::since(clock::now(), gs.snap_start_instant).unwrap_or_default();
+        if snap_window_done(window, snap_ms, elapsed) {
             gs.is_snapping = false;
+            let fallback = match window {
+                SnapWindow::Presents(_) if !PRESENT_SEEN.load(Ordering::Relaxed) =>
+                    format!(" (present not hooked, used {}ms)", snap_ms),
+                _ => "".to_owned(),
+            };
+            write_log_file(&format!("ending snapshot: window {}{}; {}ms, {} presents, {} draws, {} snapped",
+                window, fallback, elapsed.as_millis(),
+                SNAP_WINDOW_PRESENTS.load(Ordering::Relaxed),
+                SNAP_WINDOW_DRAWS.load(Ordering::Relaxed),
+                SNAP_WINDOW_SNAPS.load(Ordering::Relaxed)));
+            write_manifest(window, elapsed, gs.snap_start);
             gs.anim_snap_state.as_ref().map(|ass| {
-                let duration = now.duration_since(ass.sequence_start_time).unwrap_or_default();
+                let duration = clock::now().saturating_duration_since(ass.sequence_start_time);
                 write_log_file(&format!("captured {} anim constant sequences in {}ms", ass.next_vconst_idx, duration.as_millis()));
                 write_anim_snap_state(ass)
                 .unwrap_or_else(|e| write_log_file(&format!("failed to write anim state: {:?}", e)));
@@ -876,4 +1345,13 @@ pub fn present_process() {
 /// Called when the clear texture key is pressed, and when a new snapshot is started.
 pub fn reset() {
     // this used to load/init the snapshot toolbox (removed)
+    AUTOSNAPPED.lock().map(|mut s| s.clear())
+        .unwrap_or_else(|e| write_log_file(&format!("failed to lock autosnap list: {}", e)));
+    SNAP_WINDOW_PRESENTS.store(0, Ordering::Relaxed);
+    SNAP_WINDOW_DRAWS.store(0, Ordering::Relaxed);
+    SNAP_WINDOW_SNAPS.store(0, Ordering::Relaxed);
+    SNAP_MANIFEST.lock().map(|mut m| *m = None)
+        .unwrap_or_else(|e| write_log_file(&format!("failed to lock snap manifest: {}", e)));
+    TEXTURE_DEDUP.lock().map(|mut dd| *dd = TextureDedup::new())
+        .unwrap_or_else(|e| write_log_file(&format!("failed to lock texture dedup: {}", e)));
 }
\ No newline at end of file
@@ -0,0 +1,62 @@
+//! Sources of device state for `Input`.  The hook uses DirectInput (see `dinput`); tests use
+//! `Scripted`, which plays back a timeline of states against `shared_dx::clock`, so that a test
+//! with a `FakeClock` installed controls exactly what each poll sees.
+use std::time::{Duration, Instant};
+
+use shared_dx::clock;
+use shared_dx::error::Result;
+
+/// DirectInput keyboard state: one byte per DIK code, with the high bit set if the key is down.
+pub type KeyboardState = [u8; 256];
+
+pub trait InputBackend {
+    type State;
+    /// Read the current state of the device.
+    fn poll(&mut self, state: &mut Self::State) -> Result<()>;
+}
+
+/// Plays back a timeline of states.  Each poll sees the last state whose time (relative to when
+/// the script was created) has been reached, or the initial state before the first one.
+pub struct Scripted<S> {
+    start: Instant,
+    initial: S,
+    timeline: Vec<(Duration, S)>,
+}
+
+impl<S: Clone> Scripted<S> {
+    /// `timeline` must be in time order.
+    pub fn new(initial: S, timeline: Vec<(Duration, S)>) -> Self {
+        Self { start: clock::now(), initial, timeline }
+    }
+
+    fn current(&self) -> &S {
+        let elapsed = clock::now().saturating_duration_since(self.start);
+        self.timeline.iter()
+            .take_while(|(t, _)| *t <= elapsed)
+            .last()
+            .map(|(_, s)| s)
+            .unwrap_or(&self.initial)
+    }
+}
+
+impl Scripted<KeyboardState> {
+    /// A keyboard script from (milliseconds, keys held down) steps.
+    pub fn keys(steps: &[(u64, &[u8])]) -> Self {
+        let timeline = steps.iter().map(|&(ms, keys)| {
+            let mut state = [0; 256];
+            for &k in keys {
+                state[k as usize] = 0x80;
+            }
+            (Duration::from_millis(ms), state)
+        }).collect();
+        Self::new([0; 256], timeline)
+    }
+}
+
+impl<S: Clone> InputBackend for Scripted<S> {
+    type State = S;
+    fn poll(&mut self, state: &mut S) -> Result<()> {
+        *state = self.current().clone();
+        Ok(())
+    }
+}
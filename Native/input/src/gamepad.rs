@@ -0,0 +1,176 @@
+//! Gamepad button combos, so that commands can be run from a controller.  Buttons are named
+//! after the XInput buttons (`A`, `Back`, `DPadRight`, `LeftShoulder` or `LB`, ...) in any case,
+//! and a combo is two or more of them joined with `+`, like `Back+DPadRight`.  Single buttons
+//! aren't allowed since they would fire during normal play.
+//!
+//! A combo fires once when its last button goes down; holding it doesn't repeat.
+//!
+//! The pad is only read when `Input::process` runs, which is at most once every 16ms and, in
+//! d3d11, only every 250 draws.  Buttons are sampled, not queued, so a combo that is pressed and
+//! released between two reads is missed; it has to be held until the next one.
+use std::fmt;
+
+use shared_dx::error::{HookError, Result};
+
+use crate::backend::InputBackend;
+
+/// XInput button state (`XINPUT_GAMEPAD_*` bits).
+pub type GamepadState = u16;
+
+/// The first name for each button is the one that is displayed.
+const BUTTON_NAMES: &[(&str, u16)] = &[
+    ("DPadUp", 0x0001),
+    ("DPadDown", 0x0002),
+    ("DPadLeft", 0x0004),
+    ("DPadRight", 0x0008),
+    ("Start", 0x0010),
+    ("Back", 0x0020),
+    ("LeftThumb", 0x0040),
+    ("LS", 0x0040),
+    ("RightThumb", 0x0080),
+    ("RS", 0x0080),
+    ("LeftShoulder", 0x0100),
+    ("LB", 0x0100),
+    ("RightShoulder", 0x0200),
+    ("RB", 0x0200),
+    ("A", 0x1000),
+    ("B", 0x2000),
+    ("X", 0x4000),
+    ("Y", 0x8000),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Combo(pub GamepadState);
+
+impl Combo {
+    pub fn parse(s: &str) -> Result<Combo> {
+        let err = |msg: &str| HookError::ConfReadFailed(format!("bad button combo '{}': {}", s, msg));
+        let mut buttons = 0;
+        for name in s.split('+').map(|n| n.trim()) {
+            let button = BUTTON_NAMES.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|&(_, b)| b)
+                .ok_or_else(|| err(&format!("unknown button '{}'", name)))?;
+            buttons |= button;
+        }
+        if buttons.count_ones() < 2 {
+            return Err(err("a combo needs at least two buttons"));
+        }
+        Ok(Combo(buttons))
+    }
+
+    pub fn held(&self, state: GamepadState) -> bool {
+        state & self.0 == self.0
+    }
+
+    /// True if holding `other` also holds this combo.
+    pub fn is_within(&self, other: &Combo) -> bool {
+        self.held(other.0)
+    }
+}
+
+impl fmt::Display for Combo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut seen = 0;
+        let mut names = vec![];
+        for &(name, b) in BUTTON_NAMES {
+            if self.0 & b != 0 && seen & b == 0 {
+                names.push(name);
+                seen |= b;
+            }
+        }
+        write!(f, "{}", names.join("+"))
+    }
+}
+
+/// A gamepad source for `Input`.
+pub struct Gamepad {
+    backend: Box<dyn InputBackend<State = GamepadState>>,
+    state: GamepadState,
+    last_state: GamepadState,
+    combo_fns: Vec<(Combo, Box<dyn FnMut()>)>,
+}
+
+impl Gamepad {
+    pub fn new(backend: Box<dyn InputBackend<State = GamepadState>>) -> Self {
+        Self { backend, state: 0, last_state: 0, combo_fns: vec![] }
+    }
+
+    pub fn add_combo_fn(&mut self, combo: Combo, fun: Box<dyn FnMut()>) {
+        self.combo_fns.retain(|(c, _)| *c != combo);
+        self.combo_fns.push((combo, fun));
+    }
+
+    pub fn clear_handlers(&mut self) {
+        self.combo_fns.clear();
+    }
+
+    pub fn get_combo_fn_count(&self) -> usize {
+        self.combo_fns.len()
+    }
+
+    pub fn state(&self) -> GamepadState {
+        self.state
+    }
+
+    /// Poll the gamepad and run the handlers of combos that were just completed.
+    pub fn process(&mut self) -> Result<()> {
+        self.last_state = self.state;
+        self.backend.poll(&mut self.state)?;
+        let (state, last) = (self.state, self.last_state);
+        for (combo, fun) in self.combo_fns.iter_mut() {
+            if combo.held(state) && !combo.held(last) {
+                fun();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use shared_dx::clock::FakeClock;
+    use crate::backend::Scripted;
+
+    const BACK: u16 = 0x0020;
+    const DPAD_RIGHT: u16 = 0x0008;
+    const A: u16 = 0x1000;
+
+    #[test]
+    fn test_combos() {
+        let c = Combo::parse("back + dpadright").expect("doh");
+        assert_eq!(c, Combo(BACK | DPAD_RIGHT));
+        assert_eq!(c.to_string(), "DPadRight+Back");
+        assert_eq!(Combo::parse("LB+RightShoulder").expect("doh").to_string(), "LeftShoulder+RightShoulder");
+        assert!(c.is_within(&Combo(BACK | DPAD_RIGHT | A)));
+        assert!(!c.is_within(&Combo(BACK | A)));
+        for bad in ["", "A", "A+a", "A+Turbo", "Back+"].iter() {
+            assert!(Combo::parse(bad).is_err(), "{}", bad);
+        }
+
+        let clock = FakeClock::new();
+        let _guard = clock.install();
+        let script = Scripted::new(0, vec![
+            (Duration::from_millis(0), BACK),
+            (Duration::from_millis(20), BACK | DPAD_RIGHT),
+            (Duration::from_millis(100), BACK),
+            (Duration::from_millis(120), BACK | DPAD_RIGHT | A),
+            (Duration::from_millis(140), 0),
+        ]);
+        let mut pad = Gamepad::new(Box::new(script));
+        let count = Rc::new(Cell::new(0));
+        let cnt = count.clone();
+        pad.add_combo_fn(c, Box::new(move || cnt.set(cnt.get() + 1)));
+        for _ in 0..10 {
+            pad.process().expect("doh");
+            clock.advance(Duration::from_millis(20));
+        }
+        // fired at 20 and 120, holding doesn't repeat
+        assert_eq!(count.get(), 2);
+        assert_eq!(pad.state(), 0);
+    }
+}
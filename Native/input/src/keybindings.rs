@@ -0,0 +1,323 @@
+//! User key bindings, read from `keybindings.yaml` in the modelmod root dir.  The file maps
+//! command names (see `types::command`) to one binding or a list of them.  A binding is a chord,
+//! or a map with the chord under `keys` and its repeat settings, e.g.
+//!
+//! ```yaml
+//! reload_mods: { keys: ctrl+F1, repeat: false }
+//! take_snapshot: ctrl+alt+S
+//! select_next_texture: { keys: ctrl+F3, initial_repeat_ms: 250, repeat_ms: 40 }
+//! next_variant: [ctrl+NUMPAD8, ctrl+NUMPAD9]
+//! ```
+//!
+//! Held chords repeat with the default delays unless `repeat` is false.  Commands that aren't
+//! listed have no binding.  Unknown commands, bad chords and chords bound to more than one
+//! command are errors.  If the file is missing the input profile from the launcher is used
+//! instead.
+//!
+//! Gamepad combos (see `gamepad`) are bound the same way in `gamepadbindings.yaml`, e.g.
+//! `next_variant: Back+DPadRight`.  Gamepads are only read if that file exists.
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use shared_dx::error::{HookError, Result};
+use shared_dx::util::write_log_file;
+use types::command::Command;
+
+use crate::gamepad::Combo;
+use crate::keys::{Chord, Repeat};
+
+pub const KEY_BINDINGS_FILE: &str = "keybindings.yaml";
+pub const GAMEPAD_BINDINGS_FILE: &str = "gamepadbindings.yaml";
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedEntry {
+    keys: String,
+    #[serde(default = "default_true")]
+    repeat: bool,
+    initial_repeat_ms: Option<u16>,
+    repeat_ms: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Chord(String),
+    Detailed(DetailedEntry),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+/// Parse a bindings file into the entries for each command.  Unknown commands are added to
+/// `problems`.
+fn parse_entries<T: DeserializeOwned>(text: &str, problems: &mut Vec<String>) -> Result<Vec<(Command, Vec<T>)>> {
+    if text.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let file: Option<BTreeMap<String, OneOrMany<T>>> = serde_yaml::from_str(text)
+        .map_err(|e| HookError::SerdeError(format!("deserialize error: {}", e)))?;
+    let mut commands = vec![];
+    for (name, entries) in file.unwrap_or_default() {
+        let command = match Command::from_name(&name) {
+            Some(cmd) => cmd,
+            None => {
+                problems.push(format!("unknown command '{}'", name));
+                continue;
+            }
+        };
+        let entries = match entries {
+            OneOrMany::One(e) => vec![e],
+            OneOrMany::Many(es) => es,
+        };
+        commands.push((command, entries));
+    }
+    Ok(commands)
+}
+
+/// Read a bindings file from the root dir.  Returns None if there is no file.
+fn read_file(rootdir: &str, name: &str) -> Result<Option<String>> {
+    let path = format!("{}\\{}", rootdir, name);
+    if !std::path::Path::new(&path).is_file() {
+        return Ok(None);
+    }
+    write_log_file(&format!("loading bindings from {}", path));
+    Ok(Some(std::fs::read_to_string(&path)?))
+}
+
+fn conf_problem(e: HookError) -> String {
+    match e {
+        HookError::ConfReadFailed(e) => e,
+        e => format!("{:?}", e),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyBinding {
+    pub chord: Chord,
+    pub command: Command,
+    /// None if the command only runs once per press
+    pub repeat: Option<Repeat>,
+}
+
+impl KeyBinding {
+    fn from_entry(entry: Entry, command: Command) -> Result<Self> {
+        let e = match entry {
+            Entry::Chord(keys) =>
+                return Ok(KeyBinding { chord: Chord::parse(&keys)?, command, repeat: Some(Repeat::DEFAULT) }),
+            Entry::Detailed(e) => e,
+        };
+        let chord = Chord::parse(&e.keys)?;
+        let repeat = Repeat {
+            initial_ms: e.initial_repeat_ms.unwrap_or(Repeat::DEFAULT.initial_ms),
+            continued_ms: e.repeat_ms.unwrap_or(Repeat::DEFAULT.continued_ms),
+        };
+        if !e.repeat {
+            if e.initial_repeat_ms.is_some() || e.repeat_ms.is_some() {
+                return Err(HookError::ConfReadFailed(format!("{}: repeat delays given but repeat is off", chord)));
+            }
+            return Ok(KeyBinding { chord, command, repeat: None });
+        }
+        if repeat.initial_ms == 0 || repeat.continued_ms == 0 {
+            return Err(HookError::ConfReadFailed(format!("{}: repeat delays must be greater than 0", chord)));
+        }
+        Ok(KeyBinding { chord, command, repeat: Some(repeat) })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyBindings {
+    pub bindings: Vec<KeyBinding>,
+}
+
+impl KeyBindings {
+    /// Returns a description of each chord that is bound more than once; empty if there are
+    /// none.
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (i, b) in self.bindings.iter().enumerate() {
+            for other in self.bindings[..i].iter().filter(|o| o.chord == b.chord) {
+                if other.command == b.command {
+                    problems.push(format!("{} is bound to {} more than once", b.chord, b.command));
+                } else {
+                    problems.push(format!("{} is bound to both {} and {}", b.chord, other.command, b.command));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Parse the bindings file text.  Invalid bindings and conflicts are an error.
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        let mut kb = KeyBindings::default();
+        let mut problems = vec![];
+        for (command, entries) in parse_entries::<Entry>(text, &mut problems)? {
+            for e in entries {
+                match KeyBinding::from_entry(e, command) {
+                    Ok(b) => kb.bindings.push(b),
+                    Err(e) => problems.push(conf_problem(e)),
+                }
+            }
+        }
+        problems.extend(kb.conflicts());
+        if !problems.is_empty() {
+            return Err(HookError::ConfReadFailed(format!("invalid key bindings: {}", problems.join("; "))));
+        }
+        Ok(kb)
+    }
+
+    /// Load the bindings file from the root dir.  Returns None if there is no file.
+    pub fn load(rootdir: &str) -> Result<Option<Self>> {
+        read_file(rootdir, KEY_BINDINGS_FILE)?.map(|text| Self::from_yaml(&text)).transpose()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GamepadBindings {
+    pub bindings: Vec<(Combo, Command)>,
+}
+
+impl GamepadBindings {
+    /// Returns a description of each combo that is bound more than once or that is part of
+    /// another bound combo (so both would fire); empty if there are none.
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (i, (combo, cmd)) in self.bindings.iter().enumerate() {
+            for (other, other_cmd) in self.bindings[..i].iter() {
+                if other == combo && other_cmd == cmd {
+                    problems.push(format!("{} is bound to {} more than once", combo, cmd));
+                } else if other == combo {
+                    problems.push(format!("{} is bound to both {} and {}", combo, other_cmd, cmd));
+                } else if other.is_within(combo) || combo.is_within(other) {
+                    problems.push(format!("{} ({}) and {} ({}) overlap", other, other_cmd, combo, cmd));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Parse the gamepad bindings file text.  Invalid bindings and conflicts are an error.
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        let mut gb = GamepadBindings::default();
+        let mut problems = vec![];
+        for (command, entries) in parse_entries::<String>(text, &mut problems)? {
+            for e in entries {
+                match Combo::parse(&e) {
+                    Ok(combo) => gb.bindings.push((combo, command)),
+                    Err(e) => problems.push(conf_problem(e)),
+                }
+            }
+        }
+        problems.extend(gb.conflicts());
+        if !problems.is_empty() {
+            return Err(HookError::ConfReadFailed(format!("invalid gamepad bindings: {}", problems.join("; "))));
+        }
+        Ok(gb)
+    }
+
+    /// Load the gamepad bindings file from the root dir.  Returns None if there is no file.
+    pub fn load(rootdir: &str) -> Result<Option<Self>> {
+        read_file(rootdir, GAMEPAD_BINDINGS_FILE)?.map(|text| Self::from_yaml(&text)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Modifiers;
+
+    fn binding(chord: &str, command: Command) -> KeyBinding {
+        KeyBinding { chord: Chord::parse(chord).expect("doh"), command, repeat: Some(Repeat::DEFAULT) }
+    }
+
+    #[test]
+    fn test_from_yaml() {
+        assert_eq!(KeyBindings::from_yaml("").expect("doh"), KeyBindings::default());
+
+        let kb = KeyBindings::from_yaml(
+            "take_snapshot: ctrl+alt+S\nnext-variant: [ctrl+NUMPAD8, ctrl+DIK_NUMPAD9]\n").expect("doh");
+        assert_eq!(kb.bindings.len(), 3);
+        assert!(kb.bindings.contains(&binding("alt+ctrl+s", Command::TakeSnapshot)));
+        assert_eq!(kb.bindings.iter().filter(|b| b.command == Command::NextVariant).count(), 2);
+        assert!(kb.bindings.iter().all(|b| b.chord.mods.ctrl));
+
+        let kb = KeyBindings::from_yaml(concat!(
+            "reload_mods: { keys: ctrl+F1, repeat: false }\n",
+            "select_next_texture: [{ keys: ctrl+F3, repeat_ms: 40 }, ctrl+shift+F3]\n")).expect("doh");
+        assert_eq!(kb.bindings[0].repeat, None);
+        assert_eq!(kb.bindings[1].repeat, Some(Repeat { initial_ms: 500, continued_ms: 40 }));
+        assert_eq!(kb.bindings[2], binding("ctrl+shift+F3", Command::SelectNextTexture));
+
+        assert!(KeyBindings::from_yaml("frobnicate: ctrl+F1\n").is_err());
+        assert!(KeyBindings::from_yaml("reload_mods: ctrl+F16\n").is_err());
+        assert!(KeyBindings::from_yaml("reload_mods: 7\n").is_err());
+        assert!(KeyBindings::from_yaml("reload_mods: { keys: ctrl+F1, repeat_ms: 0 }\n").is_err());
+        assert!(KeyBindings::from_yaml("reload_mods: { keys: ctrl+F1, repeat: false, repeat_ms: 50 }\n").is_err());
+        assert!(KeyBindings::from_yaml("reload_mods: { key: ctrl+F1 }\n").is_err());
+    }
+
+    #[test]
+    fn test_doc_example() {
+        // the example in the module doc
+        let kb = KeyBindings::from_yaml(concat!(
+            "reload_mods: { keys: ctrl+F1, repeat: false }\n",
+            "take_snapshot: ctrl+alt+S\n",
+            "select_next_texture: { keys: ctrl+F3, initial_repeat_ms: 250, repeat_ms: 40 }\n",
+            "next_variant: [ctrl+NUMPAD8, ctrl+NUMPAD9]\n")).expect("doh");
+        assert_eq!(kb.bindings.len(), 5);
+        assert!(kb.bindings.contains(&binding("ctrl+alt+S", Command::TakeSnapshot)));
+        let select = kb.bindings.iter().find(|b| b.command == Command::SelectNextTexture).expect("doh");
+        assert_eq!(select.repeat, Some(Repeat { initial_ms: 250, continued_ms: 40 }));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let f1 = Chord { key: 0x3B, mods: Modifiers::CTRL };
+        let shift_f1 = Chord { key: 0x3B, mods: Modifiers { shift: true, ..Modifiers::CTRL } };
+        let b = |chord, command| KeyBinding { chord, command, repeat: None };
+        let kb = KeyBindings { bindings: vec![b(f1, Command::ReloadMods), b(shift_f1, Command::TakeSnapshot)] };
+        assert!(kb.conflicts().is_empty());
+        let kb = KeyBindings { bindings: vec![b(f1, Command::ReloadMods), b(f1, Command::TakeSnapshot)] };
+        assert_eq!(kb.conflicts(), vec!["ctrl+F1 is bound to both reload_mods and take_snapshot".to_owned()]);
+
+        let err = KeyBindings::from_yaml("reload_mods: ctrl+F1\ntake_snapshot: [ctrl+F7, control+f1]\n")
+            .expect_err("doh");
+        assert!(format!("{:?}", err).contains("ctrl+F1 is bound to both"));
+        let err = KeyBindings::from_yaml("reload_mods: [ctrl+F1, { keys: ctrl+f1, repeat: false }]\n")
+            .expect_err("doh");
+        assert!(format!("{:?}", err).contains("more than once"));
+    }
+
+    #[test]
+    fn test_gamepad_bindings() {
+        assert_eq!(GamepadBindings::from_yaml("").expect("doh"), GamepadBindings::default());
+        let gb = GamepadBindings::from_yaml(
+            "next_variant: Back+DPadRight
+toggle_show_mods: [Back+Y, LB+RB]
+").expect("doh");
+        assert_eq!(gb.bindings.len(), 3);
+        assert_eq!(gb.bindings[0], (Combo::parse("dpadright+back").expect("doh"), Command::NextVariant));
+
+        assert!(GamepadBindings::from_yaml("next_variant: A
+").is_err());
+        assert!(GamepadBindings::from_yaml("frobnicate: A+B
+").is_err());
+        let err = GamepadBindings::from_yaml("next_variant: Back+A
+reload_mods: A+Back
+").expect_err("doh");
+        assert!(format!("{:?}", err).contains("bound to both"));
+        let err = GamepadBindings::from_yaml("next_variant: Back+A
+reload_mods: Back+A+B
+").expect_err("doh");
+        assert!(format!("{:?}", err).contains("overlap"));
+    }
+}
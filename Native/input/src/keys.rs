@@ -0,0 +1,247 @@
+//! DirectInput key names and key chords such as `ctrl+F7` or `alt+shift+N`.
+//!
+//! Keys are named after their `DIK_` constants, with or without the prefix and in any case, so
+//! `F7`, `dik_f7` and `DIK_F7` are the same key.  The alternate names from `dinput.h`
+//! (`BACKSPACE`, `PGUP`, `UPARROW` etc.) are accepted too, as are `LALT`/`RALT` for
+//! `LMENU`/`RMENU`.
+use std::fmt;
+
+use shared_dx::error::{HookError, Result};
+
+const KEY_NAMES: &[(&str, u8)] = &[
+    ("ESCAPE", 0x01),
+    ("1", 0x02), ("2", 0x03), ("3", 0x04), ("4", 0x05), ("5", 0x06),
+    ("6", 0x07), ("7", 0x08), ("8", 0x09), ("9", 0x0A), ("0", 0x0B),
+    ("MINUS", 0x0C),
+    ("EQUALS", 0x0D),
+    ("BACK", 0x0E),
+    ("TAB", 0x0F),
+    ("Q", 0x10), ("W", 0x11), ("E", 0x12), ("R", 0x13), ("T", 0x14),
+    ("Y", 0x15), ("U", 0x16), ("I", 0x17), ("O", 0x18), ("P", 0x19),
+    ("LBRACKET", 0x1A),
+    ("RBRACKET", 0x1B),
+    ("RETURN", 0x1C),
+    ("LCONTROL", 0x1D),
+    ("A", 0x1E), ("S", 0x1F), ("D", 0x20), ("F", 0x21), ("G", 0x22),
+    ("H", 0x23), ("J", 0x24), ("K", 0x25), ("L", 0x26),
+    ("SEMICOLON", 0x27),
+    ("APOSTROPHE", 0x28),
+    ("GRAVE", 0x29),
+    ("LSHIFT", 0x2A),
+    ("BACKSLASH", 0x2B),
+    ("Z", 0x2C), ("X", 0x2D), ("C", 0x2E), ("V", 0x2F), ("B", 0x30),
+    ("N", 0x31), ("M", 0x32),
+    ("COMMA", 0x33),
+    ("PERIOD", 0x34),
+    ("SLASH", 0x35),
+    ("RSHIFT", 0x36),
+    ("MULTIPLY", 0x37),
+    ("LMENU", 0x38),
+    ("LALT", 0x38),
+    ("SPACE", 0x39),
+    ("CAPITAL", 0x3A),
+    ("F1", 0x3B), ("F2", 0x3C), ("F3", 0x3D), ("F4", 0x3E), ("F5", 0x3F),
+    ("F6", 0x40), ("F7", 0x41), ("F8", 0x42), ("F9", 0x43), ("F10", 0x44),
+    ("NUMLOCK", 0x45),
+    ("SCROLL", 0x46),
+    ("NUMPAD7", 0x47), ("NUMPAD8", 0x48), ("NUMPAD9", 0x49),
+    ("SUBTRACT", 0x4A),
+    ("NUMPAD4", 0x4B), ("NUMPAD5", 0x4C), ("NUMPAD6", 0x4D),
+    ("ADD", 0x4E),
+    ("NUMPAD1", 0x4F), ("NUMPAD2", 0x50), ("NUMPAD3", 0x51), ("NUMPAD0", 0x52),
+    ("DECIMAL", 0x53),
+    ("OEM_102", 0x56),
+    ("F11", 0x57),
+    ("F12", 0x58),
+    ("F13", 0x64), ("F14", 0x65), ("F15", 0x66),
+    ("KANA", 0x70),
+    ("ABNT_C1", 0x73),
+    ("CONVERT", 0x79),
+    ("NOCONVERT", 0x7B),
+    ("YEN", 0x7D),
+    ("ABNT_C2", 0x7E),
+    ("NUMPADEQUALS", 0x8D),
+    ("PREVTRACK", 0x90),
+    ("AT", 0x91),
+    ("COLON", 0x92),
+    ("UNDERLINE", 0x93),
+    ("KANJI", 0x94),
+    ("STOP", 0x95),
+    ("AX", 0x96),
+    ("UNLABELED", 0x97),
+    ("NEXTTRACK", 0x99),
+    ("NUMPADENTER", 0x9C),
+    ("RCONTROL", 0x9D),
+    ("MUTE", 0xA0),
+    ("CALCULATOR", 0xA1),
+    ("PLAYPAUSE", 0xA2),
+    ("MEDIASTOP", 0xA4),
+    ("VOLUMEDOWN", 0xAE),
+    ("VOLUMEUP", 0xB0),
+    ("WEBHOME", 0xB2),
+    ("NUMPADCOMMA", 0xB3),
+    ("DIVIDE", 0xB5),
+    ("SYSRQ", 0xB7),
+    ("RMENU", 0xB8),
+    ("RALT", 0xB8),
+    ("PAUSE", 0xC5),
+    ("HOME", 0xC7),
+    ("UP", 0xC8),
+    ("PRIOR", 0xC9),
+    ("LEFT", 0xCB),
+    ("RIGHT", 0xCD),
+    ("END", 0xCF),
+    ("DOWN", 0xD0),
+    ("NEXT", 0xD1),
+    ("INSERT", 0xD2),
+    ("DELETE", 0xD3),
+    ("LWIN", 0xDB),
+    ("RWIN", 0xDC),
+    ("APPS", 0xDD),
+    ("POWER", 0xDE),
+    ("SLEEP", 0xDF),
+    ("WAKE", 0xE3),
+    ("WEBSEARCH", 0xE5),
+    ("WEBFAVORITES", 0xE6),
+    ("WEBREFRESH", 0xE7),
+    ("WEBSTOP", 0xE8),
+    ("WEBFORWARD", 0xE9),
+    ("WEBBACK", 0xEA),
+    ("MYCOMPUTER", 0xEB),
+    ("MAIL", 0xEC),
+    ("MEDIASELECT", 0xED),
+    // alternate names, after the DIK names so those are the ones displayed
+    ("BACKSPACE", 0x0E),
+    ("NUMPADSTAR", 0x37),
+    ("CAPSLOCK", 0x3A),
+    ("NUMPADMINUS", 0x4A),
+    ("NUMPADPLUS", 0x4E),
+    ("NUMPADPERIOD", 0x53),
+    ("CIRCUMFLEX", 0x90),
+    ("NUMPADSLASH", 0xB5),
+    ("UPARROW", 0xC8),
+    ("PGUP", 0xC9),
+    ("LEFTARROW", 0xCB),
+    ("RIGHTARROW", 0xCD),
+    ("DOWNARROW", 0xD0),
+    ("PGDN", 0xD1),
+];
+
+/// Look up a key by its DIK name.
+pub fn key_from_name(name: &str) -> Option<u8> {
+    let name = name.trim().to_ascii_uppercase();
+    let name = name.strip_prefix("DIK_").unwrap_or(&name);
+    KEY_NAMES.iter().find(|(n, _)| *n == name).map(|&(_, k)| k)
+}
+
+/// The DIK name of a key (without the prefix), if it has one.
+pub fn key_name(key: u8) -> Option<&'static str> {
+    KEY_NAMES.iter().find(|&&(_, k)| k == key).map(|&(n, _)| n)
+}
+
+fn is_modifier_key(key: u8) -> bool {
+    // LCONTROL, RCONTROL, LSHIFT, RSHIFT, LMENU, RMENU
+    matches!(key, 0x1D | 0x9D | 0x2A | 0x36 | 0x38 | 0xB8)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+impl Modifiers {
+    pub const CTRL: Modifiers = Modifiers { ctrl: true, alt: false, shift: false };
+}
+
+/// A key pressed while holding a set of modifiers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub key: u8,
+    pub mods: Modifiers,
+}
+
+impl Chord {
+    /// Parse a chord like `ctrl+F7`.  Modifiers (`ctrl`, `alt`, `shift`) come first, the key is
+    /// last and must not itself be a modifier key.
+    pub fn parse(s: &str) -> Result<Chord> {
+        let err = |msg: &str| HookError::ConfReadFailed(format!("bad key chord '{}': {}", s, msg));
+        let parts: Vec<&str> = s.split('+').map(|p| p.trim()).collect();
+        let (keyname, modnames) = parts.split_last().ok_or_else(|| err("empty"))?;
+        if keyname.is_empty() {
+            return Err(err("no key"));
+        }
+        let mut mods = Modifiers::default();
+        for m in modnames {
+            match m.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => mods.ctrl = true,
+                "alt" => mods.alt = true,
+                "shift" => mods.shift = true,
+                _ => return Err(err(&format!("unknown modifier '{}'", m))),
+            }
+        }
+        let key = key_from_name(keyname).ok_or_else(|| err(&format!("unknown key '{}'", keyname)))?;
+        if is_modifier_key(key) {
+            return Err(err("the key can't be a modifier"));
+        }
+        Ok(Chord { key, mods })
+    }
+}
+
+/// How a held chord repeats: the first repeat comes `initial_ms` after the press, then one every
+/// `continued_ms`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repeat {
+    pub initial_ms: u16,
+    pub continued_ms: u16,
+}
+
+impl Repeat {
+    pub const DEFAULT: Repeat = Repeat { initial_ms: 500, continued_ms: 75 };
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.mods.ctrl {
+            write!(f, "ctrl+")?;
+        }
+        if self.mods.alt {
+            write!(f, "alt+")?;
+        }
+        if self.mods.shift {
+            write!(f, "shift+")?;
+        }
+        match key_name(self.key) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "0x{:x}", self.key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chord() {
+        assert_eq!(key_from_name("dik_f7"), Some(0x41));
+        assert_eq!(key_from_name("RAlt"), key_from_name("RMENU"));
+        assert_eq!(key_from_name("F13"), Some(0x64));
+        assert_eq!(key_from_name("F16"), None);
+        assert_eq!(key_from_name("pgup"), Some(0xC9));
+        assert_eq!(key_name(0xC9), Some("PRIOR"));
+
+        let c = Chord::parse("ctrl+F7").expect("doh");
+        assert_eq!(c, Chord { key: 0x41, mods: Modifiers::CTRL });
+        assert_eq!(c.to_string(), "ctrl+F7");
+        let c = Chord::parse(" Shift + ALT + n ").expect("doh");
+        assert_eq!(c, Chord { key: 0x31, mods: Modifiers { ctrl: false, alt: true, shift: true } });
+        assert_eq!(c.to_string(), "alt+shift+N");
+        assert_eq!(Chord::parse("DIK_NUMPAD8").expect("doh").mods, Modifiers::default());
+
+        for bad in ["", "ctrl+", "ctrl+F16", "super+F1", "ctrl+LSHIFT", "F1+F2"].iter() {
+            assert!(Chord::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
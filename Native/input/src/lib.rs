@@ -2,6 +2,7 @@
 #[cfg(windows)]
 extern crate winapi;
 
+#[cfg(windows)]
 extern crate util;
 
 //#[macro_use]
@@ -9,3 +10,15 @@ extern crate profiler;
 
 mod input;
 pub use crate::input::*;
+/// Keyboard state sources, including a scripted one for tests
+pub mod backend;
+#[cfg(windows)]
+pub mod dinput;
+/// Gamepad button combos
+pub mod gamepad;
+#[cfg(windows)]
+pub mod xinput;
+/// Key names and chords
+pub mod keys;
+/// User key bindings file
+pub mod keybindings;
@@ -0,0 +1,68 @@
+//! XInput gamepads.  The buttons of all connected controllers are combined, so any of them can
+//! be used.
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::xinput::XINPUT_STATE;
+
+use shared_dx::clock;
+use shared_dx::error::*;
+use shared_dx::util::write_log_file;
+
+use crate::backend::InputBackend;
+use crate::gamepad::GamepadState;
+
+const MAX_CONTROLLERS: usize = 4;
+/// XInputGetState is slow for controllers that aren't connected, so they are only checked
+/// this often.
+const DISCONNECTED_RETRY: Duration = Duration::from_secs(2);
+
+type XInputGetStateFn = unsafe extern "system" fn(user_index: DWORD, state: *mut XINPUT_STATE) -> DWORD;
+
+pub struct XInputGamepads {
+    get_state: XInputGetStateFn,
+    /// When to check each disconnected controller again
+    retry_at: [Option<Instant>; MAX_CONTROLLERS],
+}
+
+impl XInputGamepads {
+    pub fn new() -> Result<Self> {
+        let mut last_err = None;
+        for dll in ["xinput1_4.dll", "xinput1_3.dll", "xinput9_1_0.dll"].iter() {
+            let get_state = util::load_lib(dll)
+                .and_then(|lib| util::get_proc_address(lib, "XInputGetState"));
+            match get_state {
+                Ok(f) => {
+                    write_log_file(&format!("using {} for gamepad input", dll));
+                    let get_state: XInputGetStateFn = unsafe { std::mem::transmute(f) };
+                    return Ok(Self { get_state, retry_at: [None; MAX_CONTROLLERS] });
+                },
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| HookError::LoadLibFailed("no xinput dll could be loaded".to_owned())))
+    }
+}
+
+impl InputBackend for XInputGamepads {
+    type State = GamepadState;
+
+    fn poll(&mut self, state: &mut GamepadState) -> Result<()> {
+        let now = clock::now();
+        *state = 0;
+        for (i, retry_at) in self.retry_at.iter_mut().enumerate() {
+            if retry_at.is_some_and(|t| now < t) {
+                continue;
+            }
+            let mut xs: XINPUT_STATE = unsafe { std::mem::zeroed() };
+            if unsafe { (self.get_state)(i as DWORD, &mut xs) } == ERROR_SUCCESS {
+                *retry_at = None;
+                *state |= xs.Gamepad.wButtons;
+            } else {
+                *retry_at = Some(now + DISCONNECTED_RETRY);
+            }
+        }
+        Ok(())
+    }
+}
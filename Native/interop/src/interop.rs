@@ -5,7 +5,7 @@ use std::os::raw::c_char;
 
 
 use shared_dx::util::write_log_file;
-use global_state::HookState;
+use global_state::{HookState, D3D11KeyMode};
 use types::interop::*;
 
 lazy_static! {
@@ -147,5 +147,18 @@ pub unsafe extern "stdcall" fn OnInitialized(
 
     (*global_hookstate).interop_state = Some(is);
 
+    // the key mode is a game profile setting, so it can't be read until the profile is known
+    let profile_root = CStr::from_ptr(&(*cd).ProfileKey[0] as *const c_char).to_str();
+    if let Ok(profile_root) = profile_root {
+        let key_mode = util::reg_query_dword(profile_root, "GameProfileD3D11KeyMode")
+            .map(D3D11KeyMode::from_dword)
+            .unwrap_or(D3D11KeyMode::BufferSize);
+        (*global_hookstate).run_conf.d3d11_key_mode = key_mode;
+        write_log_file(&format!("d3d11 key mode: {:?}", key_mode));
+        if key_mode == D3D11KeyMode::IndexRange && !(*global_hookstate).run_conf.precopy_data {
+            write_log_file("WARNING: index range key mode requires precopied data, buffer size will be used instead");
+        }
+    }
+
     0
 }
@@ -0,0 +1,63 @@
+//! Sends commands to a running hook and prints the responses.
+//!
+//! Usage: `mmipc (--game ExeBaseName | --endpoint path) <command>...`
+//!
+//! Commands are `stats`, `mods`, `selected`, `snapshot`, `reload` and `run <name>`, which runs
+//! a hook command such as `take_snapshot` or `select_next_texture`.  The game must be running
+//! with the `IpcServer` registry value set.  Data is printed as JSON; errors go to stderr and
+//! set the exit code.
+use ipc::protocol::Request;
+use ipc::transport::{endpoint_for, IpcClient};
+
+fn usage() -> ! {
+    let names: Vec<&str> = Request::ALL.iter().map(|r| r.name()).collect();
+    eprintln!("usage: mmipc (--game ExeBaseName | --endpoint path) <{}|run name>...", names.join("|"));
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut endpoint = None;
+    let mut requests = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--game" => endpoint = Some(endpoint_for(&args.next().unwrap_or_else(|| usage()))),
+            "--endpoint" => endpoint = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            "run" => requests.push(Request::Run { command: args.next().unwrap_or_else(|| usage()) }),
+            _ => requests.push(Request::from_name(&arg).unwrap_or_else(|| usage())),
+        }
+    }
+    let endpoint = endpoint.unwrap_or_else(|| usage());
+    if requests.is_empty() {
+        usage();
+    }
+
+    let mut client = match IpcClient::connect(&endpoint) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error: can't connect to {}: {:?}", endpoint, e);
+            std::process::exit(1);
+        }
+    };
+    let mut failed = false;
+    for req in requests {
+        match client.request(req.clone()) {
+            Ok(resp) if resp.ok => {
+                let data = resp.data.unwrap_or(serde_json::Value::Null);
+                println!("{}", serde_json::to_string_pretty(&data).unwrap_or_default());
+            },
+            Ok(resp) => {
+                eprintln!("error: {}: {}", req, resp.error.unwrap_or_default());
+                failed = true;
+            },
+            Err(e) => {
+                eprintln!("error: {}: {:?}", req, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
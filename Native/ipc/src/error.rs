@@ -0,0 +1,27 @@
+//! Errors for the IPC crate.  These are separate from `shared_dx::error` so that the crate (and
+//! its tests) builds on any platform; the hook only logs them.
+use std::fmt;
+
+#[derive(Debug)]
+pub enum IpcError {
+    IOError(std::io::Error),
+    /// A bad or oversized message, or a failure to set up the endpoint
+    Failed(String),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcError::IOError(e) => write!(f, "{}", e),
+            IpcError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::convert::From<std::io::Error> for IpcError {
+    fn from(error: std::io::Error) -> Self {
+        IpcError::IOError(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, IpcError>;
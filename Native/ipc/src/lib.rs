@@ -0,0 +1,5 @@
+//! Local IPC for querying and controlling a running hook.  See `protocol` for the messages and
+//! `transport` for the server and client.
+pub mod error;
+pub mod protocol;
+pub mod transport;
@@ -0,0 +1,114 @@
+//! Messages exchanged with the hook.  Each message is one line of JSON; the client sends a
+//! request and the server answers with one response, e.g.
+//!
+//! ```text
+//! -> {"cmd":"stats"}
+//! <- {"ok":true,"data":{"total_frames":1234,...}}
+//! -> {"cmd":"reload"}
+//! <- {"ok":false,"error":"mods are loading"}
+//! -> {"cmd":"run","command":"take_snapshot"}
+//! <- {"ok":true,"data":{"ran":"take_snapshot"}}
+//! ```
+//!
+//! The contents of `data` depend on the command and are meant for display or simple tooling, so
+//! they aren't typed here.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Requests are limited to this size, longer lines are rejected.
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// Frame counters, fps and frame time percentiles
+    Stats,
+    /// Loaded mods and their state
+    Mods,
+    /// The texture selection state
+    Selected,
+    /// Start a snapshot
+    Snapshot,
+    /// Reload all mods
+    Reload,
+    /// Run a hook command by name, as if its key was pressed (see `types::command`)
+    Run { command: String },
+}
+
+impl Request {
+    /// The requests that don't take an argument
+    pub const ALL: [Request; 5] = [Request::Stats, Request::Mods, Request::Selected, Request::Snapshot, Request::Reload];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Stats => "stats",
+            Request::Mods => "mods",
+            Request::Selected => "selected",
+            Request::Snapshot => "snapshot",
+            Request::Reload => "reload",
+            Request::Run { .. } => "run",
+        }
+    }
+
+    /// Only finds requests in `ALL`.
+    pub fn from_name(name: &str) -> Option<Request> {
+        Self::ALL.iter().find(|r| r.name() == name).cloned()
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn ok(data: Value) -> Self {
+        Self { ok: true, data: Some(data), error: None }
+    }
+
+    pub fn error(msg: &str) -> Self {
+        Self { ok: false, data: None, error: Some(msg.to_owned()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        for r in Request::ALL.iter() {
+            let line = serde_json::to_string(r).expect("doh");
+            assert_eq!(line, format!("{{\"cmd\":\"{}\"}}", r.name()));
+            assert_eq!(&serde_json::from_str::<Request>(&line).expect("doh"), r);
+            assert_eq!(Request::from_name(r.name()).as_ref(), Some(r));
+        }
+        let run = Request::Run { command: "take_snapshot".to_owned() };
+        let line = serde_json::to_string(&run).expect("doh");
+        assert_eq!(line, "{\"cmd\":\"run\",\"command\":\"take_snapshot\"}");
+        assert_eq!(serde_json::from_str::<Request>(&line).expect("doh"), run);
+        assert!(serde_json::from_str::<Request>("{\"cmd\":\"run\"}").is_err());
+        assert_eq!(Request::from_name("run"), None);
+        assert!(serde_json::from_str::<Request>("{\"cmd\":\"format_c\"}").is_err());
+        assert_eq!(Request::from_name("format_c"), None);
+
+        let resp = Response::ok(serde_json::json!({"frames": 10}));
+        assert_eq!(serde_json::to_string(&resp).expect("doh"), "{\"ok\":true,\"data\":{\"frames\":10}}");
+        let resp = Response::error("nope");
+        let line = serde_json::to_string(&resp).expect("doh");
+        assert_eq!(line, "{\"ok\":false,\"error\":\"nope\"}");
+        assert_eq!(serde_json::from_str::<Response>(&line).expect("doh"), resp);
+    }
+}
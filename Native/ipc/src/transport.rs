@@ -0,0 +1,379 @@
+//! Local transport for the IPC protocol: a named pipe on Windows and a Unix domain socket
+//! elsewhere.  Remote clients are rejected.
+//!
+//! Each connection is answered on its own thread, so a client that stays connected without
+//! sending anything doesn't hold up the others.  A client can send several requests on one
+//! connection.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::{IpcError, Result};
+use crate::protocol::{Request, Response, MAX_MESSAGE_BYTES};
+
+/// How long the server waits for a request from a connected client, and the client waits for
+/// a response.  Named pipes don't support this, so on Windows they wait indefinitely.
+#[cfg(not(windows))]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// The server gives up after this many consecutive failures to accept a connection.
+const MAX_ACCEPT_ERRORS: u32 = 10;
+/// Connections beyond this many are refused with an error response.
+const MAX_CONNECTIONS: usize = 8;
+
+pub type Handler = Arc<dyn Fn(Request) -> Response + Send + Sync>;
+/// Where the server reports errors, since it has no caller to return them to.
+pub type LogFn = fn(&str);
+
+/// The endpoint for a name, usually the exe base name of the game.
+pub fn endpoint_for(name: &str) -> String {
+    #[cfg(windows)]
+    {
+        format!(r"\\.\pipe\modelmod.{}", name)
+    }
+    #[cfg(not(windows))]
+    {
+        std::env::temp_dir().join(format!("modelmod.{}.sock", name)).to_string_lossy().to_string()
+    }
+}
+
+/// Read one line.  Returns None at the end of the stream.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut buf = vec![];
+    let n = reader.by_ref().take(MAX_MESSAGE_BYTES as u64 + 1).read_until(b'\n', &mut buf)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.len() > MAX_MESSAGE_BYTES {
+        return Err(IpcError::Failed(format!("message longer than {} bytes", MAX_MESSAGE_BYTES)));
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).trim().to_owned()))
+}
+
+fn write_message<T: Serialize>(writer: &mut impl Write, msg: &T) -> Result<()> {
+    let mut line = serde_json::to_string(msg)
+        .map_err(|e| IpcError::Failed(format!("serialize error: {}", e)))?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Answer requests on the connection until the client disconnects.
+fn serve_connection(reader: impl Read, mut writer: impl Write, handler: &Handler) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    while let Some(line) = read_message(&mut reader)? {
+        if line.is_empty() {
+            continue;
+        }
+        let resp = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => handler(req),
+            Err(e) => Response::error(&format!("bad request: {}", e)),
+        };
+        write_message(&mut writer, &resp)?;
+    }
+    Ok(())
+}
+
+/// Serve the connection on a new thread, unless there are already too many.
+fn spawn_connection(reader: sys::Stream, mut writer: sys::Stream, handler: &Handler,
+    connections: &Arc<AtomicUsize>, log: LogFn, endpoint: &str) {
+    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+        connections.fetch_sub(1, Ordering::SeqCst);
+        let _ = write_message(&mut writer, &Response::error("too many connections"));
+        return;
+    }
+    let handler = handler.clone();
+    let connections = connections.clone();
+    let endpoint = endpoint.to_owned();
+    std::thread::spawn(move || {
+        if let Err(e) = serve_connection(reader, writer, &handler) {
+            log(&format!("ipc: client error on {}: {:?}", endpoint, e));
+        }
+        connections.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+pub struct IpcServer {
+    endpoint: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl IpcServer {
+    /// Start serving on the endpoint.  Fails if it can't be created, for instance because another
+    /// server is using it.
+    pub fn start(endpoint: &str, handler: Handler, log: LogFn) -> Result<Self> {
+        let mut listener = sys::Listener::bind(endpoint)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let endpoint = endpoint.to_owned();
+            std::thread::spawn(move || {
+                let connections = Arc::new(AtomicUsize::new(0));
+                let mut errors = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((reader, writer)) => {
+                            errors = 0;
+                            if stop.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            spawn_connection(reader, writer, &handler, &connections, log, &endpoint);
+                        },
+                        Err(e) => {
+                            errors += 1;
+                            log(&format!("ipc: accept failed on {}: {:?}", endpoint, e));
+                            if errors >= MAX_ACCEPT_ERRORS {
+                                log("ipc: too many errors, stopping server");
+                                break;
+                            }
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                    }
+                }
+                sys::cleanup(&endpoint);
+            })
+        };
+        Ok(Self { endpoint: endpoint.to_owned(), stop, thread: Some(thread) })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Stop accepting connections and wait for the accept thread to exit.  Clients that are
+    /// already connected are served until they disconnect.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            // wake up the accept
+            let _ = sys::connect(&self.endpoint);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub struct IpcClient {
+    reader: BufReader<sys::Stream>,
+    writer: sys::Stream,
+}
+
+impl IpcClient {
+    pub fn connect(endpoint: &str) -> Result<Self> {
+        let writer = sys::connect(endpoint)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    pub fn request(&mut self, req: Request) -> Result<Response> {
+        write_message(&mut self.writer, &req)?;
+        match read_message(&mut self.reader)? {
+            Some(line) => serde_json::from_str(&line)
+                .map_err(|e| IpcError::Failed(format!("bad response: {}", e))),
+            None => Err(IpcError::Failed("server closed the connection".to_owned())),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use super::*;
+
+    pub type Stream = UnixStream;
+
+    pub struct Listener {
+        listener: UnixListener,
+    }
+
+    impl Listener {
+        pub fn bind(endpoint: &str) -> Result<Self> {
+            if UnixStream::connect(endpoint).is_ok() {
+                return Err(IpcError::Failed(format!("{} is in use", endpoint)));
+            }
+            // a socket file left behind by a server that didn't stop would fail the bind
+            let _ = std::fs::remove_file(endpoint);
+            Ok(Self { listener: UnixListener::bind(endpoint)? })
+        }
+
+        pub fn accept(&mut self) -> Result<(Stream, Stream)> {
+            let (stream, _addr) = self.listener.accept()?;
+            stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+            Ok((stream.try_clone()?, stream))
+        }
+    }
+
+    pub fn connect(endpoint: &str) -> Result<Stream> {
+        let stream = UnixStream::connect(endpoint)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    pub fn cleanup(endpoint: &str) {
+        let _ = std::fs::remove_file(endpoint);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::{File, OpenOptions};
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::ptr::null_mut;
+    use winapi::shared::winerror::{ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED};
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+    use winapi::um::winbase::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+        PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT};
+    use super::*;
+
+    pub type Stream = File;
+
+    pub struct Listener {
+        name: Vec<u16>,
+        /// The pipe instance that the next client connects to.  It is created before the
+        /// previous connection is handed off, so there is always an instance to connect to
+        /// (clients that arrive in between get ERROR_PIPE_BUSY and retry).
+        next: Option<File>,
+    }
+
+    /// Create a pipe instance.  With `first`, fails if the pipe already exists.
+    fn create_pipe(name: &[u16], first: bool) -> Result<File> {
+        let flags = PIPE_ACCESS_DUPLEX | if first { FILE_FLAG_FIRST_PIPE_INSTANCE } else { 0 };
+        let handle = unsafe {
+            CreateNamedPipeW(name.as_ptr(), flags,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES, 4096, 4096, 0, null_mut())
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(IpcError::Failed(format!("CreateNamedPipe failed: {}", unsafe { GetLastError() })));
+        }
+        Ok(unsafe { File::from_raw_handle(handle as _) })
+    }
+
+    impl Listener {
+        pub fn bind(endpoint: &str) -> Result<Self> {
+            let name: Vec<u16> = endpoint.encode_utf16().chain(Some(0)).collect();
+            let first = create_pipe(&name, true)?;
+            Ok(Self { name, next: Some(first) })
+        }
+
+        pub fn accept(&mut self) -> Result<(Stream, Stream)> {
+            let pipe = match self.next.take() {
+                Some(pipe) => pipe,
+                None => create_pipe(&self.name, false)?,
+            };
+            let ok = unsafe { ConnectNamedPipe(pipe.as_raw_handle() as _, null_mut()) };
+            if ok == 0 {
+                // a client that connected between the create and the connect is fine
+                let err = unsafe { GetLastError() };
+                if err != ERROR_PIPE_CONNECTED {
+                    // the instance is dropped, the next accept creates a new one
+                    return Err(IpcError::Failed(format!("ConnectNamedPipe failed: {}", err)));
+                }
+            }
+            // if this fails the next accept tries again and reports the error
+            self.next = create_pipe(&self.name, false).ok();
+            Ok((pipe.try_clone()?, pipe))
+        }
+    }
+
+    pub fn connect(endpoint: &str) -> Result<Stream> {
+        // all instances are busy while the server is creating the next one, so wait a bit
+        for _ in 0..20 {
+            match OpenOptions::new().read(true).write(true).open(endpoint) {
+                Ok(f) => return Ok(f),
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) =>
+                    std::thread::sleep(Duration::from_millis(100)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(IpcError::Failed(format!("{} is busy", endpoint)))
+    }
+
+    pub fn cleanup(_endpoint: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_handler() -> Handler {
+        Arc::new(|req| match req {
+            Request::Stats => Response::ok(serde_json::json!({"frames": 1})),
+            r => Response::error(&format!("{} not supported", r)),
+        })
+    }
+
+    fn test_log(_msg: &str) {}
+
+    // runs against a named pipe on windows and a socket elsewhere
+    #[test]
+    fn test_server() {
+        let endpoint = endpoint_for(&format!("test{}", std::process::id()));
+        let mut server = IpcServer::start(&endpoint, test_handler(), test_log).expect("doh");
+        assert!(IpcServer::start(&endpoint, test_handler(), test_log).is_err());
+
+        let mut client = IpcClient::connect(&endpoint).expect("doh");
+        assert_eq!(client.request(Request::Stats).expect("doh"), Response::ok(serde_json::json!({"frames": 1})));
+        let resp = client.request(Request::Reload).expect("doh");
+        assert_eq!(resp.error.as_deref(), Some("reload not supported"));
+        drop(client);
+
+        let mut raw = sys::connect(&endpoint).expect("doh");
+        raw.write_all(b"\n{\"cmd\":\"format_c\"}\n").expect("doh");
+        let mut line = String::new();
+        BufReader::new(&raw).read_line(&mut line).expect("doh");
+        let resp: Response = serde_json::from_str(&line).expect("doh");
+        assert!(!resp.ok);
+        assert!(resp.error.expect("doh").starts_with("bad request"));
+        drop(raw);
+
+        server.stop();
+        #[cfg(not(windows))]
+        assert!(!std::path::Path::new(&endpoint).exists());
+        assert!(IpcClient::connect(&endpoint).is_err());
+    }
+
+    #[test]
+    fn test_idle_client() {
+        let endpoint = endpoint_for(&format!("test_idle{}", std::process::id()));
+        let mut server = IpcServer::start(&endpoint, test_handler(), test_log).expect("doh");
+        // connected but never sends anything
+        let idle = sys::connect(&endpoint).expect("doh");
+        let mut held = vec![];
+        for _ in 1..MAX_CONNECTIONS {
+            let mut client = IpcClient::connect(&endpoint).expect("doh");
+            assert!(client.request(Request::Stats).expect("doh").ok);
+            held.push(client);
+        }
+
+        // past the limit connections get an error
+        let mut refused = BufReader::new(sys::connect(&endpoint).expect("doh"));
+        let mut line = String::new();
+        refused.read_line(&mut line).expect("doh");
+        let resp: Response = serde_json::from_str(&line).expect("doh");
+        assert_eq!(resp.error.as_deref(), Some("too many connections"));
+        assert!(held[0].request(Request::Stats).expect("doh").ok);
+
+        // the connection threads notice the disconnects shortly
+        drop(held);
+        let served = (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            IpcClient::connect(&endpoint).and_then(|mut c| c.request(Request::Stats)).is_ok_and(|r| r.ok)
+        });
+        assert!(served);
+        drop(idle);
+        server.stop();
+    }
+}
@@ -26,6 +26,7 @@ use winapi::um::d3d11::D3D11_SHADER_RESOURCE_VIEW_DESC;
 use winapi::um::d3d11::D3D11_SUBRESOURCE_DATA;
 use winapi::um::d3d11::D3D11_TEXTURE2D_DESC;
 use winapi::um::d3d11::D3D11_USAGE_DEFAULT;
+use winapi::um::d3d11::ID3D11Buffer;
 use winapi::um::d3d11::ID3D11Device;
 use winapi::um::d3d11::ID3D11ShaderResourceView;
 use winapi::um::d3d11::ID3D11Texture2D;
@@ -35,6 +36,7 @@ use fnv::FnvHashMap;
 
 use util;
 use d3dx;
+use snaplib::vertex_streams::deinterleave;
 use std;
 use std::ffi::CStr;
 use std::ptr::null_mut;
@@ -250,6 +252,53 @@ pub unsafe fn load_d3d_data9(device: *mut IDirect3DDevice9, callbacks: interop::
     nmd.d3d_data = native_mod::ModD3DState::Loaded(native_mod::ModD3DData::D3D9(d3dd));
 }
 
+/// Create a vertex buffer holding `data`.  Returns None (after logging the reason) if it can't
+/// be created.
+unsafe fn create_vertex_buffer(device: *mut ID3D11Device, data: &[u8], mod_name: &str) -> Option<*mut ID3D11Buffer> {
+    let mut vb_desc = D3D11_BUFFER_DESC {
+        ByteWidth: data.len() as UINT,
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_VERTEX_BUFFER,
+        CPUAccessFlags: 0,
+        MiscFlags: 0,
+        StructureByteStride: 0,
+    };
+    let mut vb_init_data = D3D11_SUBRESOURCE_DATA {
+        pSysMem: data.as_ptr() as *const c_void,
+        SysMemPitch: 0,
+        SysMemSlicePitch: 0,
+    };
+    let mut vertex_buffer = std::ptr::null_mut();
+    let hr = (*device).CreateBuffer(
+        &mut vb_desc, &mut vb_init_data, &mut vertex_buffer);
+    if hr != 0 {
+        write_log_file(&format!(
+            "failed to create vertex buffer for mod {}: HR {:x}",
+            mod_name, hr
+        ));
+
+        use winapi::shared::winerror::*;
+        if hr == DXGI_ERROR_DEVICE_REMOVED {
+            let dev_removed_reason = (*device).GetDeviceRemovedReason();
+            match dev_removed_reason {
+                DXGI_ERROR_DEVICE_HUNG => write_log_file(&format!("device hung")),
+                DXGI_ERROR_DEVICE_REMOVED => write_log_file(&format!("device removed")),
+                DXGI_ERROR_DEVICE_RESET => write_log_file(&format!("device reset")),
+                DXGI_ERROR_DRIVER_INTERNAL_ERROR => write_log_file(&format!("driver internal error")),
+                DXGI_ERROR_INVALID_CALL => write_log_file(&format!("invalid call")),
+                _ => write_log_file(&format!("unknown device removed reason")),
+            }
+        }
+        // check for E_OUTOFMEMORY
+        else if hr as i64 == 0x8007000e {
+            write_log_file(&format!("out of memory"));
+        }
+
+        return None;
+    }
+    Some(vertex_buffer)
+}
+
 pub unsafe fn load_d3d_data11(device: *mut ID3D11Device, callbacks: interop::ManagedCallbacks, midx: i32, nmd: &mut NativeModData) -> bool {
     let mdat = &nmd.mod_data;
 
@@ -312,17 +361,27 @@ pub unsafe fn load_d3d_data11(device: *mut ID3D11Device, callbacks: interop::Man
         }
     };
 
+    // mods are filled as a single interleaved stream, like snapshots store them.  if the
+    // layout splits the vertex over several streams, the data is split up again after filling
+    // and each stream gets its own buffer.
+    let vertex_slots = vlayout.vertex_slots();
+    if vertex_slots.is_empty() {
+        write_log_file(&format!("Error, vertex layout for mod {} has no per-vertex data", nmd.name));
+        return false;
+    }
+    let fill_layout = vlayout.interleaved();
+
     // in dx11 I pass the layout as an _in_ parameter containing the layout.  Contrast with
     // dx9 where the declaration is an _out_ parameter and receives the declaration from managed
     // code.
 
     // clone data because we need a mut pointer to pass it
-    let mut layout_data: Vec<_> = vlayout.layout.clone();
+    let mut layout_data: Vec<_> = fill_layout.layout.clone();
     let decl_size = std::mem::size_of::<D3D11_INPUT_ELEMENT_DESC>() * layout_data.len();
     let decl_data = layout_data.as_mut_ptr();
 
     // set vb size and create scratch buffer
-    let vert_size = vlayout.size;
+    let vert_size = fill_layout.size;
     if vert_size <= 0 {
         // gawd get this far and size is zero??
         write_log_file(&format!("Error, vertex size is invalid for mod {}: {}", nmd.name, vert_size));
@@ -357,56 +416,41 @@ pub unsafe fn load_d3d_data11(device: *mut ID3D11Device, callbacks: interop::Man
     }
 
     let mod_ts_update = (*mdat).update_tangent_space;
-    let _ = update_normals(vb_data.as_mut_ptr(), &nmd.name, mod_ts_update, vert_count, &vlayout)
+    let _ = update_normals(vb_data.as_mut_ptr(), &nmd.name, mod_ts_update, vert_count, &fill_layout)
         .map_err(|e| {
             write_log_file(&format!("Warning: failed to update normals: {:?}", e));
         });
 
-    // create vb
-    let mut vb_desc = D3D11_BUFFER_DESC {
-        ByteWidth: vb_size as UINT,
-        Usage: D3D11_USAGE_DEFAULT,
-        BindFlags: D3D11_BIND_VERTEX_BUFFER,
-        CPUAccessFlags: 0,
-        MiscFlags: 0,
-        StructureByteStride: 0,
-    };
-    let mut vb_init_data = D3D11_SUBRESOURCE_DATA {
-        pSysMem: vb_data.as_ptr() as *const c_void,
-        SysMemPitch: 0,
-        SysMemSlicePitch: 0,
+    let streams = if vertex_slots.len() == 1 {
+        vec![vb_data]
+    } else {
+        let sizes = vertex_slots.iter().map(|(_slot,size)| *size as usize).collect::<Vec<_>>();
+        match deinterleave(&vb_data, &sizes) {
+            Ok(streams) => streams,
+            Err(e) => {
+                write_log_file(&format!("Error, failed to split vertex data into streams for mod {}: {:?}", nmd.name, e));
+                return false;
+            }
+        }
     };
-    let mut vertex_buffer = std::ptr::null_mut();
-    let hr = (*device).CreateBuffer(
-        &mut vb_desc, &mut vb_init_data, &mut vertex_buffer);
-    if hr != 0 {
-        write_log_file(&format!(
-            "failed to create vertex buffer for mod {}: HR {:x}",
-            nmd.name, hr
-        ));
 
-        use winapi::shared::winerror::*;
-        if hr == DXGI_ERROR_DEVICE_REMOVED {
-            let dev_removed_reason = (*device).GetDeviceRemovedReason();
-            match dev_removed_reason {
-                DXGI_ERROR_DEVICE_HUNG => write_log_file(&format!("device hung")),
-                DXGI_ERROR_DEVICE_REMOVED => write_log_file(&format!("device removed")),
-                DXGI_ERROR_DEVICE_RESET => write_log_file(&format!("device reset")),
-                DXGI_ERROR_DRIVER_INTERNAL_ERROR => write_log_file(&format!("driver internal error")),
-                DXGI_ERROR_INVALID_CALL => write_log_file(&format!("invalid call")),
-                _ => write_log_file(&format!("unknown device removed reason")),
+    // create a vb for each stream
+    for ((slot,stride),data) in vertex_slots.iter().zip(streams.iter()) {
+        match create_vertex_buffer(device, data, &nmd.name) {
+            Some(vb) => d3d_data.vbs.push((*slot, vb, *stride)),
+            None => {
+                // release the ones that were created, the layout is kept for the next attempt
+                for (_slot, vb, _stride) in d3d_data.vbs.drain(..) {
+                    (*vb).Release();
+                }
+                return false;
             }
         }
-        // check for E_OUTOFMEMORY
-        else if hr as i64 == 0x8007000e {
-            write_log_file(&format!("out of memory"));
-        }
-
-        return false;
     }
-
-    d3d_data.vb = vertex_buffer;
-    d3d_data.vert_size = vert_size as u32;
+    if vertex_slots.len() > 1 {
+        write_log_file(&format!("mod {} uses {} vertex streams (slot, vertex size: {:?})",
+            nmd.name, vertex_slots.len(), vertex_slots));
+    }
     d3d_data.vert_count = vert_count as u32;
 
     // load textures, if any
@@ -0,0 +1,81 @@
+//! Prints a mod usage report from mod stats logs.
+//!
+//! Usage: `mod_usage_report [--csv] [--index ModIndex.yaml]... [--exe name]... <log file or dir>...`
+//!
+//! Directories are scanned for `modstats.*` logs (usually the modelmod Logs dir).  `--index` can
+//! be given once per mod index to report mods that were never used.  `--exe` limits the report
+//! to the logs of the named exes (the base name, without `.exe`).
+use mod_stats::usage_report;
+
+fn usage() -> ! {
+    eprintln!("usage: mod_usage_report [--csv] [--index ModIndex.yaml]... [--exe name]... <log file or dir>...");
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut csv = false;
+    let mut index_files = vec![];
+    let mut exe_filter: Vec<String> = vec![];
+    let mut inputs = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--csv" => csv = true,
+            "--index" => index_files.push(args.next().unwrap_or_else(|| usage())),
+            "--exe" => exe_filter.push(args.next().unwrap_or_else(|| usage()).to_lowercase()),
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => usage(),
+            _ => inputs.push(arg),
+        }
+    }
+    if inputs.is_empty() {
+        usage();
+    }
+
+    let mut logs = vec![];
+    for input in inputs.iter() {
+        let path = std::path::Path::new(input);
+        if path.is_dir() {
+            match usage_report::find_logs(path) {
+                Ok(found) => logs.extend(found),
+                Err(e) => eprintln!("error: {}", e),
+            }
+        } else {
+            let exe = usage_report::exe_from_log_name(path).unwrap_or_else(|| {
+                path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+            });
+            logs.push((exe, input.clone()));
+        }
+    }
+    if !exe_filter.is_empty() {
+        logs.retain(|(exe, _)| exe_filter.contains(&exe.to_lowercase()));
+    }
+
+    let mut sessions = vec![];
+    for (exe, path) in logs {
+        match usage_report::read_sessions(&path) {
+            Ok((s, bad)) => {
+                if bad > 0 {
+                    eprintln!("warning: {}: skipped {} unreadable lines", path, bad);
+                }
+                sessions.push((exe, s));
+            }
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    let mut index_mods = vec![];
+    for file in index_files.iter() {
+        match usage_report::read_mod_index(file) {
+            Ok(names) => index_mods.extend(names),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    let report = usage_report::build_report(&sessions, &index_mods);
+    if csv {
+        print!("{}", usage_report::format_csv(&report));
+    } else {
+        print!("{}", usage_report::format_text(&report));
+    }
+}
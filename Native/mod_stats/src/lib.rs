@@ -1 +1,4 @@
 pub mod mod_stats;
+pub mod stats_jsonl;
+pub mod usage_report;
+pub mod stats_config;
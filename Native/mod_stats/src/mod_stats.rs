@@ -11,19 +11,31 @@
 //! whole file and writing it out again every time.
 //!
 //! A mod that was active but stops rendering will create a new entry in the log if it doesn't
-//! render for 4 minutes but then comes back.
+//! render for 4 minutes (by default) but then comes back.
+//!
+//! Alternatively the stats can be written as JSON Lines events, see `stats_jsonl`.  This is
+//! selected with the `ModStatsFormat` root registry value (0 = text, the default; 1 = jsonl).
+//! `ModStatsMaxKB` and `ModStatsMaxFiles` control rotation of the jsonl file.
+//!
+//! The idle time, update interval and minimum active time can be changed (and stats disabled,
+//! globally or for specific games) in the native config file, see `stats_config`.  The config
+//! is read on the first update.
 use std::cell::{RefCell};
 use std::io::{Seek, Read, SeekFrom};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread::JoinHandle;
-use std::time::{SystemTime, Duration};
+use std::time::{SystemTime, Instant, Duration};
 use std::collections::{HashMap, HashSet};
 
 use global_state::GLOBAL_STATE;
 use shared_dx::util::write_log_file;
+use shared_dx::clock;
 
 use util::mm_verify_load;
 
+use crate::stats_config::{ModStatsConfig, DEF_IDLE_SECS, DEF_UPD_INTERVAL_SECS, DEF_MIN_ACTIVE_TIME_SECS};
+use crate::stats_jsonl::{JsonlLog, StatsEvent, to_epoch_secs};
+
 struct LogThread {
     pub sender: Sender<ThreadCommand>,
     pub receiver: Receiver<ThreadReply>,
@@ -31,15 +43,19 @@ struct LogThread {
 }
 
 struct ModStats {
-    pub last_render_update: SystemTime,
-    pub last_rendered: HashMap<String, (SystemTime, SystemTime, Duration)>, // start, last update, total time
+    pub last_render_update: Instant,
+    /// Session start (wall clock, for reporting), last update, total time
+    pub last_rendered: HashMap<String, (SystemTime, Instant, Duration)>,
+    /// Mods whose last session has been reported as stopped
+    pub stopped: HashSet<String>,
     pub log_thread: Option<LogThread>,
 }
 impl ModStats {
     pub fn new() -> ModStats {
         ModStats {
-            last_render_update: SystemTime::now(),
+            last_render_update: clock::now(),
             last_rendered: HashMap::new(),
+            stopped: HashSet::new(),
             log_thread: None,
         }
     }
@@ -47,6 +63,17 @@ impl ModStats {
 enum ModMsg {
     NewModActive(String, SystemTime),
     ModActive(String, SystemTime, Duration),
+    /// Mod has been idle long enough that its session (which started at the time) is over
+    ModStopped(String, SystemTime, Duration),
+}
+
+/// Where the stats are written.
+#[derive(Clone, Debug, PartialEq)]
+enum StatsFormat {
+    /// Human readable report, updated in place
+    Text,
+    /// Append-only events, rotated by size
+    Jsonl { max_bytes: u64, max_files: u32 },
 }
 enum ThreadCommand {
     Stop,
@@ -59,9 +86,6 @@ enum ThreadReply {
 }
 
 const DEF_FILE_NAME: &str = "mod_stats.txt";
-const DEF_IDLE_SECS: u64 = 240;
-const DEF_UPD_INTERVAL_SECS: u64 = 5;
-const DEF_MIN_ACTIVE_TIME_SECS: u64 = 60;
 
 thread_local! {
     static MOD_STATS: RefCell<ModStats>  = RefCell::new(ModStats::new());
@@ -69,14 +93,17 @@ thread_local! {
     static IDLE_NEW: RefCell<Duration> = RefCell::new(Duration::from_secs(DEF_IDLE_SECS));
     static MOD_STAT_FILE: RefCell<String> = RefCell::new(DEF_FILE_NAME.to_string());
     static MIN_ACTIVE_TIME: RefCell<Duration> = RefCell::new(Duration::from_secs(DEF_MIN_ACTIVE_TIME_SECS));
+    static STATS_FORMAT: RefCell<StatsFormat> = RefCell::new(StatsFormat::Text);
+    static ENABLED: RefCell<bool> = RefCell::new(true);
 }
 
 #[allow(dead_code)]
 fn reset() {
     MOD_STATS.with(|s| {
         let mut s = s.borrow_mut();
-        s.last_render_update = SystemTime::now();
+        s.last_render_update = clock::now();
         s.last_rendered.clear();
+        s.stopped.clear();
         s.log_thread.as_mut().map(|lt| {
             if !lt.thread.is_finished() {
                 let _ = lt.sender.send(ThreadCommand::Stop);
@@ -93,10 +120,20 @@ fn reset() {
         *s = Duration::from_secs(DEF_IDLE_SECS);
     });
     set_filename(DEF_FILE_NAME);
+    set_format(StatsFormat::Text);
     MIN_ACTIVE_TIME.with(|s| {
         let mut s = s.borrow_mut();
         *s = Duration::from_secs(DEF_MIN_ACTIVE_TIME_SECS);
     });
+    ENABLED.with(|e| *e.borrow_mut() = true);
+}
+
+/// Apply the config to the thread locals.  The config should already be validated.
+fn apply_config(conf:&ModStatsConfig, exe_base:&str) {
+    UPD_INTERVAL.with(|ui| *ui.borrow_mut() = conf.update_interval());
+    IDLE_NEW.with(|inew| *inew.borrow_mut() = conf.idle());
+    MIN_ACTIVE_TIME.with(|min| *min.borrow_mut() = conf.min_active());
+    ENABLED.with(|e| *e.borrow_mut() = conf.enabled_for(exe_base));
 }
 
 fn set_filename(filepath:&str) {
@@ -106,6 +143,49 @@ fn set_filename(filepath:&str) {
     });
 }
 
+fn set_format(format:StatsFormat) {
+    STATS_FORMAT.with(|f| {
+        *f.borrow_mut() = format;
+    });
+}
+
+/// Read the format from the registry.
+fn query_format() -> StatsFormat {
+    let reg = |key| unsafe { util::reg_query_root_dword(key) };
+    match reg("ModStatsFormat") {
+        Ok(1) => StatsFormat::Jsonl {
+            max_bytes: reg("ModStatsMaxKB").map(|kb| kb as u64 * 1024).unwrap_or(crate::stats_jsonl::DEF_MAX_BYTES),
+            max_files: reg("ModStatsMaxFiles").unwrap_or(crate::stats_jsonl::DEF_MAX_FILES),
+        },
+        _ => StatsFormat::Text,
+    }
+}
+
+fn format_active_time(time:&Duration) -> String {
+    if time.as_secs() >= 60 {
+        format!("{}m {}s", time.as_secs() / 60, time.as_secs() % 60)
+    } else {
+        format!("{} secs", time.as_secs())
+    }
+}
+
+/// Format a line of the text report.
+pub fn format_active_line(start_time:&SystemTime, name:&str, time:&Duration) -> String {
+    format!("{}: '{}' {}", util::format_time(start_time), name, format_active_time(time))
+}
+
+/// Convert mod messages to jsonl events.
+fn msgs_to_events(msgs:&[ModMsg], now:&SystemTime) -> Vec<StatsEvent> {
+    let time = to_epoch_secs(now);
+    msgs.iter().map(|msg| match msg {
+        ModMsg::NewModActive(name, _start) => StatsEvent::Start { time, name: name.clone() },
+        ModMsg::ModActive(name, start, dur) => StatsEvent::Heartbeat {
+            time, name: name.clone(), start: to_epoch_secs(start), secs: dur.as_secs() },
+        ModMsg::ModStopped(name, start, dur) => StatsEvent::Stop {
+            time, name: name.clone(), start: to_epoch_secs(start), secs: dur.as_secs() },
+    }).collect()
+}
+
 /// Process mod messages and update the mod stats file.
 /// Updates existing lines, if found, for each mod in the list, provided the lines are within the
 /// final 10K bytes of the file.  If not, new lines will be added.
@@ -176,12 +256,7 @@ fn process_mod_msgs(msgs:&[ModMsg], filename:&str) -> Result<(), String> {
 
     // make helper function to get an updated line for a mod
     let get_updated_line = |pat:&String, time:&Duration| {
-        let timestr = if time.as_secs() >= 60 {
-            format!("{}m {}s", time.as_secs() / 60, time.as_secs() % 60)
-        } else {
-            format!("{} secs", time.as_secs())
-        };
-        format!("{} {}", pat, timestr)
+        format!("{} {}", pat, format_active_time(time))
     };
 
     // reopen file for update (this assumes it hasn't changed and I am the only one writing it)
@@ -253,10 +328,14 @@ fn process_mod_msgs(msgs:&[ModMsg], filename:&str) -> Result<(), String> {
     Ok(())
 }
 
-fn start_log_thread(filename:&str) -> LogThread {
+fn start_log_thread(filename:&str, format:StatsFormat) -> LogThread {
     let (main_sender, thrd_receiver) = channel::<ThreadCommand>();
     let (thrd_sender, main_receiver) = channel::<ThreadReply>();
     let t_filename = filename.to_string();
+    let jsonl = match format {
+        StatsFormat::Text => None,
+        StatsFormat::Jsonl { max_bytes, max_files } => Some(JsonlLog::new(filename, max_bytes, max_files)),
+    };
 
     let logger = std::thread::spawn(move || {
         let mut err: Result<(), String> = Ok(());
@@ -276,7 +355,10 @@ fn start_log_thread(filename:&str) -> LogThread {
                     },
                     ThreadCommand::UpdateDone => {
                         //eprintln!("processing {} msgs", msgs.len());
-                        let res = process_mod_msgs(&msgs, &t_filename);
+                        let res = match jsonl {
+                            None => process_mod_msgs(&msgs, &t_filename),
+                            Some(ref log) => log.append(&msgs_to_events(&msgs, &SystemTime::now())),
+                        };
                         msgs.clear();
                         if res.is_err() {
                             thrd_sender.send(ThreadReply::Error(res)).unwrap_or(());
@@ -303,13 +385,16 @@ fn start_log_thread(filename:&str) -> LogThread {
 
 }
 
-pub fn update(now:&SystemTime) -> Option<(u32,u32)> {
+pub fn update(now:&Instant) -> Option<(u32,u32)> {
+    if !ENABLED.with(|e| *e.borrow()) {
+        return None;
+    }
     MOD_STATS.with(|ms_rc| {
         let mut ms = ms_rc.borrow_mut();
         let mut elapsed = None;
         if !UPD_INTERVAL.with(|ui| {
             let ui = ui.borrow_mut();
-            let mut delapsed = now.duration_since(ms.last_render_update).unwrap_or_else(|_x| Duration::from_secs(0));
+            let mut delapsed = now.saturating_duration_since(ms.last_render_update);
             if delapsed < *ui {
                 return false;
             }
@@ -325,11 +410,11 @@ pub fn update(now:&SystemTime) -> Option<(u32,u32)> {
         }
         ms.last_render_update = *now;
 
-        MOD_STAT_FILE.with(|f| {
+        let enabled = MOD_STAT_FILE.with(|f| {
             {
                 let f = f.borrow();
                 if *f != DEF_FILE_NAME {
-                    return;
+                    return true;
                 }
             }
 
@@ -338,24 +423,42 @@ pub fn update(now:&SystemTime) -> Option<(u32,u32)> {
                 Some(dir) => dir,
                 None => {
                     write_log_file("mod_stats: no mm root found");
-                    return;
+                    return true;
                 }
             };
             let basen = util::get_module_name_base().unwrap_or("".to_owned());
             if basen.is_empty() {
-                return;
+                return true;
             }
+            let conf = ModStatsConfig::load(&mm_root);
+            write_log_file(&format!("mod stats: {}", conf));
+            apply_config(&conf, &basen);
+            if !conf.enabled_for(&basen) {
+                write_log_file(&format!("mod stats: disabled for {}", basen));
+                return false;
+            }
+            let format = query_format();
             let mut ldir = mm_root.to_owned();
             ldir.push_str("\\Logs\\");
-            let file_name = format!("modstats.{}.log", basen);
+            let file_name = match format {
+                StatsFormat::Text => format!("modstats.{}.log", basen),
+                StatsFormat::Jsonl { .. } => format!("modstats.{}.jsonl", basen),
+            };
             ldir.push_str(&file_name);
+            write_log_file(&format!("mod stats: writing {:?} to {}", format, ldir));
+            set_format(format);
             let mut f = f.borrow_mut();
             (*f) = ldir;
+            true
         });
+        if !enabled {
+            return None;
+        }
 
         if ms.log_thread.is_none() {
             let filename = MOD_STAT_FILE.with(|f| f.borrow().to_owned());
-            ms.log_thread = Some(start_log_thread(&filename));
+            let format = STATS_FORMAT.with(|f| f.borrow().clone());
+            ms.log_thread = Some(start_log_thread(&filename, format));
         }
 
         let elapsed = elapsed.unwrap_or_else(|| Duration::from_secs(0));
@@ -395,19 +498,22 @@ pub fn update(now:&SystemTime) -> Option<(u32,u32)> {
                         let modmsg;
                         match ms.last_rendered.get_mut(&nmod.name) {
                             None => {
-                                ms.last_rendered.insert(nmod.name.clone(), (*now, *now, Duration::from_secs(0)));
+                                let start = clock::wall_now();
+                                ms.last_rendered.insert(nmod.name.clone(), (start, *now, Duration::from_secs(0)));
+                                ms.stopped.remove(&nmod.name);
                                 new_active += 1;
                                 total_active += 1;
-                                modmsg = Some(ModMsg::NewModActive(nmod.name.clone(), *now));
+                                modmsg = Some(ModMsg::NewModActive(nmod.name.clone(), start));
                             },
                             // if it was idle for more than IDLE_NEW time, treat it as new
                             Some((_start, upd,  _dur))
-                                if now.duration_since(*upd)
-                                    .unwrap_or_else(|_| Duration::from_secs(0)) > idle_new => {
-                                ms.last_rendered.insert(nmod.name.clone(), (*now, *now, Duration::from_secs(0)));
+                                if now.saturating_duration_since(*upd) > idle_new => {
+                                let start = clock::wall_now();
+                                ms.last_rendered.insert(nmod.name.clone(), (start, *now, Duration::from_secs(0)));
+                                ms.stopped.remove(&nmod.name);
                                 new_active += 1;
                                 total_active += 1;
-                                modmsg = Some(ModMsg::NewModActive(nmod.name.clone(), *now));
+                                modmsg = Some(ModMsg::NewModActive(nmod.name.clone(), start));
                             },
                             Some((start_time, upd, ref mut dur)) => {
                                 total_active += 1;
@@ -430,7 +536,25 @@ pub fn update(now:&SystemTime) -> Option<(u32,u32)> {
                 }
             });
 
-        if total_active > 0 {
+        // sessions that have been idle long enough to be over are stopped.  like active
+        // messages, this is only reported for sessions that lasted at least the min active time.
+        let idle_new = IDLE_NEW.with(|inew| *inew.borrow());
+        let min_active = MIN_ACTIVE_TIME.with(|min| *min.borrow());
+        let stopped = ms.last_rendered.iter()
+            .filter(|(name, (_start, upd, dur))| {
+                *dur > min_active
+                && now.saturating_duration_since(*upd) > idle_new
+                && !ms.stopped.contains(*name)
+            })
+            .map(|(name, (start, _upd, dur))| (name.clone(), *start, *dur))
+            .collect::<Vec<_>>();
+        let num_stopped = stopped.len();
+        for (name, start, dur) in stopped {
+            send_thread_cmd(&ms.log_thread, ThreadCommand::ModMsg(ModMsg::ModStopped(name.clone(), start, dur)));
+            ms.stopped.insert(name);
+        }
+
+        if total_active > 0 || num_stopped > 0 {
             send_thread_cmd(&ms.log_thread, ThreadCommand::UpdateDone);
         }
 
@@ -467,6 +591,7 @@ mod tests {
     use fnv::FnvHashMap;
     use global_state::LoadedModState;
     use shared_dx::util::LOG_EXCL_LOCK;
+    use shared_dx::clock::FakeClock;
     use types::{native_mod::{self, NativeModData, ModD3DState, ModD3DData, MAX_RECENT_RENDER_USAGE_THRESH}, interop::ModData, d3ddata::ModD3DData11};
 
     use super::*;
@@ -683,18 +808,38 @@ mod tests {
         //super::reset();
     }
 
+    #[test]
+    fn test_disabled() {
+        let clock = FakeClock::new();
+        let _clock = clock.install();
+        set_filename("__test_mod_stats_disabled.txt");
+        set_update_interval_ms(0);
+        assert_eq!(update(&clock::now()), Some((0,0)));
+        let conf = ModStatsConfig { disabled_games: vec!["FooGame".to_owned()], ..Default::default() };
+        apply_config(&conf, "BarGame");
+        assert_eq!(UPD_INTERVAL.with(|ui| *ui.borrow()), Duration::from_secs(DEF_UPD_INTERVAL_SECS));
+        set_update_interval_ms(0);
+        assert_eq!(update(&clock::now()), Some((0,0)));
+        apply_config(&conf, "foogame");
+        set_update_interval_ms(0);
+        assert_eq!(update(&clock::now()), None);
+        super::reset();
+    }
+
     #[test]
     fn test_mod_stats_update() {
         let _loglock = LOG_EXCL_LOCK.lock().unwrap();
         let _testlog = prep_log_file(&_loglock, "__test_mod_stats_update.txt").expect("doh");
         write_log_file("test starting");
+        let clock = FakeClock::new();
+        let _clock = clock.install();
         set_filename("__test_mod_stats.txt");
         set_update_interval_ms(0);
-        assert_eq!(update(&SystemTime::now()), Some((0,0)));
+        assert_eq!(update(&clock::now()), Some((0,0)));
         set_update_interval_ms(5);
-        assert_eq!(update(&SystemTime::now()), None);
-        std::thread::sleep(Duration::from_millis(6));
-        assert_eq!(update(&SystemTime::now()), Some((0,0)));
+        assert_eq!(update(&clock::now()), None);
+        clock.advance(Duration::from_millis(6));
+        assert_eq!(update(&clock::now()), Some((0,0)));
         set_update_interval_ms(0);
 
         let mod_count = 5;
@@ -753,27 +898,27 @@ mod tests {
         };
         unsafe { GLOBAL_STATE.loaded_mods = Some(lms); };
         set_update_interval_ms(0);
-        assert_eq!(update(&SystemTime::now()), Some((0,0)));
+        assert_eq!(update(&clock::now()), Some((0,0)));
         set_mod_rendered("mod_100_200_2", 1);
-        assert_eq!(update(&SystemTime::now()), Some((1,1)));
-        std::thread::sleep(Duration::from_millis(2));
-        assert_eq!(update(&SystemTime::now()), Some((0,1)));
-        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(update(&clock::now()), Some((1,1)));
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(update(&clock::now()), Some((0,1)));
+        clock.advance(Duration::from_millis(2));
         advance_frames(MAX_RECENT_RENDER_USAGE_THRESH+1);
-        assert_eq!(update(&SystemTime::now()), Some((0,0)));
+        assert_eq!(update(&clock::now()), Some((0,0)));
         set_mod_rendered("mod_100_200_2", 1);
-        assert_eq!(update(&SystemTime::now()), Some((0,1)));
+        assert_eq!(update(&clock::now()), Some((0,1)));
         set_idle_new_ms(5);
-        std::thread::sleep(Duration::from_millis(6));
-        assert_eq!(update(&SystemTime::now()), Some((1,1)));
+        clock.advance(Duration::from_millis(6));
+        assert_eq!(update(&clock::now()), Some((1,1)));
         set_mod_rendered("mod_100_200_1", 0);
-        assert_eq!(update(&SystemTime::now()), Some((1,2)));
+        assert_eq!(update(&clock::now()), Some((1,2)));
 
         // now test that the log works
         set_mod_rendered("mod_50_150", 0);
-        assert_eq!(update(&SystemTime::now()), Some((1,3)));
-        assert_eq!(update(&SystemTime::now()), Some((0,3)));
-        assert_eq!(update(&SystemTime::now()), Some((0,3)));
+        assert_eq!(update(&clock::now()), Some((1,3)));
+        assert_eq!(update(&clock::now()), Some((0,3)));
+        assert_eq!(update(&clock::now()), Some((0,3)));
 
         std::thread::sleep(Duration::from_secs(1));
 
@@ -0,0 +1,170 @@
+//! Mod stats settings, read from the `mod_stats` section of `nativeconfig.yaml` in the modelmod
+//! root dir.  Other sections of that file are ignored here.  Example:
+//!
+//! ```yaml
+//! mod_stats:
+//!   enabled: true
+//!   idle_secs: 240
+//!   update_interval_secs: 5
+//!   min_active_secs: 60
+//!   disabled_games: ["SomeGame"]
+//! ```
+//!
+//! All settings are optional.  `disabled_games` lists exe base names (no `.exe`, case insensitive)
+//! for which no stats are collected at all.  If the file is missing the defaults are used; if
+//! the section has invalid values, the problems are logged and the defaults are used instead.
+use std::fmt;
+use std::time::Duration;
+
+use serde::Deserialize;
+use shared_dx::error::{HookError, Result};
+use shared_dx::util::write_log_file;
+
+pub const DEF_IDLE_SECS: u64 = 240;
+pub const DEF_UPD_INTERVAL_SECS: u64 = 5;
+pub const DEF_MIN_ACTIVE_TIME_SECS: u64 = 60;
+pub const MAX_UPD_INTERVAL_SECS: u64 = 3600;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ModStatsConfig {
+    pub enabled: bool,
+    /// A mod that doesn't render for this long starts a new session when it comes back
+    pub idle_secs: u64,
+    /// How often the stats are updated
+    pub update_interval_secs: u64,
+    /// Sessions shorter than this aren't reported
+    pub min_active_secs: u64,
+    /// Exe base names for which stats are disabled
+    pub disabled_games: Vec<String>,
+}
+
+impl Default for ModStatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_secs: DEF_IDLE_SECS,
+            update_interval_secs: DEF_UPD_INTERVAL_SECS,
+            min_active_secs: DEF_MIN_ACTIVE_TIME_SECS,
+            disabled_games: vec![],
+        }
+    }
+}
+
+impl fmt::Display for ModStatsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ModStatsConfig {{ enabled: {}, idle: {}s, update interval: {}s, min active: {}s, disabled games: {:?} }}",
+            self.enabled, self.idle_secs, self.update_interval_secs, self.min_active_secs, self.disabled_games)
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct NativeConfig {
+    mod_stats: Option<ModStatsConfig>,
+}
+
+impl ModStatsConfig {
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs)
+    }
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_secs(self.update_interval_secs)
+    }
+    pub fn min_active(&self) -> Duration {
+        Duration::from_secs(self.min_active_secs)
+    }
+
+    /// True if stats should be collected for the exe (base name).
+    pub fn enabled_for(&self, exe_base: &str) -> bool {
+        self.enabled && !self.disabled_games.iter().any(|g| g.eq_ignore_ascii_case(exe_base))
+    }
+
+    /// Returns a description of each invalid setting; empty if all are valid.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.update_interval_secs == 0 || self.update_interval_secs > MAX_UPD_INTERVAL_SECS {
+            problems.push(format!("update_interval_secs must be between 1 and {}, got {}",
+                MAX_UPD_INTERVAL_SECS, self.update_interval_secs));
+        }
+        // otherwise every update would look like a new session
+        if self.idle_secs <= self.update_interval_secs {
+            problems.push(format!("idle_secs ({}) must be greater than update_interval_secs ({})",
+                self.idle_secs, self.update_interval_secs));
+        }
+        if self.disabled_games.iter().any(|g| g.trim().is_empty()) {
+            problems.push("disabled_games contains an empty name".to_owned());
+        }
+        problems
+    }
+
+    /// Parse the mod stats section from the config file text.  Returns the defaults if there
+    /// is no section.  Invalid values are an error.
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        if text.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let conf: Option<NativeConfig> = serde_yaml::from_str(text)
+            .map_err(|e| HookError::SerdeError(format!("deserialize error: {}", e)))?;
+        let conf = conf.and_then(|c| c.mod_stats).unwrap_or_default();
+        let problems = conf.validate();
+        if !problems.is_empty() {
+            return Err(HookError::ConfReadFailed(format!("invalid mod_stats config: {}", problems.join("; "))));
+        }
+        Ok(conf)
+    }
+
+    /// Load from `nativeconfig.yaml` in the root dir, falling back to the defaults (with a log
+    /// message) if it is missing or invalid.
+    pub fn load(rootdir: &str) -> Self {
+        let path = format!("{}\\nativeconfig.yaml", rootdir);
+        if !std::path::Path::new(&path).is_file() {
+            write_log_file(&format!("mod stats: no native config file {}, using defaults", path));
+            return Self::default();
+        }
+        let res = std::fs::read_to_string(&path)
+            .map_err(HookError::from)
+            .and_then(|text| Self::from_yaml(&text));
+        match res {
+            Ok(conf) => conf,
+            Err(e) => {
+                write_log_file(&format!("mod stats: failed to load {}, using defaults: {:?}", path, e));
+                Self::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_yaml() {
+        assert_eq!(ModStatsConfig::from_yaml("").expect("doh"), ModStatsConfig::default());
+        assert_eq!(ModStatsConfig::from_yaml("snap: {}\n").expect("doh"), ModStatsConfig::default());
+
+        let conf = ModStatsConfig::from_yaml(
+            "mod_stats:\n  idle_secs: 600\n  min_active_secs: 0\n  disabled_games: [\"FooGame\"]\n").expect("doh");
+        assert_eq!(conf.idle(), Duration::from_secs(600));
+        assert_eq!(conf.update_interval(), Duration::from_secs(DEF_UPD_INTERVAL_SECS));
+        assert_eq!(conf.min_active(), Duration::from_secs(0));
+        assert!(conf.enabled_for("BarGame"));
+        assert!(!conf.enabled_for("foogame"));
+
+        let conf = ModStatsConfig::from_yaml("mod_stats:\n  enabled: false\n").expect("doh");
+        assert!(!conf.enabled_for("BarGame"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ModStatsConfig::default().validate().is_empty());
+        let conf = ModStatsConfig { update_interval_secs: 0, ..Default::default() };
+        assert_eq!(conf.validate().len(), 1);
+        let conf = ModStatsConfig { update_interval_secs: 10, idle_secs: 10, ..Default::default() };
+        assert_eq!(conf.validate().len(), 1);
+        let conf = ModStatsConfig { disabled_games: vec![" ".to_owned()], ..Default::default() };
+        assert_eq!(conf.validate().len(), 1);
+        assert!(ModStatsConfig::from_yaml("mod_stats:\n  update_interval_secs: 5000\n").is_err());
+        assert!(ModStatsConfig::from_yaml("mod_stats:\n  idle_secs: -1\n").is_err());
+    }
+}
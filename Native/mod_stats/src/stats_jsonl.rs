@@ -0,0 +1,274 @@
+//! JSON Lines backend for mod stats.
+//!
+//! Instead of patching the text report in place, this appends one event per line to a
+//! `modstats.$ExeBaseName.jsonl` file: when a mod becomes active, periodically while it stays
+//! active (a heartbeat with the total active time so far), and when it stops.  Appending is
+//! cheap and never rewrites existing data, so a crash can at worst lose the last line.
+//!
+//! When the file would grow past the size limit it is rotated: `file` becomes `file.1`,
+//! `file.1` becomes `file.2` and so on; the oldest file beyond the limit is deleted.
+//!
+//! The human readable report (same format as the text backend) is rebuilt from the events with
+//! `summarize` and `format_report`.  Only events in the files that are still around are counted.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+pub const DEF_MAX_BYTES: u64 = 1024 * 1024;
+pub const DEF_MAX_FILES: u32 = 4;
+
+/// An event in the log.  Times are seconds since the unix epoch.  A session of a mod is
+/// identified by its name and start time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StatsEvent {
+    /// Mod became active (first render, or first render after being idle).
+    Start { time: u64, name: String },
+    /// Mod is still active, `secs` is the active time since `start`.
+    Heartbeat { time: u64, name: String, start: u64, secs: u64 },
+    /// Mod hasn't rendered for long enough that its session is over.
+    Stop { time: u64, name: String, start: u64, secs: u64 },
+}
+
+pub fn to_epoch_secs(time: &SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub fn from_epoch_secs(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// An event log file and its rotated predecessors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonlLog {
+    pub path: String,
+    /// Rotate when the current file would exceed this size
+    pub max_bytes: u64,
+    /// Number of rotated files to keep, in addition to the current one
+    pub max_files: u32,
+}
+
+impl JsonlLog {
+    pub fn new(path: &str, max_bytes: u64, max_files: u32) -> Self {
+        Self { path: path.to_owned(), max_bytes, max_files }
+    }
+
+    /// Path of the nth file, 0 is the current file and higher numbers are older.
+    pub fn rotated_path(&self, n: u32) -> String {
+        if n == 0 {
+            self.path.clone()
+        } else {
+            format!("{}.{}", self.path, n)
+        }
+    }
+
+    fn rotate(&self) -> Result<(), String> {
+        let exists = |p: &str| std::path::Path::new(p).exists();
+        let oldest = self.rotated_path(self.max_files);
+        if exists(&oldest) {
+            std::fs::remove_file(&oldest).map_err(|e| e.to_string())?;
+        }
+        for n in (0..self.max_files).rev() {
+            let from = self.rotated_path(n);
+            if exists(&from) {
+                std::fs::rename(&from, self.rotated_path(n + 1)).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the events, rotating first if they would push the file over the size limit.  If
+    /// the file doesn't end with a newline (a partial line from a crash), one is written first
+    /// so the events don't run into it.
+    pub fn append(&self, events: &[StatsEvent]) -> Result<(), String> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut buf = String::new();
+        for ev in events {
+            buf.push_str(&serde_json::to_string(ev).map_err(|e| e.to_string())?);
+            buf.push('\n');
+        }
+        let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+        if len > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last))
+                .map_err(|e| e.to_string())?;
+            if last[0] != b'\n' {
+                buf.insert(0, '\n');
+            }
+        }
+        file.write_all(buf.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Read the events from all files, oldest first.  Lines that can't be parsed (e.g. a partial
+    /// line from a crash) are skipped; the number skipped is returned with the events.
+    pub fn read_events(&self) -> Result<(Vec<StatsEvent>, usize), String> {
+        let mut events = vec![];
+        let mut bad = 0;
+        for n in (0..=self.max_files).rev() {
+            let path = self.rotated_path(n);
+            if !std::path::Path::new(&path).exists() {
+                continue;
+            }
+            let file = std::fs::File::open(&path).map_err(|e| e.to_string())?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| e.to_string())?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<StatsEvent>(&line) {
+                    Ok(ev) => events.push(ev),
+                    Err(_) => bad += 1,
+                }
+            }
+        }
+        Ok((events, bad))
+    }
+}
+
+/// Total active time of one session of a mod.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModSession {
+    pub name: String,
+    pub start: u64,
+    pub secs: u64,
+}
+
+/// Rebuild the sessions from the events, in the order they started.  Sessions that never got a
+/// heartbeat or stop (i.e. were active for less than the minimum time) are left out, like they
+/// are in the text report.
+pub fn summarize(events: &[StatsEvent]) -> Vec<ModSession> {
+    let mut sessions: Vec<ModSession> = vec![];
+    let mut index: HashMap<(u64, &str), usize> = HashMap::new();
+    for ev in events {
+        match ev {
+            StatsEvent::Start { .. } => {}
+            StatsEvent::Heartbeat { name, start, secs, .. } | StatsEvent::Stop { name, start, secs, .. } => {
+                match index.get(&(*start, name.as_str())) {
+                    Some(idx) => {
+                        let s = &mut sessions[*idx];
+                        s.secs = s.secs.max(*secs);
+                    }
+                    None => {
+                        index.insert((*start, name.as_str()), sessions.len());
+                        sessions.push(ModSession { name: name.clone(), start: *start, secs: *secs });
+                    }
+                }
+            }
+        }
+    }
+    sessions
+}
+
+/// Format sessions the same way the text backend writes them.
+pub fn format_report(sessions: &[ModSession]) -> String {
+    let mut out = String::new();
+    for s in sessions {
+        out.push_str(&crate::mod_stats::format_active_line(
+            &from_epoch_secs(s.start), &s.name, &Duration::from_secs(s.secs)));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove_all(log: &JsonlLog) {
+        for n in 0..=log.max_files + 1 {
+            let p = log.rotated_path(n);
+            if std::path::Path::new(&p).exists() {
+                std::fs::remove_file(&p).expect("doh");
+            }
+        }
+    }
+
+    fn heartbeat(name: &str, start: u64, secs: u64) -> StatsEvent {
+        StatsEvent::Heartbeat { time: start + secs, name: name.to_owned(), start, secs }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let events = vec![
+            StatsEvent::Start { time: 100, name: "foo".to_owned() },
+            StatsEvent::Start { time: 105, name: "bar".to_owned() },
+            heartbeat("foo", 100, 65),
+            heartbeat("bar", 105, 61),
+            heartbeat("foo", 100, 70),
+            StatsEvent::Stop { time: 400, name: "foo".to_owned(), start: 100, secs: 75 },
+            // too short to have a heartbeat, doesn't show up
+            StatsEvent::Start { time: 500, name: "cat".to_owned() },
+            // foo again, a new session
+            StatsEvent::Start { time: 600, name: "foo".to_owned() },
+            heartbeat("foo", 600, 125),
+        ];
+        let sessions = summarize(&events);
+        assert_eq!(sessions, vec![
+            ModSession { name: "foo".to_owned(), start: 100, secs: 75 },
+            ModSession { name: "bar".to_owned(), start: 105, secs: 61 },
+            ModSession { name: "foo".to_owned(), start: 600, secs: 125 },
+        ]);
+        let report = format_report(&sessions);
+        let fmt = |start| util::format_time(&from_epoch_secs(start));
+        assert_eq!(report.lines().collect::<Vec<_>>(), vec![
+            format!("{}: 'foo' 1m 15s", fmt(100)),
+            format!("{}: 'bar' 1m 1s", fmt(105)),
+            format!("{}: 'foo' 2m 5s", fmt(600)),
+        ]);
+    }
+
+    #[test]
+    fn test_append_rotate_read() {
+        let log = JsonlLog::new("__test_stats_jsonl.jsonl", 300, 2);
+        remove_all(&log);
+
+        // each heartbeat line is about 80 bytes, so the file rotates every 3 or so
+        for secs in 0..20 {
+            log.append(&[heartbeat("foo", 1000, secs)]).expect("doh");
+            assert!(std::fs::metadata(&log.path).expect("doh").len() <= log.max_bytes);
+        }
+        assert!(std::path::Path::new(&log.rotated_path(1)).exists());
+        assert!(std::path::Path::new(&log.rotated_path(2)).exists());
+        assert!(!std::path::Path::new(&log.rotated_path(3)).exists());
+
+        // older events were rotated away, but the remaining ones are in order
+        let (events, bad) = log.read_events().expect("doh");
+        assert_eq!(bad, 0);
+        assert!(events.len() < 20);
+        assert_eq!(events.last(), Some(&heartbeat("foo", 1000, 19)));
+        let secs = events.iter().map(|ev| match ev {
+            StatsEvent::Heartbeat { secs, .. } => *secs,
+            _ => panic!("unexpected event: {:?}", ev),
+        }).collect::<Vec<_>>();
+        assert!(secs.windows(2).all(|w| w[0] + 1 == w[1]), "out of order: {:?}", secs);
+        assert_eq!(summarize(&events), vec![ModSession { name: "foo".to_owned(), start: 1000, secs: 19 }]);
+
+        // partial lines are skipped, and don't take the next event with them
+        std::fs::OpenOptions::new().append(true).open(&log.path).expect("doh")
+            .write_all(b"{\"event\":\"heartb").expect("doh");
+        let (_events, bad) = log.read_events().expect("doh");
+        assert_eq!(bad, 1);
+        log.append(&[heartbeat("foo", 1000, 20)]).expect("doh");
+        let (events, bad) = log.read_events().expect("doh");
+        assert_eq!(bad, 1);
+        assert_eq!(events.last(), Some(&heartbeat("foo", 1000, 20)));
+
+        remove_all(&log);
+    }
+}
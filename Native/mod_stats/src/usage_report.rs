@@ -0,0 +1,278 @@
+//! Mod usage report, built from the mod stats logs of one or more exes.
+//!
+//! Both stats formats are supported: the text report (`modstats.$Exe.log`) and the jsonl events
+//! (`modstats.$Exe.jsonl`, plus its rotated files).  Sessions from all logs are combined per mod
+//! (names are case insensitive) into the total active time, session count, and the first and last
+//! time the mod was seen.  If mod index files (`ModIndex.yaml`) are given, mods that are listed
+//! there but never showed up in the logs are reported as never used.
+//!
+//! Used by the `mod_usage_report` command.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+
+use crate::stats_jsonl::{self, JsonlLog, ModSession, from_epoch_secs};
+
+/// Usage of one mod over all the logs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModUsage {
+    pub name: String,
+    pub total_secs: u64,
+    pub sessions: u32,
+    /// Start of the first session, seconds since the unix epoch
+    pub first_seen: u64,
+    /// End of the last session, seconds since the unix epoch
+    pub last_seen: u64,
+    /// Exes the mod was used in
+    pub exes: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    /// Exes whose logs were read
+    pub exes: BTreeSet<String>,
+    /// Mods that were used, most used first
+    pub mods: Vec<ModUsage>,
+    /// Mods from the index files that were never used, sorted by name
+    pub never_used: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ModIndex {
+    #[serde(default)]
+    mods: Vec<IndexEntry>,
+}
+
+/// Returns the exe base name from a stats log file name (`modstats.$Exe.log` or
+/// `modstats.$Exe.jsonl`), or None if it isn't a stats log.
+pub fn exe_from_log_name(path: &Path) -> Option<String> {
+    let fname = path.file_name()?.to_str()?;
+    let rest = fname.strip_prefix("modstats.")?;
+    let exe = rest.strip_suffix(".jsonl").or_else(|| rest.strip_suffix(".log"))?;
+    if exe.is_empty() { None } else { Some(exe.to_owned()) }
+}
+
+/// Find the stats logs in a directory, as (exe, path).  Rotated jsonl files are not returned
+/// since they are read along with the current file.
+pub fn find_logs(dir: &Path) -> Result<Vec<(String, String)>, String> {
+    let mut logs = vec![];
+    for entry in std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if let Some(exe) = exe_from_log_name(&path) {
+            logs.push((exe, path.to_string_lossy().to_string()));
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+/// Parse one line of the text report.  Returns None if the line isn't in the expected format.
+pub fn parse_text_line(line: &str) -> Option<ModSession> {
+    // "<time>: '<name>' <duration>"
+    let (time, rest) = line.split_once(": '")?;
+    let (name, dur) = rest.rsplit_once("' ")?;
+    let ndt = NaiveDateTime::parse_from_str(time.trim(), "%Y-%m-%d %H:%M:%S").ok()?;
+    let start = Local.from_local_datetime(&ndt).earliest()?.timestamp();
+    let dur = dur.trim();
+    let secs = if let Some(s) = dur.strip_suffix(" secs") {
+        s.trim().parse::<u64>().ok()?
+    } else {
+        let (m, s) = dur.split_once("m ")?;
+        let s = s.strip_suffix('s')?;
+        m.trim().parse::<u64>().ok()? * 60 + s.trim().parse::<u64>().ok()?
+    };
+    Some(ModSession { name: name.to_owned(), start: start.max(0) as u64, secs })
+}
+
+/// Read the sessions from a stats log of either format.  Returns the sessions and the number of
+/// lines that couldn't be parsed.
+pub fn read_sessions(path: &str) -> Result<(Vec<ModSession>, usize), String> {
+    if path.ends_with(".jsonl") {
+        // the rotation limit isn't known here, so read as many rotated files as there are
+        let mut max_files = 0;
+        while Path::new(&format!("{}.{}", path, max_files + 1)).exists() {
+            max_files += 1;
+        }
+        let log = JsonlLog::new(path, 0, max_files);
+        let (events, bad) = log.read_events()?;
+        Ok((stats_jsonl::summarize(&events), bad))
+    } else {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut sessions = vec![];
+        let mut bad = 0;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            match parse_text_line(line) {
+                Some(s) => sessions.push(s),
+                None => bad += 1,
+            }
+        }
+        Ok((sessions, bad))
+    }
+}
+
+/// Read the mod names from a mod index file.
+pub fn read_mod_index(path: &str) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let index: ModIndex = serde_yaml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(index.mods.into_iter().map(|m| m.name).collect())
+}
+
+/// Build the report from the sessions of each exe, as (exe, sessions), and the mods listed in
+/// the index files.
+pub fn build_report(logs: &[(String, Vec<ModSession>)], index_mods: &[String]) -> UsageReport {
+    let mut by_name: BTreeMap<String, ModUsage> = BTreeMap::new();
+    let mut exes = BTreeSet::new();
+    for (exe, sessions) in logs {
+        exes.insert(exe.clone());
+        for s in sessions {
+            let usage = by_name.entry(s.name.to_lowercase()).or_insert_with(|| ModUsage {
+                name: s.name.clone(),
+                total_secs: 0,
+                sessions: 0,
+                first_seen: s.start,
+                last_seen: s.start,
+                exes: BTreeSet::new(),
+            });
+            usage.total_secs += s.secs;
+            usage.sessions += 1;
+            usage.first_seen = usage.first_seen.min(s.start);
+            usage.last_seen = usage.last_seen.max(s.start + s.secs);
+            usage.exes.insert(exe.clone());
+        }
+    }
+    let mut never_used = index_mods.iter()
+        .filter(|name| !by_name.contains_key(&name.to_lowercase()))
+        .cloned()
+        .collect::<Vec<_>>();
+    never_used.sort_by_key(|name| name.to_lowercase());
+    never_used.dedup_by_key(|name| name.to_lowercase());
+    let mut mods = by_name.into_values().collect::<Vec<_>>();
+    mods.sort_by(|a, b| b.total_secs.cmp(&a.total_secs).then_with(|| a.name.cmp(&b.name)));
+    UsageReport { exes, mods, never_used }
+}
+
+fn format_secs(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h {}m {}s", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn format_epoch(secs: u64) -> String {
+    util::format_time(&from_epoch_secs(secs))
+}
+
+fn join_exes(exes: &BTreeSet<String>) -> String {
+    exes.iter().cloned().collect::<Vec<_>>().join(" ")
+}
+
+/// Format the report as a table for reading.
+pub fn format_text(report: &UsageReport) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("Mod usage for {} exe(s): {}\n\n", report.exes.len(), join_exes(&report.exes)));
+    let name_width = report.mods.iter().map(|m| m.name.len()).max().unwrap_or(0).max(4);
+    out.push_str(&format!("{:<nw$}  {:>12}  {:>8}  {:<19}  {:<19}  {}\n",
+        "name", "total", "sessions", "first seen", "last seen", "exes", nw = name_width));
+    for m in report.mods.iter() {
+        out.push_str(&format!("{:<nw$}  {:>12}  {:>8}  {:<19}  {:<19}  {}\n",
+            m.name, format_secs(m.total_secs), m.sessions, format_epoch(m.first_seen),
+            format_epoch(m.last_seen), join_exes(&m.exes), nw = name_width));
+    }
+    if !report.never_used.is_empty() {
+        out.push_str(&format!("\nNever used ({}):\n", report.never_used.len()));
+        for name in report.never_used.iter() {
+            out.push_str(&format!("  {}\n", name));
+        }
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Format the report as CSV.  Never used mods are included with a zero total and no dates.
+pub fn format_csv(report: &UsageReport) -> String {
+    let mut out = String::from("name,total_secs,sessions,first_seen,last_seen,exes\n");
+    for m in report.mods.iter() {
+        out.push_str(&format!("{},{},{},{},{},{}\n", csv_field(&m.name), m.total_secs, m.sessions,
+            csv_field(&format_epoch(m.first_seen)), csv_field(&format_epoch(m.last_seen)),
+            csv_field(&join_exes(&m.exes))));
+    }
+    for name in report.never_used.iter() {
+        out.push_str(&format!("{},0,0,,,\n", csv_field(name)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(name: &str, start: u64, secs: u64) -> ModSession {
+        ModSession { name: name.to_owned(), start, secs }
+    }
+
+    #[test]
+    fn test_parse_text_line() {
+        let start = from_epoch_secs(1_600_000_000);
+        let line = crate::mod_stats::format_active_line(&start, "foo's mod", &std::time::Duration::from_secs(192));
+        assert_eq!(parse_text_line(&line), Some(session("foo's mod", 1_600_000_000, 192)));
+        let line = crate::mod_stats::format_active_line(&start, "bar", &std::time::Duration::from_secs(42));
+        assert_eq!(parse_text_line(&line), Some(session("bar", 1_600_000_000, 42)));
+        assert_eq!(parse_text_line("crapcrapcrap"), None);
+        assert_eq!(parse_text_line("2020-01-01 00:00:00: 'foo' lots"), None);
+    }
+
+    #[test]
+    fn test_build_report() {
+        let logs = vec![
+            ("game1".to_owned(), vec![session("ModA", 1000, 60), session("modb", 2000, 120), session("moda", 5000, 30)]),
+            ("game2".to_owned(), vec![session("moda", 500, 10)]),
+        ];
+        let index = vec!["ModA".to_owned(), "ModC".to_owned(), "ModB".to_owned(), "modc".to_owned()];
+        let report = build_report(&logs, &index);
+        assert_eq!(report.exes.len(), 2);
+        assert_eq!(report.never_used, vec!["ModC".to_owned()]);
+        assert_eq!(report.mods.len(), 2);
+        let a = &report.mods[1];
+        assert_eq!((a.name.as_str(), a.total_secs, a.sessions, a.first_seen, a.last_seen), ("ModA", 100, 3, 500, 5030));
+        assert_eq!(join_exes(&a.exes), "game1 game2");
+        assert_eq!(report.mods[0].name, "modb");
+
+        let csv = format_csv(&report);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("ModA,100,3,"), "{}", lines[2]);
+        assert!(lines[2].ends_with(",game1 game2"), "{}", lines[2]);
+        assert_eq!(lines[3], "ModC,0,0,,,");
+        let text = format_text(&report);
+        assert!(text.contains("1m 40s"));
+        assert!(text.contains("Never used (1):\n  ModC\n"));
+
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_exe_from_log_name() {
+        assert_eq!(exe_from_log_name(Path::new("c:/mm/Logs/modstats.Game.log")), Some("Game".to_owned()));
+        assert_eq!(exe_from_log_name(Path::new("modstats.Game.jsonl")), Some("Game".to_owned()));
+        assert_eq!(exe_from_log_name(Path::new("modstats.Game.jsonl.1")), None);
+        assert_eq!(exe_from_log_name(Path::new("mod_stats.txt")), None);
+    }
+}
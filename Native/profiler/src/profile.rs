@@ -1,10 +1,18 @@
 /*!
-A simple sampling profile.
+A simple hierarchical profiler.
 
 Reports time spent in various blocks marked by `profile_start!` and `profile_end!`.
 Reports are written to the log file every 10 seconds (or as specified in the
-`profile_summarize!` macro).  Time reported is inclusive only (that is, each block tracks
-the time spent in all subblocks referenced while it is active).
+`profile_summarize!` macro).
+
+Blocks are tracked on a stack, so a block started while another is active becomes its child
+and the report is a tree.  The same block name can show up in several places in the tree if it
+is entered from different parents.  For each block the report has:
+
+* inclusive time: all time spent in the block, including its children
+* exclusive time: time spent in the block itself, not counting its children
+* count, and min/p50/p95/p99/max of the inclusive time of a single entry (percentiles are
+  approximate, see `shared_dx::histogram`)
 
 Normally the profiler is disabled and its various macros compile to nothing, which eliminates
 any performance cost from them.
@@ -14,51 +22,505 @@ as any crates you want to profile (usually hook_core).
 
 Then run the game and let it sit in a visually complex scene for a couple minutes.
 
-An extremely crude way to examine the results is to pick some block name and search the log
-for it using git bash tools, like so:
-
-cat logfilename | grep -i "mod_precheck" | grep -v post | awk '{print $4}' | sort | uniq -c
+In addition to the log report, the `ProfileOutput` root registry value can select a structured
+output, written to the log directory on each summary:
 
-Which prints results like
-
-3 (0.0%)
-1 (0.2%)
-1 (0.3%)
-5 (0.4%)
-12 (0.5%)
-
-showing that in 12 of the samples, this particular block took 0.5% of the total time, which given
-the 10 second reporting interval is about 50ms in each of those samples.
+* 1: Chrome trace-event JSON (`profile.$name.json`), which can be loaded in `chrome://tracing`
+  or Perfetto.  Each block entry is an event; at most `MAX_TRACE_EVENTS` are written per
+  summary interval.
+* 2: folded stacks (`profile.$name.folded`), one line per stack with its exclusive time in
+  microseconds.  This can be fed to `flamegraph.pl` or similar tools (which add up the lines
+  from each summary).
 
 Using the profiler has a performance cost, and adding or removing blocks changes this cost.
 When optimizing it is best to not add or remove too many blocks,
 so that the cost of the profiler itself is relatively fixed.
 
 Every `profile_start!` should have (at least one) `profile_end!`, more than one may be necessary
-if there are multiple return paths that could end the block.  If the profile gives you screwy
-results, you are probably missing a `profile_end!`.
-
-The profiler is not thread safe, since it uses global statics to track state.
+if there are multiple return paths that could end the block.  A block that is never ended is
+dropped from the stack (and not counted) when one of its parents ends or when a block with the
+same name is started again; its time shows up as exclusive time of its parent.
+
+Blocks can be used from any thread.  Each thread that enters a block gets its own `Profiler`
+(with its own tree and stack), registered with the `ProfileRegistry` declared by
+`decl_profile_globals!`.  A thread only locks its own profiler, so threads don't contend with
+each other; the summary (which can be triggered from any thread) locks each one in turn to
+merge the results.  The report, trace events and folded stacks are broken down by thread, using
+ids assigned in the order threads first entered a block.  Profilers of threads that have exited
+are dropped after their last stats are reported.
 
 */
 
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use shared_dx::clock;
+use shared_dx::histogram::LogHistogram;
+use shared_dx::util::write_log_file;
+
+/// Blocks nested deeper than this are not tracked.
+pub const MAX_DEPTH: usize = 64;
+/// Maximum number of trace events written per summary interval.
+pub const MAX_TRACE_EVENTS: usize = 200_000;
+
+/// Where the profile is written, in addition to the log report.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProfileOutput {
+    Log,
+    /// Chrome trace-event JSON file
+    ChromeTrace(String),
+    /// Folded stacks file
+    Folded(String),
+}
+
+/// Stats for a block at one place in the tree.  Stats are for the current summary interval.
+#[derive(Debug)]
+pub struct ProfileNode {
+    pub name: &'static str,
+    /// Index of the parent node; the root is its own parent.
+    pub parent: usize,
+    pub count: u64,
+    pub inclusive: Duration,
+    pub exclusive: Duration,
+    pub hist: LogHistogram,
+}
+
+impl ProfileNode {
+    fn new(name: &'static str, parent: usize) -> Self {
+        Self { name, parent, count: 0, inclusive: Duration::ZERO, exclusive: Duration::ZERO, hist: LogHistogram::new() }
+    }
+}
+
+/// Returned by `start`, passed to `end`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProfileToken(usize);
+
+impl ProfileToken {
+    /// A token that doesn't refer to any block; ending it does nothing.
+    pub const NONE: ProfileToken = ProfileToken(usize::MAX);
+}
+
+struct Frame {
+    node: usize,
+    start: Instant,
+    /// Time spent in children that have ended
+    child: Duration,
+}
+
+struct TraceEvent {
+    name: &'static str,
+    /// Since the profiler was created
+    start: Duration,
+    dur: Duration,
+}
+
+/// Profile of a single thread.
+pub struct Profiler {
+    pub name: &'static str,
+    /// Id of the thread in the output
+    pub tid: u32,
+    pub thread_name: String,
+    /// Node 0 is the root, which is named after the profiler and has no stats.
+    pub nodes: Vec<ProfileNode>,
+    children: FnvHashMap<(usize, &'static str), usize>,
+    stack: Vec<Frame>,
+    /// Trace event times are relative to this
+    epoch: Instant,
+    output: ProfileOutput,
+    trace: Vec<TraceEvent>,
+    dropped_trace_events: usize,
+    /// Whether the thread name has been written to the trace
+    trace_named: bool,
+}
+
+/// Lock a mutex, ignoring poisoning (a panic while profiling shouldn't stop the profiler).
+pub fn lock_ignore_poison<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn fmt_dur(d: Option<Duration>) -> String {
+    match d {
+        None => "-".to_owned(),
+        Some(d) if d >= Duration::from_millis(1) => format!("{:.2}ms", d.as_secs_f64() * 1e3),
+        Some(d) => format!("{:.1}us", d.as_secs_f64() * 1e6),
+    }
+}
+
+/// Read the output setting from the registry; files go in the directory of the log file.
+pub fn query_output(name: &str) -> ProfileOutput {
+    let logpath = shared_dx::util::get_log_file_path();
+    let dir = std::path::Path::new(&logpath).parent()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file = |ext: &str| {
+        if dir.is_empty() {
+            format!("profile.{}.{}", name, ext)
+        } else {
+            format!("{}\\profile.{}.{}", dir, name, ext)
+        }
+    };
+    #[cfg(windows)]
+    let setting = unsafe { util::reg_query_root_dword("ProfileOutput") };
+    #[cfg(not(windows))]
+    let setting: shared_dx::error::Result<u32> = Err(shared_dx::error::HookError::NoRegistryKey("ProfileOutput".to_owned()));
+    match setting {
+        Ok(1) => ProfileOutput::ChromeTrace(file("json")),
+        Ok(2) => ProfileOutput::Folded(file("folded")),
+        _ => ProfileOutput::Log,
+    }
+}
+
+impl Profiler {
+    pub fn new(name: &'static str) -> Self {
+        Self::new_at(name, clock::now())
+    }
+
+    pub fn new_at(name: &'static str, epoch: Instant) -> Self {
+        Self {
+            name,
+            tid: 1,
+            thread_name: String::new(),
+            nodes: vec![ProfileNode::new(name, 0)],
+            children: FnvHashMap::with_capacity_and_hasher(100, Default::default()),
+            stack: Vec::with_capacity(MAX_DEPTH),
+            epoch,
+            output: ProfileOutput::Log,
+            trace: vec![],
+            dropped_trace_events: 0,
+            trace_named: false,
+        }
+    }
+
+    pub fn with_output(mut self, output: ProfileOutput) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_thread(mut self, tid: u32, thread_name: &str) -> Self {
+        self.tid = tid;
+        self.thread_name = thread_name.to_owned();
+        self
+    }
+
+    /// True if any block was entered in this interval.
+    pub fn has_stats(&self) -> bool {
+        self.nodes.iter().any(|n| n.count > 0)
+    }
+
+    pub fn start(&mut self, name: &'static str) -> ProfileToken {
+        self.start_at(name, clock::now())
+    }
+
+    pub fn end(&mut self, token: ProfileToken) {
+        self.end_at(token, clock::now())
+    }
+
+    pub fn start_at(&mut self, name: &'static str, now: Instant) -> ProfileToken {
+        // if the block is already on the stack, it was never ended (e.g. an early return), so
+        // drop it and anything started after it rather than nesting deeper and deeper
+        if let Some(pos) = self.stack.iter().position(|f| self.nodes[f.node].name == name) {
+            self.stack.truncate(pos);
+        }
+        if self.stack.len() >= MAX_DEPTH {
+            return ProfileToken::NONE;
+        }
+        let parent = self.stack.last().map(|f| f.node).unwrap_or(0);
+        let nodes = &mut self.nodes;
+        let node = *self.children.entry((parent, name)).or_insert_with(|| {
+            nodes.push(ProfileNode::new(name, parent));
+            nodes.len() - 1
+        });
+        self.stack.push(Frame { node, start: now, child: Duration::ZERO });
+        ProfileToken(node)
+    }
+
+    pub fn end_at(&mut self, token: ProfileToken, now: Instant) {
+        // ignore blocks that aren't on the stack (already ended on another path, or too deep)
+        let pos = match self.stack.iter().rposition(|f| f.node == token.0) {
+            Some(pos) => pos,
+            None => return,
+        };
+        // children that were never ended are dropped
+        self.stack.truncate(pos + 1);
+        let frame = match self.stack.pop() {
+            Some(f) => f,
+            None => return,
+        };
+        let elapsed = now.saturating_duration_since(frame.start);
+        let node = &mut self.nodes[frame.node];
+        node.count += 1;
+        node.inclusive += elapsed;
+        node.exclusive += elapsed.saturating_sub(frame.child);
+        node.hist.record(elapsed);
+        if let Some(parent) = self.stack.last_mut() {
+            parent.child += elapsed;
+        }
+        if let ProfileOutput::ChromeTrace(_) = self.output {
+            if self.trace.len() < MAX_TRACE_EVENTS {
+                self.trace.push(TraceEvent {
+                    name: node.name,
+                    start: frame.start.saturating_duration_since(self.epoch),
+                    dur: elapsed,
+                });
+            } else {
+                self.dropped_trace_events += 1;
+            }
+        }
+    }
+
+    /// Names of the nodes from the root (not included) to the node, separated by `;`.
+    pub fn path(&self, mut node: usize) -> String {
+        let mut names = vec![];
+        while node != 0 {
+            names.push(self.nodes[node].name);
+            node = self.nodes[node].parent;
+        }
+        names.reverse();
+        names.join(";")
+    }
+
+    /// Nodes in depth first order with their depth, children sorted by inclusive time.  Nodes
+    /// that weren't entered in this interval are left out, unless they have children that were.
+    fn sorted_tree(&self) -> Vec<(usize, usize)> {
+        let mut used = vec![false; self.nodes.len()];
+        for (i, n) in self.nodes.iter().enumerate().skip(1) {
+            let mut i = i;
+            if n.count > 0 {
+                while i != 0 && !used[i] {
+                    used[i] = true;
+                    i = self.nodes[i].parent;
+                }
+            }
+        }
+        let mut kids: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+        for (i, n) in self.nodes.iter().enumerate().skip(1) {
+            if used[i] {
+                kids[n.parent].push(i);
+            }
+        }
+        for k in kids.iter_mut() {
+            k.sort_by(|a, b| self.nodes[*b].inclusive.cmp(&self.nodes[*a].inclusive));
+        }
+        let mut out = vec![];
+        let mut todo: Vec<(usize, usize)> = kids[0].iter().rev().map(|k| (*k, 0)).collect();
+        while let Some((node, depth)) = todo.pop() {
+            out.push((node, depth));
+            todo.extend(kids[node].iter().rev().map(|k| (*k, depth + 1)));
+        }
+        out
+    }
+
+    /// The tree part of the log report, for an interval of `secs` seconds.
+    pub fn report(&self, secs: f64) -> String {
+        let mut out = String::new();
+        for (idx, depth) in self.sorted_tree() {
+            let n = &self.nodes[idx];
+            let incl = n.inclusive.as_secs_f64();
+            out.push_str(&format!(
+                "   {:indent$}{}: {:3.3} secs ({:3.1}%) excl {:3.3} secs (count: {}, min {} p50 {} p95 {} p99 {} max {})\r\n",
+                "", n.name, incl, incl / secs * 100.0, n.exclusive.as_secs_f64(), n.count,
+                fmt_dur(n.hist.min()), fmt_dur(n.hist.percentile(50.0)), fmt_dur(n.hist.percentile(95.0)),
+                fmt_dur(n.hist.percentile(99.0)), fmt_dur(n.hist.max()),
+                indent = depth * 2));
+        }
+        out
+    }
+
+    /// Folded stacks for the interval: one line per node with its exclusive time in microseconds.
+    /// Stacks start with the profiler name and the thread.
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for (idx, _depth) in self.sorted_tree() {
+            let us = self.nodes[idx].exclusive.as_micros();
+            if us > 0 {
+                out.push_str(&format!("{};thread-{};{} {}\n", self.name, self.tid, self.path(idx), us));
+            }
+        }
+        out
+    }
+
+    /// Trace events recorded in the interval, each followed by a comma and newline (the trace
+    /// format allows the array to be left open at the end).
+    pub fn trace_events(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::new();
+        for ev in self.trace.iter() {
+            out.push_str(&format!(
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{}}},\n",
+                escape(ev.name), self.name, ev.start.as_secs_f64() * 1e6, ev.dur.as_secs_f64() * 1e6, self.tid));
+        }
+        out
+    }
+
+    /// Trace metadata event that names the thread.
+    fn trace_thread_name(&self) -> String {
+        let name = format!("{} {}", self.thread_name, self.tid);
+        format!("{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}},\n",
+            self.tid, name.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    /// Clear the stats for the next interval.  Blocks that are active stay on the stack.
+    pub fn reset_interval(&mut self) {
+        for n in self.nodes.iter_mut() {
+            n.count = 0;
+            n.inclusive = Duration::ZERO;
+            n.exclusive = Duration::ZERO;
+            n.hist.clear();
+        }
+        self.trace.clear();
+        self.dropped_trace_events = 0;
+    }
+}
+
+struct RegistryState {
+    /// Time of the first registration or the last summary
+    last_summary: Option<Instant>,
+    /// Shared by all threads, set on the first registration
+    epoch: Option<Instant>,
+    output: Option<ProfileOutput>,
+    trace_started: bool,
+}
+
+/// The profilers of all threads that have entered a block, declared as a static by
+/// `decl_profile_globals!`.
+pub struct ProfileRegistry {
+    pub name: &'static str,
+    threads: Mutex<Vec<Arc<Mutex<Profiler>>>>,
+    next_tid: AtomicU32,
+    state: Mutex<RegistryState>,
+}
+
+impl ProfileRegistry {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            threads: Mutex::new(vec![]),
+            next_tid: AtomicU32::new(1),
+            state: Mutex::new(RegistryState { last_summary: None, epoch: None, output: None, trace_started: false }),
+        }
+    }
+
+    /// Set the output.  Has no effect after the first registration, which reads the output from
+    /// the registry if it wasn't set.
+    pub fn set_output(&self, output: ProfileOutput) {
+        let mut st = lock_ignore_poison(&self.state);
+        if st.epoch.is_none() {
+            st.output = Some(output);
+        }
+    }
+
+    /// Create a profiler for the current thread.
+    pub fn register(&self) -> Arc<Mutex<Profiler>> {
+        let (epoch, output) = {
+            let mut st = lock_ignore_poison(&self.state);
+            let now = clock::now();
+            let epoch = *st.epoch.get_or_insert(now);
+            st.last_summary.get_or_insert(now);
+            let output = st.output.get_or_insert_with(|| query_output(self.name)).clone();
+            (epoch, output)
+        };
+        let tid = self.next_tid.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::current();
+        let profiler = Profiler::new_at(self.name, epoch)
+            .with_output(output)
+            .with_thread(tid, thread.name().unwrap_or("thread"));
+        let profiler = Arc::new(Mutex::new(profiler));
+        lock_ignore_poison(&self.threads).push(profiler.clone());
+        profiler
+    }
+
+    fn write_output(st: &mut RegistryState, profilers: &mut [MutexGuard<Profiler>]) -> std::io::Result<()> {
+        use std::io::Write;
+        let (path, data) = match &st.output {
+            None | Some(ProfileOutput::Log) => return Ok(()),
+            Some(ProfileOutput::ChromeTrace(path)) => {
+                let mut data = String::new();
+                if !st.trace_started {
+                    data.push_str("[\n");
+                    // start a new file each run
+                    std::fs::File::create(path)?;
+                    st.trace_started = true;
+                }
+                for p in profilers.iter_mut() {
+                    if !p.trace_named && !p.trace.is_empty() {
+                        data.push_str(&p.trace_thread_name());
+                        p.trace_named = true;
+                    }
+                    data.push_str(&p.trace_events());
+                }
+                (path, data)
+            }
+            Some(ProfileOutput::Folded(path)) => (path, profilers.iter().map(|p| p.folded()).collect()),
+        };
+        std::fs::OpenOptions::new().create(true).append(true).open(path)?
+            .write_all(data.as_bytes())
+    }
+
+    /// If more than `minsec` seconds have passed since the last summary, write the report to
+    /// the log (and the structured output, if any) and start a new interval.
+    pub fn summarize(&self, minsec: f64) {
+        if let Some(report) = self.summarize_at(minsec, clock::now()) {
+            write_log_file(&report);
+        }
+    }
+
+    /// Like `summarize` but returns the report instead of logging it.
+    pub fn summarize_at(&self, minsec: f64, now: Instant) -> Option<String> {
+        let mut st = lock_ignore_poison(&self.state);
+        // None if nothing is registered yet
+        let last = st.last_summary?;
+        let secs = now.saturating_duration_since(last).as_secs_f64();
+        if secs <= minsec {
+            return None;
+        }
+        let mut threads = lock_ignore_poison(&self.threads);
+        let mut report = format!("[Profiler {}] {:3.1} secs elapsed since last profile\r\n", self.name, secs);
+        {
+            let mut profilers = threads.iter().map(|p| lock_ignore_poison(p)).collect::<Vec<_>>();
+            for p in profilers.iter().filter(|p| p.has_stats()) {
+                report.push_str(&format!("  thread {} ({}):\r\n", p.tid, p.thread_name));
+                report.push_str(&p.report(secs));
+                if p.dropped_trace_events > 0 {
+                    report.push_str(&format!("   ({} trace events dropped)\r\n", p.dropped_trace_events));
+                }
+            }
+            if let Err(e) = Self::write_output(&mut st, &mut profilers) {
+                report.push_str(&format!("   failed to write {:?}: {}\r\n", st.output, e));
+            }
+            for p in profilers.iter_mut() {
+                p.reset_interval();
+            }
+        }
+        // threads that exited have dropped their reference, their stats are now reported
+        threads.retain(|p| Arc::strong_count(p) > 1);
+        st.last_summary = Some(now);
+        Some(report)
+    }
+}
+
 #[cfg(feature = "profile")]
 #[macro_export]
 macro_rules! decl_profile_globals {
     ($v:ident) => {
         mod $v {
-            pub use fnv::FnvHashMap;
-            use std;
-
-            pub struct ProfileBlock {
-                pub name: &'static str,
-                pub start: std::time::SystemTime,
-                pub total_time: f64,
-                pub count: u64,
+            pub static REGISTRY: $crate::ProfileRegistry = $crate::ProfileRegistry::new(stringify!($v));
+
+            thread_local! {
+                static LOCAL: std::sync::Arc<std::sync::Mutex<$crate::Profiler>> = REGISTRY.register();
             }
 
-            pub static mut PROFILE_ACCUM: Option<FnvHashMap<&'static str, ProfileBlock>> = None;
-            pub static mut PROFILE_SUMMARY_T: Option<std::time::SystemTime> = None;
+            #[allow(dead_code)]
+            pub fn start(name: &'static str) -> $crate::ProfileToken {
+                LOCAL.try_with(|p| $crate::lock_ignore_poison(p).start(name))
+                    .unwrap_or($crate::ProfileToken::NONE)
+            }
+
+            #[allow(dead_code)]
+            pub fn end(token: $crate::ProfileToken) {
+                let _ = LOCAL.try_with(|p| $crate::lock_ignore_poison(p).end(token));
+            }
         }
     };
 }
@@ -73,30 +535,7 @@ macro_rules! decl_profile_globals {
 #[macro_export]
 macro_rules! profile_start {
     ($modn:ident, $v:ident) => {
-
-        let $v = unsafe {
-            let name = stringify!($v);
-            if $modn::PROFILE_ACCUM.is_none() {
-                $modn::PROFILE_ACCUM = Some(
-                    $modn::FnvHashMap::with_capacity_and_hasher((100) as usize,
-                    Default::default()));
-            }
-
-            let accum = $modn::PROFILE_ACCUM
-                        .as_mut().unwrap();
-            let $v = accum.entry(name).or_insert({
-                            $modn::ProfileBlock {
-                                name: name,
-                                start: std::time::UNIX_EPOCH,
-                                total_time: 0.0,
-                                count: 0,
-                            }
-                        });
-
-            $v.start = std::time::SystemTime::now();
-            $v.count += 1;
-            $v
-        };
+        let $v = $modn::start(stringify!($v));
     }
 }
 
@@ -110,12 +549,7 @@ macro_rules! profile_start {
 #[macro_export]
 macro_rules! profile_end {
     ($modn:ident, $v:ident) => {
-        let now = std::time::SystemTime::now();
-        let elapsed = now.duration_since($v.start).unwrap();
-
-        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
-
-        $v.total_time += secs;
+        $modn::end($v);
     };
 }
 #[cfg(not(feature = "profile"))]
@@ -128,46 +562,7 @@ macro_rules! profile_end {
 #[macro_export]
 macro_rules! profile_summarize {
     ($modn:ident, $minsec:expr) => {
-        unsafe {
-            use std::time::SystemTime;
-            if $modn::PROFILE_SUMMARY_T.is_none() {
-                $modn::PROFILE_SUMMARY_T = Some(SystemTime::now());
-            }
-
-            // report stats every 10 seconds
-            let now = SystemTime::now();
-            let selapsed = now.duration_since(*$modn::PROFILE_SUMMARY_T.as_ref().unwrap())
-                .unwrap();
-            let secs = selapsed.as_secs() as f64 + selapsed.subsec_nanos() as f64 * 1e-9;
-            if secs > $minsec {
-                let mut out = String::new();
-
-                let modn = stringify!($modn);
-                out.push_str(&format!(
-                    "[Profiler {}] {:3.1} secs elapsed since last profile\r\n",
-                    modn, secs
-                ));
-                let mut blocks:Vec<&mut $modn::ProfileBlock> =
-                    $modn::PROFILE_ACCUM.as_mut().map(|hm| hm.iter_mut().map(|(_, v)| v).collect()).unwrap_or_else(|| vec![]);
-                blocks.sort_by(|b, a| a.total_time.partial_cmp(&b.total_time).unwrap());
-                for block in blocks {
-                    let pct = block.total_time / secs * 100.0;
-
-
-                    let s = format!(
-                        "   {}: {:3.2} secs ({:3.1}%) (count: {})\r\n",
-                        block.name, block.total_time, pct, block.count
-                    );
-                    out.push_str(&s);
-
-                    block.total_time = 0.0; // reset for next round
-                }
-
-                write_log_file(&out);
-
-                $modn::PROFILE_SUMMARY_T = Some(now);
-            }
-        };
+        $modn::REGISTRY.summarize($minsec);
     };
 }
 
@@ -179,81 +574,209 @@ macro_rules! profile_summarize {
 
 #[cfg(test)]
 mod tests {
-    decl_profile_globals!(test_profiler);
+    use super::*;
 
-    #[test]
-    #[cfg(feature = "profile")]
-    #[cfg_attr(feature = "ci", ignore)]
-    fn profile_works() {
-        use shared_dx::util::*;
-
-        let _loglock = LOG_EXCL_LOCK.lock().unwrap();
-
-        // remove previous summary file
-        let testfile = "__testprofiler__test_prof_summary_log.txt";
-        std::fs::remove_file(testfile).ok();
-
-        set_log_file_path("", testfile).expect("doh");
-        const SLEEPTIME:u64 = 250;
-        let secs = 1000 / SLEEPTIME;
-        let itersec = 8;
-        profile_summarize!(test_profiler,1.0); // sets the time since last summary but doesn't print
-
-        println!("{} iters for {} each, total = {}", (itersec * secs), SLEEPTIME, (itersec * secs * SLEEPTIME));
-        // could just iterate once per second but want to simulate entering each block more
-        // frequently
-        for _i in 0..(itersec * secs) {
-            // half the time we are outside of a block
-            std::thread::sleep(std::time::Duration::from_millis(SLEEPTIME/2));
-
-            // then spend half in main1 and 2, with 2 subdivided into 2 parts
-            profile_start!(test_profiler, top);
-            profile_start!(test_profiler, main1);
-            std::thread::sleep(std::time::Duration::from_millis(SLEEPTIME/4));
-            profile_end!(test_profiler, main1);
-            profile_start!(test_profiler, main2);
-            profile_start!(test_profiler, main2_sub1);
-            std::thread::sleep(std::time::Duration::from_millis(SLEEPTIME/8));
-            profile_end!(test_profiler, main2_sub1);
-            profile_start!(test_profiler, main2_sub2);
-            std::thread::sleep(std::time::Duration::from_millis(SLEEPTIME/8));
-            profile_end!(test_profiler, main2_sub2);
-            profile_end!(test_profiler, main2);
-            profile_end!(test_profiler, top);
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Runs `iters` frames of: 10ms idle, top { main1 5ms, main2 { sub1 2ms, sub2 3ms } }
+    fn run_frames(p: &mut Profiler, t0: Instant, iters: u64) -> Instant {
+        let mut t = t0;
+        for _ in 0..iters {
+            t += ms(10);
+            let top = p.start_at("top", t);
+            let main1 = p.start_at("main1", t);
+            t += ms(5);
+            p.end_at(main1, t);
+            let main2 = p.start_at("main2", t);
+            let sub1 = p.start_at("sub1", t);
+            t += ms(2);
+            p.end_at(sub1, t);
+            let sub2 = p.start_at("sub2", t);
+            t += ms(3);
+            p.end_at(sub2, t);
+            t += ms(1);
+            p.end_at(main2, t);
+            p.end_at(top, t);
         }
-        profile_summarize!(test_profiler,1.0); // time doesn't matter here just want file to dump
-
-        // read all lines from testlog.txt
-        use std::{io::Read, slice::Iter};
-        let mut f = std::fs::File::open(testfile).expect("doh");
-        let mut contents = String::new();
-        f.read_to_string(&mut contents).expect("doh");
-        // write the results to stdout in case of fail
-        println!("{}", contents);
-        let lines:Vec<&str> = contents.split("\r\n").collect();
-        let mut lines = lines.iter();
-        let split_secs = |line:&str| {
-            let mut split = line.split("secs");
-            let first = split.next().unwrap().trim();
-            let mut split = first.rsplit(" ");
-            let count = split.next().unwrap().parse::<f64>().unwrap();
-            count
-        };
-        let next_secs = |lines:&mut Iter<&str>| {
-            lines.next().map(|line| split_secs(line)).unwrap()
-        };
-        let near = |a:f64, b:f64, ep:f64| {
-            let diff = (a-b).abs();
-            diff < ep
+        t
+    }
+
+    #[test]
+    fn test_tree() {
+        let t0 = Instant::now();
+        let mut p = Profiler::new_at("test", t0);
+        run_frames(&mut p, t0, 100);
+
+        let node = |path: &str| {
+            let idx = (0..p.nodes.len()).find(|i| p.path(*i) == path).expect("doh");
+            &p.nodes[idx]
         };
+        let top = node("top");
+        assert_eq!((top.count, top.inclusive, top.exclusive), (100, ms(1100), ms(0)));
+        let main2 = node("top;main2");
+        assert_eq!((main2.inclusive, main2.exclusive), (ms(600), ms(100)));
+        assert_eq!(main2.hist.percentile(50.0), Some(ms(6)));
+        assert_eq!(node("top;main2;sub2").exclusive, ms(300));
+
+        let folded = p.folded();
+        assert_eq!(folded.lines().collect::<Vec<_>>(), vec![
+            "test;thread-1;top;main2 100000",
+            "test;thread-1;top;main2;sub2 300000",
+            "test;thread-1;top;main2;sub1 200000",
+            "test;thread-1;top;main1 500000",
+        ]);
+
+        let report = p.report(2.1);
+        let lines = report.split("\r\n").collect::<Vec<_>>();
+        assert!(lines[0].starts_with("   top: 1.100 secs (52.4%)"), "{}", lines[0]);
+        assert!(lines[1].starts_with("     main2: 0.600 secs"), "{}", lines[1]);
+        assert!(lines[1].contains("excl 0.100 secs (count: 100"), "{}", lines[1]);
+        assert!(lines[2].starts_with("       sub2: 0.300"), "{}", lines[2]);
+
+        assert!(p.has_stats());
+        p.reset_interval();
+        assert!(!p.has_stats());
+        assert_eq!(p.report(1.0), "");
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let clock = shared_dx::clock::FakeClock::new();
+        let _clock = clock.install();
+        let mut p = Profiler::new("test");
+        let top = p.start("top");
+        clock.advance(ms(7));
+        p.end(top);
+        let idx = (0..p.nodes.len()).find(|i| p.path(*i) == "top").expect("doh");
+        assert_eq!(p.nodes[idx].inclusive, ms(7));
+    }
+
+    #[test]
+    fn test_missing_end() {
+        let t0 = Instant::now();
+        let mut p = Profiler::new_at("test", t0);
+        for i in 0..10 {
+            let t = t0 + ms(i * 10);
+            let total = p.start_at("total", t);
+            let _start = p.start_at("start", t);
+            // early return without ending either block on even frames
+            if i % 2 == 0 {
+                continue;
+            }
+            let draw = p.start_at("draw", t + ms(1));
+            p.end_at(draw, t + ms(3));
+            p.end_at(total, t + ms(4));
+            // ending again (another return path) is ignored
+            p.end_at(total, t + ms(5));
+        }
+        // never nests total under itself
+        assert!(p.stack.len() <= 2);
+        assert!((0..p.nodes.len()).all(|i| !p.path(i).contains("total;total")));
+        let total = &p.nodes[1];
+        assert_eq!((total.count, total.inclusive), (5, ms(20)));
+        // start is never ended so it isn't counted, and draw's time isn't subtracted from total
+        assert_eq!(total.exclusive, ms(20));
+        assert_eq!(p.nodes[2].count, 0);
+        let draw = (1..p.nodes.len()).find(|i| p.nodes[*i].name == "draw").expect("doh");
+        assert_eq!(p.path(draw), "total;start;draw");
+        assert_eq!(p.nodes[draw].inclusive, ms(10));
+        // draw still shows up in the report, under start
+        let report = p.report(1.0);
+        assert!(report.contains("\r\n       draw: 0.010 secs"), "{}", report);
+        p.end_at(ProfileToken::NONE, t0);
+    }
 
-        // this is obviously fuzzy but if the numbers are way off from this maybe its a bug
-        let n = next_secs(&mut lines); assert!(near(9.0, n, 1.0), "line 1: {}", n); // total time (first line)
-        let n = next_secs(&mut lines); assert!(near(5.0, n, 0.75), "line 2: {}", n); // top time == total
-        let n = next_secs(&mut lines); assert!(near(2.5, n, 0.5), "line 3: {}", n); // main 1 or 2
-        let n = next_secs(&mut lines); assert!(near(2.5, n, 0.5), "line 4: {}", n); // main 1 or 2
-        let n = next_secs(&mut lines); assert!(near(1.25, n, 0.25), "line 5: {}", n); // main2 sub
-        let n = next_secs(&mut lines); assert!(near(1.25, n, 0.25), "line: 6: {}", n); // main2 sub
+    #[test]
+    fn test_trace() {
+        let t0 = Instant::now();
+        let mut p = Profiler::new_at("test", t0)
+            .with_output(ProfileOutput::ChromeTrace("unused".to_owned()))
+            .with_thread(3, "render");
+        run_frames(&mut p, t0, 2);
+        let events = p.trace_events();
+        let lines = events.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "{\"name\":\"main1\",\"cat\":\"test\",\"ph\":\"X\",\"ts\":10000.000,\"dur\":5000.000,\"pid\":1,\"tid\":3},");
+        assert!(lines[4].starts_with("{\"name\":\"top\","), "{}", lines[4]);
+        assert!(lines[4].contains("\"dur\":11000.000"), "{}", lines[4]);
+        assert_eq!(p.trace_thread_name(),
+            "{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":3,\"args\":{\"name\":\"render 3\"}},\n");
+    }
 
+    #[test]
+    fn test_threads() {
+        static REGISTRY: ProfileRegistry = ProfileRegistry::new("mt");
+        REGISTRY.set_output(ProfileOutput::Log);
+        assert_eq!(REGISTRY.summarize_at(0.0, Instant::now()), None);
+
+        // the main thread registers but doesn't enter anything
+        let main = REGISTRY.register();
+        let nthreads = 4;
+        let frames = 50;
+        let handles = (0..nthreads).map(|i| {
+            std::thread::Builder::new().name(format!("worker{}", i)).spawn(move || {
+                let p = REGISTRY.register();
+                for _ in 0..frames {
+                    let t0 = Instant::now();
+                    let tok = lock_ignore_poison(&p).start_at("work", t0);
+                    let inner = lock_ignore_poison(&p).start_at("inner", t0);
+                    // yield so the threads interleave
+                    std::thread::yield_now();
+                    lock_ignore_poison(&p).end_at(inner, t0 + ms(1));
+                    lock_ignore_poison(&p).end_at(tok, t0 + ms(2));
+                }
+            }).expect("doh")
+        }).collect::<Vec<_>>();
+        for h in handles {
+            h.join().expect("doh");
+        }
+
+        let report = REGISTRY.summarize_at(0.0, Instant::now() + Duration::from_secs(1)).expect("doh");
+        let thread_lines = report.lines().filter(|l| l.starts_with("  thread ")).collect::<Vec<_>>();
+        assert_eq!(thread_lines.len(), nthreads, "{}", report);
+        for i in 0..nthreads {
+            assert!(report.contains(&format!("(worker{}):", i)), "{}", report);
+        }
+        let work_lines = report.lines().filter(|l| l.trim_start().starts_with("work:")).collect::<Vec<_>>();
+        assert_eq!(work_lines.len(), nthreads);
+        for l in work_lines {
+            assert!(l.contains("0.100 secs") && l.contains("excl 0.050 secs (count: 50,"), "{}", l);
+        }
+        // tids are unique
+        let mut tids = thread_lines.iter().map(|l| l.split(' ').nth(3).expect("doh")).collect::<Vec<_>>();
+        tids.sort();
+        tids.dedup();
+        assert_eq!(tids.len(), nthreads);
+
+        // the exited threads were dropped after reporting, the main thread is still registered
+        assert_eq!(lock_ignore_poison(&REGISTRY.threads).len(), 1);
+        lock_ignore_poison(&main).start("main");
+        assert_eq!(REGISTRY.summarize_at(0.0, Instant::now() + Duration::from_secs(2))
+            .expect("doh").lines().count(), 1);
+    }
+
+    #[cfg(feature = "profile")]
+    decl_profile_globals!(test_profiler);
+
+    #[test]
+    #[cfg(feature = "profile")]
+    fn profile_macros() {
+        test_profiler::REGISTRY.set_output(ProfileOutput::Log);
+        let run = || {
+            for _i in 0..3 {
+                profile_start!(test_profiler, top);
+                profile_start!(test_profiler, inner);
+                profile_end!(test_profiler, inner);
+                profile_end!(test_profiler, top);
+            }
+        };
+        run();
+        std::thread::spawn(run).join().expect("doh");
+        let report = test_profiler::REGISTRY.summarize_at(-1.0, Instant::now()).expect("doh");
+        assert_eq!(report.matches("inner:").count(), 2, "{}", report);
+        assert_eq!(report.matches("(count: 3,").count(), 4, "{}", report);
+        profile_summarize!(test_profiler, 1000.0);
     }
 }
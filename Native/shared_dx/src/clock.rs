@@ -0,0 +1,153 @@
+//! Time source for intervals and timeouts.
+//!
+//! Code that measures intervals should use `now()`, which is monotonic (an `Instant`), rather
+//! than `SystemTime`, whose `duration_since` fails (and used to panic in places) when the wall
+//! clock steps backwards.  `wall_now()` is for timestamps that are shown to the user or stored,
+//! such as log entries and file names.
+//!
+//! Tests can install a `FakeClock` on the current thread to control both; the clock only moves
+//! when the test advances it, so time dependent logic can be tested without sleeping.  Other
+//! threads (and the current thread once the guard is dropped) keep using the system clock.
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock {
+    /// Monotonic time, for intervals.
+    fn now(&self) -> Instant;
+    /// Wall clock time, for timestamps.
+    fn wall_now(&self) -> SystemTime;
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn wall_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when advanced.  Clones share the same time.
+#[derive(Clone)]
+pub struct FakeClock {
+    /// (monotonic, wall) times
+    time: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+impl FakeClock {
+    /// Starts at the current monotonic time, and a fixed wall clock time (2020-09-13 12:26:40 UTC).
+    pub fn new() -> Self {
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        Self { time: Arc::new(Mutex::new((Instant::now(), wall))) }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        let mut t = self.time.lock().unwrap_or_else(|e| e.into_inner());
+        t.0 += d;
+        t.1 += d;
+    }
+
+    /// Step only the wall clock, e.g. backwards to simulate a clock change.  The monotonic
+    /// time is not affected.
+    pub fn set_wall(&self, wall: SystemTime) {
+        self.time.lock().unwrap_or_else(|e| e.into_inner()).1 = wall;
+    }
+
+    /// Use this clock for `now()` and `wall_now()` on the current thread until the guard is
+    /// dropped.
+    pub fn install(&self) -> FakeClockGuard {
+        let prev = THREAD_CLOCK.with(|c| c.borrow_mut().replace(self.clone()));
+        FakeClockGuard { prev }
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.time.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+    fn wall_now(&self) -> SystemTime {
+        self.time.lock().unwrap_or_else(|e| e.into_inner()).1
+    }
+}
+
+/// Restores the previous clock of the thread when dropped.
+pub struct FakeClockGuard {
+    prev: Option<FakeClock>,
+}
+
+impl Drop for FakeClockGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        let _ = THREAD_CLOCK.try_with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+thread_local! {
+    static THREAD_CLOCK: RefCell<Option<FakeClock>> = const { RefCell::new(None) };
+}
+
+fn with_clock<R>(f: impl Fn(&dyn Clock) -> R) -> R {
+    THREAD_CLOCK.try_with(|c| c.borrow().as_ref().map(|c| f(c)))
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| f(&SystemClock))
+}
+
+/// Current monotonic time.
+pub fn now() -> Instant {
+    with_clock(|c| c.now())
+}
+
+/// Current wall clock time.
+pub fn wall_now() -> SystemTime {
+    with_clock(|c| c.wall_now())
+}
+
+/// Time since `earlier`, or None if there is no earlier time.  Never negative.
+pub fn since(now: Instant, earlier: Option<Instant>) -> Option<Duration> {
+    earlier.map(|e| now.saturating_duration_since(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_clock() {
+        let real = now();
+        let clock = FakeClock::new();
+        {
+            let _guard = clock.install();
+            let t0 = now();
+            let w0 = wall_now();
+            assert_eq!(now(), t0);
+            clock.advance(Duration::from_secs(5));
+            assert_eq!(now() - t0, Duration::from_secs(5));
+            assert_eq!(wall_now().duration_since(w0).expect("doh"), Duration::from_secs(5));
+
+            // wall clock going backwards doesn't affect intervals
+            clock.set_wall(w0 - Duration::from_secs(3600));
+            assert!(wall_now() < w0);
+            assert_eq!(since(now(), Some(t0)), Some(Duration::from_secs(5)));
+            assert_eq!(since(t0, Some(now())), Some(Duration::ZERO));
+            assert_eq!(since(now(), None), None);
+
+            // other threads use the real clock
+            let other = std::thread::spawn(wall_now).join().expect("doh");
+            assert!(other > w0);
+        }
+        // guard dropped, back to the real clock
+        assert!(now() >= real);
+        assert!(wall_now() > SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000 + 3600));
+    }
+}
@@ -5,9 +5,11 @@ use winapi::shared::minwindef::{UINT, INT, ULONG};
 
 use winapi::um::d3d11::{ID3D11Buffer, ID3D11InputLayout, D3D11_INPUT_ELEMENT_DESC,
     ID3D11Device, D3D11_PRIMITIVE_TOPOLOGY, ID3D11ShaderResourceView, D3D11_BUFFER_DESC,
-    D3D11_SUBRESOURCE_DATA, ID3D11Resource, D3D11_TEXTURE2D_DESC, ID3D11Texture2D};
+    D3D11_SUBRESOURCE_DATA, ID3D11Resource, D3D11_TEXTURE2D_DESC, ID3D11Texture2D,
+    ID3D11BlendState, ID3D11RasterizerState, ID3D11DepthStencilState, ID3D11SamplerState};
 use winapi::um::d3d11::ID3D11DeviceContext;
 use winapi::um::unknwnbase::IUnknown;
+use winapi::shared::dxgi::IDXGISwapChain;
 use winapi::um::winnt::HRESULT;
 
 use crate::impl_release_drop;
@@ -110,9 +112,18 @@ pub type DrawInstancedIndirectFn = unsafe extern "system" fn (
     pBufferForArgs: *mut ID3D11Buffer,
     AlignedByteOffsetForArgs: UINT,
 ) -> ();
+pub type DXGIPresentFn = unsafe extern "system" fn (
+    THIS: *mut IDXGISwapChain,
+    SyncInterval: UINT,
+    Flags: UINT,
+) -> HRESULT;
 
 impl_release_drop!(ID3D11ShaderResourceView);
 impl_release_drop!(ID3D11Buffer);
 impl_release_drop!(ID3D11Resource);
 impl_release_drop!(ID3D11DeviceContext);
-impl_release_drop!(ID3D11Texture2D);
\ No newline at end of file
+impl_release_drop!(ID3D11Texture2D);
+impl_release_drop!(ID3D11BlendState);
+impl_release_drop!(ID3D11RasterizerState);
+impl_release_drop!(ID3D11DepthStencilState);
+impl_release_drop!(ID3D11SamplerState);
\ No newline at end of file
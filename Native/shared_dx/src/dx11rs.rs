@@ -1,7 +1,8 @@
-use std::{fmt::{Display, Formatter, Error}, ffi::CStr, time::SystemTime};
+use std::{fmt::{Display, Formatter, Error}, ffi::CStr};
 
 use fnv::FnvHashMap;
-use winapi::um::{d3d11::{ID3D11InputLayout, D3D11_INPUT_ELEMENT_DESC, D3D11_PRIMITIVE_TOPOLOGY}, d3dcommon::D3D_PRIMITIVE_TOPOLOGY_UNDEFINED};
+use crate::precopy_store::{PrecopyStore, PrecopyLimits};
+use winapi::um::{d3d11::{ID3D11InputLayout, D3D11_INPUT_ELEMENT_DESC, D3D11_INPUT_PER_VERTEX_DATA, D3D11_PRIMITIVE_TOPOLOGY}, d3dcommon::D3D_PRIMITIVE_TOPOLOGY_UNDEFINED};
 
 
 /// Container for a vertex format.  Contains a list of elements used by the format and its size in bytes.
@@ -15,7 +16,15 @@ use winapi::um::{d3d11::{ID3D11InputLayout, D3D11_INPUT_ELEMENT_DESC, D3D11_PRIM
 /// make a copy, but since this aliases the pointer it should be used very sparingly.
 pub struct VertexFormat {
     pub layout: Vec<D3D11_INPUT_ELEMENT_DESC>,
+    /// Size of a vertex in the stream (input slot) that holds POSITION.
     pub size: u32,
+    /// Vertex size of each per-vertex input slot, indexed by slot.  Slots without per-vertex
+    /// elements (e.g. instance data) are 0.
+    pub slot_sizes: Vec<u32>,
+    /// Input slot that holds the POSITION element.
+    pub position_slot: u32,
+    /// Geometry fingerprint of the layout, see `types::native_mod::geom_fingerprint`.
+    pub fingerprint: u32,
 }
 
 impl VertexFormat {
@@ -25,6 +34,50 @@ impl VertexFormat {
         VertexFormat {
             layout: self.layout.clone(),
             size: self.size,
+            slot_sizes: self.slot_sizes.clone(),
+            position_slot: self.position_slot,
+            fingerprint: self.fingerprint,
+        }
+    }
+
+    /// Returns the (slot, vertex size) of each input slot that has per-vertex data.
+    pub fn vertex_slots(&self) -> Vec<(u32,u32)> {
+        self.slot_sizes.iter().enumerate()
+            .filter(|(_slot,size)| **size > 0)
+            .map(|(slot,size)| (slot as u32, *size))
+            .collect()
+    }
+
+    /// True if the per-vertex data is split over more than one vertex buffer.
+    pub fn is_multi_stream(&self) -> bool {
+        self.slot_sizes.iter().filter(|size| **size > 0).count() > 1
+    }
+
+    /// Create a shallow copy (see `shallow_copy`) that describes the per-vertex slots interleaved
+    /// into a single stream in slot order, the same way snapshots store them: every element moves
+    /// to slot 0 and its offset is shifted by the size of the slots before it.  Per-instance
+    /// elements are dropped.
+    pub fn interleaved(&self) -> Self {
+        let mut bases = Vec::with_capacity(self.slot_sizes.len());
+        let mut size = 0;
+        for slot_size in self.slot_sizes.iter() {
+            bases.push(size);
+            size += slot_size;
+        }
+        let layout = self.layout.iter()
+            .filter(|el| el.InputSlotClass == D3D11_INPUT_PER_VERTEX_DATA)
+            .filter_map(|el| bases.get(el.InputSlot as usize).map(|base| D3D11_INPUT_ELEMENT_DESC {
+                InputSlot: 0,
+                AlignedByteOffset: el.AlignedByteOffset + base,
+                ..*el
+            }))
+            .collect();
+        VertexFormat {
+            layout,
+            size,
+            slot_sizes: vec![size],
+            position_slot: 0,
+            fingerprint: self.fingerprint,
         }
     }
 }
@@ -40,12 +93,13 @@ impl Display for VertexFormat {
                 write!(f, ", ")?;
             }
         }
-        write!(f, "], size: {} }}", self.size)
+        write!(f, "], size: {}, slot sizes: {:?}, position slot: {}, fingerprint: {:#010x} }}",
+            self.size, self.slot_sizes, self.position_slot, self.fingerprint)
     }
 }
 
 pub struct DX11RenderState {
-    /// Current vertex buffer properties, vector of (buf index,byte width,stride).
+    /// Current vertex buffer properties, vector of (input slot,byte width,stride).
     pub vb_state: Vec<(u32,u32,u32)>,
     /// Number of layouts in `device_input_layouts_by_ptr`
     pub num_input_layouts: std::sync::atomic::AtomicUsize,
@@ -69,16 +123,12 @@ pub struct DX11RenderState {
     /// So probably you shouldn't clear it, unless you can clear those as well or this entire
     /// structure and you know there aren't any clones.
     pub device_semantic_string_table: FnvHashMap<String, Vec<u8>>,
-    /// When snapshotting this stores all index buffer data, because we can't read it on the fly.
-    pub device_index_buffer_data: FnvHashMap<usize, Vec<u8>>,
-    /// Controls when index data is removed
-    pub device_index_buffer_createtime: Vec<(usize,SystemTime)>,
-    pub device_index_buffer_totalsize_nextlog: (usize,usize),
-    /// When snapshotting this stores all vertex buffer data, because we can't read it on the fly.
-    pub device_vertex_buffer_data: FnvHashMap<usize, Vec<u8>>,
-    /// Controls when vertex data is removed
-    pub device_vertex_buffer_createtime: Vec<(usize,SystemTime)>,
-    pub device_vertex_buffer_totalsize_nextlog: (usize,usize),
+    /// When snapshotting this stores all index and vertex buffer data, because we can't read it
+    /// on the fly.
+    pub precopy: PrecopyStore,
+    /// Generation of the last texture created at each address, see `texture_generation`.
+    texture_generations: FnvHashMap<usize, u64>,
+    texture_generation_counter: u64,
 }
 
 impl DX11RenderState {
@@ -91,15 +141,24 @@ impl DX11RenderState {
             current_input_layout: std::ptr::null_mut(),
             prim_topology: D3D_PRIMITIVE_TOPOLOGY_UNDEFINED,
             device_semantic_string_table: FnvHashMap::with_capacity_and_hasher(64, Default::default()),
-            device_index_buffer_data: FnvHashMap::with_capacity_and_hasher(1600, Default::default()),
-            device_index_buffer_createtime: Vec::new(),
-            device_index_buffer_totalsize_nextlog: (0,0),
-            device_vertex_buffer_data: FnvHashMap::with_capacity_and_hasher(1600, Default::default()),
-            device_vertex_buffer_createtime: Vec::new(),
-            device_vertex_buffer_totalsize_nextlog: (0,0),
+            precopy: PrecopyStore::new(PrecopyLimits::default()),
+            texture_generations: FnvHashMap::with_capacity_and_hasher(1600, Default::default()),
+            texture_generation_counter: 0,
         }
     }
 
+    /// Record that a texture was created at the address.
+    pub fn texture_created(&mut self, ptr:usize) {
+        self.texture_generation_counter += 1;
+        self.texture_generations.insert(ptr, self.texture_generation_counter);
+    }
+
+    /// Generation of the texture at the address, which changes when a new texture is created
+    /// there.  None if the texture was created before the device was hooked.
+    pub fn texture_generation(&self, ptr:usize) -> Option<u64> {
+        self.texture_generations.get(&ptr).copied()
+    }
+
     pub fn get_current_vertex_format(&self) -> Option<&VertexFormat>  {
         if self.current_input_layout.is_null() {
             return None;
@@ -0,0 +1,263 @@
+//! Rolling frame time and draw call statistics.
+//!
+//! `FrameStats` keeps histograms, over one metrics interval, of the frame time, the draw calls
+//! and mod draws in each frame, and the overhead the hook adds to a draw call.  The metrics code
+//! reports the percentiles in the log (and optionally to a CSV file) and then resets it, so the
+//! numbers describe the last interval only.  That makes it possible to tell whether a stutter
+//! lines up with hook overhead or just with the game drawing more.
+//!
+//! Frames are ended by the present hook.  If present isn't hooked (possible in DX11) there are no
+//! per frame stats, but the draw overhead is still collected.
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::error::Result;
+use crate::histogram::LogHistogram;
+
+/// The hook overhead is measured for one in this many draws, since timing every draw would add
+/// overhead of its own.
+pub const OVERHEAD_SAMPLE_DRAWS: u64 = 8;
+
+pub const CSV_HEADER: &str = "time,frames,\
+    frame_ms_p50,frame_ms_p95,frame_ms_p99,frame_ms_max,\
+    draws_p50,draws_p95,draws_p99,draws_max,\
+    mod_draws_p50,mod_draws_p95,mod_draws_p99,mod_draws_max,\
+    overhead_us_p50,overhead_us_p95,overhead_us_p99,overhead_us_max";
+
+pub struct FrameStats {
+    frame_time: LogHistogram,
+    draws: LogHistogram,
+    mod_draws: LogHistogram,
+    draw_overhead: LogHistogram,
+    frame_draws: u64,
+    frame_mod_draws: u64,
+    total_draws: u64,
+    last_frame: Option<Instant>,
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameStats {
+    pub const fn new() -> Self {
+        Self {
+            frame_time: LogHistogram::new(),
+            draws: LogHistogram::new(),
+            mod_draws: LogHistogram::new(),
+            draw_overhead: LogHistogram::new(),
+            frame_draws: 0,
+            frame_mod_draws: 0,
+            total_draws: 0,
+            last_frame: None,
+        }
+    }
+
+    /// True if the next draw should be timed, see `OVERHEAD_SAMPLE_DRAWS`.
+    pub fn sample_draw(&self) -> bool {
+        self.total_draws.is_multiple_of(OVERHEAD_SAMPLE_DRAWS)
+    }
+
+    /// Count a draw call, with its hook overhead if it was timed.
+    pub fn note_draw(&mut self, overhead: Option<Duration>) {
+        self.frame_draws += 1;
+        self.total_draws += 1;
+        if let Some(d) = overhead {
+            self.draw_overhead.record(d);
+        }
+    }
+
+    pub fn note_mod_draw(&mut self) {
+        self.frame_mod_draws += 1;
+    }
+
+    /// End the current frame.  The first call only starts the frame timer.
+    pub fn end_frame(&mut self, now: Instant) {
+        if let Some(last) = self.last_frame {
+            self.frame_time.record(now.saturating_duration_since(last));
+            self.draws.record_value(self.frame_draws);
+            self.mod_draws.record_value(self.frame_mod_draws);
+        }
+        self.last_frame = Some(now);
+        self.frame_draws = 0;
+        self.frame_mod_draws = 0;
+    }
+
+    pub fn summary(&self) -> FrameStatsSummary {
+        FrameStatsSummary {
+            frames: self.frame_time.count(),
+            frame_ms: Percentiles::of_duration(&self.frame_time, 1e3),
+            draws: Percentiles::of_values(&self.draws),
+            mod_draws: Percentiles::of_values(&self.mod_draws),
+            draw_overhead_us: Percentiles::of_duration(&self.draw_overhead, 1e6),
+        }
+    }
+
+    /// Clear the histograms for the next interval.  The frame in progress carries on.
+    pub fn reset(&mut self) {
+        self.frame_time.clear();
+        self.draws.clear();
+        self.mod_draws.clear();
+        self.draw_overhead.clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    /// Durations are converted to `units_per_sec` (1e3 for milliseconds).
+    fn of_duration(h: &LogHistogram, units_per_sec: f64) -> Option<Self> {
+        let p = |pct| h.percentile(pct).map(|d| d.as_secs_f64() * units_per_sec);
+        Some(Self { p50: p(50.0)?, p95: p(95.0)?, p99: p(99.0)?, max: p(100.0)? })
+    }
+
+    fn of_values(h: &LogHistogram) -> Option<Self> {
+        let p = |pct| h.percentile_value(pct).map(|v| v as f64);
+        Some(Self { p50: p(50.0)?, p95: p(95.0)?, p99: p(99.0)?, max: p(100.0)? })
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1}/{:.1}/{:.1}/{:.1}", self.p50, self.p95, self.p99, self.max)
+    }
+}
+
+pub struct FrameStatsSummary {
+    pub frames: u64,
+    pub frame_ms: Option<Percentiles>,
+    /// Draw calls per frame
+    pub draws: Option<Percentiles>,
+    /// Mod draws per frame
+    pub mod_draws: Option<Percentiles>,
+    /// Hook overhead per draw in microseconds, from the sampled draws
+    pub draw_overhead_us: Option<Percentiles>,
+}
+
+impl FrameStatsSummary {
+    /// True if nothing was recorded in the interval.
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0 && self.draw_overhead_us.is_none()
+    }
+
+    /// A CSV line (without newline) matching `CSV_HEADER`.  Missing stats are empty fields.
+    pub fn csv_row(&self, time: &str) -> String {
+        let mut row = format!("{},{}", time, self.frames);
+        for p in [&self.frame_ms, &self.draws, &self.mod_draws, &self.draw_overhead_us] {
+            match p {
+                Some(p) => row.push_str(&format!(",{:.3},{:.3},{:.3},{:.3}", p.p50, p.p95, p.p99, p.max)),
+                None => row.push_str(",,,,"),
+            }
+        }
+        row
+    }
+}
+
+impl fmt::Display for FrameStatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |p: &Option<Percentiles>| p.map(|p| p.to_string()).unwrap_or_else(|| "n/a".to_owned());
+        write!(f, "frame stats (p50/p95/p99/max): {} frames; frame ms: {}; draws/frame: {}; mod draws/frame: {}; hook us/draw: {}",
+            self.frames, show(&self.frame_ms), show(&self.draws), show(&self.mod_draws), show(&self.draw_overhead_us))
+    }
+}
+
+/// Append the summary to a CSV file, writing the header first if the file is new.
+pub fn append_csv(path: &str, time: &str, summary: &FrameStatsSummary) -> Result<()> {
+    let new_file = !std::path::Path::new(path).exists();
+    let mut f = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    if new_file {
+        writeln!(f, "{}", CSV_HEADER)?;
+    }
+    writeln!(f, "{}", summary.csv_row(time))?;
+    Ok(())
+}
+
+/// Times the hook overhead of one draw call: the time spent in the hook minus the time spent in
+/// the real draw call.  Does nothing if the draw isn't sampled.
+pub struct DrawTimer {
+    start: Option<Instant>,
+    real: Duration,
+}
+
+impl DrawTimer {
+    pub fn start(sampled: bool) -> Self {
+        Self { start: if sampled { Some(clock::now()) } else { None }, real: Duration::ZERO }
+    }
+
+    /// Run the real draw call, excluding its time from the overhead.
+    pub fn time_real<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        if self.start.is_none() {
+            return f();
+        }
+        let start = clock::now();
+        let r = f();
+        self.real += clock::now().saturating_duration_since(start);
+        r
+    }
+
+    /// Overhead so far, None if the draw isn't sampled.
+    pub fn overhead(&self) -> Option<Duration> {
+        self.start.map(|s| clock::now().saturating_duration_since(s).saturating_sub(self.real))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn test_frame_stats() {
+        let clock = FakeClock::new();
+        let _clock = clock.install();
+        let mut fs = FrameStats::new();
+        assert!(fs.summary().is_empty());
+
+        fs.end_frame(clock::now());
+        for frame in 0..100 {
+            let draws = if frame == 99 { 500 } else { 100 };
+            for d in 0..draws {
+                let mut timer = DrawTimer::start(fs.sample_draw());
+                clock.advance(Duration::from_micros(2));
+                timer.time_real(|| clock.advance(Duration::from_micros(50)));
+                if d < 3 {
+                    fs.note_mod_draw();
+                }
+                fs.note_draw(timer.overhead());
+            }
+            let ms = if frame == 99 { 50 } else { 16 };
+            clock.advance(Duration::from_millis(ms) - Duration::from_micros(52) * draws);
+            fs.end_frame(clock::now());
+        }
+
+        let s = fs.summary();
+        assert_eq!(s.frames, 100);
+        let frame_ms = s.frame_ms.expect("doh");
+        assert!((frame_ms.p50 - 16.0).abs() < 2.0, "{:?}", frame_ms);
+        assert_eq!(frame_ms.max, 50.0);
+        let draws = s.draws.expect("doh");
+        assert!((draws.p50 - 100.0).abs() < 15.0, "{:?}", draws);
+        assert_eq!(draws.max, 500.0);
+        assert_eq!(s.mod_draws.expect("doh").p99, 3.0);
+        assert_eq!(s.draw_overhead_us.expect("doh").max, 2.0);
+
+        let line = s.to_string();
+        assert!(line.starts_with("frame stats (p50/p95/p99/max): 100 frames; frame ms: "), "{}", line);
+        assert_eq!(s.csv_row("t").split(',').count(), CSV_HEADER.split(',').count());
+
+        fs.reset();
+        let s = fs.summary();
+        assert!(s.is_empty());
+        assert_eq!(s.csv_row("t"), "t,0,,,,,,,,,,,,,,,,");
+    }
+}
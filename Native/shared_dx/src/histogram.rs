@@ -0,0 +1,173 @@
+//! Fixed size log scale histogram of durations, for cheap percentiles.  Plain counts (such as
+//! draw calls per frame) can be recorded too with `record_value`.
+//!
+//! Each power of two (in nanoseconds) is split into 4 linear buckets, so a value lands in a
+//! bucket at most 25% wider than itself.  Recording is a couple of shifts and an increment, and
+//! the histogram never allocates, so it can be updated in hot code.
+use std::time::Duration;
+
+const SUB_BUCKETS: usize = 4;
+const SUB_BITS: u32 = 2;
+/// Covers up to 2^40 ns (about 18 minutes), longer durations go in the last bucket.
+const NUM_BUCKETS: usize = 40 * SUB_BUCKETS;
+
+#[derive(Clone)]
+pub struct LogHistogram {
+    buckets: [u32; NUM_BUCKETS],
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for LogHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for LogHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LogHistogram {{ count: {}, min: {:?}, max: {:?} }}", self.count, self.min(), self.max())
+    }
+}
+
+fn bucket_of(ns: u64) -> usize {
+    if ns < SUB_BUCKETS as u64 {
+        return ns as usize;
+    }
+    let exp = 63 - ns.leading_zeros();
+    let sub = (ns >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    ((exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub).min(NUM_BUCKETS - 1)
+}
+
+/// Returns the (low, high) range of values in the bucket, high is exclusive.
+fn bucket_range(bucket: usize) -> (u64, u64) {
+    if bucket < SUB_BUCKETS {
+        return (bucket as u64, bucket as u64 + 1);
+    }
+    let shift = (bucket / SUB_BUCKETS) as u32 - 1;
+    let sub = (bucket % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub) << shift, (SUB_BUCKETS as u64 + sub + 1) << shift)
+}
+
+impl LogHistogram {
+    pub const fn new() -> Self {
+        Self { buckets: [0; NUM_BUCKETS], count: 0, min: u64::MAX, max: 0 }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        self.record_value(d.as_nanos().min(u64::MAX as u128) as u64);
+    }
+
+    /// Record a value that isn't a duration.  Don't mix these with `record` in one histogram.
+    pub fn record_value(&mut self, v: u64) {
+        let b = &mut self.buckets[bucket_of(v)];
+        *b = b.saturating_add(1);
+        self.count += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        if self.count == 0 { None } else { Some(Duration::from_nanos(self.min)) }
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.count == 0 { None } else { Some(Duration::from_nanos(self.max)) }
+    }
+
+    /// Approximate value at the percentile (0-100): the middle of the bucket it falls in,
+    /// clamped to the recorded min and max.  None if nothing was recorded.
+    pub fn percentile(&self, pct: f64) -> Option<Duration> {
+        self.percentile_value(pct).map(Duration::from_nanos)
+    }
+
+    /// Like `percentile`, for values recorded with `record_value`.
+    pub fn percentile_value(&self, pct: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((pct.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        if rank >= self.count {
+            return Some(self.max);
+        }
+        let mut seen = 0_u64;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n as u64;
+            if seen >= rank {
+                let (lo, hi) = bucket_range(i);
+                let mid = lo + (hi - lo) / 2;
+                return Some(mid.clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Add the values recorded in another histogram to this one.
+    pub fn merge(&mut self, other: &LogHistogram) {
+        for (b, o) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *b = b.saturating_add(*o);
+        }
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for ns in [0_u64, 1, 3, 4, 5, 7, 8, 100, 1000, 12345, 999_999_999, 1 << 39] {
+            let (lo, hi) = bucket_range(bucket_of(ns));
+            assert!(lo <= ns && ns < hi, "{} not in {}..{}", ns, lo, hi);
+            assert!(hi - lo <= (ns / 4).max(1), "bucket for {} too wide: {}..{}", ns, lo, hi);
+        }
+        assert_eq!(bucket_of(u64::MAX), NUM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut h = LogHistogram::new();
+        assert_eq!(h.percentile(50.0), None);
+        for us in 1..=1000 {
+            h.record(Duration::from_micros(us));
+        }
+        assert_eq!(h.count(), 1000);
+        assert_eq!(h.min(), Some(Duration::from_micros(1)));
+        assert_eq!(h.max(), Some(Duration::from_micros(1000)));
+        let near = |pct:f64, expect_us:f64| {
+            let v = h.percentile(pct).expect("doh").as_secs_f64() * 1e6;
+            assert!((v - expect_us).abs() <= expect_us * 0.15, "p{}: {} vs {}", pct, v, expect_us);
+        };
+        near(50.0, 500.0);
+        near(95.0, 950.0);
+        near(99.0, 990.0);
+        assert_eq!(h.percentile(100.0), Some(Duration::from_micros(1000)));
+
+        let mut h2 = LogHistogram::new();
+        h2.record(Duration::from_secs(2));
+        h2.merge(&h);
+        assert_eq!(h2.count(), 1001);
+        assert_eq!(h2.max(), Some(Duration::from_secs(2)));
+        h2.clear();
+        assert_eq!(h2.count(), 0);
+
+        let mut h3 = LogHistogram::new();
+        for v in [0_u64, 1, 2, 3, 3, 3, 3, 3, 3, 500] {
+            h3.record_value(v);
+        }
+        assert_eq!(h3.percentile_value(50.0), Some(3));
+        assert_eq!(h3.percentile_value(99.0), Some(500));
+    }
+}
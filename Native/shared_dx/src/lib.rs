@@ -11,14 +11,30 @@ extern crate winapi;
 #[macro_use]
 extern crate lazy_static;
 
+// The D3D declarations need winapi; the rest (errors, logging, clock, stats) also builds on
+// other platforms so that crates like `input` can run their tests anywhere.
+#[cfg(windows)]
 pub mod defs_dx11;
+#[cfg(windows)]
 pub mod types_dx11;
+#[cfg(windows)]
 pub mod defs_dx9;
+#[cfg(windows)]
 pub mod types_dx9;
 pub mod error;
 pub mod state;
 pub mod util;
+#[cfg(windows)]
 pub mod types;
 
 /// Contains DX11 render state
-pub mod dx11rs;
\ No newline at end of file
+#[cfg(windows)]
+pub mod dx11rs;
+/// Storage for buffer data copied for DX11 snapshots
+pub mod precopy_store;
+/// Log scale duration histogram, for percentiles
+pub mod histogram;
+/// Monotonic time source, with a fake clock for tests
+pub mod clock;
+/// Frame time and draw call percentiles for the metrics log
+pub mod frame_stats;
@@ -0,0 +1,427 @@
+/*!
+Storage for vertex and index buffer data that was copied when the buffer was created.
+
+In D3D11 the buffers usually can't be read back from the CPU, so when snapshotting is enabled
+the data is copied in `CreateBuffer` in case a snapshot needs it later.  Most of it is never used,
+so the store keeps the total size under a byte budget (evicting the least recently used
+entries first) and drops anything older than the age limit.
+
+Each buffer address also has a generation that changes when a new buffer is created there.  It is
+kept after the data is evicted, so that values computed from the data (like the index range of
+a draw) stay usable as long as the buffer exists.  Buffer releases aren't seen, so once there are
+too many generations, the ones without data are dropped and those buffers lose their cached
+values.
+
+The store doesn't free the evicted data itself; it hands the vectors back to the caller so that
+they can be dropped after the device lock is released.  Times are passed in so that the
+logic can be tested without a clock.
+*/
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Error};
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+
+pub const DEF_PRECOPY_BUDGET_MB: u32 = 1024;
+pub const DEF_PRECOPY_MAX_AGE_SECS: u32 = 600;
+
+/// Generations of addresses without data are dropped when there are more than this many (or more
+/// than twice the number of entries, if that is larger).
+const MAX_GENERATIONS: usize = 32768;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferKind {
+    Index,
+    Vertex,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrecopyLimits {
+    /// Max total bytes of data in the store.  0 disables the budget.
+    pub budget_bytes: usize,
+    /// Entries created longer ago than this are expired.  Zero disables the age limit.
+    pub max_age: Duration,
+}
+
+impl PrecopyLimits {
+    pub fn new(budget_mb: u32, max_age_secs: u32) -> Self {
+        Self {
+            budget_bytes: budget_mb as usize * 1024 * 1024,
+            max_age: Duration::from_secs(max_age_secs as u64),
+        }
+    }
+}
+
+impl Default for PrecopyLimits {
+    fn default() -> Self {
+        Self::new(DEF_PRECOPY_BUDGET_MB, DEF_PRECOPY_MAX_AGE_SECS)
+    }
+}
+
+struct Entry {
+    kind: BufferKind,
+    data: Vec<u8>,
+    created: Instant,
+    /// Value of `PrecopyStore.use_counter` when this was last inserted or read
+    last_use: u64,
+}
+
+/// Counters are cumulative since the store was created, sizes are current.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrecopyStats {
+    pub index_count: usize,
+    pub index_bytes: usize,
+    pub vertex_count: usize,
+    pub vertex_bytes: usize,
+    pub peak_bytes: usize,
+    pub budget_bytes: usize,
+    pub inserted: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub expired: u64,
+    pub evicted: u64,
+    pub freed_bytes: u64,
+}
+
+impl PrecopyStats {
+    pub fn total_bytes(&self) -> usize {
+        self.index_bytes + self.vertex_bytes
+    }
+}
+
+fn mb(bytes:usize) -> f32 {
+    bytes as f32 / 1024.0 / 1024.0
+}
+
+impl Display for PrecopyStats {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{} vb ({:.2} MB), {} ib ({:.2} MB), total {:.2} MB",
+            self.vertex_count, mb(self.vertex_bytes), self.index_count, mb(self.index_bytes), mb(self.total_bytes()))?;
+        if self.budget_bytes > 0 {
+            write!(f, " of {:.2} MB budget", mb(self.budget_bytes))?;
+        }
+        write!(f, ", peak {:.2} MB; {} inserted, {} hits, {} misses, {} expired, {} evicted, {:.2} MB freed",
+            mb(self.peak_bytes), self.inserted, self.hits, self.misses, self.expired, self.evicted,
+            mb(self.freed_bytes as usize))
+    }
+}
+
+pub struct PrecopyStore {
+    entries: FnvHashMap<usize, Entry>,
+    /// Buffer pointers by `Entry.last_use`, so the least recently used entry is first
+    lru: BTreeMap<u64, usize>,
+    /// Generation of the last buffer created at each address, see `generation`
+    generations: FnvHashMap<usize, u64>,
+    generation_counter: u64,
+    limits: PrecopyLimits,
+    use_counter: u64,
+    stats: PrecopyStats,
+}
+
+impl PrecopyStore {
+    pub fn new(limits:PrecopyLimits) -> Self {
+        Self {
+            entries: FnvHashMap::with_capacity_and_hasher(3200, Default::default()),
+            lru: BTreeMap::new(),
+            generations: FnvHashMap::with_capacity_and_hasher(3200, Default::default()),
+            generation_counter: 0,
+            limits,
+            use_counter: 0,
+            stats: PrecopyStats {
+                budget_bytes: limits.budget_bytes,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn limits(&self) -> PrecopyLimits {
+        self.limits
+    }
+
+    /// Change the limits.  Doesn't evict anything, that happens on the next insert or expire.
+    pub fn set_limits(&mut self, limits:PrecopyLimits) {
+        self.limits = limits;
+        self.stats.budget_bytes = limits.budget_bytes;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Exact number of data bytes currently stored.
+    pub fn total_bytes(&self) -> usize {
+        self.stats.total_bytes()
+    }
+
+    pub fn stats(&self) -> &PrecopyStats {
+        &self.stats
+    }
+
+    fn add_size(&mut self, kind:BufferKind, count:isize, bytes:isize) {
+        let (c,b) = match kind {
+            BufferKind::Index => (&mut self.stats.index_count, &mut self.stats.index_bytes),
+            BufferKind::Vertex => (&mut self.stats.vertex_count, &mut self.stats.vertex_bytes),
+        };
+        *c = (*c as isize + count) as usize;
+        *b = (*b as isize + bytes) as usize;
+    }
+
+    fn remove_entry(&mut self, ptr:usize) -> Option<Vec<u8>> {
+        let e = self.entries.remove(&ptr)?;
+        self.lru.remove(&e.last_use);
+        self.add_size(e.kind, -1, -(e.data.len() as isize));
+        self.stats.freed_bytes += e.data.len() as u64;
+        Some(e.data)
+    }
+
+    /// Store the data for a buffer.  If the budget is exceeded, least recently used entries
+    /// (other than this one) are evicted and returned.  The store may exceed the budget if a single
+    /// buffer is larger than it.
+    pub fn insert(&mut self, ptr:usize, kind:BufferKind, data:Vec<u8>, now:Instant) -> Vec<Vec<u8>> {
+        let mut removed = vec![];
+        // the game released the old buffer and the address was reused
+        if let Some(old) = self.remove_entry(ptr) {
+            removed.push(old);
+        }
+        self.use_counter += 1;
+        self.add_size(kind, 1, data.len() as isize);
+        self.entries.insert(ptr, Entry { kind, data, created: now, last_use: self.use_counter });
+        self.lru.insert(self.use_counter, ptr);
+        self.new_generation(ptr);
+        self.stats.inserted += 1;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.total_bytes());
+        self.evict_to_budget(Some(ptr), &mut removed);
+        removed
+    }
+
+    /// Return the data for the buffer and mark it as recently used.  Returns None if the
+    /// buffer wasn't stored, or was stored as a different kind.
+    pub fn get(&mut self, ptr:usize, kind:BufferKind) -> Option<&Vec<u8>> {
+        self.use_counter += 1;
+        match self.entries.get_mut(&ptr) {
+            Some(e) if e.kind == kind => {
+                self.lru.remove(&e.last_use);
+                self.lru.insert(self.use_counter, ptr);
+                e.last_use = self.use_counter;
+                self.stats.hits += 1;
+                Some(&e.data)
+            },
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Like `get`, but doesn't count as a use, so it can be called with a read lock.
+    pub fn peek(&self, ptr:usize, kind:BufferKind) -> Option<&Vec<u8>> {
+        self.entries.get(&ptr).filter(|e| e.kind == kind).map(|e| &e.data)
+    }
+
+    pub fn contains(&self, ptr:usize) -> bool {
+        self.entries.contains_key(&ptr)
+    }
+
+    fn new_generation(&mut self, ptr:usize) {
+        if self.generations.len() >= MAX_GENERATIONS.max(self.entries.len() * 2) {
+            let entries = &self.entries;
+            self.generations.retain(|p,_| entries.contains_key(p));
+        }
+        self.generation_counter += 1;
+        self.generations.insert(ptr, self.generation_counter);
+    }
+
+    /// Note that a buffer whose data wasn't copied was created at the address.  Any data for
+    /// the old buffer there is removed and returned, and the address has no generation until
+    /// something is inserted there again.
+    pub fn forget(&mut self, ptr:usize) -> Option<Vec<u8>> {
+        self.generations.remove(&ptr);
+        self.remove_entry(ptr)
+    }
+
+    /// A number that is different for each buffer created at the address, so that values
+    /// computed from a buffer's data can be cached.  It is usually kept after the data is evicted
+    /// or expired, see the module doc.  None if nothing was inserted at the address since it was
+    /// last forgotten.
+    pub fn generation(&self, ptr:usize) -> Option<u64> {
+        self.generations.get(&ptr).copied()
+    }
+
+    fn evict_to_budget(&mut self, keep:Option<usize>, removed:&mut Vec<Vec<u8>>) {
+        let budget = self.limits.budget_bytes;
+        if budget == 0 || self.total_bytes() <= budget {
+            return;
+        }
+        while self.total_bytes() > budget {
+            // `keep` was just inserted so it is last, this doesn't search far
+            let ptr = match self.lru.values().find(|ptr| Some(**ptr) != keep) {
+                Some(ptr) => *ptr,
+                None => break,
+            };
+            if let Some(data) = self.remove_entry(ptr) {
+                self.stats.evicted += 1;
+                removed.push(data);
+            }
+        }
+    }
+
+    /// Remove entries that are past the age limit, then evict until the store is within the
+    /// budget.  The removed data is appended to `removed`.  Returns the number of entries removed.
+    pub fn expire(&mut self, now:Instant, removed:&mut Vec<Vec<u8>>) -> usize {
+        let start_len = removed.len();
+        if self.limits.max_age > Duration::from_secs(0) {
+            let max_age = self.limits.max_age;
+            let old:Vec<usize> = self.entries.iter()
+                .filter(|(_,e)| now.saturating_duration_since(e.created) > max_age)
+                .map(|(ptr,_)| *ptr)
+                .collect();
+            for ptr in old {
+                if let Some(data) = self.remove_entry(ptr) {
+                    self.stats.expired += 1;
+                    removed.push(data);
+                }
+            }
+        }
+        self.evict_to_budget(None, removed);
+        removed.len() - start_len
+    }
+
+    /// Remove everything, returning the data.
+    pub fn clear(&mut self) -> Vec<Vec<u8>> {
+        let ptrs:Vec<usize> = self.entries.keys().copied().collect();
+        ptrs.into_iter().filter_map(|ptr| self.remove_entry(ptr)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref T0: Instant = Instant::now();
+    }
+
+    fn secs(t:u64) -> Instant {
+        *T0 + Duration::from_secs(t)
+    }
+
+    fn limits(budget_bytes:usize, max_age_secs:u64) -> PrecopyLimits {
+        PrecopyLimits { budget_bytes, max_age: Duration::from_secs(max_age_secs) }
+    }
+
+    #[test]
+    fn test_size_tracking() {
+        let mut store = PrecopyStore::new(limits(0, 0));
+        assert!(store.insert(1, BufferKind::Vertex, vec![0; 100], secs(0)).is_empty());
+        assert!(store.insert(2, BufferKind::Index, vec![0; 30], secs(0)).is_empty());
+        assert_eq!(store.total_bytes(), 130);
+        // replacing a reused pointer returns the old data and adjusts the size
+        let removed = store.insert(1, BufferKind::Vertex, vec![0; 40], secs(1));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].len(), 100);
+        assert_eq!(store.total_bytes(), 70);
+        let st = store.stats();
+        assert_eq!((st.vertex_count, st.vertex_bytes, st.index_count, st.index_bytes), (1, 40, 1, 30));
+        assert_eq!(st.peak_bytes, 130);
+        assert_eq!(st.freed_bytes, 100);
+
+        // each buffer at an address has its own generation
+        assert_ne!(store.generation(1), store.generation(2));
+        let gen = store.generation(1);
+        store.insert(1, BufferKind::Vertex, vec![0; 40], secs(1));
+        assert_ne!(store.generation(1), gen);
+        assert_eq!(store.generation(3), None);
+        // forgetting removes the data and the generation
+        let gen = store.generation(1);
+        assert_eq!(store.forget(1).map(|v| v.len()), Some(40));
+        assert!(!store.contains(1));
+        assert_eq!(store.generation(1), None);
+        store.insert(1, BufferKind::Vertex, vec![0; 40], secs(1));
+        assert!(store.generation(1).is_some() && store.generation(1) != gen);
+
+        assert_eq!(store.get(2, BufferKind::Index).map(|v| v.len()), Some(30));
+        assert!(store.get(2, BufferKind::Vertex).is_none());
+        assert!(store.get(3, BufferKind::Index).is_none());
+        assert_eq!((store.stats().hits, store.stats().misses), (1, 2));
+
+        assert_eq!(store.clear().len(), 2);
+        assert_eq!(store.total_bytes(), 0);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut store = PrecopyStore::new(limits(300, 0));
+        store.insert(1, BufferKind::Vertex, vec![0; 100], secs(0));
+        store.insert(2, BufferKind::Vertex, vec![0; 100], secs(0));
+        store.insert(3, BufferKind::Index, vec![0; 100], secs(0));
+        // touch the oldest so that 2 is now the least recently used
+        assert!(store.get(1, BufferKind::Vertex).is_some());
+        let removed = store.insert(4, BufferKind::Index, vec![0; 50], secs(0));
+        assert_eq!(removed.len(), 1);
+        assert!(!store.contains(2));
+        assert!(store.contains(1) && store.contains(3) && store.contains(4));
+        assert_eq!(store.total_bytes(), 250);
+
+        // a buffer bigger than the budget pushes everything else out but is kept itself
+        let removed = store.insert(5, BufferKind::Vertex, vec![0; 400], secs(0));
+        assert_eq!(removed.len(), 3);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.total_bytes(), 400);
+        assert_eq!(store.stats().evicted, 4);
+        // evicted buffers keep their generation
+        assert!(store.generation(2).is_some());
+
+        // lowering the budget takes effect on the next expire
+        store.set_limits(limits(100, 0));
+        assert_eq!(store.len(), 1);
+        let mut removed = vec![];
+        assert_eq!(store.expire(secs(0), &mut removed), 1);
+        assert_eq!(store.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_generations_bounded() {
+        let mut store = PrecopyStore::new(limits(1000, 0));
+        // one buffer whose data stays
+        store.insert(0, BufferKind::Index, vec![0; 10], secs(0));
+        for ptr in 1..(MAX_GENERATIONS * 3) {
+            if ptr % 2 == 0 {
+                store.forget(ptr);
+            } else {
+                // evicts the previous one
+                assert!(store.get(0, BufferKind::Index).is_some());
+                store.insert(ptr, BufferKind::Vertex, vec![0; 900], secs(0));
+            }
+            assert!(store.generations.len() <= MAX_GENERATIONS);
+        }
+        assert!(store.generation(0).is_some());
+        let last = MAX_GENERATIONS * 3 - 1;
+        assert!(store.contains(last) && store.generation(last).is_some());
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_age_expiry() {
+        let mut store = PrecopyStore::new(limits(0, 600));
+        store.insert(1, BufferKind::Vertex, vec![0; 10], secs(0));
+        store.insert(2, BufferKind::Index, vec![0; 20], secs(500));
+        store.insert(3, BufferKind::Index, vec![0; 30], secs(700));
+        let mut removed = vec![];
+        assert_eq!(store.expire(secs(600), &mut removed), 0);
+        assert_eq!(store.expire(secs(601), &mut removed), 1);
+        assert!(!store.contains(1));
+        // reads don't extend the age limit
+        assert!(store.get(2, BufferKind::Index).is_some());
+        assert_eq!(store.expire(secs(1101), &mut removed), 1);
+        assert_eq!(removed.iter().map(|v| v.len()).sum::<usize>(), 30);
+        assert_eq!(store.total_bytes(), 30);
+        assert_eq!(store.stats().expired, 2);
+        // times from the past don't expire anything
+        assert_eq!(store.expire(secs(0), &mut removed), 0);
+    }
+}
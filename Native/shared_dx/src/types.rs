@@ -4,8 +4,9 @@ Contains combinations of types from both DX9 and 11, most notably `HookDeviceSta
 carries the device specific state for one or the other (but not both) at runtime.
  */
 use std::ptr::null_mut;
-use std::time::SystemTime;
+use std::time::Instant;
 use fnv::FnvHashMap;
+use crate::clock;
 use winapi::shared::d3d9::LPDIRECT3DTEXTURE9;
 use winapi::shared::windef::HWND;
 use winapi::shared::d3d9::IDirect3DDevice9;
@@ -40,7 +41,7 @@ impl MetricsDrawStatus {
     }
 }
 pub struct DX11Metrics {
-    pub last_reset: SystemTime,
+    pub last_reset: Instant,
     /// Number of times `hook_VSSetConstantBuffers` was called
     pub vs_set_const_buffers_calls: u32,
     /// Number of times `hook_VSSetConstantBuffers` rehooked at least one function
@@ -54,7 +55,7 @@ pub struct DX11Metrics {
 impl DX11Metrics {
     pub fn new() -> Self {
         DX11Metrics {
-            last_reset: SystemTime::now(),
+            last_reset: clock::now(),
             vs_set_const_buffers_calls: 0,
             vs_set_const_buffers_hooks: 0,
             drawn_recently: FnvHashMap::default(),
@@ -63,7 +64,7 @@ impl DX11Metrics {
         }
     }
     pub fn reset(&mut self) {
-        self.last_reset = SystemTime::now();
+        self.last_reset = clock::now();
         self.vs_set_const_buffers_calls = 0;
         self.vs_set_const_buffers_hooks = 0;
         self.drawn_recently.clear();
@@ -72,17 +73,7 @@ impl DX11Metrics {
     }
     /// Return number of milisecs since last reset
     pub fn ms_since_reset(&self) -> u64 {
-        let now = SystemTime::now();
-        match now.duration_since(self.last_reset) {
-            Ok(d) => {
-                let secs = d.as_secs();
-                let milisecs = d.subsec_millis();
-                secs * 1000 + milisecs as u64
-            },
-            _ => {
-                0
-            }
-        }
+        clock::now().saturating_duration_since(self.last_reset).as_millis() as u64
     }
 }
 
@@ -95,9 +86,8 @@ pub struct HookD3D11State {
     /// Contains current render state for the device
     pub rs: DX11RenderState,
     pub app_hwnds: Vec<HWND>,
-    pub last_timebased_update: SystemTime,
-    pub last_data_expire: SystemTime,
-    pub last_data_expire_type_flip: bool,
+    pub last_timebased_update: Instant,
+    pub last_data_expire: Instant,
     pub app_foreground: bool,
 }
 
@@ -109,9 +99,8 @@ impl HookD3D11State {
             metrics: DX11Metrics::new(),
             rs: DX11RenderState::new(),
             app_hwnds: Vec::new(),
-            last_timebased_update: SystemTime::now(),
-            last_data_expire: SystemTime::now(),
-            last_data_expire_type_flip: false,
+            last_timebased_update: clock::now(),
+            last_data_expire: clock::now(),
             app_foreground: false,
         }
     }
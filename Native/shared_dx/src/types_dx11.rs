@@ -6,6 +6,9 @@ pub struct HookDirect3D11Device {
     pub real_query_interface: QueryInterfaceFn,
     pub real_create_input_layout: CreateInputLayoutFn
 }
+pub struct HookDXGISwapChain {
+    pub real_present: DXGIPresentFn,
+}
 pub struct HookDirect3D11Context {
     pub real_query_interface: QueryInterfaceFn,
     pub real_release: IUnknownReleaseFn,
@@ -0,0 +1,547 @@
+//! Converts snapped animation frames (`AnimFrameFile`) into formats that other tools can import.
+//!
+//! The anim snapshot just records raw shader float constants for each frame, so to turn that into
+//! something useful we need to know where the bones live.  The user supplies that as a mapping
+//! of bone to starting register; each bone is assumed to occupy three consecutive registers
+//! (R, R+1, R+2) holding the rows of a 3x4 matrix, with the translation in the last column.
+//! This is the common layout for skinning palettes in D3D9 era shaders.
+//!
+//! Two output formats are supported:
+//! * glTF: each bone becomes a node with translation/rotation/scale animation channels.  The
+//! keyframe times are the actual snap times so no resampling is done.  A `.gltf` json file and
+//! a `.bin` buffer are written side by side.
+//! * BVH: a flat hierarchy with every bone parented to a root joint.  BVH requires a fixed frame
+//! rate, so the tracks are resampled at the average frame interval of the capture.  BVH has no
+//! scale channels, so scale is dropped; a warning is logged if any bone is scaled.
+//!
+//! No skeleton hierarchy information is available in the snapshot, so in both formats the bone
+//! transforms are written as world (model space) transforms.
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::minwindef::UINT;
+
+use shared_dx::error::*;
+use shared_dx::util::write_log_file;
+
+use crate::anim_frame::AnimFrameFile;
+
+#[derive(Deserialize,Serialize,Eq,PartialEq,Copy,Clone,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimExportFormat {
+    Gltf,
+    Bvh,
+}
+
+impl AnimExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimExportFormat::Gltf => "gltf",
+            AnimExportFormat::Bvh => "bvh",
+        }
+    }
+}
+
+/// A single bone, which lives in registers `reg`, `reg+1`, `reg+2`.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct BoneMapping {
+    pub name: Option<String>,
+    pub reg: UINT,
+}
+
+/// Export settings, normally read from the `anim_export` section of the snap config.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct AnimExportConfig {
+    pub format: AnimExportFormat,
+    pub bones: Vec<BoneMapping>,
+    /// Convert from the left handed D3D convention to a right handed one by mirroring Z.
+    pub flip_z: Option<bool>,
+}
+
+/// One keyframe of a bone transform, time is in seconds from the first frame in the file.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct BoneKey {
+    pub time: f32,
+    pub translation: [f32; 3],
+    /// Quaternion, x y z w order (same as glTF)
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+#[derive(Clone,Debug)]
+pub struct BoneTrack {
+    pub name: String,
+    pub keys: Vec<BoneKey>,
+}
+
+impl BoneTrack {
+    /// Interpolate the track at time `t`, clamping to the first/last key.  Returns None if the
+    /// track has no keys.
+    pub fn sample(&self, t: f32) -> Option<BoneKey> {
+        let first = self.keys.first()?;
+        let last = self.keys.last()?;
+        if t <= first.time {
+            return Some(BoneKey { time: t, ..*first });
+        }
+        if t >= last.time {
+            return Some(BoneKey { time: t, ..*last });
+        }
+        // keys are sorted, so find the first key after t
+        let next = self.keys.iter().position(|k| k.time > t)?;
+        let k0 = &self.keys[next - 1];
+        let k1 = &self.keys[next];
+        let span = k1.time - k0.time;
+        let f = if span > 0.0 { (t - k0.time) / span } else { 0.0 };
+        let lerp = |a: f32, b: f32| a + (b - a) * f;
+        // nlerp the rotation, taking the short way around
+        let dot: f32 = (0..4).map(|i| k0.rotation[i] * k1.rotation[i]).sum();
+        let sign = if dot < 0.0 { -1.0 } else { 1.0 };
+        let mut rotation = [0.0; 4];
+        for i in 0..4 {
+            rotation[i] = lerp(k0.rotation[i], k1.rotation[i] * sign);
+        }
+        Some(BoneKey {
+            time: t,
+            translation: [lerp(k0.translation[0], k1.translation[0]),
+                lerp(k0.translation[1], k1.translation[1]),
+                lerp(k0.translation[2], k1.translation[2])],
+            rotation: normalize_quat(rotation),
+            scale: [lerp(k0.scale[0], k1.scale[0]),
+                lerp(k0.scale[1], k1.scale[1]),
+                lerp(k0.scale[2], k1.scale[2])],
+        })
+    }
+}
+
+fn normalize_quat(q: [f32; 4]) -> [f32; 4] {
+    let len = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    if len <= f32::EPSILON {
+        return [0.0, 0.0, 0.0, 1.0];
+    }
+    [q[0] / len, q[1] / len, q[2] / len, q[3] / len]
+}
+
+/// Quaternion (x y z w) from a pure rotation matrix (row major, column vector convention).
+fn quat_from_matrix(m: &[[f32; 3]; 3]) -> [f32; 4] {
+    let trace = m[0][0] + m[1][1] + m[2][2];
+    let q = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        [(m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s, 0.25 * s]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+        [0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s, (m[2][1] - m[1][2]) / s]
+    } else if m[1][1] > m[2][2] {
+        let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+        [(m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s, (m[0][2] - m[2][0]) / s]
+    } else {
+        let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+        [(m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s, (m[1][0] - m[0][1]) / s]
+    };
+    normalize_quat(q)
+}
+
+fn matrix_from_quat(q: &[f32; 4]) -> [[f32; 3]; 3] {
+    let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+    [
+        [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w)],
+        [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w)],
+        [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y)],
+    ]
+}
+
+/// Euler angles in degrees for the BVH "Zrotation Xrotation Yrotation" channel order,
+/// i.e. R = Rz * Rx * Ry.  Returned as (z, x, y).
+fn euler_zxy_degrees(q: &[f32; 4]) -> (f32, f32, f32) {
+    let m = matrix_from_quat(q);
+    let sx = m[2][1].max(-1.0).min(1.0);
+    let x = sx.asin();
+    let (z, y) = if sx.abs() < 0.9999 {
+        ((-m[0][1]).atan2(m[1][1]), (-m[2][0]).atan2(m[2][2]))
+    } else {
+        // gimbal lock, put all of the remaining rotation on z
+        (m[1][0].atan2(m[0][0]), 0.0)
+    };
+    (z.to_degrees(), x.to_degrees(), y.to_degrees())
+}
+
+/// Decompose the 3x4 row matrix from the registers into a key.
+fn decompose(rows: &[[f32; 4]; 3], time: f32, flip_z: bool) -> BoneKey {
+    let mut m = [[0.0f32; 3]; 3];
+    let mut translation = [0.0f32; 3];
+    for r in 0..3 {
+        m[r] = [rows[r][0], rows[r][1], rows[r][2]];
+        translation[r] = rows[r][3];
+    }
+    if flip_z {
+        // S * M * S with S = diag(1,1,-1)
+        m[0][2] = -m[0][2];
+        m[1][2] = -m[1][2];
+        m[2][0] = -m[2][0];
+        m[2][1] = -m[2][1];
+        translation[2] = -translation[2];
+    }
+    // scale is the length of each basis column
+    let mut scale = [0.0f32; 3];
+    for c in 0..3 {
+        scale[c] = (m[0][c] * m[0][c] + m[1][c] * m[1][c] + m[2][c] * m[2][c]).sqrt();
+    }
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det < 0.0 {
+        scale[0] = -scale[0];
+    }
+    let mut rot = m;
+    for c in 0..3 {
+        if scale[c].abs() > f32::EPSILON {
+            for r in 0..3 {
+                rot[r][c] /= scale[c];
+            }
+        }
+    }
+    BoneKey {
+        time,
+        translation,
+        rotation: quat_from_matrix(&rot),
+        scale,
+    }
+}
+
+/// Build a track for each bone in the mapping.  Frames are ordered by `sequence_offset`, and a frame
+/// is skipped for a bone if any of the bone's three registers is missing from it.
+pub fn build_tracks(frames: &AnimFrameFile, conf: &AnimExportConfig) -> Vec<BoneTrack> {
+    let mut ordered: Vec<_> = frames.frames.iter().collect();
+    ordered.sort_by_key(|f| f.sequence_offset);
+    let start = ordered.first().map(|f| f.sequence_offset);
+    let flip_z = conf.flip_z.unwrap_or(false);
+
+    conf.bones.iter().enumerate().map(|(idx, bone)| {
+        let name = bone.name.clone().unwrap_or_else(|| format!("bone{}", idx));
+        let keys = ordered.iter().filter_map(|frame| {
+            let mut rows = [[0.0f32; 4]; 3];
+            for r in 0..3 {
+                rows[r] = frame.floats.get(&(bone.reg + r as UINT))?.to_array();
+            }
+            let time = start
+                .map(|s| frame.sequence_offset.saturating_sub(s).as_secs_f32())
+                .unwrap_or(0.0);
+            Some(decompose(&rows, time, flip_z))
+        }).collect();
+        BoneTrack { name, keys }
+    }).collect()
+}
+
+/// Time step to use for fixed rate formats; the average interval between frames.
+fn average_frame_time(tracks: &[BoneTrack]) -> (f32, usize) {
+    const DEF_FRAME_TIME: f32 = 1.0 / 30.0;
+    let longest = tracks.iter().max_by_key(|t| t.keys.len());
+    match longest {
+        Some(t) if t.keys.len() > 1 => {
+            let dur = t.keys[t.keys.len() - 1].time - t.keys[0].time;
+            if dur > 0.0 {
+                (dur / (t.keys.len() - 1) as f32, t.keys.len())
+            } else {
+                (DEF_FRAME_TIME, t.keys.len())
+            }
+        },
+        Some(t) => (DEF_FRAME_TIME, t.keys.len()),
+        None => (DEF_FRAME_TIME, 0),
+    }
+}
+
+pub fn to_bvh(tracks: &[BoneTrack]) -> String {
+    use std::fmt::Write;
+
+    let (frame_time, num_frames) = average_frame_time(tracks);
+    let channels = "CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation";
+
+    // fmt::Write to a String is infallible, so the results are ignored below
+    let mut out = String::new();
+    let _ = writeln!(out, "HIERARCHY");
+    let _ = writeln!(out, "ROOT root");
+    let _ = writeln!(out, "{{");
+    let _ = writeln!(out, "\tOFFSET 0.0 0.0 0.0");
+    let _ = writeln!(out, "\t{}", channels);
+    for t in tracks {
+        let _ = writeln!(out, "\tJOINT {}", t.name.replace(char::is_whitespace, "_"));
+        let _ = writeln!(out, "\t{{");
+        let _ = writeln!(out, "\t\tOFFSET 0.0 0.0 0.0");
+        let _ = writeln!(out, "\t\t{}", channels);
+        let _ = writeln!(out, "\t\tEnd Site");
+        let _ = writeln!(out, "\t\t{{");
+        let _ = writeln!(out, "\t\t\tOFFSET 0.0 0.0 0.0");
+        let _ = writeln!(out, "\t\t}}");
+        let _ = writeln!(out, "\t}}");
+    }
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out, "MOTION");
+    let _ = writeln!(out, "Frames: {}", num_frames);
+    let _ = writeln!(out, "Frame Time: {:.6}", frame_time);
+    let start = tracks.iter().filter_map(|t| t.keys.first()).map(|k| k.time)
+        .fold(f32::MAX, f32::min);
+    let start = if start == f32::MAX { 0.0 } else { start };
+    for fidx in 0..num_frames {
+        let time = start + fidx as f32 * frame_time;
+        // root doesn't move
+        let mut line = String::from("0.0 0.0 0.0 0.0 0.0 0.0");
+        for t in tracks {
+            let key = t.sample(time).unwrap_or(BoneKey {
+                time,
+                translation: [0.0; 3],
+                rotation: [0.0, 0.0, 0.0, 1.0],
+                scale: [1.0; 3],
+            });
+            let (rz, rx, ry) = euler_zxy_degrees(&key.rotation);
+            let _ = write!(line, " {:.6} {:.6} {:.6} {:.6} {:.6} {:.6}",
+                key.translation[0], key.translation[1], key.translation[2], rz, rx, ry);
+        }
+        let _ = writeln!(out, "{}", line);
+    }
+    out
+}
+
+/// Returns the glTF json document and the contents of the binary buffer it references
+/// as `bin_name`.
+pub fn to_gltf(tracks: &[BoneTrack], bin_name: &str) -> (serde_json::Value, Vec<u8>) {
+    use serde_json::json;
+
+    let mut buffer: Vec<u8> = vec![];
+    let mut buffer_views = vec![];
+    let mut accessors = vec![];
+    let mut samplers = vec![];
+    let mut channels = vec![];
+    let mut nodes = vec![];
+
+    // append floats to the buffer, add a view and an accessor for them, return accessor index
+    let mut add_accessor = |data: Vec<f32>, count: usize, atype: &str, minmax: Option<(f32, f32)>| {
+        let offset = buffer.len();
+        for f in data.iter() {
+            buffer.extend_from_slice(&f.to_le_bytes());
+        }
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+        }));
+        let mut acc = json!({
+            "bufferView": buffer_views.len() - 1,
+            "componentType": 5126, // FLOAT
+            "count": count,
+            "type": atype,
+        });
+        if let Some((min, max)) = minmax {
+            acc["min"] = json!([min]);
+            acc["max"] = json!([max]);
+        }
+        accessors.push(acc);
+        accessors.len() - 1
+    };
+
+    for (nidx, t) in tracks.iter().enumerate() {
+        nodes.push(json!({ "name": t.name }));
+        if t.keys.is_empty() {
+            continue;
+        }
+        let count = t.keys.len();
+        let times: Vec<f32> = t.keys.iter().map(|k| k.time).collect();
+        let tmin = times.iter().cloned().fold(f32::MAX, f32::min);
+        let tmax = times.iter().cloned().fold(f32::MIN, f32::max);
+        let input = add_accessor(times, count, "SCALAR", Some((tmin, tmax)));
+        let translations = t.keys.iter().flat_map(|k| k.translation.to_vec()).collect();
+        let rotations = t.keys.iter().flat_map(|k| k.rotation.to_vec()).collect();
+        let scales = t.keys.iter().flat_map(|k| k.scale.to_vec()).collect();
+        let outputs = [
+            ("translation", add_accessor(translations, count, "VEC3", None)),
+            ("rotation", add_accessor(rotations, count, "VEC4", None)),
+            ("scale", add_accessor(scales, count, "VEC3", None)),
+        ];
+        for (path, output) in outputs.iter() {
+            samplers.push(json!({
+                "input": input,
+                "output": output,
+                "interpolation": "LINEAR",
+            }));
+            channels.push(json!({
+                "sampler": samplers.len() - 1,
+                "target": { "node": nidx, "path": path },
+            }));
+        }
+    }
+
+    let root_idx = nodes.len();
+    nodes.push(json!({
+        "name": "root",
+        "children": (0..tracks.len()).collect::<Vec<_>>(),
+    }));
+
+    let doc = json!({
+        "asset": { "version": "2.0", "generator": "ModelMod anim export" },
+        "scene": 0,
+        "scenes": [ { "nodes": [root_idx] } ],
+        "nodes": nodes,
+        "animations": [ { "name": "snapped", "samplers": samplers, "channels": channels } ],
+        "accessors": accessors,
+        "bufferViews": buffer_views,
+        "buffers": [ { "uri": bin_name, "byteLength": buffer.len() } ],
+    });
+    (doc, buffer)
+}
+
+/// Returns a warning naming the bones that are scaled (by more than a small tolerance) in some
+/// key, or None if there are none.  Used for BVH, which can't represent scale.
+pub fn scale_warning(tracks: &[BoneTrack]) -> Option<String> {
+    const TOLERANCE: f32 = 0.01;
+    let scaled: Vec<&str> = tracks.iter()
+        .filter(|t| t.keys.iter().any(|k| k.scale.iter().any(|s| (s.abs() - 1.0).abs() > TOLERANCE)))
+        .map(|t| t.name.as_str())
+        .collect();
+    if scaled.is_empty() {
+        None
+    } else {
+        Some(format!("BVH can't store scale, it was dropped for bones: {}", scaled.join(", ")))
+    }
+}
+
+/// Export the frames to `out_base` + the extension for the configured format.
+/// Returns the name of the main output file.
+pub fn export(frames: &AnimFrameFile, conf: &AnimExportConfig, out_base: &str) -> Result<String> {
+    if conf.bones.is_empty() {
+        return Err(HookError::SnapshotFailed("anim export: no bones in mapping".to_owned()));
+    }
+    let tracks = build_tracks(frames, conf);
+    let out_file = format!("{}.{}", out_base, conf.format.extension());
+    match conf.format {
+        AnimExportFormat::Bvh => {
+            if let Some(warning) = scale_warning(&tracks) {
+                write_log_file(&format!("WARNING: anim export: {}", warning));
+            }
+            std::fs::write(&out_file, to_bvh(&tracks))?;
+        },
+        AnimExportFormat::Gltf => {
+            let bin_file = format!("{}.bin", out_base);
+            // the uri in the gltf is relative to the gltf file
+            let bin_name = std::path::Path::new(&bin_file).file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_else(|| bin_file.clone());
+            let (doc, buffer) = to_gltf(&tracks, &bin_name);
+            let s = serde_json::to_string_pretty(&doc).map_err(|e| {
+                HookError::SerdeError(format!("Serialization error: {:?}", e))
+            })?;
+            std::fs::write(&bin_file, buffer)?;
+            std::fs::write(&out_file, s)?;
+        },
+    }
+    Ok(out_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anim_frame::AnimFrame;
+    use constant_tracking::Vec4;
+    use std::time::{Duration, SystemTime};
+
+    fn frame_with_bone(offset: Duration, reg: UINT, rows: [[f32; 4]; 3]) -> AnimFrame {
+        let mut floats = std::collections::BTreeMap::new();
+        for r in 0..3 {
+            let row = rows[r];
+            floats.insert(reg + r as UINT, Vec4::new(row[0], row[1], row[2], row[3]));
+        }
+        AnimFrame {
+            snapped_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000) + offset,
+            sequence_offset: offset,
+            floats,
+            transform1: None,
+            transform2: None,
+            transform3: None,
+            transform4: None,
+        }
+    }
+
+    fn conf(format: AnimExportFormat) -> AnimExportConfig {
+        AnimExportConfig {
+            format,
+            bones: vec![BoneMapping { name: Some("spine".to_owned()), reg: 10 }],
+            flip_z: None,
+        }
+    }
+
+    fn test_frames() -> AnimFrameFile {
+        let mut aff = AnimFrameFile::new();
+        // second frame first to check sorting; 90 degrees about z, translated and scaled by 2
+        aff.frames.push(frame_with_bone(Duration::from_millis(100), 10,
+            [[0.0, -2.0, 0.0, 1.0], [2.0, 0.0, 0.0, 2.0], [0.0, 0.0, 2.0, 3.0]]));
+        aff.frames.push(frame_with_bone(Duration::ZERO, 10,
+            [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]]));
+        aff
+    }
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    #[test]
+    fn test_build_tracks() {
+        let tracks = build_tracks(&test_frames(), &conf(AnimExportFormat::Gltf));
+        assert_eq!(tracks.len(), 1);
+        let keys = &tracks[0].keys;
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].time, 0.0);
+        assert_eq!(keys[0].rotation, [0.0, 0.0, 0.0, 1.0]);
+        assert!(approx(keys[1].time, 0.1));
+        assert_eq!(keys[1].translation, [1.0, 2.0, 3.0]);
+        assert!(keys[1].scale.iter().all(|s| approx(*s, 2.0)));
+        let h = std::f32::consts::FRAC_1_SQRT_2;
+        let r = keys[1].rotation;
+        assert!(approx(r[0], 0.0) && approx(r[1], 0.0) && approx(r[2], h) && approx(r[3], h),
+            "unexpected rotation: {:?}", r);
+        let (z, x, y) = euler_zxy_degrees(&r);
+        assert!(approx(z, 90.0) && approx(x, 0.0) && approx(y, 0.0));
+
+        // halfway sample is 45 degrees
+        let mid = tracks[0].sample(0.05).expect("doh");
+        let (z, _, _) = euler_zxy_degrees(&mid.rotation);
+        assert!(approx(z, 45.0));
+    }
+
+    #[test]
+    fn test_wall_clock_step_ignored() {
+        // the wall clock went back an hour between the frames, timing comes from the offsets
+        let mut frames = test_frames();
+        frames.frames[0].snapped_at = frames.frames[1].snapped_at - Duration::from_secs(3600);
+        let tracks = build_tracks(&frames, &conf(AnimExportFormat::Gltf));
+        let keys = &tracks[0].keys;
+        assert_eq!(keys[0].time, 0.0);
+        assert_eq!(keys[0].translation, [0.0, 0.0, 0.0]);
+        assert!(approx(keys[1].time, 0.1));
+        assert_eq!(keys[1].translation, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_missing_register_skips_frame() {
+        let mut frames = test_frames();
+        frames.frames[0].floats.remove(&12);
+        let tracks = build_tracks(&frames, &conf(AnimExportFormat::Bvh));
+        assert_eq!(tracks[0].keys.len(), 1);
+    }
+
+    #[test]
+    fn test_bvh_and_gltf() {
+        let tracks = build_tracks(&test_frames(), &conf(AnimExportFormat::Bvh));
+        let bvh = to_bvh(&tracks);
+        assert!(bvh.starts_with("HIERARCHY"));
+        assert!(bvh.contains("JOINT spine"));
+        assert!(bvh.contains("Frames: 2"));
+        assert!(bvh.contains("Frame Time: 0.100000"));
+        // the second key is scaled by 2
+        assert!(scale_warning(&tracks).expect("doh").contains("spine"));
+        let unscaled = BoneTrack { name: "arm".to_owned(), keys: vec![tracks[0].keys[0]] };
+        assert_eq!(scale_warning(&[unscaled]), None);
+
+        let (doc, buffer) = to_gltf(&tracks, "anim.bin");
+        // 2 keys * (1 time + 3 trans + 4 rot + 3 scale) floats
+        assert_eq!(buffer.len(), 2 * 11 * 4);
+        assert_eq!(doc["buffers"][0]["byteLength"], buffer.len());
+        assert_eq!(doc["animations"][0]["channels"].as_array().expect("doh").len(), 3);
+        assert_eq!(doc["nodes"][1]["children"][0], 0);
+    }
+}
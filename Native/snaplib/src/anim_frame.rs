@@ -12,7 +12,7 @@ use shared_dx::error::*;
 use shared_dx::util;
 
 // use std::collections::HashMap;
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 
 use constant_tracking::*;
 
@@ -44,10 +44,13 @@ where T: Serialize {
     Ok(())
 }
 
-#[derive(Serialize)]
+#[derive(Serialize,Deserialize)]
 #[repr(C)]
 pub struct AnimFrame {
     pub snapped_at: std::time::SystemTime,
+    /// Monotonic time since the start of the sequence.  Unlike `snapped_at` this can't jump if
+    /// the wall clock changes, so exports use it for ordering and timing.
+    pub sequence_offset: std::time::Duration,
     pub floats: std::collections::BTreeMap<UINT, Vec4<f32>>,
     pub transform1: Option<Vec4<f32>>,
     pub transform2: Option<Vec4<f32>>,
@@ -55,7 +58,7 @@ pub struct AnimFrame {
     pub transform4: Option<Vec4<f32>>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize,Deserialize)]
 pub struct AnimFrameFile {
     pub frames:Vec<AnimFrame>
 }
@@ -77,6 +80,14 @@ impl AnimFrameFile {
         file.write_all(&s)?;
         Ok(())
     }
+
+    /// Load a frame file previously written by `write_to_file`, see the `anim_export` bin.
+    pub fn read_from_file(name:&str) -> Result<Self> {
+        let bytes = std::fs::read(name)?;
+        bincode::deserialize(&bytes).map_err(|e| {
+            HookError::SerdeError(format!("Deserialization error: {:?}", e))
+        })
+    }
 }
 
 pub fn write_to_file(name:&str, constants:&ConstantGroup) -> Result<()> {
@@ -1,23 +1,55 @@
 use shared_dx::defs_dx9::UINT;
-use std::time::SystemTime;
-use shared_dx::error::Result;
+use std::time::{Duration, Instant, SystemTime};
 
 use constant_tracking;
+use crate::anim_frame::AnimFrame;
+use crate::frame_context::{FrameContext, FrameContextProvider, FrameTransforms};
 pub struct AnimConstants {
     pub snapped_at: SystemTime,
+    /// Monotonic time since the start of the sequence, used for export timing
+    pub sequence_offset: Duration,
     pub prim_count: UINT,
     pub vert_count: UINT,
     pub constants: constant_tracking::ConstantGroup,
     pub sequence: usize,
     pub frame: u64,
     pub capture_count: u32,
-    pub player_transform: Result<String>,
+    /// Extra transforms from the frame context provider, if any
+    pub transforms: FrameTransforms,
     pub snap_on_count: u32,
     // currently these matrices are not captured because they are identity
     // worldmat: D3DMATRIX,
     // viewmat: D3DMATRIX,
     // projmat: D3DMATRIX,
 }
+
+impl AnimConstants {
+    /// Ask the provider for the extra transforms of this capture.
+    pub fn capture_transforms(&mut self, provider: &mut dyn FrameContextProvider) {
+        let ctx = FrameContext {
+            snapped_at: self.snapped_at,
+            prim_count: self.prim_count,
+            vert_count: self.vert_count,
+            frame: self.frame,
+            sequence: self.sequence,
+        };
+        self.transforms = provider.frame_transforms(&ctx);
+    }
+
+    /// The frame that is written to the frame file for this capture.
+    pub fn to_frame(&self) -> AnimFrame {
+        AnimFrame {
+            snapped_at: self.snapped_at,
+            sequence_offset: self.sequence_offset,
+            floats: self.constants.floats.get_as_btree(),
+            transform1: self.transforms[0],
+            transform2: self.transforms[1],
+            transform3: self.transforms[2],
+            transform4: self.transforms[3],
+        }
+    }
+}
+
 use std::collections::{HashSet,HashMap}; // TODO: make sure i'm not using the slow hash function version of these
 pub struct AnimSnapState {
     pub sequence_vconstants:Vec<AnimConstants>,
@@ -26,8 +58,89 @@ pub struct AnimSnapState {
     pub capture_count_this_frame: HashMap<(UINT,UINT), u32>,
     pub seen_all: bool,
     pub next_vconst_idx: usize,
-    pub sequence_start_time: SystemTime,
+    pub sequence_start_time: Instant,
     pub curr_frame: u64,
     pub start_frame: u64,
     pub snap_dir: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constant_tracking::Vec4;
+    use crate::anim_frame::{write_obj_to_file, AnimFrameFile};
+    use crate::frame_context::NullFrameContextProvider;
+
+    /// Knows two of the four transforms.
+    struct PartialProvider;
+
+    impl FrameContextProvider for PartialProvider {
+        fn name(&self) -> &str {
+            "partial"
+        }
+        fn frame_transforms(&mut self, ctx: &FrameContext) -> FrameTransforms {
+            let f = ctx.frame as f32;
+            [Some(Vec4::new(f, 1.0, 2.0, 3.0)), None, Some(Vec4::new(4.0, 5.0, 6.0, f)), None]
+        }
+    }
+
+    fn capture(frame: u64) -> AnimConstants {
+        let mut constants = constant_tracking::ConstantGroup::new();
+        let floats = [0.5f32, 1.0, 2.0, 3.0];
+        constants.floats.set(2, floats.as_ptr(), 1);
+        AnimConstants {
+            snapped_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            sequence_offset: Duration::from_millis(frame * 16),
+            prim_count: 100,
+            vert_count: 50,
+            constants,
+            sequence: 0,
+            frame,
+            capture_count: 1,
+            transforms: [None; 4],
+            snap_on_count: 1,
+        }
+    }
+
+    fn round_trip(frame: &AnimFrame, name: &str) -> AnimFrame {
+        let dir = std::env::temp_dir().join("__test_anim_snap_state");
+        std::fs::create_dir_all(&dir).expect("doh");
+        let path = dir.join(name).to_string_lossy().to_string();
+        write_obj_to_file(&path, false, frame).expect("doh");
+        serde_yaml::from_str(&std::fs::read_to_string(&path).expect("doh")).expect("doh")
+    }
+
+    #[test]
+    fn test_partial_transforms() {
+        let mut c = capture(7);
+        c.capture_transforms(&mut PartialProvider);
+        let frame = round_trip(&c.to_frame(), "partial.yaml");
+        assert_eq!(frame.transform1, Some(Vec4::new(7.0, 1.0, 2.0, 3.0)));
+        assert_eq!(frame.transform2, None);
+        assert_eq!(frame.transform3, Some(Vec4::new(4.0, 5.0, 6.0, 7.0)));
+        assert_eq!(frame.transform4, None);
+        assert_eq!(frame.floats.get(&2), Some(&Vec4::new(0.5, 1.0, 2.0, 3.0)));
+        assert_eq!(frame.sequence_offset, Duration::from_millis(7 * 16));
+        assert_eq!(frame.snapped_at, c.snapped_at);
+    }
+
+    #[test]
+    fn test_null_provider() {
+        let mut c = capture(3);
+        c.transforms = [Some(Vec4::new(1.0, 1.0, 1.0, 1.0)); 4];
+        c.capture_transforms(&mut NullFrameContextProvider);
+        assert_eq!(c.transforms, [None; 4]);
+        let frame = round_trip(&c.to_frame(), "null.yaml");
+        assert_eq!([frame.transform1, frame.transform2, frame.transform3, frame.transform4], [None; 4]);
+
+        // and the frame file used by the exporter
+        let mut aff = AnimFrameFile::new();
+        aff.frames.push(c.to_frame());
+        let path = std::env::temp_dir().join("__test_anim_snap_state").join("null.dat")
+            .to_string_lossy().to_string();
+        aff.write_to_file(&path).expect("doh");
+        let aff = AnimFrameFile::read_from_file(&path).expect("doh");
+        assert_eq!(aff.frames.len(), 1);
+        assert_eq!(aff.frames[0].transform1, None);
+    }
+}
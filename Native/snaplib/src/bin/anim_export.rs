@@ -0,0 +1,51 @@
+//! Exports a snapped animation frame file to glTF or BVH.
+//!
+//! Usage: `anim_export <frames .dat file> <mapping .yaml> [output base]`
+//!
+//! The mapping has the same fields as the `anim_export` section of the snap config (`format`,
+//! `bones` and optionally `flip_z`), so files from snapshots taken without that section can be
+//! converted later.  The output goes next to the frame file unless a base name (without
+//! extension) is given.
+use snaplib::anim_export::{self, AnimExportConfig, AnimExportFormat};
+use snaplib::anim_frame::AnimFrameFile;
+
+fn usage() -> ! {
+    eprintln!("usage: anim_export <frames .dat file> <mapping .yaml> [output base]");
+    std::process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 || args.len() > 3 || args.iter().any(|a| a == "-h" || a == "--help") {
+        usage();
+    }
+    let (frames_file, mapping_file) = (&args[0], &args[1]);
+    let out_base = args.get(2).cloned().unwrap_or_else(|| {
+        std::path::Path::new(frames_file).with_extension("").to_string_lossy().to_string()
+    });
+
+    let frames = AnimFrameFile::read_from_file(frames_file).unwrap_or_else(|e| {
+        eprintln!("error: can't read frames from {}: {:?}", frames_file, e);
+        std::process::exit(1);
+    });
+    let conf: AnimExportConfig = std::fs::read_to_string(mapping_file)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_yaml::from_str(&text).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("error: can't read mapping from {}: {}", mapping_file, e);
+            std::process::exit(1);
+        });
+
+    if conf.format == AnimExportFormat::Bvh {
+        if let Some(warning) = anim_export::scale_warning(&anim_export::build_tracks(&frames, &conf)) {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    match anim_export::export(&frames, &conf, &out_base) {
+        Ok(out) => println!("wrote {} frames to {}", frames.frames.len(), out),
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
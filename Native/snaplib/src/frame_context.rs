@@ -0,0 +1,37 @@
+use std::time::SystemTime;
+
+use shared_dx::defs_dx9::UINT;
+use constant_tracking::Vec4;
+
+/// Extra per-frame transforms that go into the `transform1..4` slots of an `AnimFrame`.
+/// Any slot the provider doesn't know about is left as None.
+pub type FrameTransforms = [Option<Vec4<f32>>; 4];
+
+/// Describes the capture that a provider is being asked about.
+pub struct FrameContext {
+    pub snapped_at: SystemTime,
+    pub prim_count: UINT,
+    pub vert_count: UINT,
+    pub frame: u64,
+    pub sequence: usize,
+}
+
+/// Supplies optional extra data for each anim capture, for instance the player position from
+/// some external game specific tool.  Called on the render thread each time a set of constants is
+/// captured, so implementations should be quick.
+pub trait FrameContextProvider: Send {
+    fn name(&self) -> &str;
+    fn frame_transforms(&mut self, ctx: &FrameContext) -> FrameTransforms;
+}
+
+/// Default provider, supplies nothing.
+pub struct NullFrameContextProvider;
+
+impl FrameContextProvider for NullFrameContextProvider {
+    fn name(&self) -> &str {
+        "none"
+    }
+    fn frame_transforms(&mut self, _ctx: &FrameContext) -> FrameTransforms {
+        [None; 4]
+    }
+}
@@ -0,0 +1,160 @@
+//! Index helpers for triangle strips and index ranges.
+//!
+//! In D3D11 strips are always allowed to contain restart (strip cut) indices; the cut value is
+//! the max value of the index format.  Each run of indices between cuts is an independent strip,
+//! with every other triangle's winding reversed.  The prim count of a strip is the sum of
+//! `run length - 2` over the runs; triangles that span a cut aren't drawn and aren't counted.
+//! Degenerate triangles that games use to join strips instead of cuts are part of a run, so they
+//! are counted (and kept when converting to a list).
+//!
+//! The index data is only available when it was precopied (which snapshots need anyway).
+//! Without it the cuts can't be found, so mod keys fall back to `strip_prim_count_fallback`,
+//! which is only right for strips without cuts.
+//!
+//! The index range of a draw is the span of vertices it actually references, which can be much
+//! smaller than the bound vertex buffer when several meshes share one buffer.
+
+pub const RESTART_INDEX_16: u32 = 0xFFFF;
+pub const RESTART_INDEX_32: u32 = 0xFFFF_FFFF;
+
+/// Returns the strip cut value for the index size (in bytes).
+pub fn restart_index(index_size:usize) -> u32 {
+    if index_size == 2 { RESTART_INDEX_16 } else { RESTART_INDEX_32 }
+}
+
+/// Read `count` indices of `index_size` bytes starting at index `start`.  Returns None if the
+/// data is too short or the index size isn't 2 or 4.
+pub fn read_indices(data:&[u8], index_size:usize, start:usize, count:usize) -> Option<Vec<u32>> {
+    if index_size != 2 && index_size != 4 {
+        return None;
+    }
+    let begin = start.checked_mul(index_size)?;
+    let end = begin.checked_add(count.checked_mul(index_size)?)?;
+    let bytes = data.get(begin..end)?;
+    Some(bytes.chunks_exact(index_size).map(|c| match index_size {
+        2 => u16::from_le_bytes([c[0], c[1]]) as u32,
+        _ => u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+    }).collect())
+}
+
+/// Inverse of `read_indices`.
+pub fn write_indices(indices:&[u32], index_size:usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(indices.len() * index_size);
+    for i in indices {
+        match index_size {
+            2 => out.extend_from_slice(&(*i as u16).to_le_bytes()),
+            _ => out.extend_from_slice(&i.to_le_bytes()),
+        }
+    }
+    out
+}
+
+/// Number of triangles drawn by a strip, counting each run between cuts separately.
+pub fn strip_prim_count(indices:&[u32], restart:u32) -> u32 {
+    indices.split(|i| *i == restart)
+        .map(|run| run.len().saturating_sub(2) as u32)
+        .sum()
+}
+
+/// Prim count of a strip of `index_count` indices when the indices aren't available, see the
+/// module doc.
+pub fn strip_prim_count_fallback(index_count:u32) -> u32 {
+    index_count.saturating_sub(2)
+}
+
+/// Convert a strip (which may contain cuts) to a triangle list with the same winding and
+/// `strip_prim_count` prims.
+pub fn strip_to_list(indices:&[u32], restart:u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(strip_prim_count(indices, restart) as usize * 3);
+    for run in indices.split(|i| *i == restart) {
+        // the winding alternates from the start of each run
+        for (t, tri) in run.windows(3).enumerate() {
+            if t % 2 == 0 {
+                out.extend_from_slice(&[tri[0], tri[1], tri[2]]);
+            } else {
+                out.extend_from_slice(&[tri[1], tri[0], tri[2]]);
+            }
+        }
+    }
+    out
+}
+
+/// Lowest and highest index used, as (min,max).  If `restart` is set, those indices are cuts and
+/// are skipped.  Returns None if no vertex is referenced.
+pub fn index_range(indices:&[u32], restart:Option<u32>) -> Option<(u32,u32)> {
+    indices.iter()
+        .filter(|i| Some(**i) != restart)
+        .fold(None, |range, i| match range {
+            None => Some((*i, *i)),
+            Some((lo, hi)) => Some((lo.min(*i), hi.max(*i))),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_to_list() {
+        let strip = [0, 1, 2, 3, 4];
+        assert_eq!(strip_prim_count(&strip, RESTART_INDEX_16), 3);
+        assert_eq!(strip_prim_count_fallback(strip.len() as u32), 3);
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_16), vec![0,1,2, 2,1,3, 2,3,4]);
+
+        // cuts start a new strip with the winding reset, nothing is drawn across them
+        let strip = [0, 1, 2, 3, RESTART_INDEX_16, 4, 5, 6, RESTART_INDEX_16, 7, 8, RESTART_INDEX_16];
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_16), vec![0,1,2, 2,1,3, 4,5,6]);
+        let strip = [RESTART_INDEX_32, RESTART_INDEX_32, RESTART_INDEX_32, 5, 6, 7];
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_32), vec![5,6,7]);
+        // a max value index is only a cut for its own index size
+        let strip = [0, 1, RESTART_INDEX_16, 2, 3];
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_32), vec![0,1,0xFFFF, 0xFFFF,1,2, 0xFFFF,2,3]);
+
+        // degenerates that join strips are part of the run, so they are kept
+        let strip = [0, 1, 2, 2, 3, 3, 4, 5];
+        let list = strip_to_list(&strip, RESTART_INDEX_32);
+        assert_eq!(list.len() / 3, strip_prim_count(&strip, RESTART_INDEX_32) as usize);
+        assert_eq!(list.len() / 3, 6);
+    }
+
+    #[test]
+    fn test_strip_prim_count() {
+        assert_eq!(strip_prim_count(&[], RESTART_INDEX_16), 0);
+        assert_eq!(strip_prim_count(&[0, 1], RESTART_INDEX_16), 0);
+        assert_eq!(strip_prim_count(&[0, 1, 2, 3], RESTART_INDEX_16), 2);
+
+        // 4 + 2 + 0 triangles, the fallback counts the 5 that span a cut too
+        let strip = [0, 1, 2, 3, 4, 5, RESTART_INDEX_16, 6, 7, 8, 9, RESTART_INDEX_16, 10, 11];
+        assert_eq!(strip_prim_count(&strip, RESTART_INDEX_16), 6);
+        assert_eq!(strip_prim_count_fallback(strip.len() as u32), 12);
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_16).len(), 6 * 3);
+
+        let strip = [RESTART_INDEX_32, 0, 1, 2, RESTART_INDEX_32, RESTART_INDEX_32, 3, 4, 5, 6, RESTART_INDEX_32];
+        assert_eq!(strip_prim_count(&strip, RESTART_INDEX_32), 3);
+        assert_eq!(strip_to_list(&strip, RESTART_INDEX_32), vec![0,1,2, 3,4,5, 5,4,6]);
+        assert_eq!(strip_prim_count(&[RESTART_INDEX_32; 4], RESTART_INDEX_32), 0);
+    }
+
+    #[test]
+    fn test_read_write_indices() {
+        let data = write_indices(&[1, 2, 0xFFFF, 3], 2);
+        assert_eq!(data.len(), 8);
+        assert_eq!(read_indices(&data, 2, 1, 3), Some(vec![2, 0xFFFF, 3]));
+        assert_eq!(read_indices(&data, 2, 2, 3), None);
+        let data = write_indices(&[7, RESTART_INDEX_32], 4);
+        assert_eq!(read_indices(&data, 4, 0, 2), Some(vec![7, RESTART_INDEX_32]));
+        assert_eq!(restart_index(4), RESTART_INDEX_32);
+        assert_eq!(read_indices(&data, 3, 0, 1), None);
+    }
+
+    #[test]
+    fn test_index_range() {
+        assert_eq!(index_range(&[5, 3, 9, 4], None), Some((3, 9)));
+        assert_eq!(index_range(&[], None), None);
+        // cuts are only skipped for strips
+        let strip = [7, 8, RESTART_INDEX_16, 6, 10];
+        assert_eq!(index_range(&strip, Some(RESTART_INDEX_16)), Some((6, 10)));
+        assert_eq!(index_range(&strip, None), Some((6, RESTART_INDEX_16)));
+        assert_eq!(index_range(&[RESTART_INDEX_32], Some(RESTART_INDEX_32)), None);
+    }
+}
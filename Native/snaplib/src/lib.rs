@@ -3,4 +3,11 @@ extern crate serde_yaml;
 
 pub mod anim_snap_state;
 pub mod anim_frame;
+pub mod anim_export;
+pub mod frame_context;
 pub mod snap_config;
+pub mod snap_manifest;
+pub mod render_state_d3d11;
+pub mod texture_dedup;
+pub mod vertex_streams;
+pub mod index_topology;
@@ -0,0 +1,102 @@
+//! Serializable copies of the D3D11 pipeline state that affects how a snapped mesh is drawn.
+//! Enum values (blend factors, cull mode, formats etc) are stored as the raw D3D11 values, as is
+//! done for D3D9 in `RenderStateMap`.  A state that is None was not bound, which means D3D11 is
+//! using its default for that state.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct RenderTargetBlend {
+    pub blend_enable: bool,
+    pub src_blend: u32,
+    pub dest_blend: u32,
+    pub blend_op: u32,
+    pub src_blend_alpha: u32,
+    pub dest_blend_alpha: u32,
+    pub blend_op_alpha: u32,
+    pub render_target_write_mask: u8,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct BlendState {
+    pub alpha_to_coverage_enable: bool,
+    pub independent_blend_enable: bool,
+    pub blend_factor: [f32; 4],
+    pub sample_mask: u32,
+    /// Only the first target is recorded unless independent blend is enabled
+    pub render_targets: Vec<RenderTargetBlend>,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct RasterizerState {
+    pub fill_mode: u32,
+    pub cull_mode: u32,
+    pub front_counter_clockwise: bool,
+    pub depth_bias: i32,
+    pub depth_bias_clamp: f32,
+    pub slope_scaled_depth_bias: f32,
+    pub depth_clip_enable: bool,
+    pub scissor_enable: bool,
+    pub multisample_enable: bool,
+    pub antialiased_line_enable: bool,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct StencilOp {
+    pub fail_op: u32,
+    pub depth_fail_op: u32,
+    pub pass_op: u32,
+    pub func: u32,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct DepthStencilState {
+    pub depth_enable: bool,
+    pub depth_write_mask: u32,
+    pub depth_func: u32,
+    pub stencil_enable: bool,
+    pub stencil_read_mask: u8,
+    pub stencil_write_mask: u8,
+    pub front_face: StencilOp,
+    pub back_face: StencilOp,
+    pub stencil_ref: u32,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct SamplerState {
+    pub filter: u32,
+    pub address_u: u32,
+    pub address_v: u32,
+    pub address_w: u32,
+    pub mip_lod_bias: f32,
+    pub max_anisotropy: u32,
+    pub comparison_func: u32,
+    pub border_color: [f32; 4],
+    pub min_lod: f32,
+    pub max_lod: f32,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct SrvInfo {
+    /// D3D11_SRV_DIMENSION
+    pub dimension: u32,
+    /// DXGI_FORMAT of the view
+    pub format: u32,
+    /// These are only filled in for 2D textures
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mip_levels: Option<u32>,
+}
+
+/// Contents of `_rstate.yaml` for D3D11 snapshots.  Samplers and SRVs are keyed by pixel
+/// shader slot.
+#[derive(Deserialize,Serialize,Clone,Debug,Default,PartialEq)]
+pub struct D3D11RenderStateFile {
+    pub blend: Option<BlendState>,
+    pub rasterizer: Option<RasterizerState>,
+    pub depth_stencil: Option<DepthStencilState>,
+    pub samplers: BTreeMap<u32, SamplerState>,
+    pub srvs: BTreeMap<u32, SrvInfo>,
+}
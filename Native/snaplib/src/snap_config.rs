@@ -5,17 +5,18 @@ use std::collections::HashSet;
 use std::fmt;
 use shared_dx::util::write_log_file;
 use shared_dx::error::{HookError, Result};
+use crate::anim_export::AnimExportConfig;
 
-// Snapshotting currently stops after a certain amount of real time has passed from the start of
-// the snap, specified by the config (snap_ms)
+// By default snapshotting stops after a certain amount of real time has passed from the start of
+// the snap, specified by the config (snap_ms).
 // One might expect that just snapping everything drawn within a single begin/end scene combo is
 // sufficient, but this often misses data,
 // and sometimes fails to snapshot anything at all.  This may be because the game is using multiple
-// begin/end combos, so maybe
-// present->present would be more reliable (TODO: check this)
+// begin/end combos.
 // Using a window makes it much more likely that something useful is captured, at the expense of
 // some duplicates; even though
-// some objects may still be missed.  Some investigation to make this more reliable would be useful.
+// some objects may still be missed.  The window can also be bounded by a number of presents
+// (frames) or draw calls instead of time, see `SnapWindow`.
 
 #[derive(Deserialize,Serialize,Eq,PartialEq,Copy,Clone,Debug,Hash)]
 pub struct AutoSnapMesh {
@@ -31,22 +32,88 @@ impl AutoSnapMesh {
         }
     }
 }
+/// Matches any mesh whose prim and vert counts fall in the (inclusive) ranges.  Unset bounds are
+/// open, but at least one bound must be set, otherwise the wildcard matches nothing (rather
+/// than snapping every draw call in the scene).
+#[derive(Deserialize,Serialize,Eq,PartialEq,Copy,Clone,Debug)]
+pub struct AutoSnapWildcard {
+    pub min_prims: Option<UINT>,
+    pub max_prims: Option<UINT>,
+    pub min_verts: Option<UINT>,
+    pub max_verts: Option<UINT>,
+}
+impl AutoSnapWildcard {
+    pub fn is_empty(&self) -> bool {
+        self.min_prims.is_none() && self.max_prims.is_none()
+            && self.min_verts.is_none() && self.max_verts.is_none()
+    }
+    pub fn matches(&self, prims:UINT, verts:UINT) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let in_range = |v:UINT, min:Option<UINT>, max:Option<UINT>|
+            min.map(|min| v >= min).unwrap_or(true) && max.map(|max| v <= max).unwrap_or(true);
+        in_range(prims, self.min_prims, self.max_prims) && in_range(verts, self.min_verts, self.max_verts)
+    }
+}
+impl fmt::Display for AutoSnapWildcard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = |min:Option<UINT>, max:Option<UINT>| match (min, max) {
+            (None, None) => "*".to_owned(),
+            (min, max) => format!("{}..{}",
+                min.map(|v| v.to_string()).unwrap_or_default(),
+                max.map(|v| v.to_string()).unwrap_or_default()),
+        };
+        write!(f, "{}p {}v", range(self.min_prims, self.max_prims), range(self.min_verts, self.max_verts))
+    }
+}
+
+/// Determines when a snapshot ends.  In yaml this is written as a map with a single key,
+/// e.g. `snap_window: { presents: 2 }`.
+#[derive(Deserialize,Serialize,Eq,PartialEq,Copy,Clone,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapWindow {
+    /// Milliseconds of real time
+    Time(u32),
+    /// Number of presents (frames).  If the present function isn't hooked (which can happen in
+    /// DX11, depending on how the game created its swap chain), this falls back to `snap_ms`.
+    Presents(u32),
+    /// Number of draw calls
+    Draws(u32),
+}
+impl fmt::Display for SnapWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapWindow::Time(ms) => write!(f, "{}ms", ms),
+            SnapWindow::Presents(n) => write!(f, "{} presents", n),
+            SnapWindow::Draws(n) => write!(f, "{} draws", n),
+        }
+    }
+}
+
 #[derive(Deserialize,Clone,Serialize)]
 pub struct SnapConfig {
     pub snap_ms: u32,
+    /// If not set, the window is `snap_ms` of time
+    pub snap_window: Option<SnapWindow>,
     pub snap_anim: bool,
     pub require_gpu: Option<bool>,
     pub snap_anim_on_count: u32,
     pub vconsts_to_capture: usize,
     pub pconsts_to_capture: usize,
     pub autosnap:Option<HashSet<AutoSnapMesh>>,
+    /// Range based autosnap matches, not used for anim snapping (which needs exact meshes)
+    pub autosnap_wildcards:Option<Vec<AutoSnapWildcard>>,
     pub plugins:Option<Vec<String>>,
+    /// If set, anim snap frames are also exported using this bone mapping
+    pub anim_export:Option<AnimExportConfig>,
 }
 impl fmt::Display for SnapConfig {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "SnapConfig {{")?;
         writeln!(f, "  snap_ms: {}", self.snap_ms)?;
+        writeln!(f, "  snap_window: {}", self.snap_window())?;
         writeln!(f, "  snap_anim: {}", self.snap_anim)?;
         if self.snap_anim {
             writeln!(f, "  require gpu: true (due to snap anim)")?;
@@ -68,7 +135,21 @@ impl fmt::Display for SnapConfig {
                 }
             }
         }
+        if let Some(wc) = self.autosnap_wildcards.as_ref() {
+            writeln!(f, "  autosnap wildcards:")?;
+            for w in wc.iter() {
+                if w.is_empty() {
+                    writeln!(f, "    {} (ignored, no bounds set)", w)?;
+                } else {
+                    writeln!(f, "    {}", w)?;
+                }
+            }
+        }
         writeln!(f, "  plugins: {:?}", self.plugins)?;
+        match self.anim_export.as_ref() {
+            None => writeln!(f, "  no anim export")?,
+            Some(ae) => writeln!(f, "  anim export: {:?}, {} bones", ae.format, ae.bones.len())?,
+        }
 
         writeln!(f, "}}")
     }
@@ -78,13 +159,16 @@ impl SnapConfig {
     pub fn new() -> Self {
         Self {
             snap_ms: 250, // TODO11: dx11 needs longer, 5 seconds?
+            snap_window: None,
             snap_anim: false,
             require_gpu: None,
             snap_anim_on_count: 1,
             vconsts_to_capture: 224,
             pconsts_to_capture: 224,
             autosnap: None,
+            autosnap_wildcards: None,
             plugins: None,
+            anim_export: None,
         }
     }
     pub fn max_const_sequences(&self) -> usize {
@@ -95,6 +179,20 @@ impl SnapConfig {
         seqs as usize
     }
 
+    pub fn snap_window(&self) -> SnapWindow {
+        self.snap_window.unwrap_or(SnapWindow::Time(self.snap_ms))
+    }
+
+    /// True if the mesh is in the autosnap list or matches any of the wildcards.
+    pub fn autosnap_matches(&self, prims:UINT, verts:UINT) -> bool {
+        let exact = self.autosnap.as_ref()
+            .map(|hs| hs.contains(&AutoSnapMesh { prims, verts }))
+            .unwrap_or(false);
+        exact || self.autosnap_wildcards.as_ref()
+            .map(|wc| wc.iter().any(|w| w.matches(prims, verts)))
+            .unwrap_or(false)
+    }
+
     pub fn load(rootdir:&str) -> Result<Self> {
         write_log_file("loading snap config");
 
@@ -161,4 +259,42 @@ impl SnapConfig {
     //     file.write_all(&s.as_bytes())?;
     //     Ok(())
     // }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_autosnap_matches() {
+        let mut sc = SnapConfig::new();
+        assert!(!sc.autosnap_matches(1000, 3500));
+
+        let mut hs = HashSet::new();
+        hs.insert(AutoSnapMesh::new(1000, 500));
+        sc.autosnap = Some(hs);
+        assert!(sc.autosnap_matches(1000, 500));
+        assert!(!sc.autosnap_matches(1000, 3500));
+
+        let wc: Vec<AutoSnapWildcard> = serde_yaml::from_str(
+            "- min_verts: 3000\n  max_verts: 4000\n- {}\n").expect("doh");
+        assert!(wc[1].is_empty());
+        sc.autosnap_wildcards = Some(wc);
+        assert!(sc.autosnap_matches(1000, 3000));
+        assert!(sc.autosnap_matches(7, 4000));
+        assert!(!sc.autosnap_matches(1000, 2999));
+        assert!(!sc.autosnap_matches(1000, 4001));
+        // the empty wildcard must not match everything
+        assert!(!sc.autosnap_matches(1, 1));
+    }
+
+    #[test]
+    fn test_snap_window() {
+        let mut sc = SnapConfig::new();
+        assert_eq!(sc.snap_window(), SnapWindow::Time(sc.snap_ms));
+        sc.snap_window = serde_yaml::from_str("presents: 3").expect("doh");
+        assert_eq!(sc.snap_window(), SnapWindow::Presents(3));
+        let w: SnapWindow = serde_yaml::from_str("draws: 5000").expect("doh");
+        assert_eq!(w, SnapWindow::Draws(5000));
+    }
+}
@@ -0,0 +1,229 @@
+//! Per-session index of everything written by a snapshot.
+//!
+//! The snapshot dir is shared by all sessions for an exe, and the files for each capture are
+//! only tied together by their prefix (e.g. `snap_3_1234p_567v`), so without this a tool has
+//! to glob and parse file names.  The manifest is built up as draws are captured; the file
+//! list is filled in with one scan of the directory at the end of the window (since the managed
+//! code writes some of the files, native code doesn't know all of the names) and the whole thing
+//! is then written atomically, so a tool that sees a manifest can assume the session is complete.
+//!
+//! Each session's manifest is written as `manifest_<session start unix secs>.yaml` in the
+//! snapshot dir, and the same manifest is written to `manifest.yaml`, so tools can always find the
+//! latest session at a fixed path.  The older session manifests are kept alongside the captures
+//! they describe.
+
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::path::Path;
+
+use fnv::{FnvHashMap, FnvHasher};
+use serde::{Deserialize, Serialize};
+
+use shared_dx::error::*;
+
+/// Name of the copy of the most recent session's manifest.
+pub const LATEST_MANIFEST: &str = "manifest.yaml";
+
+/// Name of the manifest for the session that started at `start_secs` (unix time).
+pub fn session_manifest_name(start_secs:u64) -> String {
+    format!("manifest_{}.yaml", start_secs)
+}
+
+/// Write the file via a temp file that is renamed into place.
+fn write_atomic(dir:&Path, file_name:&str, data:&[u8]) -> Result<String> {
+    let out = dir.join(file_name);
+    let tmp = dir.join(format!("{}.tmp", file_name));
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, &out)?;
+    Ok(out.to_string_lossy().to_string())
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+pub struct ManifestFile {
+    /// Relative to the manifest
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct ManifestDraw {
+    pub prefix: String,
+    pub prim_count: u32,
+    pub vert_count: u32,
+    pub base_vertex: i32,
+    pub start_index: u32,
+    /// D3DPRIMITIVETYPE in d3d9, D3D11_PRIMITIVE_TOPOLOGY in d3d11
+    pub topology: i32,
+    /// Number of instances drawn (d3d11 DrawIndexedInstanced), 1 if the draw wasn't instanced.
+    /// The capture is of the geometry for one instance.
+    pub instance_count: u32,
+    pub texture_stages: Vec<u32>,
+    /// Stage -> de-duplicated texture in the shared textures folder (d3d11 only).  The per-draw
+    /// texture files are links to these.
+    pub shared_textures: BTreeMap<u32, String>,
+    pub vertex_shader_hash: Option<String>,
+    pub pixel_shader_hash: Option<String>,
+    pub files: Vec<ManifestFile>,
+}
+
+impl ManifestDraw {
+    pub fn new(prefix:&str, prim_count:u32, vert_count:u32, base_vertex:i32, start_index:u32, topology:i32) -> Self {
+        Self {
+            prefix: prefix.to_owned(),
+            prim_count,
+            vert_count,
+            base_vertex,
+            start_index,
+            topology,
+            instance_count: 1,
+            texture_stages: vec![],
+            shared_textures: BTreeMap::new(),
+            vertex_shader_hash: None,
+            pixel_shader_hash: None,
+            files: vec![],
+        }
+    }
+
+    /// Add a file that was written for this draw.  Saved texture stages and shader hashes are
+    /// taken from the names.
+    fn add_file(&mut self, name:String, path:&Path, size:u64) {
+        let tex_prefix = format!("{}_texture", self.prefix);
+        if let Some(stage) = name.strip_prefix(&tex_prefix)
+            .and_then(|s| s.strip_suffix(".dds"))
+            .and_then(|s| s.parse::<u32>().ok()) {
+            self.texture_stages.push(stage);
+        }
+        if name == format!("{}_vshader.dat", self.prefix) {
+            self.vertex_shader_hash = hash_file(path).ok();
+        } else if name == format!("{}_pshader.dat", self.prefix) {
+            self.pixel_shader_hash = hash_file(path).ok();
+        }
+        self.files.push(ManifestFile { path: name, size });
+    }
+}
+
+fn hash_file(path:&Path) -> Result<String> {
+    let data = std::fs::read(path)?;
+    let mut hasher = FnvHasher::default();
+    hasher.write(&data);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,Default)]
+pub struct SnapManifest {
+    pub api: String,
+    pub started_at: String,
+    pub window: String,
+    pub duration_ms: u64,
+    pub presents: u64,
+    pub draws: u64,
+    pub textures_deduplicated: u32,
+    pub texture_bytes_saved: u64,
+    pub captures: Vec<ManifestDraw>,
+    /// Not serialized, all paths in the manifest are relative to this
+    #[serde(skip)]
+    pub dir: String,
+}
+
+impl SnapManifest {
+    pub fn new(api:&str, started_at:&str) -> Self {
+        Self {
+            api: api.to_owned(),
+            started_at: started_at.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, dir:&str, draw:ManifestDraw) {
+        if self.dir.is_empty() {
+            self.dir = dir.to_owned();
+        }
+        self.captures.push(draw);
+    }
+
+    /// Fill in the files of each capture from what is in the directory.  The directory is read
+    /// once; a file belongs to the capture whose prefix it starts with, followed by `_` or `.`.
+    pub fn scan_files(&mut self) -> Result<()> {
+        let by_prefix:FnvHashMap<String,usize> = self.captures.iter().enumerate()
+            .map(|(i,draw)| (draw.prefix.clone(), i))
+            .collect();
+        for draw in self.captures.iter_mut() {
+            draw.files.clear();
+            draw.texture_stages.clear();
+        }
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let capture = name.match_indices(|c| c == '_' || c == '.')
+                .find_map(|(i,_)| by_prefix.get(&name[..i]))
+                .copied();
+            if let Some(i) = capture {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                self.captures[i].add_file(name, &entry.path(), size);
+            }
+        }
+        for draw in self.captures.iter_mut() {
+            draw.files.sort_by(|a, b| a.path.cmp(&b.path));
+            draw.texture_stages.sort();
+        }
+        Ok(())
+    }
+
+    /// Scan the files for each capture and write the manifest to `dir/file_name` and to
+    /// `dir/manifest.yaml`.  The manifest is written to a temp file first and then renamed into
+    /// place.  Returns the full path of `file_name`.
+    pub fn write(&mut self, file_name:&str) -> Result<String> {
+        if self.dir.is_empty() {
+            return Err(HookError::SnapshotFailed("manifest has no directory".to_owned()));
+        }
+        self.scan_files()?;
+        let s = serde_yaml::to_string(&*self).map_err(|e| {
+            HookError::SerdeError(format!("Serialization error: {:?}", e))
+        })?;
+        let dir = Path::new(&self.dir);
+        let out = write_atomic(dir, file_name, s.as_bytes())?;
+        write_atomic(dir, LATEST_MANIFEST, s.as_bytes())?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_manifest() {
+        let dir = std::env::temp_dir().join("__test_snap_manifest");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("doh");
+        let write = |name:&str, data:&[u8]| std::fs::write(dir.join(name), data).expect("doh");
+        write("snap_1_100p_50v.mmobj", b"mesh");
+        write("snap_1_100p_50v_texture0.dds", b"tex0");
+        write("snap_1_100p_50v_texture2.dds", b"tex2");
+        write("snap_1_100p_50v_vshader.dat", b"vs");
+        write("snap_11_100p_50v.mmobj", b"other snap");
+
+        let dirs = dir.to_string_lossy().to_string();
+        let mut m = SnapManifest::new("d3d9", "now");
+        m.add(&dirs, ManifestDraw::new("snap_1_100p_50v", 100, 50, 0, 0, 4));
+        m.add(&dirs, ManifestDraw::new("snap_11_100p_50v", 100, 50, 0, 0, 4));
+        let out = m.write(&session_manifest_name(1600000000)).expect("doh");
+        assert!(out.ends_with("manifest_1600000000.yaml"));
+        assert!(!dir.join("manifest_1600000000.yaml.tmp").exists());
+        // the latest session is also at a fixed path
+        let latest = std::fs::read_to_string(dir.join(LATEST_MANIFEST)).expect("doh");
+        assert_eq!(latest, std::fs::read_to_string(&out).expect("doh"));
+
+        let m: SnapManifest = serde_yaml::from_str(&std::fs::read_to_string(&out).expect("doh")).expect("doh");
+        assert_eq!(m.captures.len(), 2);
+        assert_eq!(m.captures[1].files.len(), 1);
+        let draw = &m.captures[0];
+        assert_eq!(draw.files.len(), 4);
+        assert_eq!(draw.files[0], ManifestFile { path: "snap_1_100p_50v.mmobj".to_owned(), size: 4 });
+        assert_eq!(draw.texture_stages, vec![0, 2]);
+        assert!(draw.vertex_shader_hash.is_some());
+        assert!(draw.pixel_shader_hash.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
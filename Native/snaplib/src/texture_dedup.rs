@@ -0,0 +1,165 @@
+//! De-duplication of snapshot textures within a session.
+//!
+//! Each unique texture (by content hash) is stored once in the `textures/` folder under the
+//! snapshot dir, named by its hash.  The per-draw file name that the mesh refers to
+//! (`<prefix>_texture<N>.dds`) is then created as a hard link to the shared file, so existing
+//! mesh files keep working without using extra disk.  If a hard link can't be created the file is
+//! copied instead (and doesn't count as saved).
+//!
+//! Textures whose contents don't change (not render targets or dynamic) can also be looked up by
+//! resource, so that they are only saved the first time they are seen in the session.  The
+//! game may free a texture and create a new one at the same address, so the resource is
+//! identified by its address and creation generation.  Other textures are saved and hashed
+//! every time.
+
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+
+use fnv::{FnvHashMap, FnvHasher};
+
+use shared_dx::error::*;
+
+pub const SHARED_TEXTURE_DIR: &str = "textures";
+
+/// A texture resource, as (address, creation generation).
+pub type ResourceKey = (usize, u64);
+
+#[derive(Default)]
+pub struct TextureDedup {
+    pub unique_count: u32,
+    pub dup_count: u32,
+    pub bytes_saved: u64,
+    /// Shared path of each resource that was stored with a key
+    by_resource: FnvHashMap<ResourceKey, String>,
+}
+
+fn hash_bytes(data:&[u8]) -> String {
+    let mut hasher = FnvHasher::default();
+    hasher.write(data);
+    format!("{:016x}", hasher.finish())
+}
+
+impl TextureDedup {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// If the resource was stored earlier in the session, create `dest` as a link to its shared
+    /// file and return the shared path.  Returns None if it wasn't, then the texture has to be
+    /// saved and passed to `store`.
+    pub fn store_known(&mut self, snap_dir:&str, resource:ResourceKey, dest:&Path) -> Result<Option<String>> {
+        let shared = match self.by_resource.get(&resource) {
+            Some(shared) if PathBuf::from(snap_dir).join(shared).exists() => shared.clone(),
+            _ => return Ok(None),
+        };
+        self.link(snap_dir, &shared, dest)?;
+        Ok(Some(shared))
+    }
+
+    /// `saved` is a texture file that was just written.  Adds it to the shared folder (or
+    /// replaces it with a link to the shared file, if the content is already there).  Returns
+    /// the shared path, relative to `snap_dir`.  If `resource` is set, later saves of the
+    /// resource can use `store_known` instead.  If this fails `saved` is left as it was.
+    pub fn store(&mut self, snap_dir:&str, saved:&Path, resource:Option<ResourceKey>) -> Result<String> {
+        let data = std::fs::read(saved)?;
+        let shared = format!("{}/{}.dds", SHARED_TEXTURE_DIR, hash_bytes(&data));
+        let shared_path = PathBuf::from(snap_dir).join(&shared);
+        if shared_path.exists() {
+            // can't link over the existing file, so link next to it and then replace it
+            let tmp = saved.with_extension("dds.tmp");
+            let _ = std::fs::remove_file(&tmp);
+            self.link(snap_dir, &shared, &tmp)?;
+            std::fs::rename(&tmp, saved).map_err(|e| {
+                let _ = std::fs::remove_file(&tmp);
+                e
+            })?;
+        } else {
+            std::fs::create_dir_all(PathBuf::from(snap_dir).join(SHARED_TEXTURE_DIR))?;
+            Self::link_or_copy(saved, &shared_path)?;
+            self.unique_count += 1;
+        }
+        if let Some(resource) = resource {
+            self.by_resource.insert(resource, shared.clone());
+        }
+        Ok(shared)
+    }
+
+    fn link(&mut self, snap_dir:&str, shared:&str, dest:&Path) -> Result<()> {
+        let shared_path = PathBuf::from(snap_dir).join(shared);
+        if Self::link_or_copy(&shared_path, dest)? {
+            self.bytes_saved += std::fs::metadata(&shared_path).map(|m| m.len()).unwrap_or(0);
+        }
+        self.dup_count += 1;
+        Ok(())
+    }
+
+    /// Returns true if a link was made, false if the file had to be copied.
+    fn link_or_copy(src:&Path, dest:&Path) -> Result<bool> {
+        match std::fs::hard_link(src, dest) {
+            Ok(_) => Ok(true),
+            Err(_) => {
+                std::fs::copy(src, dest)?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let dir = std::env::temp_dir().join("__test_texture_dedup");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("doh");
+        let snap_dir = dir.to_string_lossy().to_string();
+
+        let mut dd = TextureDedup::new();
+        let t0 = dir.join("snap_1_texture0.dds");
+        std::fs::write(&t0, b"diffuse").expect("doh");
+        let shared = dd.store(&snap_dir, &t0, Some((0x1000, 1))).expect("doh");
+        assert!(shared.starts_with("textures/"));
+        assert!(dir.join(&shared).exists());
+        assert_eq!(std::fs::read(&t0).expect("doh"), b"diffuse");
+
+        // same contents on another draw (whatever resource it came from) is linked
+        let t1 = dir.join("snap_2_texture1.dds");
+        std::fs::write(&t1, b"diffuse").expect("doh");
+        assert_eq!(dd.store(&snap_dir, &t1, None).expect("doh"), shared);
+        assert!(!dir.join("snap_2_texture1.dds.tmp").exists());
+        assert_eq!(std::fs::read(&t1).expect("doh"), b"diffuse");
+
+        // changed contents (e.g. a render target drawn to since) get their own file
+        let t2 = dir.join("snap_3_texture0.dds");
+        std::fs::write(&t2, b"diffuse2").expect("doh");
+        let shared2 = dd.store(&snap_dir, &t2, None).expect("doh");
+        assert_ne!(shared2, shared);
+        assert_eq!(std::fs::read(&t2).expect("doh"), b"diffuse2");
+        assert_eq!(std::fs::read(&t0).expect("doh"), b"diffuse");
+
+        assert_eq!(dd.unique_count, 2);
+        assert_eq!(dd.dup_count, 1);
+        assert_eq!(dd.bytes_saved, 7);
+
+        // a resource stored earlier is linked without saving it again
+        let t3 = dir.join("snap_4_texture0.dds");
+        assert_eq!(dd.store_known(&snap_dir, (0x1000, 1), &t3).expect("doh"), Some(shared.clone()));
+        assert_eq!(std::fs::read(&t3).expect("doh"), b"diffuse");
+        assert_eq!(dd.dup_count, 2);
+        // but not a new texture at the same address, or one stored without a key
+        assert_eq!(dd.store_known(&snap_dir, (0x1000, 2), &t3).expect("doh"), None);
+        assert_eq!(dd.store_known(&snap_dir, (0x2000, 1), &t3).expect("doh"), None);
+
+        // if linking fails the saved file is left alone
+        let t4 = dir.join("snap_5_texture0.dds");
+        std::fs::write(&t4, b"diffuse").expect("doh");
+        std::fs::remove_file(dir.join(&shared)).expect("doh");
+        std::fs::create_dir(dir.join(&shared)).expect("doh");
+        assert!(dd.store(&snap_dir, &t4, None).is_err());
+        assert_eq!(std::fs::read(&t4).expect("doh"), b"diffuse");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,113 @@
+//! Combining multiple vertex streams into one buffer for snapshots.
+//!
+//! Some games split the vertex data over several vertex buffers (e.g. positions in one,
+//! normals and uvs in another).  The managed snapshot code only knows how to read a single
+//! interleaved buffer, so the per-vertex streams are interleaved here in slot order and the
+//! input layout is rewritten to match: every element moves to slot 0 and its offset is shifted by
+//! the size of the streams that come before it.
+//!
+//! Mods for those games are filled in the interleaved form, and split back into one buffer per
+//! stream with `deinterleave`.
+
+use shared_dx::error::*;
+
+/// One per-vertex stream bound to the input assembler.
+pub struct VertexStream<'a> {
+    pub slot: u32,
+    /// Entire contents of the bound buffer
+    pub data: &'a [u8],
+    /// Offset of the first vertex in `data`, as passed to IASetVertexBuffers
+    pub offset: usize,
+    /// Distance between vertices in `data`
+    pub stride: usize,
+    /// Bytes of each vertex used by the layout.  Always <= stride.
+    pub size: usize,
+}
+
+/// Returns the offset of each stream in an interleaved vertex, as (slot, offset), and the total
+/// size of the interleaved vertex.
+pub fn stream_offsets(streams:&[VertexStream]) -> (Vec<(u32,u32)>, u32) {
+    let mut base = 0;
+    let offsets = streams.iter().map(|s| {
+        let off = (s.slot, base);
+        base += s.size as u32;
+        off
+    }).collect();
+    (offsets, base)
+}
+
+/// Interleave `num_verts` vertices from each of the streams.  Fails if any stream doesn't have
+/// enough data.
+pub fn interleave(streams:&[VertexStream], num_verts:usize) -> Result<Vec<u8>> {
+    let (_, vert_size) = stream_offsets(streams);
+    for s in streams {
+        if s.size > s.stride {
+            return Err(HookError::SnapshotFailed(format!("stream {} vertex size {} is larger than stride {}",
+                s.slot, s.size, s.stride)));
+        }
+        let needed = if num_verts == 0 { 0 } else { s.offset + (num_verts - 1) * s.stride + s.size };
+        if s.data.len() < needed {
+            return Err(HookError::SnapshotFailed(format!("stream {} too small for {} verts: {} bytes, need {}",
+                s.slot, num_verts, s.data.len(), needed)));
+        }
+    }
+    let mut out = Vec::with_capacity(num_verts * vert_size as usize);
+    for v in 0..num_verts {
+        for s in streams {
+            let start = s.offset + v * s.stride;
+            out.extend_from_slice(&s.data[start..start + s.size]);
+        }
+    }
+    Ok(out)
+}
+
+/// Split interleaved vertices into one buffer per stream; `sizes` is the size of each stream's
+/// part of the vertex, in the order they are interleaved.  Fails if the data isn't a whole number
+/// of vertices.
+pub fn deinterleave(data:&[u8], sizes:&[usize]) -> Result<Vec<Vec<u8>>> {
+    let vert_size:usize = sizes.iter().sum();
+    if vert_size == 0 || data.len() % vert_size != 0 {
+        return Err(HookError::ConversionFailed(format!("can't split {} bytes into vertices of {} bytes",
+            data.len(), vert_size)));
+    }
+    let num_verts = data.len() / vert_size;
+    let mut out:Vec<Vec<u8>> = sizes.iter().map(|size| Vec::with_capacity(size * num_verts)).collect();
+    for vert in data.chunks_exact(vert_size) {
+        let mut start = 0;
+        for (stream, size) in out.iter_mut().zip(sizes.iter()) {
+            stream.extend_from_slice(&vert[start..start + size]);
+            start += size;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        // 3 verts of 4 byte "positions", and 2 byte "uvs" with a 4 byte stride and 4 byte offset
+        let pos:Vec<u8> = (0..12).collect();
+        let uv:Vec<u8> = vec![0xff,0xff,0xff,0xff, 100,101,0,0, 102,103,0,0, 104,105];
+        let streams = [
+            VertexStream { slot: 0, data: &pos, offset: 0, stride: 4, size: 4 },
+            VertexStream { slot: 2, data: &uv, offset: 4, stride: 4, size: 2 },
+        ];
+        let (offsets, size) = stream_offsets(&streams);
+        assert_eq!(offsets, vec![(0,0), (2,4)]);
+        assert_eq!(size, 6);
+        let out = interleave(&streams, 3).expect("doh");
+        assert_eq!(out, vec![0,1,2,3,100,101, 4,5,6,7,102,103, 8,9,10,11,104,105]);
+
+        // not enough data for a 4th vert
+        assert!(interleave(&streams, 4).is_err());
+
+        // splitting gets the packed streams back
+        let split = deinterleave(&out, &[4, 2]).expect("doh");
+        assert_eq!(split, vec![pos, vec![100,101, 102,103, 104,105]]);
+        assert!(deinterleave(&out[1..], &[4, 2]).is_err());
+        assert!(deinterleave(&out, &[]).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Commands that can be run in the hook.  They can come from the keyboard, the IPC channel or a
+//! command file; the sources other than the keyboard refer to commands by name.
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Command {
+    ReloadMods,
+    ToggleShowMods,
+    SelectNextTexture,
+    SelectPrevTexture,
+    ClearTextureLists,
+    TakeSnapshot,
+    NextVariant,
+}
+
+impl Command {
+    pub const ALL: [Command; 7] = [
+        Command::ReloadMods,
+        Command::ToggleShowMods,
+        Command::SelectNextTexture,
+        Command::SelectPrevTexture,
+        Command::ClearTextureLists,
+        Command::TakeSnapshot,
+        Command::NextVariant,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::ReloadMods => "reload_mods",
+            Command::ToggleShowMods => "toggle_show_mods",
+            Command::SelectNextTexture => "select_next_texture",
+            Command::SelectPrevTexture => "select_prev_texture",
+            Command::ClearTextureLists => "clear_texture_lists",
+            Command::TakeSnapshot => "take_snapshot",
+            Command::NextVariant => "next_variant",
+        }
+    }
+
+    /// Case insensitive, and `-` can be used instead of `_`.
+    pub fn from_name(name: &str) -> Option<Command> {
+        let name = name.trim().to_ascii_lowercase().replace('-', "_");
+        Self::ALL.iter().find(|c| c.name() == name).copied()
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parse a list of commands, one name per line.  Blank lines and lines starting with `#` are
+/// ignored.  Returns the commands and a description of each line that isn't a command.
+pub fn parse_command_list(text: &str) -> (Vec<Command>, Vec<String>) {
+    let mut commands = vec![];
+    let mut errors = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Command::from_name(line) {
+            Some(cmd) => commands.push(cmd),
+            None => errors.push(format!("line {}: unknown command '{}'", i + 1, line)),
+        }
+    }
+    (commands, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command_list() {
+        for c in Command::ALL.iter() {
+            assert_eq!(Command::from_name(c.name()), Some(*c));
+        }
+        assert_eq!(Command::from_name(" Take-Snapshot "), Some(Command::TakeSnapshot));
+
+        let (cmds, errors) = parse_command_list(
+            "# select and snap\nselect_next_texture\n\n  take_snapshot\nfrobnicate\r\nnext_variant\n");
+        assert_eq!(cmds, vec![Command::SelectNextTexture, Command::TakeSnapshot, Command::NextVariant]);
+        assert_eq!(errors, vec!["line 5: unknown command 'frobnicate'".to_owned()]);
+    }
+}
@@ -39,12 +39,13 @@ impl ModD3DData9 {
 }
 
 pub struct ModD3DData11 {
-    pub vb: *mut ID3D11Buffer,
+    /// Vertex buffer for each per-vertex input slot of the layout, as (slot, buffer, stride).
+    /// There is more than one if the layout splits the vertex over several streams.
+    pub vbs: Vec<(u32, *mut ID3D11Buffer, u32)>,
     pub vlayout: *mut ID3D11InputLayout,
     pub textures: [*mut ID3D11Texture2D; 4],
     pub has_textures: bool,
     pub srvs: [*mut ID3D11ShaderResourceView; 4],
-    pub vert_size:u32,
     pub vert_count:u32,
 }
 
@@ -53,12 +54,11 @@ impl ModD3DData11 {
         use std::ptr::null_mut;
 
         Self {
-            vb: null_mut(),
+            vbs: vec![],
             vlayout: null_mut(),
             textures: [null_mut(); 4],
             has_textures: false,
             srvs: [null_mut(); 4],
-            vert_size: 0,
             vert_count: 0,
         }
     }
@@ -67,21 +67,21 @@ impl ModD3DData11 {
         use std::ptr::null_mut;
 
         Self {
-            vb: null_mut(),
+            vbs: vec![],
             vlayout: layout,
             textures: [null_mut(); 4],
             has_textures: false,
             srvs: [null_mut(); 4],
-            vert_size: 0,
             vert_count: 0,
         }
     }
 
     pub fn release(&mut self) {
         unsafe {
-            if !self.vb.is_null() {
-                (*self.vb).Release();
-                self.vb = std::ptr::null_mut();
+            for (_slot, vb, _stride) in self.vbs.drain(..) {
+                if !vb.is_null() {
+                    (*vb).Release();
+                }
             }
             if !self.vlayout.is_null() {
                 (*self.vlayout).Release();
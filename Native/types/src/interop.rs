@@ -31,6 +31,8 @@ pub struct ModNumbers {
     pub decl_size_bytes: i32,
     pub vert_size_bytes: i32,
     pub index_elem_size_bytes: i32,
+    /// Geometry fingerprint from the reference, `native_mod::NO_FINGERPRINT` if it doesn't have one.
+    pub ref_fingerprint: u32,
 }
 #[repr(C)]
 #[derive(Copy, Clone)]
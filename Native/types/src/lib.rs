@@ -1,6 +1,13 @@
+// Only `command` is platform neutral; the rest are interop and D3D types.
+#[cfg(windows)]
 pub mod interop;
+#[cfg(windows)]
 pub mod native_mod;
+#[cfg(windows)]
 pub mod d3dx;
+#[cfg(windows)]
 pub mod d3ddata;
+pub mod command;
 
-pub use shared_dx::types::TexPtr;
\ No newline at end of file
+#[cfg(windows)]
+pub use shared_dx::types::TexPtr;
@@ -43,6 +43,8 @@ pub struct NativeModData {
 }
 
 pub const MAX_RECENT_RENDER_USAGE_THRESH:u64 = 500;
+/// Fingerprint value that means "unknown".  It matches every other fingerprint.
+pub const NO_FINGERPRINT:u32 = 0;
 pub const MAX_RECENT_RENDER_PARENT_THRESH:u64 = 150;
 
 impl NativeModData {
@@ -61,6 +63,12 @@ impl NativeModData {
         //https://en.wikipedia.org/wiki/Pairing_function#Cantor_pairing_function
         ((vert_count + prim_count) * (vert_count + prim_count + 1) / 2) + prim_count
     }
+    /// True if the mod can be used for a draw with the given geometry fingerprint.  Mods whose
+    /// reference has no fingerprint match anything, as do draws whose fingerprint is unknown.
+    pub fn fingerprint_matches(&self, fingerprint:u32) -> bool {
+        let ref_fp = self.mod_data.numbers.ref_fingerprint;
+        ref_fp == NO_FINGERPRINT || fingerprint == NO_FINGERPRINT || ref_fp == fingerprint
+    }
     /// True if mod has been used (rendered) recently, as in the past few seconds.  This activity
     /// window is signficantly longer than that of `recently_rendered` so it can be used by
     /// processes that update less frequently.
@@ -89,3 +97,25 @@ impl NativeModData {
     }
 }
 
+fn fnv1a(mut hash:u32, bytes:&[u8]) -> u32 {
+    for b in bytes {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Compute the geometry fingerprint of a draw, which tells apart meshes that have the same mod
+/// key (prim and vert count).  It is a hash of the input layout, given as (semantic name, semantic
+/// index, format) for each element, and the vertex size.  Never returns `NO_FINGERPRINT`.
+pub fn geom_fingerprint<'a, I>(elements:I, vert_size:u32) -> u32
+where I: IntoIterator<Item=(&'a str,u32,u32)> {
+    let mut hash = 0x811c_9dc5;
+    for (name, index, format) in elements {
+        hash = fnv1a(hash, name.to_ascii_lowercase().as_bytes());
+        hash = fnv1a(hash, &index.to_le_bytes());
+        hash = fnv1a(hash, &format.to_le_bytes());
+    }
+    hash = fnv1a(hash, &vert_size.to_le_bytes());
+    if hash == NO_FINGERPRINT { 1 } else { hash }
+}